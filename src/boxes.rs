@@ -0,0 +1,500 @@
+use crate::nal::HevcSps;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+const HEADER_SIZE: usize = 8;
+
+/// The `mean` of the freeform (`----`) metadata items written for
+/// [`crate::ConvertOptions::metadata`].
+pub(crate) const FREEFORM_MEAN: &str = "com.voysys.vraw_convert";
+
+/// Serializes a box of the given type around `payload`.
+pub(crate) fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(&((HEADER_SIZE + payload.len()) as u32).to_be_bytes());
+    bytes.extend_from_slice(box_type);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Serializes a full box, i.e. a box starting with a version and 24 bits of flags.
+pub(crate) fn full_box(box_type: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + payload.len());
+    body.extend_from_slice(&((version as u32) << 24 | (flags & 0x00FF_FFFF)).to_be_bytes());
+    body.extend_from_slice(payload);
+    mp4_box(box_type, &body)
+}
+
+/// Serializes an iTunes style `meta` box (as read by QuickTime, exiftool and ffprobe)
+/// holding the given UTF-8 text tags, e.g. `(*b"\xA9nam", "title")`, followed by freeform
+/// items named after the keys of `freeform`, in the [`FREEFORM_MEAN`] namespace.
+pub(crate) fn ilst_meta(tags: &[([u8; 4], &str)], freeform: &[(&str, &str)]) -> Vec<u8> {
+    let mut hdlr = Vec::new();
+    hdlr.extend_from_slice(&0u32.to_be_bytes()); // pre_defined
+    hdlr.extend_from_slice(b"mdir");
+    hdlr.extend_from_slice(b"appl");
+    hdlr.extend_from_slice(&[0; 8]); // reserved
+    hdlr.push(0); // empty name
+
+    let mut items = Vec::new();
+    for (key, value) in tags {
+        let mut data = Vec::with_capacity(4 + value.len());
+        data.extend_from_slice(&0u32.to_be_bytes()); // locale
+        data.extend_from_slice(value.as_bytes());
+
+        // The flags of the data box hold the value type, 1 is UTF-8 text
+        items.extend_from_slice(&mp4_box(key, &full_box(b"data", 0, 1, &data)));
+    }
+    for (name, value) in freeform {
+        let mut data = Vec::with_capacity(4 + value.len());
+        data.extend_from_slice(&0u32.to_be_bytes()); // locale
+        data.extend_from_slice(value.as_bytes());
+
+        let mut item = full_box(b"mean", 0, 0, FREEFORM_MEAN.as_bytes());
+        item.extend_from_slice(&full_box(b"name", 0, 0, name.as_bytes()));
+        item.extend_from_slice(&full_box(b"data", 0, 1, &data));
+        items.extend_from_slice(&mp4_box(b"----", &item));
+    }
+
+    let mut meta = full_box(b"hdlr", 0, 0, &hdlr);
+    meta.extend_from_slice(&mp4_box(b"ilst", &items));
+    full_box(b"meta", 0, 0, &meta)
+}
+
+/// Reads the freeform items in the [`FREEFORM_MEAN`] namespace from an `ilst` box
+/// (including its header), as written by [`ilst_meta`]. Items that are not UTF-8 text are
+/// left out.
+pub(crate) fn read_freeform_tags(ilst: &[u8]) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+
+    for (box_type, item) in children(ilst.get(HEADER_SIZE..).unwrap_or_default()) {
+        if box_type != b"----" {
+            continue;
+        }
+
+        // The children are full boxes, and data has a locale after the flags
+        let (mut mean, mut name, mut value) = (None, None, None);
+        for (box_type, payload) in children(item) {
+            match box_type {
+                b"mean" => mean = payload.get(4..),
+                b"name" => name = payload.get(4..),
+                b"data" if payload.get(..4) == Some(&[0, 0, 0, 1]) => value = payload.get(8..),
+                _ => {}
+            }
+        }
+
+        if mean != Some(FREEFORM_MEAN.as_bytes()) {
+            continue;
+        }
+        if let (Some(Ok(name)), Some(Ok(value))) = (
+            name.map(std::str::from_utf8),
+            value.map(std::str::from_utf8),
+        ) {
+            tags.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    tags
+}
+
+/// Splits the payload of a box into the types and payloads of its child boxes, up to the
+/// first box whose size does not fit.
+fn children(mut bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32::from_be_bytes(bytes.get(..4)?.try_into().unwrap()) as usize;
+        if size < HEADER_SIZE || size > bytes.len() {
+            return None;
+        }
+
+        let (child, rest) = bytes.split_at(size);
+        bytes = rest;
+        Some((&child[4..HEADER_SIZE], &child[HEADER_SIZE..]))
+    })
+}
+
+/// Serializes a Nero chapter list (`chpl`) box from chapter start times in 100 ns units
+/// and titles. The box holds at most 255 chapters with titles of at most 255 bytes, the
+/// rest is cut off.
+pub(crate) fn chpl(chapters: &[(u64, &str)]) -> Vec<u8> {
+    let chapters = &chapters[..chapters.len().min(255)];
+
+    let mut payload = vec![0; 4]; // reserved
+    payload.push(chapters.len() as u8);
+    for (start, title) in chapters {
+        let mut end = title.len().min(255);
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        payload.extend_from_slice(&start.to_be_bytes());
+        payload.push(end as u8);
+        payload.extend_from_slice(&title.as_bytes()[..end]);
+    }
+
+    full_box(b"chpl", 1, 0, &payload)
+}
+
+/// Serializes a sample group description (`sgpd`) box and a sample to group (`sbgp`) box
+/// of `grouping_type`. The descriptions all have the same length, and `groups` holds the
+/// 1-based index into `descriptions` of every sample, 0 for samples in no group.
+pub(crate) fn sample_group(
+    grouping_type: &[u8; 4],
+    descriptions: &[&[u8]],
+    groups: &[u32],
+) -> Vec<u8> {
+    let mut sgpd = grouping_type.to_vec();
+    let default_length = descriptions.first().map_or(0, |entry| entry.len());
+    sgpd.extend_from_slice(&(default_length as u32).to_be_bytes());
+    sgpd.extend_from_slice(&(descriptions.len() as u32).to_be_bytes());
+    for entry in descriptions {
+        sgpd.extend_from_slice(entry);
+    }
+
+    // Runs of consecutive samples in the same group
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &group in groups {
+        match runs.last_mut() {
+            Some((count, last)) if *last == group => *count += 1,
+            _ => runs.push((1, group)),
+        }
+    }
+
+    let mut sbgp = grouping_type.to_vec();
+    sbgp.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+    for (count, group) in runs {
+        sbgp.extend_from_slice(&count.to_be_bytes());
+        sbgp.extend_from_slice(&group.to_be_bytes());
+    }
+
+    let mut boxes = full_box(b"sgpd", 1, 0, &sgpd);
+    boxes.extend_from_slice(&full_box(b"sbgp", 0, 0, &sbgp));
+    boxes
+}
+
+/// Serializes an `hvcC` box (HEVCDecoderConfigurationRecord) for the stream described by
+/// `sps`, holding the given VPS, SPS and PPS NAL units.
+pub(crate) fn hvcc(sps: &HevcSps, parameter_sets: &[&[u8]]) -> Vec<u8> {
+    let mut record = vec![1]; // configurationVersion
+    record.extend_from_slice(&sps.profile_tier_level);
+    record.extend_from_slice(&0xF000u16.to_be_bytes()); // min_spatial_segmentation_idc
+    record.push(0xFC); // parallelismType
+    record.push(0xFC | sps.chroma_format_idc);
+    record.push(0xF8 | sps.bit_depth_luma_minus8);
+    record.push(0xF8 | sps.bit_depth_chroma_minus8);
+    record.extend_from_slice(&0u16.to_be_bytes()); // avgFrameRate
+    let length_size_minus_one = 3; // 4 byte NAL lengths
+    record.push(
+        (sps.max_sub_layers_minus1 + 1) << 3
+            | (sps.temporal_id_nesting as u8) << 2
+            | length_size_minus_one,
+    );
+
+    // The parameter sets may be repeated in the stream, so the arrays are not complete
+    record.push(parameter_sets.len() as u8);
+    for nal in parameter_sets {
+        record.push(crate::nal::h265_nal_type(nal));
+        record.extend_from_slice(&1u16.to_be_bytes());
+        record.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        record.extend_from_slice(nal);
+    }
+
+    mp4_box(b"hvcC", &record)
+}
+
+/// The frame rate of a timecode track, see [`timecode_trak`].
+pub(crate) struct TimecodeRate {
+    /// Ticks per second of the track.
+    pub(crate) timescale: u32,
+    /// Ticks per frame.
+    pub(crate) frame_duration: u32,
+    /// Frames per second of the timecode, i.e. the frame rate rounded to a whole number.
+    pub(crate) frames_per_second: u8,
+}
+
+/// Serializes the `trak` box of a QuickTime timecode track whose single sample (the frame
+/// count the timecode starts at) is at `sample_offset` in the file, lasting `duration` in
+/// the movie timescale `movie_timescale`.
+pub(crate) fn timecode_trak(
+    track_id: u32,
+    rate: &TimecodeRate,
+    movie_timescale: u32,
+    duration: u64,
+    sample_offset: u64,
+) -> Vec<u8> {
+    let media_duration = duration * rate.timescale as u64 / movie_timescale.max(1) as u64;
+    let u32_or_max = |value: u64| u32::try_from(value).unwrap_or(u32::MAX);
+
+    let mut tkhd = vec![0; 8]; // creation_time and modification_time
+    tkhd.extend_from_slice(&track_id.to_be_bytes());
+    tkhd.extend_from_slice(&[0; 4]); // reserved
+    tkhd.extend_from_slice(&u32_or_max(duration).to_be_bytes());
+    tkhd.extend_from_slice(&[0; 16]); // reserved, layer, alternate_group, volume, reserved
+    for value in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        tkhd.extend_from_slice(&value.to_be_bytes()); // unity matrix
+    }
+    tkhd.extend_from_slice(&[0; 8]); // width and height
+    let tkhd = full_box(b"tkhd", 0, 0x3, &tkhd); // enabled and in movie
+
+    let mut mdhd = vec![0; 8]; // creation_time and modification_time
+    mdhd.extend_from_slice(&rate.timescale.to_be_bytes());
+    mdhd.extend_from_slice(&u32_or_max(media_duration).to_be_bytes());
+    mdhd.extend_from_slice(&0x55C4u16.to_be_bytes()); // "und"
+    mdhd.extend_from_slice(&[0; 2]); // pre_defined
+
+    let mut hdlr = b"mhlr".to_vec(); // QuickTime component type
+    hdlr.extend_from_slice(b"tmcd");
+    hdlr.extend_from_slice(&[0; 12]); // reserved
+    hdlr.extend_from_slice(b"TimeCodeHandler\0");
+
+    let mut gmin = 0x40u16.to_be_bytes().to_vec(); // graphics mode: dither copy
+    for value in [0x8000u16, 0x8000, 0x8000, 0, 0] {
+        gmin.extend_from_slice(&value.to_be_bytes()); // opcolor, balance, reserved
+    }
+    let mut tcmi = [0u16, 0, 12, 0, 0xFFFF, 0xFFFF, 0xFFFF, 0, 0, 0]
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect::<Vec<u8>>(); // font, face, size, reserved, text and background color
+    tcmi.push(0); // empty font name
+    let mut gmhd = full_box(b"gmin", 0, 0, &gmin);
+    gmhd.extend_from_slice(&mp4_box(b"tmcd", &full_box(b"tcmi", 0, 0, &tcmi)));
+
+    let mut dref = 1u32.to_be_bytes().to_vec();
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[])); // in this file
+
+    let mut sample_entry = vec![0; 6]; // reserved
+    sample_entry.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+    sample_entry.extend_from_slice(&[0; 4]); // reserved
+    sample_entry.extend_from_slice(&0x2u32.to_be_bytes()); // flags: wraps at 24 hours
+    sample_entry.extend_from_slice(&rate.timescale.to_be_bytes());
+    sample_entry.extend_from_slice(&rate.frame_duration.to_be_bytes());
+    sample_entry.push(rate.frames_per_second);
+    sample_entry.push(0); // reserved
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend_from_slice(&mp4_box(b"tmcd", &sample_entry));
+
+    let table = |values: &[u32]| -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    };
+    let mut stbl = full_box(b"stsd", 0, 0, &stsd);
+    stbl.extend_from_slice(&full_box(
+        b"stts",
+        0,
+        0,
+        &table(&[1, 1, u32_or_max(media_duration)]),
+    ));
+    stbl.extend_from_slice(&full_box(b"stsc", 0, 0, &table(&[1, 1, 1, 1])));
+    stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &table(&[4, 1])));
+    match u32::try_from(sample_offset) {
+        Ok(offset) => stbl.extend_from_slice(&full_box(b"stco", 0, 0, &table(&[1, offset]))),
+        Err(_) => {
+            let mut co64 = 1u32.to_be_bytes().to_vec();
+            co64.extend_from_slice(&sample_offset.to_be_bytes());
+            stbl.extend_from_slice(&full_box(b"co64", 0, 0, &co64));
+        }
+    }
+
+    let mut minf = mp4_box(b"gmhd", &gmhd);
+    minf.extend_from_slice(&mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref)));
+    minf.extend_from_slice(&mp4_box(b"stbl", &stbl));
+
+    let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+    mdia.extend_from_slice(&full_box(b"hdlr", 0, 0, &hdlr));
+    mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+    let mut trak = tkhd;
+    trak.extend_from_slice(&mp4_box(b"mdia", &mdia));
+    mp4_box(b"trak", &trak)
+}
+
+/// The `moov` box of a finished mp4 file, for adding the boxes that the mp4 crate
+/// does not know how to write.
+pub(crate) struct Moov {
+    offset: u64,
+    bytes: Vec<u8>,
+}
+
+impl Moov {
+    /// Reads the `moov` box, which has to be the last box in the file since it is
+    /// rewritten in place.
+    pub(crate) fn read<R: Read + Seek>(file: &mut R) -> io::Result<Self> {
+        let moov = Self::read_anywhere(file)?;
+
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if moov.offset + moov.bytes.len() as u64 != file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "moov is not the last box",
+            ));
+        }
+
+        Ok(moov)
+    }
+
+    /// Reads the `moov` box wherever it is in the file, only to look at it.
+    pub(crate) fn read_anywhere<R: Read + Seek>(file: &mut R) -> io::Result<Self> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+
+        let mut offset = 0;
+        while offset < file_size {
+            file.seek(SeekFrom::Start(offset))?;
+
+            let mut header = [0; HEADER_SIZE];
+            file.read_exact(&mut header)?;
+            let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => file_size - offset,
+                1 => {
+                    let mut large_size = [0; 8];
+                    file.read_exact(&mut large_size)?;
+                    u64::from_be_bytes(large_size)
+                }
+                size => size as u64,
+            };
+
+            if size < HEADER_SIZE as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid box size",
+                ));
+            }
+
+            if &header[4..] == b"moov" {
+                let mut bytes = vec![0; size as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut bytes)?;
+
+                return Ok(Moov { offset, bytes });
+            }
+
+            offset += size;
+        }
+
+        Err(io::Error::new(io::ErrorKind::InvalidData, "no moov box"))
+    }
+
+    /// Appends `child` to the box at `path` below `moov`, creating the boxes along the
+    /// path that do not exist yet. Where a path element matches several boxes the first
+    /// one is used.
+    pub(crate) fn append(&mut self, path: &[&[u8; 4]], child: &[u8]) {
+        // Start offsets of the boxes from moov down to the current one
+        let mut ancestors = vec![0];
+
+        for box_type in path {
+            let parent = *ancestors.last().unwrap();
+
+            let position = match self.find_child(parent, box_type) {
+                Some(position) => position,
+                None => {
+                    let position = parent + self.size(parent);
+                    self.splice(&ancestors, position..position, &mp4_box(box_type, &[]));
+                    position
+                }
+            };
+
+            ancestors.push(position);
+        }
+
+        let parent = *ancestors.last().unwrap();
+        let position = parent + self.size(parent);
+        self.splice(&ancestors, position..position, child);
+    }
+
+    /// Returns the box at `path` below `moov`, including its header.
+    pub(crate) fn get(&self, path: &[&[u8; 4]]) -> Option<&[u8]> {
+        let position = *self.find(path)?.last().unwrap();
+        Some(&self.bytes[position..position + self.size(position)])
+    }
+
+    /// Replaces the box at `path` below `moov` with `bytes`. Returns false if there is no
+    /// such box.
+    pub(crate) fn replace(&mut self, path: &[&[u8; 4]], bytes: &[u8]) -> bool {
+        let Some(mut ancestors) = self.find(path) else {
+            return false;
+        };
+
+        let position = ancestors.pop().unwrap();
+        let size = self.size(position);
+        self.splice(&ancestors, position..position + size, bytes);
+        true
+    }
+
+    /// Writes `bytes` where the `moov` box starts in the file, so that [`Moov::write`]
+    /// writes the box after them. Returns the offset `bytes` are written at.
+    pub(crate) fn insert_before<W: Write + Seek>(
+        &mut self,
+        file: &mut W,
+        bytes: &[u8],
+    ) -> io::Result<u64> {
+        let offset = self.offset;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+
+        Ok(offset)
+    }
+
+    /// Writes the `moov` box back to where it was read from, or after what
+    /// [`Moov::insert_before`] wrote there. The box is the last one in the file, so if it
+    /// shrunk the file has to be truncated after it.
+    pub(crate) fn write<W: Write + Seek>(&self, file: &mut W) -> io::Result<()> {
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.write_all(&self.bytes)
+    }
+
+    /// The serialized `moov` box.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn size(&self, position: usize) -> usize {
+        let bytes = &self.bytes[position..position + 4];
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+    }
+
+    /// Start offsets of the boxes from moov down to the box at `path`.
+    fn find(&self, path: &[&[u8; 4]]) -> Option<Vec<usize>> {
+        let mut positions = vec![0];
+        for box_type in path {
+            positions.push(self.find_child(*positions.last().unwrap(), box_type)?);
+        }
+
+        Some(positions)
+    }
+
+    fn find_child(&self, parent: usize, box_type: &[u8; 4]) -> Option<usize> {
+        let end = parent + self.size(parent);
+
+        // Some boxes have fields before their children
+        let fields = match &self.bytes[parent + 4..parent + HEADER_SIZE] {
+            b"meta" => 4,
+            b"stsd" => 8,
+            b"avc1" | b"hev1" | b"hvc1" => 78,
+            _ => 0,
+        };
+
+        let mut position = parent + HEADER_SIZE + fields;
+        while position + HEADER_SIZE <= end {
+            if &self.bytes[position + 4..position + HEADER_SIZE] == box_type {
+                return Some(position);
+            }
+
+            position += self.size(position).max(HEADER_SIZE);
+        }
+
+        None
+    }
+
+    /// Replaces `range` with `bytes` and resizes the boxes starting at `ancestors` to match.
+    fn splice(&mut self, ancestors: &[usize], range: Range<usize>, bytes: &[u8]) {
+        let removed = range.len();
+        self.bytes.splice(range, bytes.iter().copied());
+
+        for &ancestor in ancestors {
+            let size = (self.size(ancestor) + bytes.len() - removed) as u32;
+            self.bytes[ancestor..ancestor + 4].copy_from_slice(&size.to_be_bytes());
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::error::ConvertError;
+use crate::processing::{convert_vraw_to_mp4_with_options, ConvertOptions, ConvertSummary};
+use crate::session::Session;
+use crate::source::VrawFrameSource;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+
+/// An event of a conversion run by [`convert_vraw_to_mp4_channel`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// `frames_read` of the `total_frames` frames of the index (of any format) have been
+    /// read, see [`ConvertOptions::on_progress`].
+    Progress {
+        frames_read: usize,
+        total_frames: usize,
+    },
+
+    /// The conversion succeeded, its [`ConvertSummary`] is returned by the thread.
+    Finished { samples_written: usize },
+
+    /// The conversion failed with this error, which is returned by the thread.
+    Failed(String),
+}
+
+/// Converts a .vraw file on a new thread, for a UI that must not block on the conversion
+/// or run a callback on the converting thread. The events of the conversion arrive on the
+/// receiver, the last one being [`ProgressEvent::Finished`] or [`ProgressEvent::Failed`],
+/// after which the channel is closed. Joining the thread returns the result.
+///
+/// Options borrowing callbacks cannot be sent to another thread, so `configure` is called
+/// on the converting thread to set the [`ConvertOptions`], e.g. `|_| {}` for the default
+/// ones. [`ConvertOptions::on_progress`] is taken by the channel.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .mp4 file to create, named after the input if None (see
+/// [`crate::convert_vraw_to_mp4`])
+pub fn convert_vraw_to_mp4_channel<F>(
+    input: String,
+    output: Option<String>,
+    configure: F,
+) -> (
+    JoinHandle<Result<ConvertSummary, ConvertError>>,
+    Receiver<ProgressEvent>,
+)
+where
+    F: FnOnce(&mut ConvertOptions) + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        // The receiver may be gone already, which only means nobody is listening
+        let total_frames = Cell::new(0);
+        let mut on_progress = |frames_read| {
+            let _ = sender.send(ProgressEvent::Progress {
+                frames_read,
+                total_frames: total_frames.get(),
+            });
+        };
+
+        let mut options = ConvertOptions::default();
+        configure(&mut options);
+
+        let result = match index_len(&input, options.session) {
+            Ok(len) => {
+                total_frames.set(len);
+                options.on_progress = Some(&mut on_progress);
+                convert_vraw_to_mp4_with_options(&input, output, &mut options)
+            }
+            Err(e) => Err(e),
+        };
+
+        let _ = sender.send(match &result {
+            Ok(summary) => ProgressEvent::Finished {
+                samples_written: summary.samples_written,
+            },
+            Err(e) => ProgressEvent::Failed(e.to_string()),
+        });
+
+        result
+    });
+
+    (handle, receiver)
+}
+
+/// The number of frames in the index of the given sessions of a .vraw file.
+fn index_len(input: &str, session: Session) -> Result<usize, ConvertError> {
+    let source = VrawFrameSource::with_session(BufReader::new(File::open(input)?), session)?;
+
+    Ok(source.len())
+}
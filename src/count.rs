@@ -0,0 +1,86 @@
+use crate::error::ConvertError;
+use crate::parser::{read_frame_meta, read_index, VideoCaptureFormat};
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+
+/// Frame counts of a recording, see [`count_frames`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameCounts {
+    /// The format of the first video frame, [`VideoCaptureFormat::Stats`] if there is none.
+    pub codec: VideoCaptureFormat,
+
+    /// Number of frames in `codec`, i.e. the frames that end up in a converted file.
+    pub video: usize,
+
+    /// Number of [`VideoCaptureFormat::Stats`] frames.
+    pub stats: usize,
+
+    /// Number of frames in any other format.
+    pub other: usize,
+
+    /// Number of frames per format, in order of first appearance.
+    pub per_format: Vec<(VideoCaptureFormat, usize)>,
+
+    /// Time between the first and the last video frame.
+    pub duration: Duration,
+}
+
+/// Counts the frames of a .vraw file by reading only the index and the frame headers,
+/// without reading any payload.
+///
+/// input: path to .vraw file
+pub fn count_frames(input: &str) -> Result<FrameCounts, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    let mut counts = FrameCounts {
+        codec: VideoCaptureFormat::Stats,
+        video: 0,
+        stats: 0,
+        other: 0,
+        per_format: Vec::new(),
+        duration: Duration::ZERO,
+    };
+    let mut first_timestamp = None;
+    let mut last_timestamp = 0;
+
+    for entry in read_index(&mut f)? {
+        let meta = read_frame_meta(&mut f, &entry)?;
+        let format = meta.format;
+
+        match counts
+            .per_format
+            .iter_mut()
+            .find(|(counted, _)| *counted == format)
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.per_format.push((format, 1)),
+        }
+
+        if format == VideoCaptureFormat::Stats {
+            counts.stats += 1;
+            continue;
+        }
+
+        if counts.codec == VideoCaptureFormat::Stats {
+            counts.codec = format;
+        }
+
+        if format == counts.codec {
+            counts.video += 1;
+
+            let timestamp = meta.timestamp;
+            first_timestamp.get_or_insert(timestamp);
+            last_timestamp = timestamp;
+        } else {
+            counts.other += 1;
+        }
+    }
+
+    if let Some(first_timestamp) = first_timestamp {
+        counts.duration =
+            Duration::from_nanos(last_timestamp.saturating_sub(first_timestamp).max(0) as u64);
+    }
+
+    Ok(counts)
+}
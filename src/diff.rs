@@ -0,0 +1,96 @@
+use crate::error::ConvertError;
+use crate::parser::{read_frame_meta, read_index, VideoCaptureFormat};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufReader;
+
+/// How the indexes of two recordings differ, see [`diff_indexes`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IndexDiff {
+    /// Number of frames in the index of the first recording.
+    pub frames_a: usize,
+
+    /// Number of frames in the index of the second recording.
+    pub frames_b: usize,
+
+    /// Position of the first frame whose format or relative timestamp differs, or of the
+    /// first frame only one of the recordings has. None if the indexes agree.
+    pub first_difference: Option<usize>,
+
+    /// For every frame both recordings have, how much later it comes in the second one
+    /// than in the first, in nanoseconds. The timestamps are taken relative to the first
+    /// frame of each recording, so two captures started at different times do not differ.
+    pub timestamp_deltas: Vec<i64>,
+
+    /// The frames whose format differs.
+    pub format_mismatches: Vec<FormatMismatch>,
+}
+
+/// A frame of an [`IndexDiff`] whose format differs between the recordings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FormatMismatch {
+    /// Position of the frame in the index.
+    pub index: usize,
+
+    pub format_a: VideoCaptureFormat,
+    pub format_b: VideoCaptureFormat,
+}
+
+/// Compares the indexes of two .vraw files frame by frame, e.g. two captures of the same
+/// scene to see where a firmware change makes them diverge. Only the indexes and the frame
+/// headers (for the formats) are read, no payload.
+///
+/// a, b: paths to the .vraw files
+pub fn diff_indexes(a: &str, b: &str) -> Result<IndexDiff, ConvertError> {
+    let frames_a = read_frames(a)?;
+    let frames_b = read_frames(b)?;
+
+    let first_a = frames_a.first().map_or(0, |(_, timestamp)| *timestamp);
+    let first_b = frames_b.first().map_or(0, |(_, timestamp)| *timestamp);
+
+    let mut diff = IndexDiff {
+        frames_a: frames_a.len(),
+        frames_b: frames_b.len(),
+        first_difference: None,
+        timestamp_deltas: Vec::new(),
+        format_mismatches: Vec::new(),
+    };
+
+    for (index, ((format_a, timestamp_a), (format_b, timestamp_b))) in
+        frames_a.iter().zip(&frames_b).enumerate()
+    {
+        let delta = (timestamp_b - first_b) - (timestamp_a - first_a);
+        diff.timestamp_deltas.push(delta);
+
+        if format_a != format_b {
+            diff.format_mismatches.push(FormatMismatch {
+                index,
+                format_a: *format_a,
+                format_b: *format_b,
+            });
+        }
+
+        if diff.first_difference.is_none() && (delta != 0 || format_a != format_b) {
+            diff.first_difference = Some(index);
+        }
+    }
+
+    if diff.first_difference.is_none() && frames_a.len() != frames_b.len() {
+        diff.first_difference = Some(frames_a.len().min(frames_b.len()));
+    }
+
+    Ok(diff)
+}
+
+/// The format and index timestamp of every frame of a recording.
+fn read_frames(input: &str) -> Result<Vec<(VideoCaptureFormat, i64)>, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    let mut frames = Vec::new();
+    for entry in read_index(&mut f)? {
+        let meta = read_frame_meta(&mut f, &entry)?;
+        frames.push((meta.format, entry.receive_timestamp.get()));
+    }
+
+    Ok(frames)
+}
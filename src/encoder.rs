@@ -0,0 +1,212 @@
+use crate::error::ConvertError;
+use crate::parser::{
+    read_frame_record, read_index, read_recording_metadata, RecordingIndexEntry,
+    RecordingIndexFooter, RecordingIndexHeader, I64, RECORDING_INDEX_FOOTER_MAGIC,
+    RECORDING_INDEX_HEADER_MAGIC, U32,
+};
+#[cfg(any(test, feature = "fixtures"))]
+use crate::parser::{
+    GenericMetadataHeader, RecordedFrameMetadata, RecordingMetadata, GENERIC_METADATA_FOOTER_MAGIC,
+    GENERIC_METADATA_HEADER_MAGIC, I32, RECORDING_FRAME_MAGIC, RECORDING_MAGIC, U64,
+};
+#[cfg(any(test, feature = "fixtures"))]
+use crate::VideoCaptureFormat;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+#[cfg(any(test, feature = "fixtures"))]
+use std::mem::size_of;
+use zerocopy::AsBytes;
+
+/// Description of a single frame for [`build_vraw`].
+#[cfg(any(test, feature = "fixtures"))]
+#[derive(Debug, Clone)]
+pub struct FrameSpec {
+    pub format: VideoCaptureFormat,
+    /// Width in pixels, must be positive for uncompressed formats and 0 for coded ones.
+    pub width: u32,
+    /// Height in pixels, must be positive for uncompressed formats and 0 for coded ones.
+    pub height: u32,
+    /// Receive timestamp in nanoseconds.
+    pub timestamp: i64,
+    pub payload: Vec<u8>,
+}
+
+#[cfg(any(test, feature = "fixtures"))]
+impl FrameSpec {
+    /// A frame without dimensions, as used for coded and Stats frames.
+    pub fn new(format: VideoCaptureFormat, timestamp: i64, payload: Vec<u8>) -> Self {
+        FrameSpec {
+            format,
+            width: 0,
+            height: 0,
+            timestamp,
+            payload,
+        }
+    }
+}
+
+/// Builds a complete .vraw recording in memory: the recording header, every frame with
+/// its (empty) generic metadata and the index.
+///
+/// Payloads are written as is, so no video placement data is added.
+#[cfg(any(test, feature = "fixtures"))]
+pub fn build_vraw(frames: &[FrameSpec]) -> Vec<u8> {
+    build_vraw_sessions(&[frames])
+}
+
+/// Builds a .vraw recording of several sessions, as written by a recorder appending to an
+/// existing file: each session is a complete recording as built by [`build_vraw`], with
+/// its frame offsets counted from the start of the file.
+#[cfg(any(test, feature = "fixtures"))]
+pub fn build_vraw_sessions(sessions: &[&[FrameSpec]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for frames in sessions {
+        append_session(&mut bytes, frames);
+    }
+
+    bytes
+}
+
+/// Builds a .vraw recording like [`build_vraw`], but with the index block right after the
+/// recording header instead of at the end, as written by recorders that reserve room for
+/// the index up front.
+#[cfg(any(test, feature = "fixtures"))]
+pub fn build_vraw_header_indexed(frames: &[FrameSpec]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    append_recording_metadata(&mut bytes);
+
+    // The frame offsets depend on the size of the index, which is known up front
+    let index_start = bytes.len();
+    let index_size = size_of::<RecordingIndexHeader>()
+        + frames.len() * size_of::<RecordingIndexEntry>()
+        + size_of::<RecordingIndexFooter>();
+    bytes.resize(index_start + index_size, 0);
+
+    let index = append_frames(&mut bytes, frames);
+    let mut index_block = Vec::with_capacity(index_size);
+    write_index(&mut index_block, &index).unwrap(); // Writing to a Vec cannot fail
+    bytes[index_start..index_start + index_size].copy_from_slice(&index_block);
+
+    bytes
+}
+
+/// Appends a recording header, the frames and their index to `bytes`.
+#[cfg(any(test, feature = "fixtures"))]
+fn append_session(bytes: &mut Vec<u8>, frames: &[FrameSpec]) {
+    append_recording_metadata(bytes);
+    let index = append_frames(bytes, frames);
+
+    write_index(bytes, &index).unwrap(); // Writing to a Vec cannot fail
+}
+
+#[cfg(any(test, feature = "fixtures"))]
+fn append_recording_metadata(bytes: &mut Vec<u8>) {
+    let recording_metadata = RecordingMetadata {
+        magic: U32::new(RECORDING_MAGIC),
+        unix_epoch_time_relative_nsec: U32::new(0),
+        unix_epoch_time_sec: U64::new(0),
+    };
+    bytes.extend_from_slice(recording_metadata.as_bytes());
+}
+
+/// Appends the frames with their (empty) generic metadata to `bytes` and returns their
+/// index entries.
+#[cfg(any(test, feature = "fixtures"))]
+fn append_frames(bytes: &mut Vec<u8>, frames: &[FrameSpec]) -> Vec<RecordingIndexEntry> {
+    let mut index = Vec::with_capacity(frames.len());
+    for frame in frames {
+        index.push(RecordingIndexEntry {
+            offset: I64::new(bytes.len() as i64),
+            receive_timestamp: I64::new(frame.timestamp),
+        });
+
+        let recorded_frame_metadata = RecordedFrameMetadata {
+            magic: U32::new(RECORDING_FRAME_MAGIC),
+            id: I32::new(1),
+            padding: I32::new(0),
+            width: I32::new(frame.width as i32),
+            height: I32::new(frame.height as i32),
+            format: I32::new(frame.format.code()),
+            timestamp: I64::new(0),
+            receive_timestamp: I64::new(frame.timestamp),
+            size: I64::new(frame.payload.len() as i64),
+        };
+        bytes.extend_from_slice(recorded_frame_metadata.as_bytes());
+        bytes.extend_from_slice(&frame.payload);
+
+        // The generic metadata footer has the same layout as the header
+        for magic in [GENERIC_METADATA_HEADER_MAGIC, GENERIC_METADATA_FOOTER_MAGIC] {
+            let generic_metadata = GenericMetadataHeader {
+                magic: U32::new(magic),
+                generic_metadata_size: U32::new(0),
+            };
+            bytes.extend_from_slice(generic_metadata.as_bytes());
+        }
+    }
+
+    index
+}
+
+/// Copies a .vraw file, replacing the receive timestamp of every frame (in the frame header
+/// and in the index) with `remap(frame_index, timestamp)`. Everything else, including the
+/// capture timestamp and the generic metadata of the frames, is copied unchanged.
+///
+/// The frames are written in index order, followed by a rebuilt index.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .vraw file to create, must differ from input.
+pub fn rewrite_vraw(
+    input: &str,
+    output: &str,
+    remap: impl Fn(usize, u64) -> u64,
+) -> Result<(), ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+    let mut out = BufWriter::new(File::create(output)?);
+
+    let recording_metadata = read_recording_metadata(&mut f)?;
+    out.write_all(recording_metadata.as_bytes())?;
+    let mut offset = recording_metadata.as_bytes().len() as u64;
+
+    let entries = read_index(&mut f)?;
+    let mut index = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let (mut recorded_frame_metadata, record) = read_frame_record(&mut f, entry)?;
+
+        let timestamp = remap(i, recorded_frame_metadata.receive_timestamp.get() as u64) as i64;
+        recorded_frame_metadata.receive_timestamp = I64::new(timestamp);
+
+        index.push(RecordingIndexEntry {
+            offset: I64::new(offset as i64),
+            receive_timestamp: I64::new(timestamp),
+        });
+
+        out.write_all(recorded_frame_metadata.as_bytes())?;
+        out.write_all(&record)?;
+        offset += (recorded_frame_metadata.as_bytes().len() + record.len()) as u64;
+    }
+
+    write_index(&mut out, &index)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Writes the index block that ends a recording.
+fn write_index<W: Write>(out: &mut W, index: &[RecordingIndexEntry]) -> std::io::Result<()> {
+    let index_header = RecordingIndexHeader {
+        magic: U32::new(RECORDING_INDEX_HEADER_MAGIC),
+        padding: U32::new(0),
+    };
+    out.write_all(index_header.as_bytes())?;
+
+    for entry in index {
+        out.write_all(entry.as_bytes())?;
+    }
+
+    let index_footer = RecordingIndexFooter {
+        magic: U32::new(RECORDING_INDEX_FOOTER_MAGIC),
+        frame_count: U32::new(index.len() as u32),
+    };
+    out.write_all(index_footer.as_bytes())
+}
@@ -0,0 +1,82 @@
+use crate::parser::{ParseError, VideoCaptureFormat};
+use std::io;
+
+/// Errors that can occur while inspecting or converting a .vraw recording.
+///
+/// The underlying error, if any, is available through [`std::error::Error::source`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConvertError {
+    /// Opening, reading or writing a file failed.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The recording could not be parsed.
+    #[error("Failed to read recording: {0}")]
+    Parse(#[from] ParseError),
+    /// Writing the mp4 file failed.
+    #[error("Failed to write mp4: {0}")]
+    Mp4(#[from] mp4::Error),
+    /// The index of the recording lists no frames.
+    #[error("Index contains no frames")]
+    NoFrames,
+    /// The video frames are in a format that cannot be written to an mp4 file.
+    #[error("Video capture format {0:?} not supported")]
+    UnsupportedFormat(VideoCaptureFormat),
+    /// The NAL units of the first video frame do not match its format, the recording is
+    /// mislabeled or corrupt.
+    #[error("First video frame is not valid {0:?}")]
+    CodecValidationFailed(VideoCaptureFormat),
+    /// The input file is compressed (e.g. a `.vraw.zst` archive). Recordings have to be
+    /// decompressed first, possibly into memory for [`crate::convert_vraw_reader`].
+    #[error("Input is {0} compressed, decompress it first")]
+    CompressedInput(&'static str),
+    /// The parameter sets of the stream change to a different picture size at the video
+    /// frame with this number (counting from 0). An mp4 track would need a second sample
+    /// description from there on, which is not supported.
+    #[error("Picture size changes at video frame {at_frame}, which is not supported")]
+    ResolutionChanged { at_frame: usize },
+    /// The timestamps of the index span this many milliseconds over this many frames, a
+    /// frame rate that suggests they are not in nanoseconds. Only returned with
+    /// [`crate::ConvertOptions::strict_timestamps`], a warning otherwise.
+    #[error(
+        "Timestamps span {span_ms} ms over {frames} frames, check that they are in nanoseconds"
+    )]
+    ImplausibleTimestamps { span_ms: i64, frames: usize },
+    /// With [`crate::ConvertOptions::preserve_timestamps`], the sample with this number
+    /// (counting from 1) of the written file is presented at `found` instead of `expected`
+    /// timescale units, or is missing if `found` is None.
+    #[error("Sample {sample} is not presented at {expected} as its timestamp is")]
+    TimestampMismatch {
+        sample: u32,
+        expected: u64,
+        found: Option<i64>,
+    },
+    /// This line (counting from 1) of a timestamp log is not a timestamp, see
+    /// [`crate::convert_vraw_with_timestamp_log`].
+    #[error("Line {line} of the timestamp log is not a timestamp")]
+    TimestampLog { line: usize },
+    /// A timestamp log has this many entries for a recording with this many video frames.
+    #[error("Timestamp log has {entries} entries for {frames} video frames")]
+    TimestampLogLength { entries: usize, frames: usize },
+    /// With [`crate::ConvertOptions::strict`], the output would not be fully conformant
+    /// for the given reason.
+    #[error("Output would not be conformant: {0}")]
+    NotConformant(String),
+    /// The video frame with this number (counting from 0) has the timestamp 0, which
+    /// recorders write when the timestamp is unavailable. Only returned with the default
+    /// [`crate::ZeroTimestampPolicy::Error`].
+    #[error("Video frame {at_frame} has no timestamp (0), see zero_timestamp_policy")]
+    ZeroTimestamp { at_frame: usize },
+    /// The file written by [`crate::convert_vraw_to_mp4_verified`] failed its verification
+    /// for the given reason, and was removed.
+    #[error("Written file failed verification: {0}")]
+    VerificationFailed(String),
+    /// A crop rectangle reaches past the edges of a frame of this size.
+    #[error("Crop rectangle does not fit the {frame_width}x{frame_height} frame")]
+    CropOutsideFrame { frame_width: u32, frame_height: u32 },
+    /// No output file name can be made from this input path.
+    #[error("Cannot name the output after {0}")]
+    InvalidPath(String),
+    /// A conversion option has a value that cannot be used.
+    #[error("Invalid option: {0}")]
+    InvalidOption(&'static str),
+}
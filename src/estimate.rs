@@ -0,0 +1,235 @@
+use crate::error::ConvertError;
+use crate::nal::{h265_frame_temporal_id, is_keyframe, parse_hevc_sps, ParameterSets};
+use crate::parser::{
+    parse_raw_frame, read_all_indexes, read_frame_meta, video_placement_len, VideoCaptureFormat,
+    VIDEO_PLACEMENT_TAIL_LEN,
+};
+use crate::processing::{grid_time, supported_output_codecs, ConvertOptions};
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+/// The boxes of every output whose size does not depend on the frames: `ftyp`, the
+/// `mdat` header and the `moov` box of a track without samples, with room for the
+/// parameter sets and the default metadata.
+const FIXED_OVERHEAD: u64 = 900;
+
+/// Bytes of the sample tables for every sample: its size in `stsz` and its duration in
+/// `stts`, where the durations of a recording with timing jitter hardly ever repeat.
+const SAMPLE_TABLE_BYTES: u64 = 12;
+
+/// Bytes of a track fragment run for every sample: its duration, size and flags.
+const TRUN_SAMPLE_BYTES: u64 = 12;
+
+/// Bytes of a fragment without its samples: the `moof` box with its `mfhd`, `traf`,
+/// `tfhd`, `tfdt` and `trun` headers, and the `mdat` header.
+const FRAGMENT_OVERHEAD: u64 = 120;
+
+/// Bytes of the timecode track of [`ConvertOptions::timecode`], the reference to it from
+/// the video track and the `mdat` box of its sample.
+const TIMECODE_TRACK: u64 = 487;
+
+/// Estimates the size in bytes of the mp4 file that converting a .vraw file with these
+/// options writes, for checking storage or upload quotas before converting.
+///
+/// The estimate is the sum of the video payloads of the selected
+/// [`ConvertOptions::session`] plus the sample tables, or the fragments for fragmented
+/// output, assuming a chunk or fragment every second, and the metadata. The frame count
+/// is scaled for [`ConvertOptions::resample_to_fps`], and the tables written for
+/// [`ConvertOptions::sample_dependencies`] and [`ConvertOptions::force_co64`] are added.
+/// The composition offsets of [`ConvertOptions::preserve_timestamps`] mostly repeat and
+/// take little room.
+///
+/// Only the index, the frame headers and the last bytes of every payload (for its
+/// alignment data) are read, so this is fast even for long recordings. Options that
+/// depend on what the payload holds need the frames in full: fragmented output starts at
+/// the first keyframe and a [`ConvertOptions::timelapse_stride`] time-lapse only keeps
+/// keyframes, so the frames up to the first keyframe or all frames the stride does not
+/// skip are read, and [`ConvertOptions::strip_parameter_sets`],
+/// [`ConvertOptions::max_temporal_id`] and [`ConvertOptions::drop_trailing_partial_gop`]
+/// read every frame.
+///
+/// [`ConvertOptions::frame_filter`] and [`ConvertOptions::fill_gaps_ms`] are not accounted
+/// for. Without them the estimate is usually within a few percent of the actual size.
+///
+/// input: path to .vraw file
+pub fn estimate_output_size(input: &str, options: &ConvertOptions) -> Result<u64, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+    let (entries, _) = options.session.select(read_all_indexes(&mut f)?)?;
+
+    let stride = options.timelapse_stride.unwrap_or(1).max(1);
+    // Fragmented output starts at the first keyframe and a time-lapse only keeps keyframes
+    let mut before_keyframe = options.is_fragmented();
+
+    let read_every_frame = options.strip_parameter_sets
+        || options.max_temporal_id.is_some()
+        || options.drop_trailing_partial_gop;
+    // The parameter sets of the sample entry, which strip_parameter_sets removes from the
+    // frames
+    let mut parameter_sets = ParameterSets::default();
+
+    let mut codec = None;
+    let mut source_frames = 0u64;
+    // The timestamp and size of every frame that is written, and whether it is a keyframe
+    let mut frames: Vec<(i64, u64, bool)> = Vec::new();
+    let mut next_target = 0;
+    for entry in &entries {
+        let meta = read_frame_meta(&mut f, entry)?;
+        if meta.format == VideoCaptureFormat::Stats {
+            continue;
+        }
+        if *codec.get_or_insert(meta.format) != meta.format {
+            continue;
+        }
+
+        let needs_payload =
+            read_every_frame || ((before_keyframe || stride > 1) && source_frames >= next_target);
+        let frame = if needs_payload && meta.payload_len > 0 {
+            Some(parse_raw_frame(&mut f, entry)?)
+        } else {
+            None
+        };
+        if let Some(frame) = &frame {
+            if !parameter_sets.is_complete(frame.format) {
+                parameter_sets.merge(ParameterSets::find(frame.format, &frame.raw_data));
+            }
+            // Dropped before the time-lapse counts the frames
+            if frame.format == VideoCaptureFormat::H265
+                && options.max_temporal_id.is_some_and(|max_temporal_id| {
+                    h265_frame_temporal_id(&frame.raw_data)
+                        .is_some_and(|temporal_id| temporal_id > max_temporal_id)
+                })
+            {
+                continue;
+            }
+        }
+
+        let position = source_frames;
+        source_frames += 1;
+        if meta.payload_len == 0 || position < next_target {
+            continue;
+        }
+
+        let (frame_len, keyframe) = if let Some(frame) = frame {
+            let keyframe = is_keyframe(frame.format, &frame.raw_data);
+            if (before_keyframe || stride > 1) && !keyframe {
+                continue;
+            }
+            before_keyframe = false;
+
+            // As in the conversion, an H.265 sample entry only has the parameter sets if
+            // its SPS parses
+            let strip = options.strip_parameter_sets
+                && (frame.format != VideoCaptureFormat::H265
+                    || options.hevc_tier_level.is_some()
+                    || parameter_sets
+                        .sps
+                        .as_deref()
+                        .and_then(parse_hevc_sps)
+                        .is_some());
+            let stripped = strip
+                .then(|| parameter_sets.strip_repeated(frame.format, &frame.raw_data))
+                .flatten();
+            let len = stripped.as_ref().unwrap_or(&frame.raw_data).len();
+            (len as u64, keyframe)
+        } else {
+            // The header leaves the reader at the start of the payload
+            let tail_len = meta.payload_len.min(VIDEO_PLACEMENT_TAIL_LEN as u64);
+            f.seek_relative((meta.payload_len - tail_len) as i64)?;
+            let mut tail = vec![0; tail_len as usize];
+            f.read_exact(&mut tail)?;
+            let len = meta.payload_len - video_placement_len(&tail).unwrap_or(0) as u64;
+            (len, false)
+        };
+        if stride > 1 {
+            next_target = (position / stride as u64 + 1) * stride as u64;
+        }
+
+        frames.push((meta.timestamp, frame_len, keyframe));
+    }
+    if options.drop_trailing_partial_gop {
+        let kept = frames.iter().rposition(|&(_, _, keyframe)| keyframe);
+        frames.truncate(kept.map_or(0, |last_keyframe| last_keyframe + 1));
+    }
+
+    let codec = codec.ok_or(ConvertError::NoFrames)?;
+    if !supported_output_codecs().contains(&codec) {
+        return Err(ConvertError::UnsupportedFormat(codec));
+    }
+    let (Some(&(first_timestamp, ..)), Some(&(last_timestamp, ..))) =
+        (frames.first(), frames.last())
+    else {
+        return Err(ConvertError::NoFrames);
+    };
+
+    let mut duration = (last_timestamp - first_timestamp).max(0) as f64 / 1_000_000_000.0;
+    if stride > 1 {
+        // Every kept frame lasts the average frame interval of the source
+        duration *= frames.len() as f64 / source_frames as f64;
+    }
+    let seconds = duration.ceil() as u64 + 1;
+
+    let (samples, payload) = match options.resample_to_fps {
+        // Every grid point shows the closer of the frames around it, as in the conversion
+        Some(fps) => {
+            let (mut samples, mut payload) = (0, 0);
+            let mut previous: Option<(i64, u64)> = None;
+            for &(timestamp, len, _) in &frames {
+                let time = timestamp.saturating_sub(first_timestamp);
+                while grid_time(samples, fps) <= time as f64 {
+                    let grid_start = grid_time(samples, fps);
+                    payload += match previous {
+                        Some((previous_time, previous_len))
+                            if grid_start - (previous_time as f64) < time as f64 - grid_start =>
+                        {
+                            previous_len
+                        }
+                        _ => len,
+                    };
+                    samples += 1;
+                }
+                previous = Some((time, len));
+            }
+            (samples, payload)
+        }
+        None => (
+            frames.len() as u64,
+            frames.iter().map(|&(_, len, _)| len).sum(),
+        ),
+    };
+
+    let mut size = FIXED_OVERHEAD + payload;
+    if options.is_fragmented() {
+        size += samples * TRUN_SAMPLE_BYTES + seconds * FRAGMENT_OVERHEAD;
+    } else {
+        size += samples * SAMPLE_TABLE_BYTES;
+        // A chunk offset in stco or co64 for every second
+        size += seconds * if options.force_co64 { 8 } else { 4 };
+        if options.sample_dependencies {
+            size += samples;
+        }
+        if options.timecode {
+            size += TIMECODE_TRACK;
+        }
+    }
+
+    let metadata = options
+        .title
+        .iter()
+        .chain(&options.comment)
+        .map(|text| text.len() as u64 + 24)
+        .chain(
+            options
+                .metadata
+                .iter()
+                .map(|(key, value)| key.len() as u64 + value.len() as u64 + 64),
+        )
+        .sum::<u64>();
+
+    // The handler name replaces `VideoHandler` in `hdlr` and is repeated in `udta/name`
+    let handler_name = options
+        .handler_name
+        .as_ref()
+        .map_or(0, |name| 2 * name.len() as u64 + 16);
+
+    Ok(size + metadata + handler_name)
+}
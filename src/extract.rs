@@ -0,0 +1,70 @@
+use crate::error::ConvertError;
+use crate::parser::{parse_raw_frame, read_frame_meta, read_index, ParseError, VideoCaptureFormat};
+use std::fs::{self, File};
+use std::io::BufReader;
+
+/// Writes the payload of a single frame of a .vraw file to a file, e.g. to inspect a
+/// glitchy frame with other tools. H.264 and H.265 frames are written as an Annex B byte
+/// stream, with start codes and without the alignment data of the recording.
+///
+/// input: path to .vraw file
+///
+/// frame_index: position of the frame in the index of the recording, counting
+/// [`VideoCaptureFormat::Stats`] frames as well
+///
+/// output: path of the file to create
+pub fn extract_frame(input: &str, frame_index: usize, output: &str) -> Result<(), ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    let entries = read_index(&mut f)?;
+    let entry = entries.get(frame_index).ok_or(ParseError::MissingFrame {
+        index: frame_index,
+        frames: entries.len(),
+    })?;
+
+    let frame = parse_raw_frame(&mut f, entry)?;
+    fs::write(output, frame.raw_data)?;
+
+    Ok(())
+}
+
+/// Like [`extract_frame`], selecting the video frame shown at `timestamp` (in nanoseconds,
+/// as in [`crate::RawFrame::timestamp`]): the last one at or before it, or the first one
+/// if the recording starts later. The index does not have to be sorted by time.
+/// [`VideoCaptureFormat::Stats`] frames and frames in another format than the first video
+/// frame are never selected.
+///
+/// Returns the position of the extracted frame in the index.
+pub fn extract_frame_at(input: &str, timestamp: i64, output: &str) -> Result<usize, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    let mut codec = VideoCaptureFormat::Stats;
+    // The latest frame up to the timestamp and the earliest frame, with their timestamps
+    let mut latest: Option<(usize, i64)> = None;
+    let mut earliest: Option<(usize, i64)> = None;
+    for (index, entry) in read_index(&mut f)?.iter().enumerate() {
+        let meta = read_frame_meta(&mut f, entry)?;
+        if meta.format == VideoCaptureFormat::Stats {
+            continue;
+        }
+        if codec == VideoCaptureFormat::Stats {
+            codec = meta.format;
+        }
+
+        if meta.format != codec {
+            continue;
+        }
+
+        if meta.timestamp <= timestamp && latest.is_none_or(|(_, t)| meta.timestamp >= t) {
+            latest = Some((index, meta.timestamp));
+        }
+        if earliest.is_none_or(|(_, t)| meta.timestamp < t) {
+            earliest = Some((index, meta.timestamp));
+        }
+    }
+
+    let (index, _) = latest.or(earliest).ok_or(ConvertError::NoFrames)?;
+    extract_frame(input, index, output)?;
+
+    Ok(index)
+}
@@ -0,0 +1,200 @@
+use crate::boxes::{full_box, mp4_box, Moov};
+use mp4::{Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
+use std::io::{Cursor, Write};
+
+/// `sample_depends_on` = 2 (does not depend on other samples)
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+
+/// `sample_depends_on` = 1 and `sample_is_non_sync_sample`
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// `default-base-is-moof`, sample data offsets are relative to the `moof` box
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+
+/// `data-offset-present`, `sample-duration-present`, `sample-size-present` and
+/// `sample-flags-present`
+const TRUN_FLAGS: u32 = 0x00_0701;
+
+/// `sample-composition-time-offsets-present`
+const TRUN_COMPOSITION_OFFSETS: u32 = 0x00_0800;
+
+const TRACK_ID: u32 = 1;
+
+/// Writes a single track fragmented mp4: an init segment (`ftyp` and a `moov` without
+/// samples) followed by `moof`/`mdat` pairs. Nothing is ever rewritten, so the output
+/// does not need to be seekable and can be streamed while it is written.
+///
+/// Every fragment starts on a sync sample. Samples before the first sync sample cannot
+/// be decoded and are dropped.
+pub(crate) struct FragmentedMp4Writer<W: Write> {
+    writer: W,
+    fragment_duration: u64,
+    sequence_number: u32,
+    decode_time: u64,
+    samples: Vec<(u32, u32, u32, i32)>, // duration, size, flags and composition offset
+    data: Vec<u8>,
+    buffered_duration: u64,
+}
+
+impl<W: Write> FragmentedMp4Writer<W> {
+    /// Writes the init segment.
+    ///
+    /// fragment_duration: minimum duration of a fragment in track timescale units. A new
+    /// fragment is started at the first sync sample after that, 0 starts one at every sync
+    /// sample.
+    ///
+    /// complete_moov: adds the boxes the mp4 crate does not write to the init segment.
+    pub(crate) fn new(
+        mut writer: W,
+        config: &Mp4Config,
+        track: &TrackConfig,
+        fragment_duration: u64,
+        complete_moov: impl FnOnce(&mut Moov),
+    ) -> mp4::Result<Self> {
+        // Let the mp4 crate write the sample description, the `moov` of a file without
+        // any samples is exactly what the init segment needs
+        let mut init = Mp4Writer::write_start(Cursor::new(Vec::new()), config)?;
+        init.add_track(track)?;
+        init.write_end()?;
+        let mut init = init.into_writer();
+
+        let mut moov = Moov::read(&mut init)?;
+        moov.append(&[], &mp4_box(b"mvex", &trex()));
+        complete_moov(&mut moov);
+
+        let init = init.into_inner();
+        let ftyp_size = u32::from_be_bytes([init[0], init[1], init[2], init[3]]) as usize;
+        writer.write_all(&init[..ftyp_size])?;
+        writer.write_all(moov.as_bytes())?;
+
+        Ok(FragmentedMp4Writer {
+            writer,
+            fragment_duration,
+            sequence_number: 0,
+            decode_time: 0,
+            samples: Vec::new(),
+            data: Vec::new(),
+            buffered_duration: 0,
+        })
+    }
+
+    /// Buffers a sample, writing the buffered fragment first if the sample can start a
+    /// new one. Returns false if the sample was dropped because no sync sample came
+    /// before it.
+    pub(crate) fn write_sample(&mut self, sample: &Mp4Sample) -> mp4::Result<bool> {
+        if self.samples.is_empty() && !sample.is_sync {
+            return Ok(false);
+        }
+
+        if sample.is_sync
+            && !self.samples.is_empty()
+            && self.buffered_duration >= self.fragment_duration
+        {
+            self.write_fragment()?;
+        }
+
+        let flags = if sample.is_sync {
+            SYNC_SAMPLE_FLAGS
+        } else {
+            NON_SYNC_SAMPLE_FLAGS
+        };
+        self.samples.push((
+            sample.duration,
+            sample.bytes.len() as u32,
+            flags,
+            sample.rendering_offset,
+        ));
+        self.data.extend_from_slice(&sample.bytes);
+        self.buffered_duration += sample.duration as u64;
+
+        Ok(true)
+    }
+
+    /// Writes the last fragment and returns the underlying writer.
+    pub(crate) fn write_end(mut self) -> mp4::Result<W> {
+        if !self.samples.is_empty() {
+            self.write_fragment()?;
+        }
+
+        Ok(self.writer)
+    }
+
+    fn write_fragment(&mut self) -> mp4::Result<()> {
+        self.sequence_number += 1;
+
+        let mdat_header_size = if 8 + self.data.len() as u64 > u32::MAX as u64 {
+            16
+        } else {
+            8
+        };
+
+        // The data offset does not change the size of the moof, so it is calculated from
+        // a moof written without it
+        let moof_size = self.moof(0).len();
+        let moof = self.moof((moof_size + mdat_header_size) as i32);
+        self.writer.write_all(&moof)?;
+
+        if mdat_header_size == 16 {
+            self.writer.write_all(&1u32.to_be_bytes())?;
+            self.writer.write_all(b"mdat")?;
+            self.writer
+                .write_all(&(16 + self.data.len() as u64).to_be_bytes())?;
+        } else {
+            self.writer
+                .write_all(&(8 + self.data.len() as u32).to_be_bytes())?;
+            self.writer.write_all(b"mdat")?;
+        }
+        self.writer.write_all(&self.data)?;
+
+        self.decode_time += self.buffered_duration;
+        self.buffered_duration = 0;
+        self.samples.clear();
+        self.data.clear();
+
+        Ok(())
+    }
+
+    fn moof(&self, data_offset: i32) -> Vec<u8> {
+        let mfhd = full_box(b"mfhd", 0, 0, &self.sequence_number.to_be_bytes());
+
+        let tfhd = full_box(
+            b"tfhd",
+            0,
+            TFHD_DEFAULT_BASE_IS_MOOF,
+            &TRACK_ID.to_be_bytes(),
+        );
+        let tfdt = full_box(b"tfdt", 1, 0, &self.decode_time.to_be_bytes());
+
+        // Composition offsets are only written if a sample has one, signed (version 1)
+        let offsets = self.samples.iter().any(|sample| sample.3 != 0);
+
+        let mut trun = Vec::with_capacity(8 + self.samples.len() * 16);
+        trun.extend_from_slice(&(self.samples.len() as u32).to_be_bytes());
+        trun.extend_from_slice(&data_offset.to_be_bytes());
+        for (duration, size, flags, offset) in &self.samples {
+            trun.extend_from_slice(&duration.to_be_bytes());
+            trun.extend_from_slice(&size.to_be_bytes());
+            trun.extend_from_slice(&flags.to_be_bytes());
+            if offsets {
+                trun.extend_from_slice(&offset.to_be_bytes());
+            }
+        }
+        let trun = if offsets {
+            full_box(b"trun", 1, TRUN_FLAGS | TRUN_COMPOSITION_OFFSETS, &trun)
+        } else {
+            full_box(b"trun", 0, TRUN_FLAGS, &trun)
+        };
+
+        let traf = mp4_box(b"traf", &[tfhd, tfdt, trun].concat());
+        mp4_box(b"moof", &[mfhd, traf].concat())
+    }
+}
+
+/// The `trex` box with the defaults of the single track, which every fragment overrides.
+fn trex() -> Vec<u8> {
+    let mut trex = Vec::with_capacity(20);
+    trex.extend_from_slice(&TRACK_ID.to_be_bytes());
+    trex.extend_from_slice(&1u32.to_be_bytes()); // default_sample_description_index
+    trex.extend_from_slice(&[0; 12]); // default duration, size and flags
+    full_box(b"trex", 0, 0, &trex)
+}
@@ -0,0 +1,209 @@
+use crate::error::ConvertError;
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::session::Session;
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::File;
+use std::io::BufReader;
+
+/// Hashes the video content of a .vraw file, to find duplicate recordings: the SHA-256 of
+/// the payloads of its video frames in index order, as lowercase hex.
+///
+/// Only the frame payloads are hashed, not the headers, timestamps or stats frames, and
+/// the frames of every recording session are included, so the hash does not depend on
+/// any [`crate::ConvertOptions`]. Frames without payload are left out, and a broken frame
+/// ends the recording as for the conversion.
+///
+/// input: path to .vraw file
+pub fn content_hash(input: &str) -> Result<String, ConvertError> {
+    let mut source =
+        VrawFrameSource::with_session(BufReader::new(File::open(input)?), Session::All)?;
+    let codec = source.codec();
+
+    let mut hasher = Sha256::new();
+    while let Some(Ok(frame)) = source.next_frame() {
+        if frame.format == codec {
+            hasher.update(&frame.raw_data);
+        }
+    }
+
+    Ok(hex(&hasher.finish()))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Computes the [`content_hash`] of the frames passing through a source, so a conversion
+/// reading every frame of the recording in index order needs no pass of its own.
+pub(crate) struct HashingSource<S> {
+    inner: S,
+    hasher: Option<Sha256>,
+    // Set once the inner source ended, which is also where content_hash stops
+    ended: bool,
+}
+
+impl<S: FrameSource> HashingSource<S> {
+    /// Wraps `inner`, passing its frames through without hashing them unless `enabled`.
+    pub(crate) fn new(inner: S, enabled: bool) -> Self {
+        HashingSource {
+            inner,
+            hasher: enabled.then(Sha256::new),
+            ended: false,
+        }
+    }
+
+    /// The hash of the frames, None unless enabled or before the inner source ended.
+    pub(crate) fn content_hash(&self) -> Option<String> {
+        if !self.ended {
+            return None;
+        }
+
+        self.hasher.clone().map(|hasher| hex(&hasher.finish()))
+    }
+}
+
+impl<S: FrameSource> FrameSource for HashingSource<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        let frame = self.inner.next_frame();
+
+        match (&frame, &mut self.hasher) {
+            _ if self.ended => {}
+            (None | Some(Err(_)), _) => self.ended = true,
+            (Some(Ok(frame)), Some(hasher)) if frame.format == self.inner.codec() => {
+                hasher.update(&frame.raw_data)
+            }
+            _ => {}
+        }
+
+        frame
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4) over data passed in pieces.
+#[derive(Clone)]
+pub(crate) struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        while !data.is_empty() {
+            let taken = data.len().min(64 - self.block_len);
+            self.block[self.block_len..self.block_len + taken].copy_from_slice(&data[..taken]);
+            self.block_len += taken;
+            data = &data[taken..];
+
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> [u8; 32] {
+        let bit_len = self.total_len * 8;
+
+        // A one bit, zeros up to 8 bytes before the end of a block and the length in bits
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(self.block.chunks(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ schedule[i - 15] >> 3;
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ schedule[i - 2] >> 10;
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(*constant)
+                .wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::error::ConvertError;
+use crate::parser::VideoCaptureFormat;
+use crate::source::{FrameSource, VrawFrameSource};
+use crate::stats::StatsFrame;
+use crate::text::{write_text_file, TextOutputOptions};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufReader;
+
+/// Camera exposure settings read from a [`StatsFrame`], see [`write_exposure_vtt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exposure {
+    /// Sensor gain as an ISO value.
+    pub gain: u32,
+
+    /// Exposure time in microseconds.
+    pub exposure_us: u32,
+}
+
+/// Writes the exposure settings of a .vraw recording as a WebVTT subtitle, to be shown as
+/// a HUD over the converted video.
+///
+/// Every stats frame is passed to `exposure`, which reads the settings from the payload
+/// (its layout is not part of the recording format) or returns None if the frame holds
+/// none. A cue starts at every stats frame whose settings differ from the previous ones
+/// and lasts until the settings change again or the video ends, so runs of identical
+/// frames do not flicker. Times are relative to the first video frame like the samples of
+/// the converted mp4.
+///
+/// `template` is the text of a cue, in which `{gain}`, `{exposure}` (in µs) and
+/// `{timestamp}` (the start of the cue) are replaced, e.g. `ISO {gain} | {exposure}µs |
+/// {timestamp}`.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .vtt file to create
+///
+/// Returns the number of cues written.
+pub fn write_exposure_vtt(
+    input: &str,
+    output: &str,
+    template: &str,
+    exposure: impl FnMut(&StatsFrame) -> Option<Exposure>,
+) -> Result<usize, ConvertError> {
+    write_exposure_vtt_with_options(
+        input,
+        output,
+        template,
+        exposure,
+        &TextOutputOptions::default(),
+    )
+}
+
+/// [`write_exposure_vtt`] with the line endings and byte order mark of `text_options`.
+pub fn write_exposure_vtt_with_options(
+    input: &str,
+    output: &str,
+    template: &str,
+    mut exposure: impl FnMut(&StatsFrame) -> Option<Exposure>,
+    text_options: &TextOutputOptions,
+) -> Result<usize, ConvertError> {
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    let codec = source.codec();
+
+    let mut changes: Vec<(i64, Exposure)> = Vec::new();
+    let mut first_video = None;
+    let mut last_video = 0;
+
+    while let Some(frame) = source.next_frame() {
+        let Ok(frame) = frame else {
+            // A broken frame ends the recording, as for the conversion
+            break;
+        };
+
+        if frame.format == VideoCaptureFormat::Stats {
+            let stats = StatsFrame {
+                timestamp: frame.timestamp,
+                raw_data: frame.raw_data,
+            };
+            if let Some(settings) = exposure(&stats) {
+                if changes.last().map(|(_, last)| *last) != Some(settings) {
+                    changes.push((stats.timestamp, settings));
+                }
+            }
+        } else if frame.format == codec {
+            first_video.get_or_insert(frame.timestamp);
+            last_video = frame.timestamp;
+        }
+    }
+
+    let mut vtt = String::from("WEBVTT\n");
+
+    let mut cues = 0;
+    if let Some(first) = first_video {
+        let end_of_video = last_video - first;
+
+        for (i, (timestamp, settings)) in changes.iter().enumerate() {
+            let start = (timestamp - first).max(0);
+            let end = changes
+                .get(i + 1)
+                .map(|(next, _)| next - first)
+                .unwrap_or(end_of_video)
+                .min(end_of_video);
+            if start >= end {
+                continue;
+            }
+
+            let text = template
+                .replace("{gain}", &settings.gain.to_string())
+                .replace("{exposure}", &settings.exposure_us.to_string())
+                .replace("{timestamp}", &vtt_time(start));
+            writeln!(vtt, "\n{} --> {}\n{}", vtt_time(start), vtt_time(end), text).unwrap();
+            cues += 1;
+        }
+    }
+
+    write_text_file(output, &vtt, text_options)?;
+
+    Ok(cues)
+}
+
+/// Formats nanoseconds as a WebVTT timestamp, `hh:mm:ss.ttt`.
+fn vtt_time(nanoseconds: i64) -> String {
+    let milliseconds = nanoseconds / 1_000_000;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        milliseconds / 3_600_000,
+        milliseconds / 60_000 % 60,
+        milliseconds / 1000 % 60,
+        milliseconds % 1000
+    )
+}
@@ -1,10 +1,140 @@
+mod boxes;
+mod channel;
+mod count;
+mod diff;
+mod encoder;
+mod error;
+mod estimate;
+mod extract;
+mod fragmented;
+#[cfg(feature = "content-hash")]
+mod hash;
+mod hud;
+mod nal;
 mod parser;
+#[cfg(feature = "parallel")]
+mod prefetch;
+mod preview;
 mod processing;
+mod retime;
+mod scene;
+mod seek;
+mod session;
+mod source;
+mod stats;
+mod text;
+#[cfg(feature = "mpegts")]
+mod ts;
+mod validate;
 
-pub use processing::convert_vraw_to_mp4;
+pub use encoder::rewrite_vraw;
+#[cfg(any(test, feature = "fixtures"))]
+pub use encoder::{build_vraw, build_vraw_header_indexed, build_vraw_sessions, FrameSpec};
+
+pub use channel::{convert_vraw_to_mp4_channel, ProgressEvent};
+pub use count::{count_frames, FrameCounts};
+pub use diff::{diff_indexes, FormatMismatch, IndexDiff};
+pub use error::ConvertError;
+pub use estimate::estimate_output_size;
+pub use extract::{extract_frame, extract_frame_at};
+#[cfg(feature = "content-hash")]
+pub use hash::content_hash;
+pub use hud::{write_exposure_vtt, write_exposure_vtt_with_options, Exposure};
+pub use parser::{
+    peek_frame_meta, read_raw_header, read_raw_index_bytes, FrameMeta, HeaderFields, ParseError,
+    RawFrame, VideoCaptureFormat,
+};
+pub use preview::{export_image_sequence, write_luma_preview, Crop};
+pub use processing::{
+    convert_frame_source_to_mp4, convert_vraw_append, convert_vraw_reader, convert_vraw_to_mp4,
+    convert_vraw_to_mp4_verified, convert_vraw_to_mp4_with_options, supported_containers,
+    supported_output_codecs, CaptureDate, ChapterCallback, ClockKind, Container, ConvertOptions,
+    ConvertSummary, FilenameTz, FrameFilter, FrameOrder, HevcEntryKind, HevcTierLevel,
+    ProgressCallback, SampleCallback, ZeroTimestampPolicy, DEFAULT_BUFFER_SIZE,
+};
+pub use retime::convert_vraw_with_timestamp_log;
+pub use scene::{convert_vraw_by_scene, Scene, SceneDetector};
+pub use seek::{export_seek_index, write_seek_index_json, SeekEntry};
+pub use session::{read_all_indexes, IndexEntry, Session};
+pub use source::{FrameSource, VrawFrameSource};
+pub use stats::{pair_stats_with_frames, StatsFrame};
+pub use text::{LineEnding, TextOutputOptions};
+#[cfg(feature = "mpegts")]
+pub use ts::convert_vraw_to_ts;
+pub use validate::{validate_mp4, Mp4Report, TrackReport};
 
 #[cfg(test)]
 mod tests {
+    use std::path::{Path, PathBuf};
+
+    /// An H.265 IDR slice, the smallest keyframe a conversion takes.
+    const H265_KEYFRAME: &[u8] = &[0, 0, 0, 1, 0x26, 1, 0xaf];
+
+    /// An H.265 trailing (inter) slice.
+    const H265_INTER_FRAME: &[u8] = &[0, 0, 0, 1, 2, 1, 0xd0];
+
+    /// A file in the temporary directory, removed when the test ends whether it passes or
+    /// not.
+    struct TempPath(PathBuf);
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempPath {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    /// The path `vraw_convert_<name>` in the temporary directory.
+    fn temp_path(name: &str) -> TempPath {
+        TempPath(std::env::temp_dir().join(format!("vraw_convert_{name}")))
+    }
+
+    /// Writes a recording of `frames` to `vraw_convert_<name>` in the temporary directory.
+    fn temp_vraw(name: &str, frames: &[crate::FrameSpec]) -> TempPath {
+        let path = temp_path(name);
+        std::fs::write(&path, crate::build_vraw(frames)).unwrap();
+        path
+    }
+
+    /// `count` H.265 inter frames `interval` nanoseconds apart, starting at 0.
+    fn h265_frames(count: i64, interval: i64) -> Vec<crate::FrameSpec> {
+        h265_frames_at(&(0..count).map(|i| i * interval).collect::<Vec<_>>())
+    }
+
+    /// H.265 inter frames with these timestamps.
+    fn h265_frames_at(timestamps: &[i64]) -> Vec<crate::FrameSpec> {
+        timestamps
+            .iter()
+            .map(|&timestamp| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    timestamp,
+                    H265_INTER_FRAME.to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    /// Reads the header of the mp4 file at `path`.
+    fn open_mp4(path: impl AsRef<Path>) -> mp4::Mp4Reader<std::io::BufReader<std::fs::File>> {
+        let file = std::fs::File::open(path).unwrap();
+        let size = file.metadata().unwrap().len();
+        mp4::Mp4Reader::read_header(std::io::BufReader::new(file), size).unwrap()
+    }
+
     #[test]
     fn try_convert_h265() {
         crate::processing::convert_vraw_to_mp4(&"assets/h265.vraw".to_string(), None).unwrap();
@@ -18,4 +148,3936 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn exact_timestamps_follow_source() {
+        let input = "assets/h265.vraw".to_string();
+        let output = temp_path("exact_timestamps.mp4");
+
+        crate::convert_vraw_to_mp4_with_options(
+            &input,
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                exact_timestamps: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut f = std::io::BufReader::new(std::fs::File::open(&input).unwrap());
+        let timestamps: Vec<i64> = crate::parser::read_index(&mut f)
+            .unwrap()
+            .iter()
+            .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+            .filter(|frame| frame.format != crate::VideoCaptureFormat::Stats)
+            .map(|frame| frame.timestamp)
+            .collect();
+
+        let mut mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap() as usize, timestamps.len());
+
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let sample = mp4.read_sample(1, i as u32 + 1).unwrap().unwrap();
+            let expected = (timestamp - timestamps[0]) as u64 * 1000 / 1_000_000_000;
+            assert_eq!(sample.start_time, expected);
+        }
+    }
+
+    #[test]
+    fn samples_equal_source_frames() {
+        let output = temp_path("sample_bytes.mp4");
+
+        for input in ["assets/h265.vraw", "assets/no_output_alignment.vraw"] {
+            crate::convert_vraw_to_mp4(
+                &input.to_string(),
+                Some(output.to_string_lossy().to_string()),
+            )
+            .unwrap();
+
+            let mut f = std::io::BufReader::new(std::fs::File::open(input).unwrap());
+            let frames: Vec<crate::RawFrame> = crate::parser::read_index(&mut f)
+                .unwrap()
+                .iter()
+                .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+                .filter(|frame| frame.format == crate::VideoCaptureFormat::H265)
+                .collect();
+
+            let mut mp4 = open_mp4(&output);
+            assert_eq!(mp4.sample_count(1).unwrap() as usize, frames.len());
+
+            // The payload is written as is, without the video placement data of the record
+            for (sample_id, frame) in (1..).zip(&frames) {
+                let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+                assert_eq!(
+                    &sample.bytes[..],
+                    &frame.raw_data[..],
+                    "{input} {sample_id}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn appending_matches_a_full_conversion() {
+        let appended = temp_path("append.mp4");
+        let full = temp_path("append_full.mp4");
+
+        // Jittered, so that every frame has a duration of its own
+        let frames: Vec<_> = (0..10u8)
+            .map(|i| {
+                let nal_type = if i % 5 == 0 { 0x26 } else { 0x02 };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000 + [0, 3, 1, 7, 2][i as usize % 5] * 1_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, i],
+                )
+            })
+            .collect();
+        let partial_input = temp_vraw("append_partial.vraw", &frames[..6]);
+        let input = temp_vraw("append.vraw", &frames);
+
+        let read_samples = |path: &Path| {
+            let mut mp4 = open_mp4(path);
+            (1..=mp4.sample_count(1).unwrap())
+                .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap())
+                .map(|sample| {
+                    (
+                        sample.start_time,
+                        sample.duration,
+                        sample.is_sync,
+                        sample.bytes,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let path = |path: &Path| path.to_string_lossy().to_string();
+        for exact_timestamps in [false, true] {
+            let options = || crate::ConvertOptions {
+                exact_timestamps,
+                ..Default::default()
+            };
+            crate::convert_vraw_to_mp4_with_options(
+                &path(&partial_input),
+                Some(path(&appended)),
+                &mut options(),
+            )
+            .unwrap();
+            let summary =
+                crate::convert_vraw_append(&path(&input), &path(&appended), 6, &mut options())
+                    .unwrap();
+            assert_eq!(summary.samples_written, 10);
+            crate::convert_vraw_to_mp4_with_options(
+                &path(&input),
+                Some(path(&full)),
+                &mut options(),
+            )
+            .unwrap();
+
+            assert_eq!(read_samples(&appended), read_samples(&full));
+            assert!(!Path::new(&format!("{}.append", path(&appended))).exists());
+        }
+    }
+
+    struct InMemorySource {
+        frames: std::collections::VecDeque<crate::RawFrame>,
+    }
+
+    impl crate::FrameSource for InMemorySource {
+        fn codec(&self) -> crate::VideoCaptureFormat {
+            crate::VideoCaptureFormat::H265
+        }
+
+        fn next_frame(&mut self) -> Option<Result<crate::RawFrame, crate::ParseError>> {
+            self.frames.pop_front().map(Ok)
+        }
+    }
+
+    #[test]
+    fn convert_in_memory_source() {
+        let frames: Vec<crate::RawFrame> = (0..10)
+            .map(|i| crate::RawFrame {
+                format: if i % 3 == 0 {
+                    crate::VideoCaptureFormat::Stats
+                } else {
+                    crate::VideoCaptureFormat::H265
+                },
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 2, 1, i as u8],
+                timestamp: i * 10_000_000,
+            })
+            .collect();
+        let output = temp_path("in_memory_source.mp4");
+
+        let mut source = InMemorySource {
+            frames: frames.iter().cloned().collect(),
+        };
+        crate::convert_frame_source_to_mp4(
+            &mut source,
+            &output.to_string_lossy(),
+            &mut crate::ConvertOptions::default(),
+        )
+        .unwrap();
+
+        let mut mp4 = open_mp4(&output);
+
+        let video_frames: Vec<_> = frames
+            .iter()
+            .filter(|frame| frame.format == crate::VideoCaptureFormat::H265)
+            .collect();
+        assert_eq!(mp4.sample_count(1).unwrap() as usize, video_frames.len());
+        for (i, frame) in video_frames.iter().enumerate() {
+            let sample = mp4.read_sample(1, i as u32 + 1).unwrap().unwrap();
+            assert_eq!(&sample.bytes[..], &frame.raw_data[..]);
+        }
+    }
+
+    #[test]
+    fn on_sample_rewrites_and_drops_samples() {
+        let frames: Vec<crate::RawFrame> = (0..6)
+            .map(|i| crate::RawFrame {
+                format: crate::VideoCaptureFormat::H265,
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 2, 1, i as u8],
+                timestamp: i * 10_000_000,
+            })
+            .collect();
+        let output = temp_path("on_sample.mp4");
+
+        let mut seen = Vec::new();
+        let mut on_sample = |frame: &crate::RawFrame, payload: &mut Vec<u8>| {
+            seen.push(frame.timestamp);
+            if frame.timestamp % 30_000_000 == 0 {
+                payload.clear();
+            } else {
+                payload.push(0xff);
+            }
+        };
+
+        let mut source = InMemorySource {
+            frames: frames.iter().cloned().collect(),
+        };
+        crate::convert_frame_source_to_mp4(
+            &mut source,
+            &output.to_string_lossy(),
+            &mut crate::ConvertOptions {
+                on_sample: Some(&mut on_sample),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let expected: Vec<i64> = frames.iter().map(|frame| frame.timestamp).collect();
+        assert_eq!(seen, expected);
+
+        let mut mp4 = open_mp4(&output);
+
+        // Frames 0 and 3 are dropped
+        assert_eq!(mp4.sample_count(1).unwrap(), 4);
+        for (sample_id, i) in (1..).zip([1, 2, 4, 5]) {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            assert_eq!(&sample.bytes[..], &[0, 0, 0, 1, 2, 1, i, 0xff]);
+        }
+    }
+
+    #[test]
+    fn flush_every_writes_progress_to_disk() {
+        let frames: Vec<crate::RawFrame> = (0..6)
+            .map(|i| crate::RawFrame {
+                format: crate::VideoCaptureFormat::H265,
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 0x26, 1, i as u8],
+                timestamp: i * 40_000_000,
+            })
+            .collect();
+        let output = temp_path("flush_every.mp4");
+
+        for flush_every in [None, Some(1)] {
+            // Every frame is a keyframe, so every sample writes the previous fragment
+            let mut sizes = Vec::new();
+            let mut on_sample = |_: &crate::RawFrame, _: &mut Vec<u8>| {
+                sizes.push(std::fs::metadata(&output).unwrap().len());
+            };
+
+            let mut source = InMemorySource {
+                frames: frames.iter().cloned().collect(),
+            };
+            crate::convert_frame_source_to_mp4(
+                &mut source,
+                &output.to_string_lossy(),
+                &mut crate::ConvertOptions {
+                    container: crate::Container::FragmentedMp4,
+                    flush_every,
+                    on_sample: Some(&mut on_sample),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            if flush_every.is_some() {
+                assert!(sizes[1..].windows(2).all(|w| w[0] < w[1]), "{sizes:?}");
+            } else {
+                assert!(sizes.iter().all(|&size| size == 0), "{sizes:?}");
+            }
+        }
+    }
+
+    /// An [`InMemorySource`] that gets `more` frames on the first refresh.
+    struct GrowingSource {
+        source: InMemorySource,
+        more: Vec<crate::RawFrame>,
+    }
+
+    impl crate::FrameSource for GrowingSource {
+        fn codec(&self) -> crate::VideoCaptureFormat {
+            self.source.codec()
+        }
+
+        fn next_frame(&mut self) -> Option<Result<crate::RawFrame, crate::ParseError>> {
+            self.source.next_frame()
+        }
+
+        fn refresh(&mut self) -> Result<usize, crate::ParseError> {
+            let more = self.more.len();
+            self.source.frames.extend(self.more.drain(..));
+            Ok(more)
+        }
+    }
+
+    #[test]
+    fn follow_interval_picks_up_new_frames() {
+        let frames: Vec<crate::RawFrame> = (0..8)
+            .map(|i| crate::RawFrame {
+                format: crate::VideoCaptureFormat::H265,
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 2, 1, i as u8],
+                timestamp: i * 40_000_000,
+            })
+            .collect();
+        let output = temp_path("follow.mp4");
+
+        for (follow_interval, sample_count) in [(None, 5), (Some(1), 8)] {
+            let mut source = GrowingSource {
+                source: InMemorySource {
+                    frames: frames[..5].iter().cloned().collect(),
+                },
+                more: frames[5..].to_vec(),
+            };
+            crate::convert_frame_source_to_mp4(
+                &mut source,
+                &output.to_string_lossy(),
+                &mut crate::ConvertOptions {
+                    follow_interval: follow_interval.map(std::time::Duration::from_millis),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mp4 = open_mp4(&output);
+            assert_eq!(mp4.sample_count(1).unwrap(), sample_count);
+        }
+    }
+
+    #[cfg(feature = "content-hash")]
+    #[test]
+    fn content_hash() {
+        let sha256 = |data: &[u8]| {
+            let mut hasher = crate::hash::Sha256::new();
+            // Split to cross block boundaries
+            for piece in data.chunks(7) {
+                hasher.update(piece);
+            }
+            hasher.finish().map(|byte| format!("{byte:02x}")).concat()
+        };
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+
+        let output = temp_path("content_hash.mp4");
+        let convert = |options: &mut crate::ConvertOptions| {
+            options.content_hash = true;
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                options,
+            )
+            .unwrap()
+            .content_hash
+            .unwrap()
+        };
+
+        let hash = convert(&mut Default::default());
+        assert_eq!(hash, crate::content_hash("assets/h265.vraw").unwrap());
+        let report = crate::validate_mp4(&output.to_string_lossy()).unwrap();
+        assert_eq!(report.metadata["content_hash"], hash);
+
+        let other_options = convert(&mut crate::ConvertOptions {
+            timelapse_stride: Some(10),
+            timescale: Some(90_000),
+            ..Default::default()
+        });
+        assert_eq!(other_options, hash);
+        // Hashed in a pass of its own, as the moov box comes first
+        let fragmented = convert(&mut crate::ConvertOptions {
+            container: crate::Container::FragmentedMp4,
+            ..Default::default()
+        });
+        assert_eq!(fragmented, hash);
+
+        assert_ne!(
+            crate::content_hash("assets/no_output_alignment.vraw").unwrap(),
+            hash
+        );
+    }
+
+    #[cfg(feature = "mpegts")]
+    #[test]
+    fn transport_stream() {
+        let output = temp_path("transport_stream.ts");
+        let frames =
+            crate::convert_vraw_to_ts("assets/h265.vraw", &output.to_string_lossy()).unwrap();
+        assert_eq!(frames, 1265);
+
+        let bytes = std::fs::read(&output).unwrap();
+        assert_eq!(bytes.len() % 188, 0);
+
+        let mut counters = std::collections::HashMap::new();
+        let mut pes_packets = 0;
+        let mut random_access_points = 0;
+        let mut last_pts = None;
+        for packet in bytes.chunks(188) {
+            assert_eq!(packet[0], 0x47);
+            let payload_unit_start = packet[1] & 0x40 != 0;
+            let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+
+            let counter = packet[3] & 0x0f;
+            if let Some(last) = counters.insert(pid, counter) {
+                assert_eq!(counter, (last + 1) & 0x0f, "PID {pid:#x}");
+            }
+
+            let payload = match packet[3] & 0x30 {
+                0x10 => &packet[4..],
+                0x30 => &packet[5 + packet[4] as usize..],
+                _ => panic!("packet without payload"),
+            };
+            match pid {
+                0x0000 | 0x1000 => {
+                    assert!(payload_unit_start);
+                    let length = u16::from_be_bytes([payload[2] & 0x0f, payload[3]]) as usize;
+                    let section = &payload[1..4 + length];
+                    assert_eq!(crate::ts::crc32_mpeg2(section), 0);
+                    if pid == 0x1000 {
+                        // The stream type of H.265 video
+                        assert_eq!(section[12], 0x24);
+                    }
+                }
+                0x0100 if payload_unit_start => {
+                    pes_packets += 1;
+                    // Every frame starts with the PCR
+                    assert_eq!(packet[3] & 0x20, 0x20);
+                    assert_eq!(packet[5] & 0x10, 0x10);
+                    random_access_points += (packet[5] & 0x40 != 0) as usize;
+
+                    assert_eq!(payload[..4], [0, 0, 1, 0xe0]);
+                    let pts = (payload[9] as u64 & 0x0e) << 29
+                        | (payload[10] as u64) << 22
+                        | (payload[11] as u64 & 0xfe) << 14
+                        | (payload[12] as u64) << 7
+                        | payload[13] as u64 >> 1;
+                    if last_pts.is_none() {
+                        assert_eq!(pts, 90_000);
+                    }
+                    assert!(last_pts.is_none_or(|last| pts > last));
+                    last_pts = Some(pts);
+                }
+                0x0100 => {}
+                _ => panic!("unexpected PID {pid:#x}"),
+            }
+        }
+        assert_eq!(pes_packets, frames);
+        assert!(random_access_points > 0);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn prefetch_keeps_order_and_refreshes() {
+        use crate::FrameSource;
+
+        let frames: Vec<crate::RawFrame> = (0..40)
+            .map(|i| crate::RawFrame {
+                format: crate::VideoCaptureFormat::H265,
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 2, 1, i as u8],
+                timestamp: i * 40_000_000,
+            })
+            .collect();
+        let source = GrowingSource {
+            source: InMemorySource {
+                frames: frames[..30].iter().cloned().collect(),
+            },
+            more: frames[30..].to_vec(),
+        };
+
+        let timestamps = crate::prefetch::with_prefetch(source, 4, |prefetch| {
+            let mut timestamps = Vec::new();
+            while let Some(frame) = prefetch.next_frame() {
+                timestamps.push(frame.unwrap().timestamp);
+            }
+            assert_eq!(prefetch.refresh().unwrap(), 10);
+            while let Some(frame) = prefetch.next_frame() {
+                timestamps.push(frame.unwrap().timestamp);
+            }
+            assert_eq!(prefetch.refresh().unwrap(), 0);
+            timestamps
+        });
+
+        let expected: Vec<i64> = frames.iter().map(|frame| frame.timestamp).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn first_sample_duration_after_leading_stats() {
+        let output = temp_path("leading_stats.mp4");
+
+        let mut frames = Vec::new();
+        for i in 0..10 {
+            frames.push(crate::FrameSpec::new(
+                crate::VideoCaptureFormat::Stats,
+                i * 1_000_000,
+                vec![0; 24],
+            ));
+        }
+        for i in 0..5 {
+            frames.push(crate::FrameSpec::new(
+                crate::VideoCaptureFormat::H265,
+                100_000_000 + i * 40_000_000,
+                vec![0, 0, 0, 1, 2, 1, 0xd0, 0x0f, 0x23, 0x70, 0xef, 0x02],
+            ));
+        }
+        let input = temp_vraw("leading_stats.vraw", &frames);
+
+        crate::convert_vraw_to_mp4(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+        )
+        .unwrap();
+
+        let mut mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap(), 5);
+        for sample_id in 1..=5 {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            assert_eq!(sample.duration, 40);
+        }
+    }
+
+    #[test]
+    fn out_of_range_durations_are_clamped() {
+        // 40 ms, then a jump of ~58 days and a jump back in time
+        let frames = h265_frames_at(&[
+            0,
+            40_000_000,
+            5_000_000_000_000_000,
+            80_000_000,
+            120_000_000,
+        ]);
+        let input = temp_vraw("clamped_durations.vraw", &frames);
+
+        for (name, exact_timestamps, expected) in [
+            (
+                "default",
+                false,
+                [40, 40, crate::processing::max_duration(1000), 0, 40],
+            ),
+            (
+                "exact",
+                true,
+                [40, crate::processing::max_duration(1000), 0, 40, 40],
+            ),
+        ] {
+            let output = temp_path(&format!("clamped_{name}.mp4"));
+
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    exact_timestamps,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mut mp4 = open_mp4(&output);
+            let durations: Vec<u32> = (1..=5)
+                .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap().duration)
+                .collect();
+            assert_eq!(durations, expected, "{name}");
+
+            std::fs::remove_file(output).unwrap();
+        }
+    }
+
+    #[test]
+    fn timescale_without_drift() {
+        let output = temp_path("timescale.mp4");
+
+        // 23.976 fps, i.e. 24000 frames every 1001 seconds
+        let timestamps: Vec<i64> = (0..10_000)
+            .map(|i| i * 1_001_000_000_000 / 24_000)
+            .collect();
+        let input = temp_vraw("timescale.vraw", &h265_frames_at(&timestamps));
+
+        for exact_timestamps in [true, false] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    exact_timestamps,
+                    timescale: Some(90_000),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mut mp4 = open_mp4(&output);
+            assert_eq!(mp4.tracks()[&1].timescale(), 90_000);
+            assert_eq!(mp4.sample_count(1).unwrap(), 10_000);
+            for (sample_id, timestamp) in (1..).zip(&timestamps) {
+                let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+                let start_time = (*timestamp as u64 * 90_000) / 1_000_000_000;
+                if exact_timestamps {
+                    assert_eq!(sample.start_time, start_time, "sample {sample_id}");
+                } else {
+                    // The first sample takes the duration of the second, which moves the
+                    // others by the difference between two rounded frame durations
+                    assert!(
+                        sample.start_time.abs_diff(start_time) <= 1,
+                        "sample {sample_id}: {} instead of {start_time}",
+                        sample.start_time
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_gaps_with_repeated_frames() {
+        let output = temp_path("fill_gaps.mp4");
+
+        // A gap of 320 ms after the third frame
+        let frames: Vec<_> = [0, 40, 80, 400, 440]
+            .iter()
+            .map(|&ms| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    ms * 1_000_000,
+                    vec![0, 0, 0, 1, 2, 1, (ms / 40) as u8],
+                )
+            })
+            .collect();
+        let input = temp_vraw("fill_gaps.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                exact_timestamps: true,
+                fill_gaps_ms: Some(50),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.filler_frames, 6);
+        assert_eq!(summary.samples_written, 11);
+
+        let mut mp4 = open_mp4(&output);
+        let samples: Vec<_> = (1..=11)
+            .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap())
+            .collect();
+        let durations: Vec<u32> = samples.iter().map(|sample| sample.duration).collect();
+        assert_eq!(durations, [40, 40, 50, 45, 45, 45, 45, 45, 45, 40, 40]);
+
+        // The gap is filled with the frame before it and the next frame keeps its time
+        assert!(samples[2..9].iter().all(|s| s.bytes == samples[2].bytes));
+        assert_eq!(samples[9].start_time, 400);
+        assert_eq!(samples[9].bytes, frames[3].payload);
+
+        let error = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                fill_gaps_ms: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn max_frame_duration_clamps_gaps() {
+        let output = temp_path("max_frame_duration.mp4");
+
+        // A gap of 5 seconds after the second frame
+        let frames = h265_frames_at(&[0, 40_000_000, 5_040_000_000, 5_080_000_000]);
+        let input = temp_vraw("max_frame_duration.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                max_frame_duration_ms: Some(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.clamped_frames, 1);
+        assert_eq!(summary.duration_ms, 220);
+
+        let mut mp4 = open_mp4(&output);
+        let durations: Vec<u32> = (1..=4)
+            .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap().duration)
+            .collect();
+        assert_eq!(durations, [40, 40, 100, 40]);
+    }
+
+    #[test]
+    fn millisecond_timestamps_are_implausible() {
+        let output = temp_path("millisecond_timestamps.mp4");
+
+        // 25 fps with timestamps in milliseconds
+        let input = temp_vraw("millisecond_timestamps.vraw", &h265_frames(10, 40));
+
+        let convert = |strict_timestamps| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    strict_timestamps,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let summary = convert(false).unwrap();
+        assert_eq!(
+            summary.warnings,
+            ["Timestamps span 0 ms over 10 frames, check that they are in nanoseconds"]
+        );
+        std::fs::remove_file(&output).unwrap();
+
+        let error = convert(true).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::ImplausibleTimestamps {
+                    span_ms: 0,
+                    frames: 10
+                }
+            ),
+            "{error}"
+        );
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn frame_source_random_access() {
+        use crate::FrameSource;
+
+        let frames: Vec<_> = (0..5)
+            .map(|i| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i * 40_000_000,
+                    vec![0, 0, 0, 1, 2, 1, i as u8],
+                )
+            })
+            .collect();
+        let mut source =
+            crate::VrawFrameSource::new(std::io::Cursor::new(crate::build_vraw(&frames))).unwrap();
+        assert_eq!(source.len(), 5);
+
+        let frame = source.get(3).unwrap();
+        assert_eq!(frame.timestamp, 120_000_000);
+        assert_eq!(frame.raw_data, [0, 0, 0, 1, 2, 1, 3]);
+
+        let error = source.get(5).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ParseError::MissingFrame {
+                    index: 5,
+                    frames: 5
+                }
+            ),
+            "{error}"
+        );
+
+        assert_eq!(source.next_frame().unwrap().unwrap().timestamp, 0);
+    }
+
+    #[test]
+    fn hvcc_tier_and_level() {
+        for (name, hevc_tier_level, expected) in [
+            ("sps", None, (0x01, 123)),
+            (
+                "override",
+                Some(crate::HevcTierLevel {
+                    high_tier: true,
+                    level_idc: 153,
+                }),
+                (0x21, 153),
+            ),
+        ] {
+            let output = temp_path(&format!("hvcc_{name}.mp4"));
+
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    hevc_tier_level,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mp4 = open_mp4(&output);
+            assert_eq!(mp4.sample_count(1).unwrap(), 1265);
+
+            let bytes = std::fs::read(&output).unwrap();
+            let position = bytes
+                .windows(4)
+                .position(|window| window == b"hvcC")
+                .unwrap();
+            let record = &bytes[position + 4..];
+
+            // Main profile, level 4.1 in the SPS of the recording
+            assert_eq!((record[1], record[12]), expected, "{name}");
+            assert_eq!(&record[2..6], &[0x40, 0, 0, 0]);
+            assert_eq!(record[16], 0xFC | 1); // 4:2:0
+            assert_eq!(record[22], 3); // VPS, SPS and PPS
+
+            std::fs::remove_file(output).unwrap();
+        }
+    }
+
+    #[test]
+    fn avcc_level_override() {
+        let output = temp_path("avc_level.mp4");
+
+        // SPS (baseline, level 3.0), PPS and an IDR slice
+        let keyframe = [
+            &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1e, 0xf8, 0x41, 0xa2][..],
+            &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+        ]
+        .concat();
+        let frames: Vec<_> = (0..3)
+            .map(|i| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H264,
+                    i * 40_000_000,
+                    keyframe.clone(),
+                )
+            })
+            .collect();
+        let input = temp_vraw("avc_level.vraw", &frames);
+
+        for (avc_level, expected) in [(None, 0x1e), (Some(51), 51)] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    avc_level,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let bytes = std::fs::read(&output).unwrap();
+            let position = bytes
+                .windows(4)
+                .position(|window| window == b"avcC")
+                .unwrap();
+            assert_eq!(
+                &bytes[position + 4..position + 8],
+                &[1, 0x42, 0x00, expected]
+            );
+        }
+    }
+
+    #[test]
+    fn parameter_sets_after_inter_frames() {
+        let output = temp_path("late_parameter_sets.mp4");
+
+        // The recording starts on a P-frame, and the PPS follows the keyframe with the SPS
+        let sps = [0x67, 0x42, 0x00, 0x1e, 0xf8, 0x41, 0xa2];
+        let pps = [0x68, 0xeb, 0xe3, 0xcb];
+        let payloads = [
+            vec![0, 0, 0, 1, 0x41, 0x9a, 0x02],
+            [
+                &[0, 0, 0, 1][..],
+                &sps,
+                &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+            ]
+            .concat(),
+            [&[0, 0, 0, 1][..], &pps, &[0, 0, 0, 1, 0x41, 0x9a, 0x04]].concat(),
+            vec![0, 0, 0, 1, 0x41, 0x9a, 0x06],
+        ];
+        let frames: Vec<_> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H264,
+                    i as i64 * 40_000_000,
+                    payload.clone(),
+                )
+            })
+            .collect();
+        let input = temp_vraw("late_parameter_sets.vraw", &frames);
+
+        crate::convert_vraw_to_mp4(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+        )
+        .unwrap();
+
+        let mut file = std::fs::File::open(&output).unwrap();
+        let moov = crate::boxes::Moov::read(&mut file).unwrap();
+        let avcc = moov
+            .get(&[
+                b"trak", b"mdia", b"minf", b"stbl", b"stsd", b"avc1", b"avcC",
+            ])
+            .unwrap();
+        assert!(avcc.windows(sps.len()).any(|window| window == sps));
+        assert!(avcc.windows(pps.len()).any(|window| window == pps));
+    }
+
+    #[test]
+    fn resolution_change_is_an_error() {
+        let output = temp_path("resolution_change.mp4");
+
+        // Keyframes with the SPS of a 128x96 and a 160x96 stream
+        let keyframe = |sps: &[u8]| {
+            [
+                &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1e][..],
+                sps,
+                &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+                &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+            ]
+            .concat()
+        };
+        let inter_frame = vec![0, 0, 0, 1, 0x41, 0x9a, 0x02];
+        let payloads = [
+            keyframe(&[0xf8, 0x41, 0xa2]),
+            inter_frame.clone(),
+            keyframe(&[0xf8, 0x41, 0xa2]),
+            keyframe(&[0xf8, 0x51, 0xb2]),
+            inter_frame,
+        ];
+        let frames: Vec<_> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H264,
+                    i as i64 * 40_000_000,
+                    payload.clone(),
+                )
+            })
+            .collect();
+        let input = temp_vraw("resolution_change.vraw", &frames);
+
+        let error = crate::convert_vraw_to_mp4(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::ResolutionChanged { at_frame: 3 }
+            ),
+            "{error}"
+        );
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn raw_header_and_index_bytes() {
+        let frames = [
+            crate::FrameSpec::new(crate::VideoCaptureFormat::Stats, 0, vec![1]),
+            crate::FrameSpec::new(crate::VideoCaptureFormat::Stats, 40_000_000, vec![2]),
+        ];
+
+        let bytes = crate::build_vraw(&frames);
+        let mut f = std::io::Cursor::new(&bytes);
+        let (header, raw_header) = crate::read_raw_header(&mut f).unwrap();
+        assert_eq!(raw_header, bytes[..16]);
+        assert_eq!(
+            header.unix_epoch_time_sec,
+            u64::from_le_bytes(bytes[8..16].try_into().unwrap())
+        );
+        assert_eq!(
+            header.unix_epoch_time_relative_nsec,
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap())
+        );
+
+        let index = crate::read_raw_index_bytes(&mut f).unwrap();
+        assert_eq!(index.len(), 8 + 2 * 16 + 8);
+        assert_eq!(index, bytes[bytes.len() - index.len()..]);
+        assert_eq!(index[..4], 0xABCDFEEDu32.to_le_bytes());
+
+        let bytes = crate::build_vraw_header_indexed(&frames);
+        let index = crate::read_raw_index_bytes(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(index, bytes[16..16 + 8 + 2 * 16 + 8]);
+    }
+
+    #[test]
+    fn corrupt_sps_size() {
+        use crate::VideoCaptureFormat::H264;
+
+        let output = temp_path("corrupt_sps.mp4");
+
+        let sps = |size: &[u8]| [&[0x67, 0x42, 0x00, 0x1e][..], size].concat();
+        // 2^32 - 1 macroblocks wide, and 2^31 map units high in fields
+        let too_wide = sps(&[0xf8, 0x00, 0x00, 0x00, 0x07, 0xff, 0xff, 0xff, 0xff]);
+        let too_high = sps(&[0xfa, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(crate::nal::sps_size(H264, &too_wide), None);
+        assert_eq!(crate::nal::sps_size(H264, &too_high), None);
+        assert_eq!(
+            crate::nal::sps_size(H264, &sps(&[0xf8, 0x41, 0xa2])),
+            Some((128, 96))
+        );
+
+        // A corrupt SPS in the middle of the stream is not taken for a new picture size
+        let keyframe = |sps: &[u8]| {
+            [
+                &[0, 0, 0, 1][..],
+                sps,
+                &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+                &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+            ]
+            .concat()
+        };
+        let frames = [
+            crate::FrameSpec::new(H264, 0, keyframe(&sps(&[0xf8, 0x41, 0xa2]))),
+            crate::FrameSpec::new(H264, 40_000_000, keyframe(&too_wide)),
+            crate::FrameSpec::new(H264, 80_000_000, keyframe(&too_high)),
+        ];
+        let input = temp_vraw("corrupt_sps.vraw", &frames);
+        let summary = crate::convert_vraw_to_mp4(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+        )
+        .unwrap();
+        assert_eq!(summary.samples_written, 3);
+    }
+
+    #[test]
+    fn strict_conformance() {
+        use crate::VideoCaptureFormat::{H264, H265};
+
+        let input = temp_path("strict.vraw");
+        let output = temp_path("strict.mp4");
+
+        // A keyframe with the SPS and PPS of a 128x96 stream
+        let keyframe = [
+            &[0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1e, 0xf8, 0x41, 0xa2][..],
+            &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+        ]
+        .concat();
+        let inter_frame = vec![0, 0, 0, 1, 0x41, 0x9a, 0x02];
+
+        let convert = |frames: &[crate::FrameSpec], tolerate_format_mismatch| {
+            std::fs::write(&input, crate::build_vraw(frames)).unwrap();
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    strict: true,
+                    tolerate_format_mismatch,
+                    ..Default::default()
+                },
+            )
+        };
+        let not_conformant = |result: Result<crate::ConvertSummary, crate::ConvertError>| {
+            let error = result.unwrap_err();
+            assert!(
+                matches!(error, crate::ConvertError::NotConformant(_)),
+                "{error}"
+            );
+            assert!(!output.exists());
+        };
+
+        let mut frames = vec![
+            crate::FrameSpec::new(H264, 0, keyframe),
+            crate::FrameSpec::new(H264, 40_000_000, inter_frame.clone()),
+            crate::FrameSpec::new(H264, 80_000_000, inter_frame.clone()),
+        ];
+        convert(&frames, false).unwrap();
+        let mp4 = open_mp4(&output);
+        assert_eq!(
+            (mp4.tracks()[&1].width(), mp4.tracks()[&1].height()),
+            (128, 96)
+        );
+        std::fs::remove_file(&output).unwrap();
+
+        // A warning about a skipped frame
+        frames.push(crate::FrameSpec::new(
+            H265,
+            120_000_000,
+            inter_frame.clone(),
+        ));
+        not_conformant(convert(&frames, true));
+
+        // A timestamp that does not increase
+        frames[3] = crate::FrameSpec::new(H264, 80_000_000, inter_frame.clone());
+        not_conformant(convert(&frames, false));
+
+        // No parameter sets, which would get placeholder ones
+        not_conformant(convert(&frames[1..3], false));
+
+        // A 1920x1080 stream is coded as 1920x1088 and cropped by 8 lines
+        let sps_1080p = [0x67, 0x42, 0x00, 0x28, 0xf4, 0x03, 0xc0, 0x11, 0x3f, 0x2a];
+        let keyframe = [
+            &[0, 0, 0, 1][..],
+            &sps_1080p,
+            &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+        ]
+        .concat();
+        let frames = [
+            crate::FrameSpec::new(H264, 0, keyframe),
+            crate::FrameSpec::new(H264, 40_000_000, inter_frame.clone()),
+        ];
+        convert(&frames, false).unwrap();
+        let mp4 = open_mp4(&output);
+        assert_eq!(
+            (mp4.tracks()[&1].width(), mp4.tracks()[&1].height()),
+            (1920, 1080)
+        );
+        std::fs::remove_file(&output).unwrap();
+
+        // The same for H.265, with the conformance window in chroma samples
+        let hevc_sps = [
+            0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x78, 0xa0, 0x03, 0xc0, 0x80, 0x11, 0x07, 0xcb, 0xc0,
+        ];
+        assert_eq!(crate::nal::sps_size(H265, &hevc_sps), Some((1920, 1080)));
+
+        // A size beyond the 16 bits of the track header, 4096 macroblocks wide
+        let sps_too_wide = [0x67, 0x42, 0x00, 0x28, 0xf8, 0x00, 0x20, 0x01, 0xc8];
+        assert_eq!(crate::nal::sps_size(H264, &sps_too_wide), Some((65536, 16)));
+        let keyframe = [
+            &[0, 0, 0, 1][..],
+            &sps_too_wide,
+            &[0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80],
+            &[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00],
+        ]
+        .concat();
+        let error = convert(&[crate::FrameSpec::new(H264, 0, keyframe)], false).unwrap_err();
+        assert!(
+            error.to_string().contains("65536x16 does not fit"),
+            "{error}"
+        );
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn estimate_output_size() {
+        let output = temp_path("estimate_output_size.mp4");
+
+        for input in ["assets/h265.vraw", "assets/no_output_alignment.vraw"] {
+            let option_sets: [fn() -> crate::ConvertOptions<'static>; 5] = [
+                Default::default,
+                || crate::ConvertOptions {
+                    exact_timestamps: true,
+                    preserve_timestamps: true,
+                    ..Default::default()
+                },
+                crate::ConvertOptions::web,
+                || crate::ConvertOptions {
+                    timelapse_stride: Some(4),
+                    ..Default::default()
+                },
+                || crate::ConvertOptions::editing(15.0),
+            ];
+
+            for options in option_sets {
+                let estimate = crate::estimate_output_size(input, &options()).unwrap();
+                crate::convert_vraw_to_mp4_with_options(
+                    &input.to_string(),
+                    Some(output.to_string_lossy().to_string()),
+                    &mut options(),
+                )
+                .unwrap();
+                let size = std::fs::metadata(&output).unwrap().len();
+
+                assert!(
+                    estimate.abs_diff(size) * 20 <= size,
+                    "{input}: estimated {estimate} bytes for {size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn estimate_small_frames() {
+        use crate::FrameSource;
+
+        let output = temp_path("estimate_small_frames.mp4");
+
+        // Small frames that repeat the parameter sets of the asset, which then make up most
+        // of every keyframe
+        let source = crate::VrawFrameSource::new(std::io::BufReader::new(
+            std::fs::File::open("assets/h265.vraw").unwrap(),
+        ))
+        .unwrap();
+        let mut keyframe: Vec<u8> = [source.vps(), source.sps(), source.pps()]
+            .into_iter()
+            .flat_map(|nal| [&[0, 0, 0, 1][..], nal.unwrap()].concat())
+            .collect();
+        // A keyframe slice longer than the inter frames, so the sample sizes differ
+        keyframe.extend_from_slice(H265_KEYFRAME);
+        keyframe.extend_from_slice(&[0x80, 0x40]);
+        // Jittered timestamps, with two frames of a higher temporal sublayer in every GOP
+        // and a partial GOP at the end
+        let frames: Vec<_> = (0..803)
+            .map(|i| {
+                let payload = match i % 4 {
+                    0 => keyframe.clone(),
+                    1 | 3 => vec![0, 0, 0, 1, 2, 2, 0xd0],
+                    _ => H265_INTER_FRAME.to_vec(),
+                };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i * 40_000_000 + [0, 3, 1, 7, 2][i as usize % 5] * 1_000_000,
+                    payload,
+                )
+            })
+            .collect();
+        let input = temp_vraw("estimate_small_frames.vraw", &frames);
+
+        let option_sets: [fn() -> crate::ConvertOptions<'static>; 3] = [
+            || crate::ConvertOptions {
+                strip_parameter_sets: true,
+                ..Default::default()
+            },
+            || crate::ConvertOptions {
+                max_temporal_id: Some(0),
+                ..Default::default()
+            },
+            || crate::ConvertOptions {
+                drop_trailing_partial_gop: true,
+                ..Default::default()
+            },
+        ];
+        for options in option_sets {
+            let input = input.to_string_lossy().to_string();
+            let estimate = crate::estimate_output_size(&input, &options()).unwrap();
+            crate::convert_vraw_to_mp4_with_options(
+                &input,
+                Some(output.to_string_lossy().to_string()),
+                &mut options(),
+            )
+            .unwrap();
+            let size = std::fs::metadata(&output).unwrap().len();
+
+            assert!(
+                estimate.abs_diff(size) * 20 <= size,
+                "{options:?}: estimated {estimate} bytes for {size}",
+                options = options()
+            );
+        }
+
+        // The two frames after the last keyframe are too small to matter above
+        let estimate = |drop_trailing_partial_gop| {
+            let options = crate::ConvertOptions {
+                drop_trailing_partial_gop,
+                ..Default::default()
+            };
+            crate::estimate_output_size(&input.to_string_lossy(), &options).unwrap()
+        };
+        assert!(estimate(false) - estimate(true) >= 2 * H265_INTER_FRAME.len() as u64);
+    }
+
+    #[test]
+    fn verified_conversion() {
+        let output = temp_path("verified.mp4");
+        let report_path = temp_path("verified.json");
+
+        let expected = crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                preserve_timestamps: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&output).unwrap();
+
+        let handle = crate::convert_vraw_to_mp4_verified(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                preserve_timestamps: true,
+                report_path: Some(report_path.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!(summary, expected);
+
+        let report: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+        assert_eq!(report["samples_written"], 1265);
+        assert!(output.exists());
+    }
+
+    #[test]
+    fn unknown_format_code() {
+        use crate::VideoCaptureFormat::{Unknown, H265};
+
+        let input = temp_path("unknown_format.vraw");
+        let output = temp_path("unknown_format.mp4");
+        let input = input.to_string_lossy().to_string();
+
+        assert_eq!(crate::VideoCaptureFormat::from(0x37), Unknown(0x37));
+        assert_eq!(crate::VideoCaptureFormat::from(-4602), H265);
+        assert_eq!(Unknown(0xffff_ee04).code(), -4604);
+
+        std::fs::write(
+            &input,
+            crate::build_vraw(&[
+                crate::FrameSpec::new(Unknown(0x37), 0, vec![1, 2, 3]),
+                crate::FrameSpec::new(Unknown(0x37), 40_000_000, vec![4, 5, 6]),
+            ]),
+        )
+        .unwrap();
+
+        let counts = crate::count_frames(&input).unwrap();
+        assert_eq!(counts.codec, Unknown(0x37));
+        assert_eq!(counts.per_format, [(Unknown(0x37), 2)]);
+
+        let error = crate::convert_vraw_to_mp4_with_options(
+            &input,
+            Some(output.to_string_lossy().to_string()),
+            &mut Default::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            crate::ConvertError::UnsupportedFormat(Unknown(0x37))
+        ));
+        assert!(error.to_string().contains("Unknown(55)"), "{error}");
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn track_language_and_name() {
+        let output = temp_path("track_language.mp4");
+        let convert = |fragmented| {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    language: Some("swe".to_string()),
+                    handler_name: Some("Main Camera".to_string()),
+                    container: if fragmented {
+                        crate::Container::FragmentedMp4
+                    } else {
+                        crate::Container::Mp4
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        for fragmented in [false, true] {
+            convert(fragmented).unwrap();
+
+            let mp4 = open_mp4(&output);
+            let track = &mp4.tracks()[&1];
+            assert_eq!(track.language(), "swe");
+            assert_eq!(track.trak.mdia.hdlr.name, "Main Camera");
+            assert_eq!(track.trak.mdia.hdlr.handler_type.to_string(), "vide");
+
+            let moov =
+                crate::boxes::Moov::read_anywhere(&mut std::fs::File::open(&output).unwrap())
+                    .unwrap();
+            assert_eq!(
+                moov.get(&[b"trak", b"udta", b"name"]).unwrap()[8..],
+                b"Main Camera"[..]
+            );
+        }
+
+        crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions::default(),
+        )
+        .unwrap();
+        let mp4 = open_mp4(&output);
+        assert_eq!(mp4.tracks()[&1].language(), "und");
+
+        for language in ["en", "ENG", "e1g"] {
+            let result = crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    language: Some(language.to_string()),
+                    ..Default::default()
+                },
+            );
+            assert!(matches!(result, Err(crate::ConvertError::InvalidOption(_))));
+        }
+    }
+
+    #[test]
+    fn faststart() {
+        let output = temp_path("faststart.mp4");
+        let faststart_output = temp_path("faststart_moved.mp4");
+        let convert = |output: &std::path::Path, faststart, force_co64| {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    faststart,
+                    force_co64,
+                    chunk_per_gop: force_co64,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            open_mp4(output)
+        };
+
+        for force_co64 in [false, true] {
+            let mut mp4 = convert(&output, false, force_co64);
+            let mut moved = convert(&faststart_output, true, force_co64);
+            assert_eq!(
+                std::fs::metadata(&output).unwrap().len(),
+                std::fs::metadata(&faststart_output).unwrap().len()
+            );
+
+            let bytes = std::fs::read(&faststart_output).unwrap();
+            assert_eq!(&bytes[4..8], b"ftyp");
+            let ftyp_size = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+            assert_eq!(&bytes[ftyp_size + 4..ftyp_size + 8], b"moov");
+
+            assert_eq!(moved.sample_count(1).unwrap(), 1265);
+            for id in 1..=mp4.sample_count(1).unwrap() {
+                let sample = mp4.read_sample(1, id).unwrap().unwrap();
+                let moved_sample = moved.read_sample(1, id).unwrap().unwrap();
+                assert_eq!(sample, moved_sample, "sample {id}");
+            }
+        }
+
+        let result = crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                faststart: true,
+                container: crate::Container::FragmentedMp4,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(crate::ConvertError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn unreadable_frame_in_the_middle() {
+        let output = temp_path("unreadable_frame.mp4");
+
+        // Break the header magic of the 1000th frame
+        let mut bytes = std::fs::read("assets/h265.vraw").unwrap();
+        let mut f = std::io::Cursor::new(&bytes);
+        let index = crate::parser::read_index(&mut f).unwrap();
+        let frames = index.len();
+        bytes[index[999].offset.get() as usize] ^= 0xff;
+        let input = temp_path("unreadable_frame.vraw");
+        std::fs::write(&input, bytes).unwrap();
+
+        let convert = |strict| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    strict,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let summary = convert(false).unwrap();
+        assert!(
+            summary
+                .warnings
+                .iter()
+                .any(|warning| warning.contains("frame 1000 ")
+                    && warning.contains(&format!("the {} frames", frames - 1000))),
+            "{:?}",
+            summary.warnings
+        );
+        assert!(open_mp4(&output).sample_count(1).unwrap() < 1000);
+
+        let error = convert(true).unwrap_err();
+        assert!(matches!(error, crate::ConvertError::Parse(_)), "{error}");
+    }
+
+    #[test]
+    fn timecode_track() {
+        let output = temp_path("timecode.mov");
+
+        // Captured at 2024-05-17T08:30:15Z
+        let start = 1_715_934_615_000_000_000;
+        let mut frames =
+            h265_frames_at(&(0..50).map(|i| start + i * 40_000_000).collect::<Vec<_>>());
+        frames[0].payload = H265_KEYFRAME.to_vec();
+        let input = temp_vraw("timecode.vraw", &frames);
+
+        let convert = |mut options: crate::ConvertOptions| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut options,
+            )
+        };
+        let summary = convert(crate::ConvertOptions::editing(25.0)).unwrap();
+        assert!(summary.warnings.is_empty(), "{:?}", summary.warnings);
+
+        let mut mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap(), 50);
+        let timecode = mp4.read_sample(2, 1).unwrap().unwrap();
+        let frame_count = (8 * 3600 + 30 * 60 + 15) * 25u32;
+        assert_eq!(&timecode.bytes[..], frame_count.to_be_bytes());
+
+        let moov =
+            crate::boxes::Moov::read_anywhere(&mut std::fs::File::open(&output).unwrap()).unwrap();
+        assert_eq!(
+            moov.get(&[b"trak", b"tref", b"tmcd"]).unwrap()[8..],
+            2u32.to_be_bytes()
+        );
+        let report = crate::validate_mp4(&output.to_string_lossy()).unwrap();
+        assert!(report.valid_moov);
+        assert_eq!(report.tracks[1].duration, report.tracks[0].duration);
+
+        for options in [
+            crate::ConvertOptions {
+                timecode: true,
+                ..Default::default()
+            },
+            crate::ConvertOptions {
+                faststart: true,
+                ..crate::ConvertOptions::editing(25.0)
+            },
+        ] {
+            let error = convert(options).unwrap_err();
+            assert!(
+                matches!(error, crate::ConvertError::InvalidOption(_)),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_per_gop() {
+        let output = temp_path("chunk_per_gop.mp4");
+        let gop_output = temp_path("chunk_per_gop_gops.mp4");
+
+        for (path, chunk_per_gop) in [(&output, false), (&gop_output, true)] {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(path.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    chunk_per_gop,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let mut mp4 = open_mp4(&output);
+        let mut gop_mp4 = open_mp4(&gop_output);
+
+        let stbl = &gop_mp4.tracks()[&1].trak.mdia.minf.stbl;
+        let keyframes = stbl.stss.as_ref().unwrap().entries.clone();
+        // The frames before the first keyframe make up a chunk of their own
+        let chunks = keyframes.len() + (keyframes[0] != 1) as usize;
+        assert_eq!(stbl.stco.as_ref().unwrap().entries.len(), chunks);
+        assert!(keyframes.len() > 1);
+
+        // Each chunk starts at a keyframe, and every sample reads back the same
+        let mut first_sample = 1;
+        for (chunk, entry) in stbl.stsc.entries.iter().enumerate() {
+            let next_first = stbl
+                .stsc
+                .entries
+                .get(chunk + 1)
+                .map(|next| next.first_chunk)
+                .unwrap_or(chunks as u32 + 1);
+            for _ in entry.first_chunk..next_first {
+                assert!(first_sample == 1 || keyframes.contains(&first_sample));
+                first_sample += entry.samples_per_chunk;
+            }
+        }
+        assert_eq!(first_sample, mp4.sample_count(1).unwrap() + 1);
+
+        for id in 1..=mp4.sample_count(1).unwrap() {
+            let sample = mp4.read_sample(1, id).unwrap().unwrap();
+            let gop_sample = gop_mp4.read_sample(1, id).unwrap().unwrap();
+            assert_eq!(sample, gop_sample, "sample {id}");
+        }
+    }
+
+    #[test]
+    fn presets() {
+        use crate::VideoCaptureFormat::H265;
+
+        let output = temp_path("presets.mp4");
+        let report = temp_path("presets.json");
+        let convert = |options: &mut crate::ConvertOptions| {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                options,
+            )
+            .unwrap();
+            crate::validate_mp4(&output.to_string_lossy()).unwrap()
+        };
+
+        let web = convert(&mut crate::ConvertOptions::web());
+        assert!(!web.fragmented);
+        assert_eq!(web.tracks[0].sample_count, 1265);
+        let bytes = std::fs::read(&output).unwrap();
+        let ftyp_size = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        assert_eq!(&bytes[ftyp_size + 4..ftyp_size + 8], b"moov");
+        assert!(bytes.windows(4).any(|window| window == b"hvc1"));
+
+        // The repeated parameter sets are gone from the samples, those of the sample entry
+        // are still there
+        let mut mp4 = open_mp4(&output);
+        for id in 1..=mp4.sample_count(1).unwrap() {
+            let sample = mp4.read_sample(1, id).unwrap().unwrap();
+            let parameter_sets = crate::nal::ParameterSets::find(H265, &sample.bytes);
+            assert!(parameter_sets.vps.is_none() && parameter_sets.sps.is_none());
+        }
+        let hvcc =
+            crate::boxes::Moov::read_anywhere(&mut std::fs::File::open(&output).unwrap()).unwrap();
+        let hvcc = hvcc
+            .get(&[
+                b"trak", b"mdia", b"minf", b"stbl", b"stsd", b"hvc1", b"hvcC",
+            ])
+            .unwrap();
+        assert_eq!(hvcc[8 + 22], 3); // numOfArrays
+
+        let archive = convert(&mut crate::ConvertOptions::archival(
+            report.to_string_lossy(),
+        ));
+        assert!(!archive.fragmented);
+        assert_eq!(archive.tracks[0].sample_count, 1265);
+        assert!(report.exists());
+
+        let edit = convert(&mut crate::ConvertOptions {
+            title: Some("Edit".to_string()),
+            ..crate::ConvertOptions::editing(25.0)
+        });
+        assert!(!edit.fragmented);
+        assert!(edit.valid_moov);
+        assert_eq!(edit.tracks.len(), 2); // video and timecode
+        assert_eq!(&std::fs::read(&output).unwrap()[4..12], b"ftypqt  ");
+    }
+
+    #[test]
+    fn zero_timestamp_policy() {
+        use crate::ZeroTimestampPolicy::{Error, Interpolate, Skip};
+
+        let output = temp_path("zero_timestamp.mp4");
+
+        // Frames at 120, 200, 240 and 320 ms have lost their timestamp
+        let frames: Vec<crate::FrameSpec> = [0, 40, 80, 0, 160, 0, 0, 280, 0]
+            .iter()
+            .enumerate()
+            .map(|(i, &ms)| {
+                let payload = match i {
+                    0 => vec![0, 0, 0, 1, 0x26, 1, 0xaf, i as u8],
+                    _ => vec![0, 0, 0, 1, 2, 1, 0xd0, i as u8],
+                };
+                crate::FrameSpec::new(crate::VideoCaptureFormat::H265, ms * 1_000_000, payload)
+            })
+            .collect();
+        let input = temp_vraw("zero_timestamp.vraw", &frames);
+
+        let convert = |zero_timestamp_policy| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    exact_timestamps: true,
+                    zero_timestamp_policy,
+                    ..Default::default()
+                },
+            )
+        };
+        let samples = || {
+            let mut mp4 = open_mp4(&output);
+            (1..=mp4.sample_count(1).unwrap())
+                .map(|id| {
+                    let sample = mp4.read_sample(1, id).unwrap().unwrap();
+                    (*sample.bytes.last().unwrap(), sample.duration)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let error = convert(Error).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::ZeroTimestamp { at_frame: 3 }),
+            "{error}"
+        );
+        assert!(!output.exists());
+
+        let summary = convert(Skip).unwrap();
+        assert_eq!(summary.zero_timestamp_frames, 4);
+        assert_eq!(samples(), [(0, 40), (1, 40), (2, 80), (4, 120), (7, 120)]);
+
+        let summary = convert(Interpolate).unwrap();
+        assert_eq!(summary.zero_timestamp_frames, 4);
+        assert_eq!(samples(), (0..9).map(|i| (i, 40)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn frame_filter() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let output = temp_path("frame_filter.mp4");
+
+        let keyframe = H265_KEYFRAME.to_vec();
+        let frame = |ms: i64, payload: &[u8]| {
+            crate::FrameSpec::new(H265, ms * 1_000_000, [payload, &[ms as u8]].concat())
+        };
+        let frames = vec![
+            frame(0, &keyframe),
+            crate::FrameSpec::new(Stats, 10_000_000, vec![1]),
+            frame(40, H265_INTER_FRAME),
+            frame(80, H265_INTER_FRAME),
+            frame(120, H265_INTER_FRAME),
+            frame(160, &keyframe),
+            frame(200, H265_INTER_FRAME),
+        ];
+        let input = temp_vraw("frame_filter.vraw", &frames);
+
+        let mut seen = Vec::new();
+        let mut filter = |frame: &crate::RawFrame| {
+            seen.push(frame.format);
+            frame.format != Stats && ![80, 160].contains(&(frame.timestamp / 1_000_000))
+        };
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                exact_timestamps: true,
+                frame_filter: Some(&mut filter),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(seen, [H265, Stats, H265, H265, H265, H265, H265]);
+        assert_eq!(summary.filtered_frames, 3);
+        assert_eq!(summary.samples_written, 4);
+        assert_eq!(summary.warnings.len(), 1);
+        assert!(
+            summary.warnings[0].contains("keyframe"),
+            "{:?}",
+            summary.warnings
+        );
+
+        let mut mp4 = open_mp4(&output);
+        let samples: Vec<(u8, u32)> = (1..=4)
+            .map(|id| {
+                let sample = mp4.read_sample(1, id).unwrap().unwrap();
+                (*sample.bytes.last().unwrap(), sample.duration)
+            })
+            .collect();
+        assert_eq!(samples, [(0, 40), (40, 80), (120, 80), (200, 80)]);
+    }
+
+    #[test]
+    fn mismatched_frame_format() {
+        use crate::VideoCaptureFormat::{H264, H265};
+
+        let output = temp_path("format_mismatch.mp4");
+
+        let frames: Vec<_> = [H265, H265, H264, H265]
+            .iter()
+            .enumerate()
+            .map(|(i, &format)| {
+                crate::FrameSpec::new(format, i as i64 * 40_000_000, H265_INTER_FRAME.to_vec())
+            })
+            .collect();
+        let input = temp_vraw("format_mismatch.vraw", &frames);
+
+        let convert = |tolerate_format_mismatch| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    tolerate_format_mismatch,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let error = convert(false).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::Parse(crate::ParseError::FormatMismatch {
+                    frame_fmt: H264,
+                    ..
+                })
+            ),
+            "{error}"
+        );
+        assert!(!output.exists());
+
+        convert(true).unwrap();
+        let mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap(), 3);
+    }
+
+    #[test]
+    fn seek_index_points_at_frames() {
+        let index = crate::export_seek_index("assets/h265.vraw").unwrap();
+        assert_eq!(index.len(), 1265);
+        assert!(!index[0].is_keyframe);
+        assert!(index.iter().any(|entry| entry.is_keyframe));
+        assert!(index
+            .windows(2)
+            .all(|pair| pair[0].byte_offset < pair[1].byte_offset));
+
+        let mut f = std::io::BufReader::new(std::fs::File::open("assets/h265.vraw").unwrap());
+        let entries = crate::parser::read_index(&mut f).unwrap();
+        for seek_entry in [index[0], index[index.len() / 2], index[index.len() - 1]] {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.offset.get() as u64 == seek_entry.byte_offset)
+                .unwrap();
+            let frame = crate::parser::parse_raw_frame(&mut f, entry).unwrap();
+            assert_eq!(frame.format, crate::VideoCaptureFormat::H265);
+            assert_eq!(frame.timestamp, seek_entry.timestamp_ns);
+        }
+
+        let output = temp_path("seek_index.json");
+        let written =
+            crate::write_seek_index_json("assets/h265.vraw", &output.to_string_lossy()).unwrap();
+        assert_eq!(written, index.len());
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&output).unwrap()).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), index.len());
+        assert_eq!(json[0]["byte_offset"], index[0].byte_offset);
+        assert_eq!(json[0]["is_keyframe"], false);
+    }
+
+    #[test]
+    fn default_filename_timezone() {
+        let dir = temp_path("filename_timezone");
+        std::fs::create_dir_all(dir.join("recordings")).unwrap();
+        let input = dir.join("recordings").join("drive.vraw");
+        let frame =
+            crate::FrameSpec::new(crate::VideoCaptureFormat::H265, 0, H265_KEYFRAME.to_vec());
+        std::fs::write(&input, crate::build_vraw(&[frame])).unwrap();
+
+        let convert = |filename_timezone| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                None,
+                &mut crate::ConvertOptions {
+                    filename_timezone,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // UTC+14:00 is never the local time of a build machine in practice
+        let offset = chrono::FixedOffset::east_opt(14 * 3600).unwrap();
+        let name = || {
+            let now = chrono::Utc::now().with_timezone(&offset);
+            dir.join(format!("drive_{}.mp4", now.format("%Y-%m-%dT%H_%M_%S")))
+        };
+        let before = name();
+        let summary = convert(crate::FilenameTz::Fixed(14 * 3600)).unwrap();
+        let after = name();
+        assert!(summary.output_path == before || summary.output_path == after);
+        assert!(summary.output_path.exists());
+
+        // A second conversion within the same second gets a counter instead of overwriting
+        let first = summary.output_path;
+        std::fs::copy(&first, name()).unwrap();
+        let second = convert(crate::FilenameTz::Fixed(14 * 3600))
+            .unwrap()
+            .output_path;
+        assert_ne!(second, first);
+        assert!(first.exists() && second.exists());
+
+        let error = convert(crate::FilenameTz::Fixed(24 * 3600)).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error}"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn max_temporal_id_keeps_the_base_layer() {
+        let output = temp_path("temporal_id.mp4");
+
+        // An IDR slice followed by TRAIL_R slices alternating between temporal id 1 and 0
+        let frames: Vec<_> = (0..8u8)
+            .map(|i| {
+                let header = match i {
+                    0 => [0x26, 0x01],
+                    _ if i % 2 == 1 => [0x02, 0x02],
+                    _ => [0x02, 0x01],
+                };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 20_000_000,
+                    vec![0, 0, 0, 1, header[0], header[1], 0xaf, i],
+                )
+            })
+            .collect();
+        let input = temp_vraw("temporal_id.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                max_temporal_id: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.dropped_sublayer_frames, 4);
+
+        let mut mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap(), 4);
+        for (sample_id, i) in (1..=4).zip([0, 2, 4, 6]) {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            assert_eq!(sample.duration, 40);
+            assert_eq!(sample.bytes.last(), Some(&i));
+        }
+    }
+
+    #[test]
+    fn default_filename_edge_cases() {
+        let vraw = crate::build_vraw(&[crate::FrameSpec::new(
+            crate::VideoCaptureFormat::H265,
+            0,
+            H265_KEYFRAME.to_vec(),
+        )]);
+
+        for (name, prefix) in [
+            ("録画_テスト.vraw", "録画_テスト_"),
+            (".vraw", ".vraw_"),
+            ("drive.vraw.vraw", "drive.vraw_"),
+        ] {
+            let dir = temp_path("filename_edge_cases");
+            std::fs::create_dir_all(dir.join("recordings")).unwrap();
+            let input = dir.join("recordings").join(name);
+            std::fs::write(&input, &vraw).unwrap();
+
+            crate::convert_vraw_to_mp4(&input.to_string_lossy().to_string(), None).unwrap();
+
+            let outputs: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|file_name| file_name != "recordings")
+                .collect();
+            assert_eq!(outputs.len(), 1, "{outputs:?}");
+            assert!(outputs[0].starts_with(prefix), "{outputs:?}");
+            assert!(outputs[0].ends_with(".mp4"), "{outputs:?}");
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        let error = crate::convert_vraw_to_mp4(&"..".to_string(), None).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidPath(_)),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn appended_sessions() {
+        let input = temp_path("sessions.vraw");
+        let output = temp_path("sessions.mp4");
+
+        // The second session restarts the clock
+        let session = |frames: usize, start: i64| -> Vec<_> {
+            (0..frames)
+                .map(|i| {
+                    let nal_type = if i == 0 { 0x26 } else { 0x02 };
+                    crate::FrameSpec::new(
+                        crate::VideoCaptureFormat::H265,
+                        start + i as i64 * 40_000_000,
+                        vec![0, 0, 0, 1, nal_type, 1, 0xaf, i as u8],
+                    )
+                })
+                .collect()
+        };
+        let (first, second) = (session(4, 50_000_000_000), session(5, 1_000_000_000));
+        std::fs::write(&input, crate::build_vraw_sessions(&[&first, &second])).unwrap();
+        let input = input.to_string_lossy().to_string();
+
+        let indexes = crate::read_all_indexes(&input).unwrap();
+        assert_eq!(
+            indexes.iter().map(|index| index.len()).collect::<Vec<_>>(),
+            [4, 5]
+        );
+        assert_eq!(indexes[1][0].receive_timestamp, 1_000_000_000);
+        assert_eq!(
+            crate::read_all_indexes("assets/h265.vraw").unwrap().len(),
+            1
+        );
+
+        let convert = |session| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input,
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    session,
+                    ..Default::default()
+                },
+            )
+        };
+        let durations = || {
+            let mut mp4 = open_mp4(&output);
+            (1..=mp4.sample_count(1).unwrap())
+                .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap().duration)
+                .collect::<Vec<_>>()
+        };
+
+        convert(crate::Session::Last).unwrap();
+        assert_eq!(durations(), [40; 5]);
+        convert(crate::Session::Nth(0)).unwrap();
+        assert_eq!(durations(), [40; 4]);
+        convert(crate::Session::All).unwrap();
+        assert_eq!(durations(), [40; 9]);
+
+        let error = convert(crate::Session::Nth(2)).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::Parse(crate::ParseError::MissingSession {
+                    session: 2,
+                    sessions: 2
+                })
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn panics_remove_the_partial_output() {
+        let output = temp_path("panic.mp4");
+
+        let mut samples = 0;
+        let mut on_sample = |_: &crate::RawFrame, _: &mut Vec<u8>| {
+            samples += 1;
+            assert!(samples < 100, "injected failure");
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    on_sample: Some(&mut on_sample),
+                    flush_every: Some(10),
+                    ..Default::default()
+                },
+            )
+        }));
+
+        assert!(result.is_err());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn timelapse_keeps_keyframes() {
+        let output = temp_path("timelapse.mp4");
+
+        // 40 frames every 40 ms with a keyframe every 5 frames
+        let frames: Vec<_> = (0..40u8)
+            .map(|i| {
+                let nal_type = if i % 5 == 0 { 0x26 } else { 0x02 };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, 0xaf, i],
+                )
+            })
+            .collect();
+        let input = temp_vraw("timelapse.vraw", &frames);
+
+        // A stride below the keyframe interval keeps every keyframe
+        for (stride, kept, speedup) in [
+            (10, vec![0, 10, 20, 30], 10.0),
+            (3, vec![0, 5, 10, 15, 20, 25, 30, 35], 5.0),
+        ] {
+            let summary = crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    timelapse_stride: Some(stride),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(summary.samples_written, kept.len());
+            assert_eq!(summary.timelapse_speedup, Some(speedup));
+
+            let mut mp4 = open_mp4(&output);
+            for (sample_id, i) in (1..).zip(kept) {
+                let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+                assert!(sample.is_sync);
+                assert_eq!(sample.duration, 40);
+                assert_eq!(sample.bytes.last(), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn shuffled_index_is_sorted_by_timestamp() {
+        let output = temp_path("shuffled.mp4");
+
+        // The index lists the frames out of timestamp order
+        let frames: Vec<_> = [0u8, 2, 1, 3, 4]
+            .iter()
+            .map(|&i| {
+                let nal_type = if i == 0 { 0x26 } else { 0x02 };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, 0xaf, i],
+                )
+            })
+            .collect();
+        let input = temp_vraw("shuffled.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions::default(),
+        )
+        .unwrap();
+        assert!(summary
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("timestamp order")));
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                sort_by_timestamp: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.samples_written, 5);
+        assert!(summary.warnings.is_empty());
+
+        let mut mp4 = open_mp4(&output);
+        for i in 0..5u8 {
+            let sample = mp4.read_sample(1, i as u32 + 1).unwrap().unwrap();
+            assert_eq!(sample.duration, 40);
+            assert_eq!(sample.bytes.last(), Some(&i));
+        }
+    }
+
+    #[test]
+    fn decode_order_writes_composition_offsets() {
+        let output = temp_path("decode_order.mp4");
+
+        // Frames in decode order with B-frames, the timestamps are presentation times
+        let presentation = [0u8, 3, 1, 2, 6, 4, 5];
+        let frames: Vec<_> = presentation
+            .iter()
+            .map(|&i| {
+                let nal_type = if i == 0 { 0x26 } else { 0x02 };
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, 0xaf, i],
+                )
+            })
+            .collect();
+        let input = temp_vraw("decode_order.vraw", &frames);
+
+        let convert = |fragmented| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    frame_order: crate::FrameOrder::Decode,
+                    container: if fragmented {
+                        crate::Container::FragmentedMp4
+                    } else {
+                        crate::Container::Mp4
+                    },
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let summary = convert(false);
+        assert_eq!(summary.samples_written, 7);
+        assert!(summary.warnings.is_empty());
+
+        let mut mp4 = open_mp4(&output);
+        for (sample_id, (decode, &i)) in (1..).zip(presentation.iter().enumerate()) {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            assert_eq!(sample.bytes.last(), Some(&i));
+            assert_eq!(sample.start_time, decode as u64 * 40);
+            assert_eq!(sample.duration, 40);
+            assert_eq!(sample.rendering_offset, (i as i32 - decode as i32) * 40);
+        }
+
+        // Fragmented output carries the offsets in a version 1 track run
+        convert(true);
+        let bytes = std::fs::read(&output).unwrap();
+        assert!(bytes
+            .windows(8)
+            .any(|window| window == b"trun\x01\0\x0f\x01"));
+    }
+
+    #[test]
+    fn compressed_and_in_memory_inputs() {
+        let input = temp_path("compressed.vraw.zst");
+        let output = temp_path("in_memory.mp4");
+
+        for (magic, compression) in [
+            (&[0x28, 0xb5, 0x2f, 0xfd][..], "zstd"),
+            (&[0x1f, 0x8b, 8][..], "gzip"),
+        ] {
+            std::fs::write(&input, magic).unwrap();
+            let error =
+                crate::convert_vraw_to_mp4(&input.to_string_lossy().to_string(), None).unwrap_err();
+            assert!(
+                matches!(error, crate::ConvertError::CompressedInput(c) if c == compression),
+                "{error}"
+            );
+        }
+
+        let recording = std::fs::read("assets/h265.vraw").unwrap();
+        let summary = crate::convert_vraw_reader(
+            std::io::Cursor::new(recording),
+            &output.to_string_lossy(),
+            &mut crate::ConvertOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.samples_written, 1265);
+    }
+
+    #[test]
+    fn peeked_frame_meta_matches_parsed_frames() {
+        let indexes = crate::read_all_indexes("assets/h265.vraw").unwrap();
+        let mut f = std::io::BufReader::new(std::fs::File::open("assets/h265.vraw").unwrap());
+        let entries = crate::parser::read_index(&mut f).unwrap();
+        assert_eq!(indexes[0].len(), entries.len());
+
+        for (index_entry, entry) in indexes[0].iter().zip(&entries) {
+            let meta = crate::peek_frame_meta(&mut f, index_entry).unwrap();
+            let frame = crate::parser::parse_raw_frame(&mut f, entry).unwrap();
+            assert_eq!(meta.format, frame.format);
+            assert_eq!(meta.timestamp, frame.timestamp);
+            assert!(meta.payload_len >= frame.raw_data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn extract_single_frames() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let input = temp_path("extract.vraw");
+        let output = temp_path("extract.h265");
+        let input_path = input.to_string_lossy().to_string();
+        let output_path = output.to_string_lossy().to_string();
+
+        // Video frames every 40 ms, starting at 100 ms, with a stats frame after each
+        let mut frames = Vec::new();
+        for i in 0..4u8 {
+            let timestamp = 100_000_000 + i as i64 * 40_000_000;
+            frames.push(crate::FrameSpec::new(
+                H265,
+                timestamp,
+                vec![0, 0, 0, 1, 2, 1, 0xaf, i],
+            ));
+            frames.push(crate::FrameSpec::new(Stats, timestamp + 1, vec![i]));
+        }
+        std::fs::write(&input, crate::build_vraw(&frames)).unwrap();
+
+        crate::extract_frame(&input_path, 2, &output_path).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), [0, 0, 0, 1, 2, 1, 0xaf, 1]);
+        crate::extract_frame(&input_path, 3, &output_path).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), [1]);
+
+        let error = crate::extract_frame(&input_path, 8, &output_path).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::Parse(crate::ParseError::MissingFrame {
+                    index: 8,
+                    frames: 8
+                })
+            ),
+            "{error}"
+        );
+
+        // Between frames the earlier one is shown, before the first frame the first one
+        for (timestamp, expected_index, expected_byte) in [
+            (190_000_000, 4, 2),
+            (180_000_000, 4, 2),
+            (0, 0, 0),
+            (i64::MAX, 6, 3),
+        ] {
+            let index = crate::extract_frame_at(&input_path, timestamp, &output_path).unwrap();
+            assert_eq!(index, expected_index, "{timestamp}");
+            assert_eq!(std::fs::read(&output).unwrap().last(), Some(&expected_byte));
+        }
+    }
+
+    #[test]
+    fn luma_preview_of_uncompressed_frames() {
+        use crate::VideoCaptureFormat::{Mono16, Mono8, Rgb, Uyvy, Yuyv, H265};
+
+        let input = temp_path("luma_preview.vraw");
+        let prefix = std::env::temp_dir()
+            .join("vraw_convert_luma_preview_")
+            .to_string_lossy()
+            .to_string();
+
+        // 2x1 pixel frames of each format and the luma they hold
+        for (format, payload, luma) in [
+            (Mono8, vec![16, 235], vec![16, 235]),
+            (
+                Mono16,
+                vec![0x34, 0x12, 0x78, 0x56],
+                vec![0x12, 0x34, 0x56, 0x78],
+            ),
+            (Yuyv, vec![16, 128, 235, 128], vec![16, 235]),
+            (Uyvy, vec![128, 16, 128, 235], vec![16, 235]),
+            (Rgb, vec![16, 16, 16, 235, 235, 235], vec![16, 235]),
+        ] {
+            let frames: Vec<_> = (0..3)
+                .map(|i| crate::FrameSpec {
+                    width: 2,
+                    height: 1,
+                    ..crate::FrameSpec::new(format, i * 40_000_000, payload.clone())
+                })
+                .collect();
+            std::fs::write(&input, crate::build_vraw(&frames)).unwrap();
+
+            let images = crate::write_luma_preview(&input.to_string_lossy(), &prefix).unwrap();
+            assert_eq!(images, 3, "{format:?}");
+
+            let max_value = if format == Mono16 { 65535 } else { 255 };
+            let mut expected = format!("P5\n2 1\n{max_value}\n").into_bytes();
+            expected.extend(luma);
+            for i in 0..3 {
+                let path = format!("{prefix}{i:05}.pgm");
+                assert_eq!(std::fs::read(&path).unwrap(), expected, "{format:?}");
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+
+        let coded = crate::FrameSpec::new(H265, 0, H265_KEYFRAME.to_vec());
+        std::fs::write(&input, crate::build_vraw(&[coded])).unwrap();
+        let error = crate::write_luma_preview(&input.to_string_lossy(), &prefix).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::UnsupportedFormat(H265)),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn export_image_sequence_with_stride() {
+        use crate::VideoCaptureFormat::{Rgb, Stats};
+
+        let out_dir = temp_path("image_sequence");
+
+        let mut frames: Vec<_> = (0..5)
+            .map(|i| crate::FrameSpec {
+                width: 2,
+                height: 1,
+                ..crate::FrameSpec::new(Rgb, i * 40_000_000, vec![1, 2, 3, 4, 5, 6])
+            })
+            .collect();
+        frames.insert(1, crate::FrameSpec::new(Stats, 20_000_000, vec![0; 24]));
+        let input = temp_vraw("image_sequence.vraw", &frames);
+
+        let images = crate::export_image_sequence(
+            &input.to_string_lossy(),
+            &out_dir.to_string_lossy(),
+            "frame_{index}_{timestamp}.bmp",
+            2,
+            None,
+        )
+        .unwrap();
+        assert_eq!(images, 3);
+
+        let mut names: Vec<String> = std::fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "frame_000000_0.bmp",
+                "frame_000002_80000000.bmp",
+                "frame_000004_160000000.bmp"
+            ]
+        );
+
+        let image = std::fs::read(out_dir.join(&names[0])).unwrap();
+        assert_eq!(&image[..2], b"BM");
+        assert_eq!(image.len(), 14 + 40 + 8);
+        assert_eq!(&image[22..26], &(-1i32).to_le_bytes());
+        assert_eq!(&image[54..], [3, 2, 1, 6, 5, 4, 0, 0]);
+
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+
+    #[test]
+    fn export_image_sequence_with_crop() {
+        use crate::VideoCaptureFormat::{Mono8, H265};
+
+        let input = temp_path("image_crop.vraw");
+        let out_dir = temp_path("image_crop");
+        let export = |crop| {
+            crate::export_image_sequence(
+                &input.to_string_lossy(),
+                &out_dir.to_string_lossy(),
+                "frame_{index}.bmp",
+                1,
+                Some(crop),
+            )
+        };
+
+        // A 4x3 frame whose pixels are numbered row by row
+        let frame = crate::FrameSpec {
+            width: 4,
+            height: 3,
+            ..crate::FrameSpec::new(Mono8, 0, (0..12).collect())
+        };
+        std::fs::write(&input, crate::build_vraw(&[frame])).unwrap();
+
+        let center = crate::Crop {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        assert_eq!(export(center).unwrap(), 1);
+        let image = std::fs::read(out_dir.join("frame_000000.bmp")).unwrap();
+        assert_eq!(&image[18..26], [2, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(
+            &image[54..],
+            [5, 5, 5, 6, 6, 6, 0, 0, 9, 9, 9, 10, 10, 10, 0, 0]
+        );
+
+        let error = export(crate::Crop { x: 3, ..center }).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::CropOutsideFrame {
+                    frame_width: 4,
+                    frame_height: 3
+                }
+            ),
+            "{error}"
+        );
+
+        let coded = crate::FrameSpec::new(H265, 0, H265_KEYFRAME.to_vec());
+        std::fs::write(&input, crate::build_vraw(&[coded])).unwrap();
+        let error = export(center).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error}"
+        );
+
+        std::fs::remove_dir_all(out_dir).unwrap();
+    }
+
+    #[test]
+    fn supported_output_codecs_convert() {
+        use crate::VideoCaptureFormat::{Mjpeg, Mono8, H264, H265};
+
+        let input = temp_path("supported_codecs.vraw");
+        let output = temp_path("supported_codecs.mp4");
+
+        assert_eq!(crate::supported_output_codecs(), &[H264, H265]);
+        for (format, payload) in [
+            (H264, vec![0, 0, 0, 1, 0x65, 0x88, 0x84]),
+            (H265, H265_KEYFRAME.to_vec()),
+            (Mjpeg, vec![0xff, 0xd8, 0xff, 0xd9]),
+            (Mono8, vec![0x80]),
+        ] {
+            // Only uncompressed frames have dimensions
+            let size = !format.is_coded() as u32;
+            let frame = crate::FrameSpec {
+                width: size,
+                height: size,
+                ..crate::FrameSpec::new(format, 0, payload)
+            };
+            std::fs::write(&input, crate::build_vraw(&[frame])).unwrap();
+
+            let result = crate::convert_vraw_to_mp4(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+            );
+            if crate::supported_output_codecs().contains(&format) {
+                result.unwrap();
+            } else {
+                let error = result.unwrap_err();
+                assert!(
+                    matches!(error, crate::ConvertError::UnsupportedFormat(f) if f == format),
+                    "{error}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mislabeled_first_frame() {
+        let input = temp_path("mislabeled.vraw");
+        let output = temp_path("mislabeled.mp4");
+
+        let convert = |payload: Vec<u8>| {
+            let frame = crate::FrameSpec::new(crate::VideoCaptureFormat::H265, 0, payload);
+            std::fs::write(&input, crate::build_vraw(&[frame])).unwrap();
+
+            crate::convert_vraw_to_mp4(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+            )
+        };
+
+        // An H.265 VPS and IDR slice
+        convert(vec![
+            0, 0, 0, 1, 0x40, 0x01, 0x0c, 0, 0, 1, 0x26, 0x01, 0xaf,
+        ])
+        .unwrap();
+
+        // An H.264 SPS and IDR slice, read as H.265 headers they have a nonzero layer id
+        let error = convert(vec![
+            0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f, 0, 0, 1, 0x65, 0x88,
+        ])
+        .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::CodecValidationFailed(crate::VideoCaptureFormat::H265)
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn conversion_report() {
+        use crate::VideoCaptureFormat::{H264, H265};
+
+        let output = temp_path("report.mp4");
+        let report = temp_path("report.json");
+
+        let frames: Vec<_> = [H265, H265, H264, H265]
+            .iter()
+            .enumerate()
+            .map(|(i, &format)| {
+                crate::FrameSpec::new(format, i as i64 * 40_000_000, H265_INTER_FRAME.to_vec())
+            })
+            .collect();
+        let input = temp_vraw("report.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                tolerate_format_mismatch: true,
+                report_path: Some(report.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.codec, H265);
+        assert_eq!(summary.samples_written, 3);
+        assert_eq!(summary.skipped_frames, 1);
+        assert_eq!(summary.warnings.len(), 1);
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+        assert_eq!(json["input"], input.to_string_lossy().as_ref());
+        assert_eq!(json["output"], output.to_string_lossy().as_ref());
+        assert_eq!(json["codec"], "H265");
+        assert_eq!(json["samples_written"], 3);
+        assert_eq!(json["skipped_frames"], 1);
+        assert_eq!(json["duration_ms"], summary.duration_ms);
+        assert_eq!(json["options"]["tolerate_format_mismatch"], true);
+        assert_eq!(json["warnings"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wall_clock_timestamps_date_the_output() {
+        use crate::ClockKind;
+
+        let output = temp_path("wall_clock.mp4");
+
+        // 2023-01-01T00:00:00Z, a stats frame first and 5 frames 40 ms apart
+        let start = 1_672_531_200_000_000_000;
+        let mut frames = vec![crate::FrameSpec::new(
+            crate::VideoCaptureFormat::Stats,
+            start - 1_000_000_000,
+            vec![1, 2, 3],
+        )];
+        frames.extend(h265_frames(5, 40_000_000).into_iter().map(|mut frame| {
+            frame.timestamp += start;
+            frame
+        }));
+        let input = temp_vraw("wall_clock.vraw", &frames);
+
+        let mp4_creation_time = 1_672_531_200 + 2_082_844_800;
+        for (clock, creation_time) in [
+            (ClockKind::Auto, mp4_creation_time),
+            (ClockKind::WallClock, mp4_creation_time),
+            (ClockKind::Monotonic, 0),
+        ] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    clock,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mp4 = open_mp4(&output);
+            assert_eq!(mp4.moov.mvhd.creation_time, creation_time, "{clock:?}");
+            assert_eq!(mp4.moov.mvhd.modification_time, creation_time, "{clock:?}");
+
+            let track = &mp4.tracks()[&1];
+            assert_eq!(track.trak.tkhd.creation_time, creation_time, "{clock:?}");
+            assert_eq!(
+                track.trak.mdia.mdhd.creation_time, creation_time,
+                "{clock:?}"
+            );
+            assert_eq!(track.sample_count(), 5);
+            assert_eq!(mp4.duration(), std::time::Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn error_sources_are_preserved() {
+        use std::error::Error;
+
+        // Too short to hold the index footer
+        let input = temp_path("error_sources.vraw");
+        std::fs::write(&input, [0; 4]).unwrap();
+
+        let error =
+            crate::convert_vraw_to_mp4(&input.to_string_lossy().to_string(), None).unwrap_err();
+        assert!(matches!(error, crate::ConvertError::Parse(_)), "{error:?}");
+
+        let parse_error = error.source().unwrap();
+        assert!(parse_error.is::<crate::ParseError>());
+        let io_error = parse_error.source().unwrap();
+        assert!(io_error.is::<std::io::Error>());
+    }
+
+    #[test]
+    fn chapters_start_on_keyframes_after_markers() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let output = temp_path("chapters.mp4");
+
+        // Video frames every 40 ms with keyframes at 0 and 200 ms, the first stats byte
+        // marks an event
+        let mut frames = vec![crate::FrameSpec::new(Stats, -1, vec![1])];
+        for i in 0..10 {
+            let nal_type = if i % 5 == 0 { 0x26 } else { 0x02 };
+            frames.push(crate::FrameSpec::new(
+                H265,
+                i * 40_000_000,
+                vec![0, 0, 0, 1, nal_type, 1, 0xd0],
+            ));
+            frames.push(crate::FrameSpec::new(
+                Stats,
+                i * 40_000_000 + 10_000_000,
+                vec![(i == 1 || i == 2) as u8],
+            ));
+        }
+        let input = temp_vraw("chapters.vraw", &frames);
+
+        let mut events = 0;
+        let mut chapter_marker = |stats: &crate::StatsFrame| {
+            (stats.raw_data[0] == 1).then(|| {
+                events += 1;
+                format!("Event {events}")
+            })
+        };
+        crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                chapter_marker: Some(&mut chapter_marker),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut file = std::fs::File::open(&output).unwrap();
+        let moov = crate::boxes::Moov::read(&mut file).unwrap();
+        let chpl = moov.get(&[b"udta", b"chpl"]).unwrap();
+
+        // Events 2 and 3 both lead up to the keyframe at 200 ms
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "Event 1"), (2_000_000, "Event 2")] {
+            expected.extend_from_slice(&start.to_be_bytes());
+            expected.push(title.len() as u8);
+            expected.extend_from_slice(title.as_bytes());
+        }
+        assert_eq!(&chpl[8..], &expected[..]);
+    }
+
+    #[test]
+    fn header_and_footer_indexed_recordings() {
+        let header_indexed = temp_path("header_indexed.vraw");
+
+        let mut frames = h265_frames(5, 40_000_000);
+        frames[0].payload = H265_KEYFRAME.to_vec();
+        let footer_indexed = temp_vraw("footer_indexed.vraw", &frames);
+        std::fs::write(&header_indexed, crate::build_vraw_header_indexed(&frames)).unwrap();
+
+        let mut reports = Vec::new();
+        for input in [&footer_indexed, &header_indexed] {
+            let mut f = std::fs::File::open(input).unwrap();
+            let index = crate::parser::read_index(&mut f).unwrap();
+            let timestamps: Vec<i64> = index.iter().map(|e| e.receive_timestamp.get()).collect();
+            assert_eq!(
+                timestamps,
+                [0, 40_000_000, 80_000_000, 120_000_000, 160_000_000]
+            );
+            assert_eq!(
+                crate::read_all_indexes(&input.to_string_lossy())
+                    .unwrap()
+                    .len(),
+                1
+            );
+
+            let output = input.with_extension("mp4");
+            let summary = crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(summary.samples_written, 5);
+            reports.push(crate::validate_mp4(&output.to_string_lossy()).unwrap());
+
+            std::fs::remove_file(input).unwrap();
+            std::fs::remove_file(output).unwrap();
+        }
+        assert_eq!(reports[0], reports[1]);
+    }
+
+    #[test]
+    fn convert_on_channel() {
+        let output = temp_path("channel.mp4");
+
+        let frames: Vec<_> = (0..4)
+            .map(|i| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i * 40_000_000,
+                    H265_KEYFRAME.to_vec(),
+                )
+            })
+            .collect();
+        let input = temp_vraw("channel.vraw", &frames);
+
+        let (handle, events) = crate::convert_vraw_to_mp4_channel(
+            input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            |options: &mut crate::ConvertOptions| options.exact_timestamps = true,
+        );
+        let events: Vec<_> = events.iter().collect();
+        let summary = handle.join().unwrap().unwrap();
+        assert_eq!(summary.samples_written, 4);
+
+        let progress = (1..=4).map(|frames_read| crate::ProgressEvent::Progress {
+            frames_read,
+            total_frames: 4,
+        });
+        let finished = crate::ProgressEvent::Finished { samples_written: 4 };
+        assert_eq!(events, progress.chain([finished]).collect::<Vec<_>>());
+
+        // Errors are reported on the channel too
+        let (handle, events) = crate::convert_vraw_to_mp4_channel(
+            output.to_string_lossy().to_string(),
+            None,
+            |_: &mut crate::ConvertOptions| {},
+        );
+        assert!(matches!(
+            events.iter().last(),
+            Some(crate::ProgressEvent::Failed(_))
+        ));
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn timestamp_log() {
+        let output = temp_path("timestamp_log.mp4");
+        let log = temp_path("timestamp_log.txt");
+
+        // Embedded timestamps 40 ms apart and a stats frame, which needs no log entry
+        let mut frames = h265_frames(3, 40_000_000);
+        frames[0].payload = H265_KEYFRAME.to_vec();
+        frames.insert(
+            1,
+            crate::FrameSpec::new(crate::VideoCaptureFormat::Stats, 5_000_000, vec![1]),
+        );
+        let input = temp_vraw("timestamp_log.vraw", &frames);
+
+        let convert = |log_lines: &str| {
+            std::fs::write(&log, log_lines).unwrap();
+            crate::convert_vraw_with_timestamp_log(
+                &input.to_string_lossy(),
+                &output.to_string_lossy(),
+                &log.to_string_lossy(),
+            )
+        };
+
+        let error = convert("1000000000\n1050000000\n").unwrap_err();
+        assert!(
+            matches!(
+                error,
+                crate::ConvertError::TimestampLogLength {
+                    entries: 2,
+                    frames: 3
+                }
+            ),
+            "{error}"
+        );
+        assert!(!output.exists());
+
+        let error = convert("1000000000\n1050000000\n11OO000000\n").unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::TimestampLog { line: 3 }),
+            "{error}"
+        );
+
+        let summary = convert("1000000000\n1050000000\n\n1075000000\n").unwrap();
+        assert_eq!(summary.samples_written, 3);
+
+        let mut mp4 = open_mp4(&output);
+        let start_times: Vec<u64> = (1..=3)
+            .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap().start_time)
+            .collect();
+        assert_eq!(start_times, [0, 50, 75]);
+    }
+
+    #[test]
+    fn zero_length_frames() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let output = temp_path("zero_length.mp4");
+
+        // Keepalive frames without payload before, between and after the real frames
+        let frames = [
+            crate::FrameSpec::new(H265, 0, vec![]),
+            crate::FrameSpec::new(H265, 10_000_000, H265_KEYFRAME.to_vec()),
+            crate::FrameSpec::new(Stats, 20_000_000, vec![]),
+            crate::FrameSpec::new(H265, 30_000_000, vec![]),
+            crate::FrameSpec::new(H265, 50_000_000, H265_INTER_FRAME.to_vec()),
+            crate::FrameSpec::new(H265, 90_000_000, H265_INTER_FRAME.to_vec()),
+            crate::FrameSpec::new(H265, 100_000_000, vec![]),
+        ];
+        let input = temp_vraw("zero_length.vraw", &frames);
+
+        let mut empty_stats = 0;
+        let mut chapter_marker = |stats: &crate::StatsFrame| {
+            empty_stats += stats.raw_data.is_empty() as usize;
+            None
+        };
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                chapter_marker: Some(&mut chapter_marker),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.samples_written, 3);
+        assert_eq!(summary.empty_frames, 3);
+        assert_eq!(empty_stats, 1);
+
+        let report = crate::validate_mp4(&output.to_string_lossy()).unwrap();
+        assert_eq!(report.tracks[0].sample_count, 3);
+        assert!(report.tracks[0].has_sync_samples);
+    }
+
+    #[test]
+    fn preserve_timestamps() {
+        let output = temp_path("preserve_timestamps.mp4");
+
+        // Jittery frames at about 30 fps with a gap of two seconds in the middle
+        let timestamps: Vec<i64> = (0..60)
+            .map(|i| 1_000_000_000 + i * 33_366_667 + (i % 3) * 1_234_567 + (i / 30) * 2e9 as i64)
+            .collect();
+        let mut frames = h265_frames_at(&timestamps);
+        frames[0].payload = H265_KEYFRAME.to_vec();
+        let input = temp_vraw("preserve_timestamps.vraw", &frames);
+
+        let summary = crate::convert_vraw_to_mp4_with_options(
+            &input.to_string_lossy().to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                preserve_timestamps: true,
+                timescale: Some(90000),
+                max_frame_duration_ms: Some(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.clamped_frames, 1);
+
+        let mut mp4 = open_mp4(&output);
+        for (sample_id, timestamp) in (1..).zip(&timestamps) {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            let presentation_time = sample.start_time as i64 + sample.rendering_offset as i64;
+            assert_eq!(
+                presentation_time,
+                (timestamp - timestamps[0]) * 90000 / 1_000_000_000
+            );
+        }
+
+        // Always version 1, which allows negative offsets
+        let mut file = std::fs::File::open(&output).unwrap();
+        let moov = crate::boxes::Moov::read(&mut file).unwrap();
+        let ctts = moov
+            .get(&[b"trak", b"mdia", b"minf", b"stbl", b"ctts"])
+            .unwrap();
+        assert_eq!(ctts[8], 1);
+    }
+
+    #[test]
+    fn sample_dependencies() {
+        let output = temp_path("sample_dependencies.mp4");
+
+        // IDR_W_RADL, RADL_N, TRAIL_R and TRAIL_N frames
+        let frames: Vec<_> = [0x26, 0x0c, 0x02, 0x00]
+            .iter()
+            .enumerate()
+            .map(|(i, &nal_type)| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, 0xd0],
+                )
+            })
+            .collect();
+        let input = temp_vraw("sample_dependencies.vraw", &frames);
+
+        let convert = |sample_dependencies| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    sample_dependencies,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let mut file = std::fs::File::open(&output).unwrap();
+            let moov = crate::boxes::Moov::read(&mut file).unwrap();
+            moov.get(&[b"trak", b"mdia", b"minf", b"stbl", b"sdtp"])
+                .map(|sdtp| sdtp[12..].to_vec())
+        };
+        assert_eq!(convert(false), None);
+        assert_eq!(convert(true).unwrap(), [0xa6, 0xda, 0x96, 0x9a]);
+    }
+
+    #[test]
+    fn random_access_groups() {
+        let output = temp_path("random_access.mp4");
+
+        // An IDR frame followed by CRA frames (type 21) and trailing frames
+        let frames: Vec<_> = [0x26, 0x02, 0x02, 0x2a, 0x02, 0x2a]
+            .iter()
+            .enumerate()
+            .map(|(i, &nal_type)| {
+                crate::FrameSpec::new(
+                    crate::VideoCaptureFormat::H265,
+                    i as i64 * 40_000_000,
+                    vec![0, 0, 0, 1, nal_type, 1, 0xaf],
+                )
+            })
+            .collect();
+        let input = temp_vraw("random_access.vraw", &frames);
+
+        let convert = |fragmented| {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    random_access_groups: true,
+                    container: if fragmented {
+                        crate::Container::FragmentedMp4
+                    } else {
+                        crate::Container::Mp4
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+        let error = convert(true).unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error}"
+        );
+        convert(false).unwrap();
+
+        let mut file = std::fs::File::open(&output).unwrap();
+        let moov = crate::boxes::Moov::read(&mut file).unwrap();
+        let stbl = [b"trak", b"mdia", b"minf", b"stbl"];
+
+        // Version 1, grouping type, default length 1 and the IDR_W_RADL and CRA_NUT types
+        let sgpd = moov.get(&[&stbl[..], &[b"sgpd"]].concat()).unwrap();
+        assert_eq!(&sgpd[8..], b"\x01\0\0\0sync\0\0\0\x01\0\0\0\x02\x13\x15");
+
+        let runs = |runs: &[(u32, u32)]| {
+            let mut sbgp = vec![0; 4];
+            sbgp.extend_from_slice(b"sync");
+            sbgp.extend_from_slice(&(runs.len() as u32).to_be_bytes());
+            for (count, group) in runs {
+                sbgp.extend_from_slice(&count.to_be_bytes());
+                sbgp.extend_from_slice(&group.to_be_bytes());
+            }
+            sbgp
+        };
+        let sbgp = moov.get(&[&stbl[..], &[b"sbgp"]].concat()).unwrap();
+        assert_eq!(
+            &sbgp[8..],
+            &runs(&[(1, 1), (2, 0), (1, 2), (1, 0), (1, 2)])[..]
+        );
+
+        let mut rap = runs(&[(3, 0), (1, 1), (1, 0), (1, 1)]);
+        rap[4..8].copy_from_slice(b"rap ");
+        let stbl = moov.get(&stbl).unwrap();
+        assert!(stbl.windows(rap.len()).any(|window| window == rap));
+
+        // The sample groups do not get in the way of reading the file
+        let report = crate::validate_mp4(&output.to_string_lossy()).unwrap();
+        assert_eq!(report.tracks[0].sample_count, 6);
+        assert!(report.valid_moov);
+    }
+
+    #[test]
+    fn validate_converted_files() {
+        let input = "assets/h265.vraw".to_string();
+        let output = temp_path("validate.mp4");
+        let frames = crate::count_frames(&input).unwrap().video;
+        let metadata: std::collections::BTreeMap<String, String> = [
+            ("operator", "Jane Doe"),
+            ("mission id", "M-42"),
+            ("location", "Göteborg"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+        for fragmented in [false, true] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input,
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    container: if fragmented {
+                        crate::Container::FragmentedMp4
+                    } else {
+                        crate::Container::Mp4
+                    },
+                    metadata: metadata.clone(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let report = crate::validate_mp4(&output.to_string_lossy()).unwrap();
+            assert_eq!(report.fragmented, fragmented);
+            assert!(report.valid_moov);
+            assert_eq!(report.metadata, metadata);
+            assert_eq!(report.tracks.len(), 1);
+
+            let track = &report.tracks[0];
+            assert_eq!(track.codec.as_deref(), Some("hev1"));
+            assert!(track.has_sync_samples);
+            assert!(track.duration > std::time::Duration::from_secs(1));
+            assert_eq!(report.duration, track.duration);
+            if fragmented {
+                // The frames before the first keyframe are dropped
+                assert!(track.sample_count > 0 && (track.sample_count as usize) < frames);
+            } else {
+                assert_eq!(track.sample_count as usize, frames);
+            }
+        }
+
+        std::fs::write(&output, b"not an mp4").unwrap();
+        let error = crate::validate_mp4(&output.to_string_lossy()).unwrap_err();
+        assert!(matches!(error, crate::ConvertError::Mp4(_)), "{error:?}");
+    }
+
+    #[test]
+    fn buffer_sizes_do_not_change_output() {
+        let input = "assets/no_output_alignment.vraw".to_string();
+        let output = temp_path("buffer_sizes.mp4");
+
+        let mut outputs = Vec::new();
+        for buffer_size in [None, Some(0), Some(100)] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input,
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    read_buffer_size: buffer_size,
+                    write_buffer_size: buffer_size,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            outputs.push(std::fs::read(&output).unwrap());
+        }
+        assert!(outputs.iter().all(|bytes| *bytes == outputs[0]));
+    }
+
+    #[test]
+    fn exposure_vtt_coalesces_identical_stats() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let output = temp_path("exposure.vtt");
+
+        // Video frames from 10 ms to 410 ms, the stats hold the gain and exposure as u16
+        let mut frames: Vec<_> = (0..11)
+            .map(|i| crate::FrameSpec::new(H265, 10_000_000 + i * 40_000_000, vec![0, 0, 1, 2]))
+            .collect();
+        for (ms, payload) in [
+            (0, vec![100, 0, 0xe8, 0x03]),
+            (60, vec![100, 0, 0xe8, 0x03]),
+            (130, vec![200, 0, 0xf4, 0x01]),
+            (200, vec![0]),
+            (310, vec![200, 0, 0xf4, 0x01]),
+        ] {
+            frames.push(crate::FrameSpec::new(Stats, ms * 1_000_000, payload));
+        }
+        frames.sort_by_key(|frame| frame.timestamp);
+        let input = temp_vraw("exposure.vraw", &frames);
+
+        let cues = crate::write_exposure_vtt(
+            &input.to_string_lossy(),
+            &output.to_string_lossy(),
+            "ISO {gain} | {exposure}µs | {timestamp}",
+            |stats| {
+                let field =
+                    |i: usize| u16::from_le_bytes([stats.raw_data[i], stats.raw_data[i + 1]]);
+                (stats.raw_data.len() == 4).then(|| crate::Exposure {
+                    gain: field(0) as u32,
+                    exposure_us: field(2) as u32,
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!(cues, 2);
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "WEBVTT\n\
+             \n00:00:00.000 --> 00:00:00.120\nISO 100 | 1000µs | 00:00:00.000\n\
+             \n00:00:00.120 --> 00:00:00.400\nISO 200 | 500µs | 00:00:00.120\n"
+        );
+
+        // Line breaks of the template are written with the chosen line ending too
+        crate::write_exposure_vtt_with_options(
+            &input.to_string_lossy(),
+            &output.to_string_lossy(),
+            "ISO {gain}\r\n{exposure}µs",
+            |stats| {
+                (stats.raw_data.len() == 4).then_some(crate::Exposure {
+                    gain: stats.raw_data[0] as u32,
+                    exposure_us: 1000,
+                })
+            },
+            &crate::TextOutputOptions {
+                line_ending: crate::LineEnding::CrLf,
+                bom: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read(&output).unwrap(),
+            "\u{feff}WEBVTT\r\n\
+             \r\n00:00:00.000 --> 00:00:00.120\r\nISO 100\r\n1000µs\r\n\
+             \r\n00:00:00.120 --> 00:00:00.400\r\nISO 200\r\n1000µs\r\n"
+                .as_bytes()
+        );
+    }
+
+    #[test]
+    fn scenes_are_split_on_keyframes() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let prefix = temp_path("scene_");
+        let prefix = prefix.to_string_lossy();
+
+        // A keyframe every 5 frames, the one at frame 10 is a lot larger. A stats frame
+        // flagging a cut follows frame 17.
+        let mut frames = Vec::new();
+        for i in 0..25 {
+            let payload = match i {
+                10 => [vec![0, 0, 1, 0x26, 1], vec![0xaa; 1000]].concat(),
+                i if i % 5 == 0 => [vec![0, 0, 1, 0x26, 1], vec![0xaa; 100]].concat(),
+                _ => vec![0, 0, 1, 0x02, 1, 0xd0],
+            };
+            frames.push(crate::FrameSpec::new(H265, i * 40_000_000, payload));
+            frames.push(crate::FrameSpec::new(
+                Stats,
+                i * 40_000_000 + 1,
+                vec![(i == 17) as u8],
+            ));
+        }
+        let input = temp_vraw("scenes.vraw", &frames);
+        let input = input.to_string_lossy();
+
+        let sample_counts = |scenes: &[crate::Scene]| -> Vec<u32> {
+            scenes
+                .iter()
+                .map(|scene| {
+                    let mp4 = open_mp4(&scene.path);
+                    std::fs::remove_file(&scene.path).unwrap();
+                    mp4.sample_count(1).unwrap()
+                })
+                .collect()
+        };
+
+        let scenes = crate::convert_vraw_by_scene(
+            &input,
+            &prefix,
+            crate::SceneDetector::KeyframeSizeSpike { factor: 3.0 },
+        )
+        .unwrap();
+        assert_eq!(
+            scenes,
+            vec![
+                crate::Scene {
+                    path: format!("{prefix}000.mp4"),
+                    start_timestamp: 0,
+                    end_timestamp: 360_000_000,
+                    frames: 10,
+                },
+                crate::Scene {
+                    path: format!("{prefix}001.mp4"),
+                    start_timestamp: 400_000_000,
+                    end_timestamp: 960_000_000,
+                    frames: 15,
+                },
+            ]
+        );
+        assert_eq!(sample_counts(&scenes), [10, 15]);
+
+        let mut flagged = |frame: &crate::RawFrame| frame.format == Stats && frame.raw_data[0] == 1;
+        let scenes = crate::convert_vraw_by_scene(
+            &input,
+            &prefix,
+            crate::SceneDetector::Custom(&mut flagged),
+        )
+        .unwrap();
+        let starts: Vec<i64> = scenes.iter().map(|scene| scene.start_timestamp).collect();
+        assert_eq!(starts, [0, 800_000_000]);
+        assert_eq!(sample_counts(&scenes), [20, 5]);
+
+        std::fs::remove_file(&*input).unwrap();
+    }
+
+    #[test]
+    fn resampling_duplicates_and_drops_frames() {
+        let frames: Vec<crate::RawFrame> = [0, 50, 60, 200]
+            .iter()
+            .enumerate()
+            .map(|(i, ms)| crate::RawFrame {
+                format: crate::VideoCaptureFormat::H265,
+                width: 0,
+                height: 0,
+                raw_data: vec![0, 0, 0, 1, 2, 1, i as u8],
+                timestamp: 1_000_000_000 + ms * 1_000_000,
+            })
+            .collect();
+        let output = temp_path("resample.mp4");
+
+        let mut source = InMemorySource {
+            frames: frames.iter().cloned().collect(),
+        };
+        crate::convert_frame_source_to_mp4(
+            &mut source,
+            &output.to_string_lossy(),
+            &mut crate::ConvertOptions {
+                resample_to_fps: Some(25.0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut mp4 = open_mp4(&output);
+
+        // The grid points 0, 40, 80, 120, 160 and 200 ms show the closest frames
+        assert_eq!(mp4.sample_count(1).unwrap(), 6);
+        for (sample_id, i) in (1..).zip([0, 1, 2, 2, 3, 3]) {
+            let sample = mp4.read_sample(1, sample_id).unwrap().unwrap();
+            assert_eq!(&sample.bytes[..], &frames[i].raw_data[..]);
+            assert_eq!(sample.start_time, (sample_id as u64 - 1) * 40);
+            assert_eq!(sample.duration, 40);
+        }
+
+        let mut source = InMemorySource {
+            frames: frames.iter().cloned().collect(),
+        };
+        let error = crate::convert_frame_source_to_mp4(
+            &mut source,
+            &output.to_string_lossy(),
+            &mut crate::ConvertOptions {
+                resample_to_fps: Some(0.0),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn trailing_partial_gop_is_dropped() {
+        let input = "assets/h265.vraw".to_string();
+        let output = temp_path("trailing_gop.mp4");
+
+        let mut f = std::io::BufReader::new(std::fs::File::open(&input).unwrap());
+        let keyframes: Vec<bool> = crate::parser::read_index(&mut f)
+            .unwrap()
+            .iter()
+            .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+            .filter(|frame| frame.format == crate::VideoCaptureFormat::H265)
+            .map(|frame| crate::nal::is_keyframe(frame.format, &frame.raw_data))
+            .collect();
+        let trailing = keyframes.iter().rev().take_while(|&&key| !key).count();
+        assert!(trailing > 0);
+
+        for (drop_trailing_partial_gop, dropped) in [(false, 0), (true, trailing)] {
+            let summary = crate::convert_vraw_to_mp4_with_options(
+                &input,
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    drop_trailing_partial_gop,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(summary.dropped_trailing_frames, dropped);
+
+            let mut mp4 = open_mp4(&output);
+            let sample_count = mp4.sample_count(1).unwrap();
+            assert_eq!(sample_count as usize, keyframes.len() - dropped);
+
+            if drop_trailing_partial_gop {
+                // Nothing follows the last keyframe anymore
+                let last = mp4.read_sample(1, sample_count).unwrap().unwrap();
+                assert!(last.is_sync);
+            }
+        }
+    }
+
+    #[test]
+    fn tags_are_written() {
+        use mp4::Metadata;
+
+        let output = temp_path("tags.mp4");
+
+        crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                title: Some("Main camera".to_string()),
+                comment: Some("Test drive".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mp4 = open_mp4(&output);
+        assert_eq!(mp4.metadata().title().unwrap(), "Main camera");
+        assert_eq!(mp4.sample_count(1).unwrap(), 1265);
+
+        let bytes = std::fs::read(&output).unwrap();
+        for tag in [&b"\xA9cmt"[..], b"Test drive", b"\xA9too", b"vraw_convert "] {
+            assert!(bytes.windows(tag.len()).any(|window| window == tag));
+        }
+    }
+
+    #[test]
+    fn force_co64() {
+        let output = temp_path("co64.mp4");
+        let read = |force_co64| {
+            crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    force_co64,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let mut mp4 = open_mp4(&output);
+            let stbl = &mp4.tracks()[&1].trak.mdia.minf.stbl;
+            let tables = (stbl.stco.is_some(), stbl.co64.is_some());
+            let samples: Vec<mp4::Bytes> = (1..=mp4.sample_count(1).unwrap())
+                .map(|sample_id| mp4.read_sample(1, sample_id).unwrap().unwrap().bytes)
+                .collect();
+            (tables, samples)
+        };
+
+        let (tables, samples) = read(false);
+        assert_eq!(tables, (true, false));
+        let (tables, co64_samples) = read(true);
+        assert_eq!(tables, (false, true));
+        assert_eq!(co64_samples, samples);
+        assert!(
+            crate::validate_mp4(&output.to_string_lossy())
+                .unwrap()
+                .valid_moov
+        );
+    }
+
+    #[test]
+    fn mov_container() {
+        let output = temp_path("mov.mov");
+
+        crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                container: crate::Container::Mov,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let bytes = std::fs::read(&output).unwrap();
+        assert_eq!(&bytes[4..12], b"ftypqt  ");
+        let has = |tag: &[u8]| bytes.windows(tag.len()).any(|window| window == tag);
+        assert!(has(b"hvc1"));
+        assert!(!has(b"hev1"));
+
+        let mp4 = open_mp4(&output);
+        assert_eq!(mp4.sample_count(1).unwrap(), 1265);
+
+        #[allow(deprecated)]
+        let error = crate::convert_vraw_to_mp4_with_options(
+            &"assets/h265.vraw".to_string(),
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                container: crate::Container::Mov,
+                fragmented: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, crate::ConvertError::InvalidOption(_)),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn forced_hevc_sample_entry() {
+        let output = temp_path("hevc_sample_entry.mp4");
+
+        let convert = |container, hevc_sample_entry| {
+            let summary = crate::convert_vraw_to_mp4_with_options(
+                &"assets/h265.vraw".to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    container,
+                    hevc_sample_entry: Some(hevc_sample_entry),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            // The ftyp box lists hev1 as a compatible brand
+            let bytes = std::fs::read(&output).unwrap();
+            let ftyp_size = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            let has = |tag: &[u8]| {
+                bytes[ftyp_size..]
+                    .windows(tag.len())
+                    .any(|window| window == tag)
+            };
+            (has(b"hvc1"), has(b"hev1"), summary.warnings)
+        };
+
+        let (hvc1, hev1, warnings) = convert(crate::Container::Mov, crate::HevcEntryKind::Hev1);
+        assert!(!hvc1 && hev1);
+        assert!(warnings.is_empty(), "{warnings:?}");
+
+        // The frames of the recording carry their parameter sets
+        let (hvc1, hev1, warnings) = convert(crate::Container::Mp4, crate::HevcEntryKind::Hvc1);
+        assert!(hvc1 && !hev1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("not conforming"), "{warnings:?}");
+    }
+
+    #[test]
+    fn capture_date_tag() {
+        let output = temp_path("capture_date.mp4");
+
+        // 2024-05-17T08:30:00Z in UNIX nanoseconds
+        let frames = h265_frames_at(&[
+            1_715_934_600_000_000_000,
+            1_715_934_600_040_000_000,
+            1_715_934_600_080_000_000,
+        ]);
+        let input = temp_vraw("capture_date.vraw", &frames);
+
+        for (capture_date, expected) in [
+            (crate::CaptureDate::FirstFrame, Some("2024-05-17T08:30:00Z")),
+            (
+                crate::CaptureDate::Fixed("2020-01-02T03:04:05+01:00".to_string()),
+                Some("2020-01-02T03:04:05+01:00"),
+            ),
+            (crate::CaptureDate::None, None),
+        ] {
+            crate::convert_vraw_to_mp4_with_options(
+                &input.to_string_lossy().to_string(),
+                Some(output.to_string_lossy().to_string()),
+                &mut crate::ConvertOptions {
+                    capture_date,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+            let bytes = std::fs::read(&output).unwrap();
+            let has_tag = |tag: &[u8]| bytes.windows(tag.len()).any(|window| window == tag);
+            assert_eq!(has_tag(b"\xA9day"), expected.is_some());
+            if let Some(expected) = expected {
+                assert!(has_tag(expected.as_bytes()), "{expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn stats_are_paired_with_following_frames() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let frames: Vec<_> = [H265, Stats, H265, H265, Stats, Stats, H265]
+            .iter()
+            .enumerate()
+            .map(|(i, &format)| crate::FrameSpec::new(format, i as i64 * 1_000, vec![i as u8; 24]))
+            .collect();
+        let input = temp_vraw("stats_pairing.vraw", &frames);
+
+        let pairs = crate::pair_stats_with_frames(&input.to_string_lossy()).unwrap();
+
+        let timestamps: Vec<(i64, Option<i64>)> = pairs
+            .iter()
+            .map(|(frame, stats)| (frame.timestamp, stats.as_ref().map(|stats| stats.timestamp)))
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                (0, None),
+                (2_000, Some(1_000)),
+                (3_000, Some(1_000)),
+                (6_000, Some(5_000))
+            ]
+        );
+        assert_eq!(pairs[3].1.as_ref().unwrap().raw_data, vec![5; 24]);
+    }
+
+    #[test]
+    fn diff_indexes_of_two_captures() {
+        use crate::VideoCaptureFormat::{Stats, H265};
+
+        let a = temp_path("diff_a.vraw");
+
+        let frames_a = h265_frames(4, 40_000_000);
+        // Started later, with a stats frame in place of the third frame, a late fourth frame
+        // and one more frame
+        let frames_b: Vec<_> = [(H265, 0), (H265, 40), (Stats, 80), (H265, 125), (H265, 160)]
+            .iter()
+            .map(|&(format, ms)| {
+                crate::FrameSpec::new(
+                    format,
+                    7_000_000_000 + ms * 1_000_000,
+                    H265_INTER_FRAME.to_vec(),
+                )
+            })
+            .collect();
+        std::fs::write(&a, crate::build_vraw(&frames_a)).unwrap();
+        let b = temp_vraw("diff_b.vraw", &frames_b);
+
+        let diff = crate::diff_indexes(&a.to_string_lossy(), &b.to_string_lossy()).unwrap();
+        assert_eq!(
+            diff,
+            crate::IndexDiff {
+                frames_a: 4,
+                frames_b: 5,
+                first_difference: Some(2),
+                timestamp_deltas: vec![0, 0, 0, 5_000_000],
+                format_mismatches: vec![crate::FormatMismatch {
+                    index: 2,
+                    format_a: H265,
+                    format_b: Stats,
+                }],
+            }
+        );
+
+        let same = crate::diff_indexes(&a.to_string_lossy(), &a.to_string_lossy()).unwrap();
+        assert_eq!(same.first_difference, None);
+    }
+
+    #[test]
+    fn count_frames_matches_parsed_frames() {
+        for (input, video, stats) in [
+            ("assets/h265.vraw", 1265, 1716),
+            ("assets/no_output_alignment.vraw", 1255, 1713),
+        ] {
+            let counts = crate::count_frames(input).unwrap();
+
+            let mut f = std::io::BufReader::new(std::fs::File::open(input).unwrap());
+            let timestamps: Vec<i64> = crate::parser::read_index(&mut f)
+                .unwrap()
+                .iter()
+                .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+                .filter(|frame| frame.format == crate::VideoCaptureFormat::H265)
+                .map(|frame| frame.timestamp)
+                .collect();
+
+            assert_eq!(counts.codec, crate::VideoCaptureFormat::H265);
+            assert_eq!(
+                (counts.video, counts.stats, counts.other),
+                (video, stats, 0)
+            );
+            assert_eq!(counts.video, timestamps.len());
+            assert_eq!(counts.per_format.len(), 2);
+            assert!(counts
+                .per_format
+                .contains(&(crate::VideoCaptureFormat::Stats, stats)));
+            assert_eq!(
+                counts.duration.as_nanos() as i64,
+                timestamps.last().unwrap() - timestamps[0]
+            );
+        }
+    }
+
+    #[test]
+    fn rewrite_vraw_remaps_timestamps() {
+        let input = "assets/no_output_alignment.vraw";
+        let output = temp_path("rewritten.vraw");
+
+        // Pretend the clock jumped back by a second after frame 100
+        crate::rewrite_vraw(input, &output.to_string_lossy(), |i, timestamp| {
+            if i >= 100 {
+                timestamp + 1_000_000_000
+            } else {
+                timestamp
+            }
+        })
+        .unwrap();
+
+        let read_frames = |path: &std::path::Path| {
+            let mut f = std::io::BufReader::new(std::fs::File::open(path).unwrap());
+            let index = crate::parser::read_index(&mut f).unwrap();
+            let frames: Vec<crate::RawFrame> = index
+                .iter()
+                .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+                .collect();
+            (index, frames)
+        };
+
+        let (_, original) = read_frames(std::path::Path::new(input));
+        let (index, rewritten) = read_frames(&output);
+        assert_eq!(rewritten.len(), original.len());
+
+        for (i, (original, rewritten)) in original.iter().zip(&rewritten).enumerate() {
+            let shift = if i >= 100 { 1_000_000_000 } else { 0 };
+            assert_eq!(rewritten.timestamp, original.timestamp + shift);
+            assert_eq!(index[i].receive_timestamp.get(), rewritten.timestamp);
+            assert_eq!(rewritten.format, original.format);
+            assert_eq!(rewritten.raw_data, original.raw_data);
+        }
+    }
+
+    /// A recording larger than 4 GB without the disk space: the recording header, then zeros
+    /// up to `body_offset`, followed by the frames and the index.
+    struct SparseRecording {
+        header: Vec<u8>,
+        body_offset: u64,
+        body: Vec<u8>,
+        position: u64,
+    }
+
+    impl std::io::Read for SparseRecording {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let (data, start, end) = if self.position < self.header.len() as u64 {
+                (&self.header[..], 0, self.header.len() as u64)
+            } else if self.position < self.body_offset {
+                (&[][..], self.header.len() as u64, self.body_offset)
+            } else {
+                (
+                    &self.body[..],
+                    self.body_offset,
+                    self.body_offset + self.body.len() as u64,
+                )
+            };
+
+            let count = (buf.len() as u64).min(end.saturating_sub(self.position)) as usize;
+            for (i, byte) in buf[..count].iter_mut().enumerate() {
+                let position = (self.position - start) as usize + i;
+                *byte = data.get(position).copied().unwrap_or(0);
+            }
+            self.position += count as u64;
+
+            Ok(count)
+        }
+    }
+
+    impl std::io::Seek for SparseRecording {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            let len = self.body_offset + self.body.len() as u64;
+            self.position = match pos {
+                std::io::SeekFrom::Start(offset) => offset,
+                std::io::SeekFrom::End(offset) => len.checked_add_signed(offset).unwrap(),
+                std::io::SeekFrom::Current(offset) => {
+                    self.position.checked_add_signed(offset).unwrap()
+                }
+            };
+
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn frames_past_4_gb() {
+        use zerocopy::AsBytes;
+
+        let frames: Vec<_> = (0..20)
+            .map(|i| {
+                let format = if i % 2 == 0 {
+                    crate::VideoCaptureFormat::Stats
+                } else {
+                    crate::VideoCaptureFormat::H265
+                };
+                crate::FrameSpec::new(format, i * 20_000_000, vec![0, 0, 0, 1, 2, 1, i as u8, 1])
+            })
+            .collect();
+        let bytes = crate::build_vraw(&frames);
+        let entries = crate::parser::read_index(&mut std::io::Cursor::new(&bytes)).unwrap();
+
+        // The first frame straddles the 4 GB boundary
+        let header_size = entries[0].offset.get() as usize;
+        let index_start = bytes.len() - 16 - 16 * entries.len();
+        let body_offset = (1u64 << 32) - 10;
+
+        let mut body = bytes[header_size..index_start].to_vec();
+        body.extend_from_slice(&bytes[index_start..index_start + 8]); // index header
+        for entry in &entries {
+            let entry = crate::parser::RecordingIndexEntry {
+                offset: crate::parser::I64::new(
+                    entry.offset.get() - header_size as i64 + body_offset as i64,
+                ),
+                receive_timestamp: entry.receive_timestamp,
+            };
+            body.extend_from_slice(entry.as_bytes());
+        }
+        body.extend_from_slice(&bytes[bytes.len() - 8..]); // index footer
+
+        let recording = SparseRecording {
+            header: bytes[..header_size].to_vec(),
+            body_offset,
+            body,
+            position: 0,
+        };
+        let mut source = crate::VrawFrameSource::new(recording).unwrap();
+        assert_eq!(source.len(), frames.len());
+
+        for spec in &frames {
+            let frame = crate::FrameSource::next_frame(&mut source)
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.format, spec.format);
+            assert_eq!(frame.timestamp, spec.timestamp);
+            assert_eq!(frame.raw_data, spec.payload);
+        }
+    }
+
+    #[test]
+    fn build_vraw_round_trip() {
+        let frames = vec![
+            crate::FrameSpec::new(crate::VideoCaptureFormat::Stats, 1_000, vec![7; 24]),
+            crate::FrameSpec::new(crate::VideoCaptureFormat::H265, 2_000, vec![0, 0, 0, 1, 2]),
+            crate::FrameSpec::new(crate::VideoCaptureFormat::H265, 3_000, (0..=255).collect()),
+            crate::FrameSpec {
+                width: 4,
+                height: 2,
+                ..crate::FrameSpec::new(crate::VideoCaptureFormat::Mono8, 4_000, vec![1; 8])
+            },
+        ];
+
+        let mut source =
+            crate::VrawFrameSource::new(std::io::Cursor::new(crate::build_vraw(&frames))).unwrap();
+        assert_eq!(source.len(), frames.len());
+        assert_eq!(
+            crate::FrameSource::codec(&source),
+            crate::VideoCaptureFormat::H265
+        );
+
+        for spec in &frames {
+            let frame = crate::FrameSource::next_frame(&mut source)
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.format, spec.format);
+            assert_eq!(frame.width, spec.width);
+            assert_eq!(frame.height, spec.height);
+            assert_eq!(frame.timestamp, spec.timestamp);
+            assert_eq!(frame.raw_data, spec.payload);
+        }
+        assert!(crate::FrameSource::next_frame(&mut source).is_none());
+    }
+
+    /// Splits the payload of a box into its child boxes.
+    fn child_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+
+        let mut position = 0;
+        while position < data.len() {
+            let size = u32::from_be_bytes(data[position..position + 4].try_into().unwrap());
+            let box_type = data[position + 4..position + 8].try_into().unwrap();
+            boxes.push((box_type, &data[position + 8..position + size as usize]));
+            position += size as usize;
+        }
+
+        boxes
+    }
+
+    #[test]
+    fn fragmented_output_starts_fragments_on_keyframes() {
+        use mp4::Metadata;
+
+        let input = "assets/h265.vraw".to_string();
+        let output = temp_path("fragmented.mp4");
+
+        crate::convert_vraw_to_mp4_with_options(
+            &input,
+            Some(output.to_string_lossy().to_string()),
+            &mut crate::ConvertOptions {
+                title: Some("Main camera".to_string()),
+                container: crate::Container::FragmentedMp4,
+                fragment_duration_ms: Some(1000),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let mut f = std::io::BufReader::new(std::fs::File::open(&input).unwrap());
+        let video_frames: Vec<crate::RawFrame> = crate::parser::read_index(&mut f)
+            .unwrap()
+            .iter()
+            .map(|entry| crate::parser::parse_raw_frame(&mut f, entry).unwrap())
+            .filter(|frame| frame.format == crate::VideoCaptureFormat::H265)
+            .skip_while(|frame| !crate::nal::is_keyframe(frame.format, &frame.raw_data))
+            .collect();
+
+        let mp4 = open_mp4(&output);
+        assert!(mp4.is_fragmented());
+        assert_eq!(mp4.metadata().title().unwrap(), "Main camera");
+
+        let bytes = std::fs::read(&output).unwrap();
+        let boxes = child_boxes(&bytes);
+        assert_eq!(&boxes[0].0, b"ftyp");
+        assert_eq!(&boxes[1].0, b"moov");
+        assert!(child_boxes(boxes[1].1).iter().any(|(t, _)| t == b"mvex"));
+
+        let mut samples = video_frames.iter();
+        let fragments = &boxes[2..];
+        assert!(fragments.len() > 2);
+        for (n, fragment) in fragments.chunks(2).enumerate() {
+            let [(moof_type, moof), (mdat_type, mdat)] = fragment else {
+                panic!("moof without mdat");
+            };
+            assert_eq!((moof_type, mdat_type), (b"moof", b"mdat"));
+
+            let moof_boxes = child_boxes(moof);
+            let mfhd = moof_boxes[0].1;
+            assert_eq!(
+                u32::from_be_bytes(mfhd[4..8].try_into().unwrap()),
+                n as u32 + 1
+            );
+
+            let traf = child_boxes(moof_boxes[1].1);
+            let trun = traf.iter().find(|(t, _)| t == b"trun").unwrap().1;
+            let sample_count = u32::from_be_bytes(trun[4..8].try_into().unwrap());
+            let data_offset = i32::from_be_bytes(trun[8..12].try_into().unwrap());
+            // Relative to the start of the moof, past both box headers
+            assert_eq!(data_offset as usize, 8 + moof.len() + 8);
+
+            let mut position = 0;
+            for i in 0..sample_count as usize {
+                let entry = &trun[12 + i * 12..24 + i * 12];
+                let sample_size = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+                let flags = u32::from_be_bytes(entry[8..12].try_into().unwrap());
+                if i == 0 {
+                    assert_eq!(flags, 0x0200_0000);
+                }
+
+                let frame = samples.next().unwrap();
+                let data = &mdat[position..position + sample_size];
+                assert_eq!(data, &frame.raw_data[..]);
+                assert_eq!(
+                    flags == 0x0200_0000,
+                    crate::nal::is_keyframe(frame.format, data)
+                );
+                position += sample_size;
+            }
+            assert_eq!(position, mdat.len());
+        }
+        assert!(samples.next().is_none());
+    }
 }
@@ -1,10 +1,12 @@
 use clap::Parser;
 use msgbox::IconType;
 use std::error::Error;
-use vraw_convert::convert_vraw_to_mp4;
-
-mod parser;
-mod processing;
+use std::time::Duration;
+use vraw_convert::{
+    convert_vraw_to_mp4_with_options, supported_containers, supported_output_codecs, CaptureDate,
+    ClockKind, Container, ConvertOptions, FilenameTz, FrameOrder, HevcEntryKind, HevcTierLevel,
+    Session, ZeroTimestampPolicy,
+};
 
 #[derive(Parser)]
 #[clap(
@@ -20,16 +22,381 @@ pub struct Config {
 
     /// Specifies the output file name ex. video.mp4 (Folder path must exist)
     output: Option<String>,
+
+    /// Write exact per-frame start times and durations (next frame start - this frame start)
+    #[clap(long)]
+    exact_timestamps: bool,
+
+    /// Title written to the metadata of the output
+    #[clap(long)]
+    title: Option<String>,
+
+    /// Comment written to the metadata of the output
+    #[clap(long)]
+    comment: Option<String>,
+
+    /// ISO 639-2 language code of the track, e.g. eng [default: und]
+    #[clap(long)]
+    language: Option<String>,
+
+    /// Name of the track, e.g. "Main Camera"
+    #[clap(long)]
+    handler_name: Option<String>,
+
+    /// Custom metadata written to the output as KEY=VALUE, can be repeated
+    #[clap(long, value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+
+    /// Hash the video frames of the recording, print the hash and write it to the metadata
+    #[cfg(feature = "content-hash")]
+    #[clap(long)]
+    content_hash: bool,
+
+    /// Write a fragmented mp4 (init segment + fragments starting on keyframes) for DASH/CMAF
+    #[clap(long)]
+    fragmented: bool,
+
+    /// Write a QuickTime .mov (qt brand, hvc1 sample entries) for editors that prefer it
+    #[clap(long, conflicts_with = "fragmented")]
+    mov: bool,
+
+    /// Minimum fragment duration in milliseconds, by default every keyframe starts a fragment
+    #[clap(long)]
+    fragment_duration_ms: Option<u32>,
+
+    /// Advertise this H.264 level_idc (e.g. 51) in the avcC box instead of the SPS level
+    #[clap(long)]
+    avc_level: Option<u8>,
+
+    /// Advertise this H.265 general_level_idc (e.g. 153 for 5.1) in the hvcC box instead of the SPS level
+    #[clap(long)]
+    hevc_level: Option<u8>,
+
+    /// Advertise the H.265 high tier together with --hevc-level
+    #[clap(long, requires = "hevc_level")]
+    hevc_high_tier: bool,
+
+    /// Force the H.265 sample entry type: hvc1 or hev1 (hvc1 is not conforming with parameter sets in the frames)
+    #[clap(long, value_parser = parse_hevc_sample_entry)]
+    hevc_sample_entry: Option<HevcEntryKind>,
+
+    /// Remove the parameter sets from the frames that repeat those of the sample entry, as hvc1 requires
+    #[clap(long)]
+    strip_parameter_sets: bool,
+
+    /// Skip video frames in another format than the stream instead of failing
+    #[clap(long)]
+    tolerate_format_mismatch: bool,
+
+    /// Drop the frames after the last keyframe so the output ends on a complete GOP
+    #[clap(long)]
+    drop_trailing_partial_gop: bool,
+
+    /// Clock of the frame timestamps: auto, monotonic or wall-clock (UNIX nanoseconds)
+    #[clap(long, default_value = "auto", value_parser = parse_clock)]
+    clock: ClockKind,
+
+    /// Flush the output file every this many samples, e.g. to follow a long conversion
+    #[clap(long)]
+    flush_every: Option<usize>,
+
+    /// Keep converting a recording that is still being written, re-reading its index every this many milliseconds until it stops growing
+    #[clap(long)]
+    follow_ms: Option<u64>,
+
+    /// Size of the read buffer in bytes (default 1 MiB), 0 disables buffering
+    #[clap(long)]
+    read_buffer_size: Option<usize>,
+
+    /// Size of the write buffer in bytes (default 1 MiB), 0 disables buffering
+    #[clap(long)]
+    write_buffer_size: Option<usize>,
+
+    /// Resample to this frame rate by duplicating and dropping frames (changes the frame count)
+    #[clap(long)]
+    resample_fps: Option<f64>,
+
+    /// Write a JSON report of the conversion (options, frame counts, warnings) to this file
+    #[clap(long)]
+    report: Option<String>,
+
+    /// Timezone of the time in the default output file name: local, utc or an offset like +02:00
+    #[clap(long, default_value = "local", value_parser = parse_filename_timezone)]
+    filename_timezone: FilenameTz,
+
+    /// Keep only the H.265 temporal sublayers up to this temporal id, e.g. 0 for the base layer
+    #[clap(long)]
+    max_temporal_id: Option<u8>,
+
+    /// Recording session to convert if sessions were appended to the file: last, all or its number starting at 0
+    #[clap(long, default_value = "last", value_parser = parse_session)]
+    session: Session,
+
+    /// Make a time-lapse keeping about every this many frames (snapped to keyframes), played at the original frame rate
+    #[clap(long)]
+    timelapse_stride: Option<usize>,
+
+    /// Convert the frames in timestamp order when the index of the recording is not sorted by time
+    #[clap(long)]
+    sort_by_timestamp: bool,
+
+    /// Write sample groups marking the IDR and open-GOP (CRA/BLA) random access points of H.265
+    #[clap(long)]
+    random_access_groups: bool,
+
+    /// Write an sdtp box marking the keyframes and the disposable (non-reference) frames, for trick play
+    #[clap(long)]
+    sample_dependencies: bool,
+
+    /// The index lists the frames in decode order (e.g. with B-frames) and their timestamps are presentation times
+    #[clap(long)]
+    decode_order: bool,
+
+    /// What to do with video frames whose timestamp is 0 (unavailable): error, interpolate or skip
+    #[clap(long, default_value = "error", value_parser = parse_zero_timestamps)]
+    zero_timestamps: ZeroTimestampPolicy,
+
+    /// Shorten samples lasting longer than this many milliseconds, e.g. after a corrupt timestamp
+    #[clap(long)]
+    max_frame_duration_ms: Option<u32>,
+
+    /// Fill gaps longer than this many milliseconds with copies of the previous frame, for a steady cadence
+    #[clap(long)]
+    fill_gaps_ms: Option<u32>,
+
+    /// Fail instead of warning when the timestamps imply an implausible frame rate
+    #[clap(long)]
+    strict_timestamps: bool,
+
+    /// Fail instead of writing anything a strict MP4 validator could reject, including on any warning
+    #[clap(long)]
+    strict: bool,
+
+    /// Source of the ©day capture date tag: first-frame (if wall-clock), file-modified, none or an ISO 8601 date to write
+    #[clap(long, default_value = "first-frame", value_parser = parse_capture_date)]
+    capture_date: CaptureDate,
+
+    /// Write 64 bit chunk offsets (co64) even below 4 GB, above 4 GB they are always used
+    #[clap(long)]
+    force_co64: bool,
+
+    /// Write every GOP as one chunk, for smaller sample tables and chunks starting at keyframes
+    #[clap(long)]
+    chunk_per_gop: bool,
+
+    /// Move the moov box in front of the media data, so playback can start while downloading
+    #[clap(long)]
+    faststart: bool,
+
+    /// Add a timecode track starting at the capture time, for editors; needs --resample-fps
+    #[clap(long)]
+    timecode: bool,
+
+    /// Ticks per second of the written track (default 1000), e.g. 90000 with --exact-timestamps for 23.976 or 59.94 fps
+    #[clap(long)]
+    timescale: Option<u32>,
+
+    /// Write composition offsets that reproduce every frame timestamp exactly and check them by reading the file back
+    #[clap(long)]
+    preserve_timestamps: bool,
+
+    /// Print the video codecs and containers this build can write and exit
+    #[clap(long)]
+    list_formats: bool,
+}
+
+fn parse_clock(clock: &str) -> Result<ClockKind, String> {
+    match clock {
+        "auto" => Ok(ClockKind::Auto),
+        "monotonic" => Ok(ClockKind::Monotonic),
+        "wall-clock" => Ok(ClockKind::WallClock),
+        _ => Err(format!(
+            "unknown clock {clock}, expected auto, monotonic or wall-clock"
+        )),
+    }
+}
+
+fn parse_hevc_sample_entry(sample_entry: &str) -> Result<HevcEntryKind, String> {
+    match sample_entry {
+        "hvc1" => Ok(HevcEntryKind::Hvc1),
+        "hev1" => Ok(HevcEntryKind::Hev1),
+        _ => Err(format!(
+            "unknown sample entry {sample_entry}, expected hvc1 or hev1"
+        )),
+    }
+}
+
+fn parse_zero_timestamps(policy: &str) -> Result<ZeroTimestampPolicy, String> {
+    match policy {
+        "error" => Ok(ZeroTimestampPolicy::Error),
+        "interpolate" => Ok(ZeroTimestampPolicy::Interpolate),
+        "skip" => Ok(ZeroTimestampPolicy::Skip),
+        _ => Err(format!(
+            "unknown policy {policy}, expected error, interpolate or skip"
+        )),
+    }
+}
+
+fn parse_filename_timezone(timezone: &str) -> Result<FilenameTz, String> {
+    let invalid =
+        || format!("unknown timezone {timezone}, expected local, utc or an offset like +02:00");
+
+    let (sign, offset) = match timezone {
+        "local" => return Ok(FilenameTz::Local),
+        "utc" => return Ok(FilenameTz::Utc),
+        _ => match (timezone.strip_prefix('+'), timezone.strip_prefix('-')) {
+            (Some(offset), _) => (1, offset),
+            (_, Some(offset)) => (-1, offset),
+            _ => return Err(invalid()),
+        },
+    };
+
+    let (hours, minutes) = offset.split_once(':').ok_or_else(invalid)?;
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+        return Err(invalid());
+    }
+
+    Ok(FilenameTz::Fixed(sign * (hours * 3600 + minutes * 60)))
+}
+
+fn parse_session(session: &str) -> Result<Session, String> {
+    match session {
+        "last" => Ok(Session::Last),
+        "all" => Ok(Session::All),
+        _ => session.parse().map(Session::Nth).map_err(|_| {
+            format!("unknown session {session}, expected last, all or a session number")
+        }),
+    }
+}
+
+fn parse_capture_date(capture_date: &str) -> Result<CaptureDate, String> {
+    Ok(match capture_date {
+        "first-frame" => CaptureDate::FirstFrame,
+        "file-modified" => CaptureDate::FileModified,
+        "none" => CaptureDate::None,
+        date => CaptureDate::Fixed(date.to_string()),
+    })
+}
+
+fn parse_metadata(entry: &str) -> Result<(String, String), String> {
+    entry
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid metadata {entry}, expected KEY=VALUE"))
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let config = Config::parse();
 
-    if let Err(e) = convert_vraw_to_mp4(&config.input, config.output) {
-        println!("Application error: {}", e);
+    if config.list_formats {
+        println!("Codecs: {:?}", supported_output_codecs());
+        println!("Containers: {:?}", supported_containers());
+        return Ok(());
+    }
+
+    let mut options = ConvertOptions {
+        exact_timestamps: config.exact_timestamps,
+        title: config.title,
+        comment: config.comment,
+        language: config.language,
+        handler_name: config.handler_name,
+        metadata: config.metadata.into_iter().collect(),
+        #[cfg(feature = "content-hash")]
+        content_hash: config.content_hash,
+        container: if config.mov {
+            Container::Mov
+        } else if config.fragmented {
+            Container::FragmentedMp4
+        } else {
+            Container::Mp4
+        },
+        fragment_duration_ms: config.fragment_duration_ms,
+        avc_level: config.avc_level,
+        hevc_tier_level: config.hevc_level.map(|level_idc| HevcTierLevel {
+            high_tier: config.hevc_high_tier,
+            level_idc,
+        }),
+        hevc_sample_entry: config.hevc_sample_entry,
+        strip_parameter_sets: config.strip_parameter_sets,
+        tolerate_format_mismatch: config.tolerate_format_mismatch,
+        drop_trailing_partial_gop: config.drop_trailing_partial_gop,
+        clock: config.clock,
+        flush_every: config.flush_every,
+        follow_interval: config.follow_ms.map(Duration::from_millis),
+        read_buffer_size: config.read_buffer_size,
+        write_buffer_size: config.write_buffer_size,
+        resample_to_fps: config.resample_fps,
+        report_path: config.report,
+        filename_timezone: config.filename_timezone,
+        max_temporal_id: config.max_temporal_id,
+        session: config.session,
+        timelapse_stride: config.timelapse_stride,
+        sort_by_timestamp: config.sort_by_timestamp,
+        random_access_groups: config.random_access_groups,
+        sample_dependencies: config.sample_dependencies,
+        frame_order: if config.decode_order {
+            FrameOrder::Decode
+        } else {
+            FrameOrder::Presentation
+        },
+        zero_timestamp_policy: config.zero_timestamps,
+        max_frame_duration_ms: config.max_frame_duration_ms,
+        fill_gaps_ms: config.fill_gaps_ms,
+        strict_timestamps: config.strict_timestamps,
+        strict: config.strict,
+        capture_date: config.capture_date,
+        force_co64: config.force_co64,
+        chunk_per_gop: config.chunk_per_gop,
+        faststart: config.faststart,
+        timecode: config.timecode,
+        timescale: config.timescale,
+        preserve_timestamps: config.preserve_timestamps,
+        ..Default::default()
+    };
+
+    match convert_vraw_to_mp4_with_options(&config.input, config.output, &mut options) {
+        Ok(summary) => {
+            for warning in &summary.warnings {
+                eprintln!("Warning: {warning}");
+            }
+            println!("Wrote {}", summary.output_path.display());
+            #[cfg(feature = "content-hash")]
+            if let Some(content_hash) = &summary.content_hash {
+                println!("Content hash {content_hash}");
+            }
+            if summary.empty_frames > 0 {
+                println!("Skipped {} empty frames", summary.empty_frames);
+            }
+            if summary.dropped_trailing_frames > 0 {
+                println!(
+                    "Dropped {} frames after the last keyframe",
+                    summary.dropped_trailing_frames
+                );
+            }
+            if summary.zero_timestamp_frames > 0 {
+                println!(
+                    "Interpolated or skipped {} frames without timestamp",
+                    summary.zero_timestamp_frames
+                );
+            }
+            if summary.filler_frames > 0 {
+                println!("Filled gaps with {} repeated frames", summary.filler_frames);
+            }
+            if summary.clamped_frames > 0 {
+                println!(
+                    "Shortened {} frames to the maximum frame duration",
+                    summary.clamped_frames
+                );
+            }
+        }
+        Err(e) => {
+            println!("Application error: {}", e);
 
-        let err_msg: String = e.to_string();
-        msgbox::create("vraw_convert", &err_msg, IconType::Info)?;
+            let err_msg: String = e.to_string();
+            msgbox::create("vraw_convert", &err_msg, IconType::Info)?;
+        }
     }
 
     Ok(())
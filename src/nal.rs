@@ -0,0 +1,517 @@
+use crate::VideoCaptureFormat;
+
+/// Splits an Annex B byte stream into its NAL units, without start codes.
+pub(crate) fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).map(|next| next - 3).unwrap_or(data.len());
+            let mut nal = &data[start..end];
+
+            // Zeros before the next start code belong to its 4 byte form (or are padding)
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// The `nal_unit_type` of an H.264 NAL unit.
+pub(crate) fn h264_nal_type(nal: &[u8]) -> u8 {
+    nal[0] & 0x1F
+}
+
+/// The `nal_unit_type` of an H.265 NAL unit.
+pub(crate) fn h265_nal_type(nal: &[u8]) -> u8 {
+    (nal[0] >> 1) & 0x3F
+}
+
+/// The `TemporalId` of an H.265 NAL unit, `nuh_temporal_id_plus1 - 1`.
+pub(crate) fn h265_temporal_id(nal: &[u8]) -> u8 {
+    (nal[1] & 0x07).saturating_sub(1)
+}
+
+/// The highest temporal id of the slices of an H.265 frame, None if it has no slice.
+/// Parameter sets and SEI messages are left out, they belong to every layer they are sent
+/// for.
+pub(crate) fn h265_frame_temporal_id(data: &[u8]) -> Option<u8> {
+    nal_units(data)
+        .iter()
+        .filter(|nal| nal.len() >= 2 && h265_nal_type(nal) < 32)
+        .map(|nal| h265_temporal_id(nal))
+        .max()
+}
+
+/// Returns true if the frame is an Annex B byte stream whose NAL unit headers are valid
+/// for the format: the forbidden zero bit is clear and the unit types are defined. For
+/// H.265 the two byte header must also have a layer id of 0 (the recorder writes a single
+/// layer) and a temporal id plus one that is not 0. Frames of any other format are not
+/// checked.
+pub(crate) fn has_valid_nal_headers(format: VideoCaptureFormat, data: &[u8]) -> bool {
+    let nals = nal_units(data);
+    let valid = |nal: &&[u8]| match format {
+        VideoCaptureFormat::H264 => nal[0] & 0x80 == 0 && matches!(h264_nal_type(nal), 1..=23),
+        VideoCaptureFormat::H265 => match nal {
+            [first, second, ..] => {
+                let layer_id = ((first & 1) << 5) | (second >> 3);
+                first & 0x80 == 0 && h265_nal_type(nal) <= 40 && layer_id == 0 && second & 0x07 != 0
+            }
+            _ => false,
+        },
+        _ => true,
+    };
+
+    match format {
+        VideoCaptureFormat::H264 | VideoCaptureFormat::H265 => {
+            !nals.is_empty() && nals.iter().all(valid)
+        }
+        _ => true,
+    }
+}
+
+/// Returns true if the frame can be decoded without any previous frame.
+///
+/// For H.264 frames this is an IDR slice and for H.265 an IRAP (IDR, CRA or BLA) slice.
+/// The recorder codes intra frames as regular slices preceded by the parameter sets, so
+/// frames carrying an SPS (or VPS for H.265) count as keyframes as well. Frames of any
+/// other format are always keyframes.
+pub(crate) fn is_keyframe(format: VideoCaptureFormat, data: &[u8]) -> bool {
+    match format {
+        VideoCaptureFormat::H264 => nal_units(data)
+            .iter()
+            .any(|nal| matches!(h264_nal_type(nal), 5 | 7)),
+        VideoCaptureFormat::H265 => nal_units(data)
+            .iter()
+            .any(|nal| matches!(h265_nal_type(nal), 16..=23 | 32 | 33)),
+        _ => true,
+    }
+}
+
+/// The `nal_unit_type` of the first IRAP slice (BLA, IDR or CRA) of an H.265 frame, None
+/// if the frame has none. Keyframes that are regular slices preceded by the parameter sets
+/// (see [`is_keyframe`]) have no IRAP slice.
+pub(crate) fn h265_irap_type(data: &[u8]) -> Option<u8> {
+    nal_units(data)
+        .iter()
+        .map(|nal| h265_nal_type(nal))
+        .find(|nal_type| matches!(nal_type, 16..=23))
+}
+
+/// The `sdtp` (ISO/IEC 14496-12) dependency flags of a frame: `is_leading`,
+/// `sample_depends_on`, `sample_is_depended_on` and `sample_has_redundancy`, two bits each.
+///
+/// Keyframes (see [`is_keyframe`]) depend on no other frame. A frame is disposable if all
+/// its slices are non-reference: `nal_ref_idc` 0 for H.264 and a sub-layer non-reference
+/// type (e.g. `TRAIL_N`) for H.265. H.265 RASL and RADL pictures are leading, the former
+/// not decodable after seeking to their IRAP, and H.265 has no redundant pictures. Flags
+/// that cannot be told from the NAL unit headers are 0 (unknown), as are all flags of a
+/// frame without slices.
+pub(crate) fn sample_dependency(format: VideoCaptureFormat, data: &[u8]) -> u8 {
+    let nals = nal_units(data);
+    let depends_on = if is_keyframe(format, data) { 2 } else { 1 };
+
+    match format {
+        VideoCaptureFormat::H264 => {
+            let slices: Vec<&&[u8]> = nals
+                .iter()
+                .filter(|nal| matches!(h264_nal_type(nal), 1 | 5))
+                .collect();
+            if slices.is_empty() {
+                return 0;
+            }
+
+            let disposable = slices.iter().all(|nal| nal[0] & 0x60 == 0);
+            depends_on << 4 | if disposable { 2 } else { 1 } << 2
+        }
+        VideoCaptureFormat::H265 => {
+            let slices: Vec<u8> = nals
+                .iter()
+                .filter(|nal| nal.len() >= 2)
+                .map(|nal| h265_nal_type(nal))
+                .filter(|&nal_type| nal_type < 32)
+                .collect();
+            if slices.is_empty() {
+                return 0;
+            }
+
+            let is_leading = match slices[0] {
+                8 | 9 => 1,
+                6 | 7 => 3,
+                _ => 2,
+            };
+            let disposable = slices
+                .iter()
+                .all(|&nal_type| nal_type < 16 && nal_type % 2 == 0);
+            is_leading << 6 | depends_on << 4 | if disposable { 2 } else { 1 } << 2 | 2
+        }
+        _ => 0,
+    }
+}
+
+/// Removes the emulation prevention bytes (the 3 in `00 00 03`) from a NAL unit.
+pub(crate) fn rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+
+    let mut zeros = 0;
+    for &byte in nal {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+/// Reads the bits of an RBSP, most significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bits(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u32;
+            self.position += 1;
+        }
+
+        Some(value)
+    }
+
+    fn skip_bits(&mut self, count: usize) {
+        self.position += count;
+    }
+
+    /// Reads an unsigned Exp-Golomb code.
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bits(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+
+        Some((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+
+    /// Reads a signed Exp-Golomb code.
+    fn read_se(&mut self) -> Option<i32> {
+        let code = self.read_ue()? as i64;
+        Some(if code % 2 == 1 {
+            ((code + 1) / 2) as i32
+        } else {
+            (-code / 2) as i32
+        })
+    }
+}
+
+/// The fields of an H.265 SPS that go into the `hvcC` box, and the picture size within
+/// the conformance window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HevcSps {
+    /// The general part of `profile_tier_level`: profile space, tier and profile, the
+    /// profile compatibility and constraint flags and finally the level.
+    pub(crate) profile_tier_level: [u8; 12],
+    pub(crate) max_sub_layers_minus1: u8,
+    pub(crate) temporal_id_nesting: bool,
+    pub(crate) chroma_format_idc: u8,
+    pub(crate) bit_depth_luma_minus8: u8,
+    pub(crate) bit_depth_chroma_minus8: u8,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// Parses the start of an H.265 SPS NAL unit (including its NAL unit header).
+pub(crate) fn parse_hevc_sps(nal: &[u8]) -> Option<HevcSps> {
+    let rbsp = rbsp(nal);
+    let mut reader = BitReader {
+        data: rbsp.get(2..)?,
+        position: 0,
+    };
+
+    reader.skip_bits(4); // sps_video_parameter_set_id
+    let max_sub_layers_minus1 = reader.read_bits(3)? as u8;
+    let temporal_id_nesting = reader.read_bits(1)? == 1;
+
+    // The general profile_tier_level is byte aligned after the first byte
+    let profile_tier_level = rbsp.get(3..15)?.try_into().ok()?;
+    reader.skip_bits(96);
+
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = reader.read_bits(1)? == 1;
+        let level_present = reader.read_bits(1)? == 1;
+        sub_layers.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        reader.skip_bits(2 * (8 - max_sub_layers_minus1 as usize));
+    }
+    for (profile_present, level_present) in sub_layers {
+        if profile_present {
+            reader.skip_bits(88);
+        }
+        if level_present {
+            reader.skip_bits(8);
+        }
+    }
+
+    reader.read_ue()?; // sps_seq_parameter_set_id
+    let chroma_format_idc = reader.read_ue()? as u8;
+    if chroma_format_idc == 3 {
+        reader.skip_bits(1); // separate_colour_plane_flag
+    }
+    let mut width = reader.read_ue()?; // pic_width_in_luma_samples
+    let mut height = reader.read_ue()?; // pic_height_in_luma_samples
+    if reader.read_bits(1)? == 1 {
+        // conformance window offsets, in chroma samples (SubWidthC and SubHeightC)
+        let (unit_x, unit_y) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        width = cropped(width, unit_x, reader.read_ue()?, reader.read_ue()?)?;
+        height = cropped(height, unit_y, reader.read_ue()?, reader.read_ue()?)?;
+    }
+
+    Some(HevcSps {
+        profile_tier_level,
+        max_sub_layers_minus1,
+        temporal_id_nesting,
+        chroma_format_idc,
+        bit_depth_luma_minus8: reader.read_ue()? as u8,
+        bit_depth_chroma_minus8: reader.read_ue()? as u8,
+        width,
+        height,
+    })
+}
+
+/// Subtracts the crop offsets at both ends of a picture dimension, in units of `unit`
+/// samples. None if they leave no picture.
+fn cropped(size: u32, unit: u32, start: u32, end: u32) -> Option<u32> {
+    let crop = unit as u64 * (start as u64 + end as u64);
+    u32::try_from((size as u64).checked_sub(crop)?).ok()
+}
+
+/// The picture size coded in an H.264 SPS NAL unit (including its NAL unit header), after
+/// the frame cropping.
+fn avc_sps_size(nal: &[u8]) -> Option<(u32, u32)> {
+    let rbsp = rbsp(nal);
+    let profile_idc = *rbsp.get(1)?;
+    let mut reader = BitReader {
+        data: rbsp.get(4..)?,
+        position: 0,
+    };
+
+    reader.read_ue()?; // seq_parameter_set_id
+                       // ChromaArrayType, 4:2:0 unless the profile codes it
+    let mut chroma_array_type = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        let chroma_format_idc = reader.read_ue()?;
+        chroma_array_type = chroma_format_idc;
+        if chroma_format_idc == 3 && reader.read_bits(1)? == 1 {
+            // separate_colour_plane_flag, the planes are coded as monochrome pictures
+            chroma_array_type = 0;
+        }
+        reader.read_ue()?; // bit_depth_luma_minus8
+        reader.read_ue()?; // bit_depth_chroma_minus8
+        reader.skip_bits(1); // qpprime_y_zero_transform_bypass_flag
+
+        if reader.read_bits(1)? == 1 {
+            // seq_scaling_list_present_flag and the scaling lists
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.read_bits(1)? == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8, 8);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + reader.read_se()? as i64).rem_euclid(256);
+                        }
+                        last = if next == 0 { last } else { next };
+                    }
+                }
+            }
+        }
+    }
+
+    reader.read_ue()?; // log2_max_frame_num_minus4
+    match reader.read_ue()? {
+        // pic_order_cnt_type
+        0 => {
+            reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.skip_bits(1); // delta_pic_order_always_zero_flag
+            reader.read_se()?; // offset_for_non_ref_pic
+            reader.read_se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.read_ue()? {
+                reader.read_se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    reader.read_ue()?; // max_num_ref_frames
+    reader.skip_bits(1); // gaps_in_frame_num_value_allowed_flag
+
+    let width_in_mbs = reader.read_ue()? + 1;
+    let height_in_map_units = reader.read_ue()? + 1;
+    let frame_mbs_only = reader.read_bits(1)?;
+    if frame_mbs_only == 0 {
+        reader.skip_bits(1); // mb_adaptive_frame_field_flag
+    }
+    reader.skip_bits(1); // direct_8x8_inference_flag
+
+    // A corrupt SPS can code sizes that do not fit 32 bits
+    let mut width = width_in_mbs.checked_mul(16)?;
+    let mut height = (2 - frame_mbs_only)
+        .checked_mul(height_in_map_units)?
+        .checked_mul(16)?;
+    if reader.read_bits(1)? == 1 {
+        // frame_cropping_flag, the offsets are in CropUnitX and CropUnitY samples
+        let (sub_width, sub_height) = match chroma_array_type {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let unit_y = sub_height * (2 - frame_mbs_only);
+        width = cropped(width, sub_width, reader.read_ue()?, reader.read_ue()?)?;
+        height = cropped(height, unit_y, reader.read_ue()?, reader.read_ue()?)?;
+    }
+
+    Some((width, height))
+}
+
+/// The picture size coded in an SPS NAL unit, None for other formats or if the SPS cannot
+/// be parsed.
+pub(crate) fn sps_size(format: VideoCaptureFormat, nal: &[u8]) -> Option<(u32, u32)> {
+    match format {
+        VideoCaptureFormat::H264 => avc_sps_size(nal),
+        VideoCaptureFormat::H265 => parse_hevc_sps(nal).map(|sps| (sps.width, sps.height)),
+        _ => None,
+    }
+}
+
+/// The first VPS (H.265 only), SPS and PPS of a stream, without start codes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ParameterSets {
+    pub(crate) vps: Option<Vec<u8>>,
+    pub(crate) sps: Option<Vec<u8>>,
+    pub(crate) pps: Option<Vec<u8>>,
+}
+
+impl ParameterSets {
+    /// Collects the parameter sets carried by a frame.
+    pub(crate) fn find(format: VideoCaptureFormat, data: &[u8]) -> Self {
+        let mut parameter_sets = ParameterSets::default();
+
+        for nal in nal_units(data) {
+            // Too short to hold the profile and level, which the mp4 config boxes copy
+            if nal.len() < 4 {
+                continue;
+            }
+
+            let slot = match (format, nal) {
+                (VideoCaptureFormat::H264, nal) => match h264_nal_type(nal) {
+                    7 => &mut parameter_sets.sps,
+                    8 => &mut parameter_sets.pps,
+                    _ => continue,
+                },
+                (VideoCaptureFormat::H265, nal) => match h265_nal_type(nal) {
+                    32 => &mut parameter_sets.vps,
+                    33 => &mut parameter_sets.sps,
+                    34 => &mut parameter_sets.pps,
+                    _ => continue,
+                },
+                _ => break,
+            };
+
+            slot.get_or_insert_with(|| nal.to_vec());
+        }
+
+        parameter_sets
+    }
+
+    /// Removes the NAL units of `data` that repeat one of these parameter sets, returning
+    /// the other NAL units with 4 byte start codes. None if there is nothing to remove, or
+    /// nothing would remain.
+    pub(crate) fn strip_repeated(
+        &self,
+        format: VideoCaptureFormat,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let nals = nal_units(data);
+        let kept: Vec<&[u8]> = nals
+            .iter()
+            .copied()
+            .filter(|nal| !self.repeats(format, nal))
+            .collect();
+        if kept.len() == nals.len() || kept.is_empty() {
+            return None;
+        }
+
+        Some(
+            kept.iter()
+                .flat_map(|nal| [&[0, 0, 0, 1][..], nal].concat())
+                .collect(),
+        )
+    }
+
+    fn repeats(&self, format: VideoCaptureFormat, nal: &[u8]) -> bool {
+        let parameter_set = match format {
+            VideoCaptureFormat::H264 => match h264_nal_type(nal) {
+                7 => &self.sps,
+                8 => &self.pps,
+                _ => return false,
+            },
+            VideoCaptureFormat::H265 => match h265_nal_type(nal) {
+                32 => &self.vps,
+                33 => &self.sps,
+                34 => &self.pps,
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        parameter_set.as_deref() == Some(nal)
+    }
+
+    /// Takes the parameter sets of `other` that are still missing.
+    pub(crate) fn merge(&mut self, other: ParameterSets) {
+        self.vps = self.vps.take().or(other.vps);
+        self.sps = self.sps.take().or(other.sps);
+        self.pps = self.pps.take().or(other.pps);
+    }
+
+    /// Returns true if every parameter set the mp4 config of the format needs is known.
+    pub(crate) fn is_complete(&self, format: VideoCaptureFormat) -> bool {
+        self.sps.is_some()
+            && self.pps.is_some()
+            && (format != VideoCaptureFormat::H265 || self.vps.is_some())
+    }
+}
@@ -2,16 +2,19 @@ use byteorder::LittleEndian;
 use static_assertions::const_assert_eq;
 use std::{
     convert::TryFrom,
-    error::Error,
-    fs::File,
-    io::{BufReader, Read, Seek, SeekFrom},
+    io,
+    io::{Read, Seek, SeekFrom},
     mem::{self, size_of},
 };
 use zerocopy::{AsBytes, FromBytes, LayoutVerified, Unaligned};
 
-const GENERIC_METADATA_HEADER_MAGIC: u32 = 0xBACCDEEF;
-const RECORDING_FRAME_MAGIC: u32 = 0xAAAAFEED;
-const RECORDING_INDEX_FOOTER_MAGIC: u32 = 0xDCBAFEED;
+pub(crate) const RECORDING_MAGIC: u32 = 0xFEEDFEED;
+pub(crate) const RECORDING_FRAME_MAGIC: u32 = 0xAAAAFEED;
+pub(crate) const GENERIC_METADATA_HEADER_MAGIC: u32 = 0xBACCDEEF;
+#[cfg(any(test, feature = "fixtures"))]
+pub(crate) const GENERIC_METADATA_FOOTER_MAGIC: u32 = 0xBACCBEEF;
+pub(crate) const RECORDING_INDEX_HEADER_MAGIC: u32 = 0xABCDFEED;
+pub(crate) const RECORDING_INDEX_FOOTER_MAGIC: u32 = 0xDCBAFEED;
 
 const VIDEO_PLACEMENT_METADATA_MAGIC_1: u8 = 0x00;
 const VIDEO_PLACEMENT_METADATA_MAGIC_2: u8 = 0x00;
@@ -19,70 +22,72 @@ const VIDEO_PLACEMENT_METADATA_MAGIC_3: u8 = 0x00;
 const VIDEO_PLACEMENT_METADATA_MAGIC_4: u8 = 0x56;
 const VIDEO_PLACEMENT_METADATA_MAGIC_5: u8 = 0x4A;
 
-type I32 = zerocopy::I32<LittleEndian>;
-type I64 = zerocopy::I64<LittleEndian>;
-type U16 = zerocopy::U16<LittleEndian>;
-type U32 = zerocopy::U32<LittleEndian>;
-type U64 = zerocopy::U64<LittleEndian>;
+pub(crate) type I32 = zerocopy::I32<LittleEndian>;
+pub(crate) type I64 = zerocopy::I64<LittleEndian>;
+pub(crate) type U16 = zerocopy::U16<LittleEndian>;
+pub(crate) type U32 = zerocopy::U32<LittleEndian>;
+pub(crate) type U64 = zerocopy::U64<LittleEndian>;
 
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
-struct RecordingMetadata {
-    magic: U32,
-    unix_epoch_time_relative_nsec: U32,
-    unix_epoch_time_sec: U64,
+pub(crate) struct RecordingMetadata {
+    pub(crate) magic: U32,
+    pub(crate) unix_epoch_time_relative_nsec: U32,
+    pub(crate) unix_epoch_time_sec: U64,
 }
 
 const_assert_eq!(mem::size_of::<RecordingMetadata>(), 16);
 
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
-struct RecordedFrameMetadata {
-    magic: U32,
-    id: I32,
-    padding: I32,
-    width: I32,
-    height: I32,
-    format: I32,
-    timestamp: I64,
-    receive_timestamp: I64,
-    size: I64,
+pub(crate) struct RecordedFrameMetadata {
+    pub(crate) magic: U32,
+    pub(crate) id: I32,
+    pub(crate) padding: I32,
+    pub(crate) width: I32,
+    pub(crate) height: I32,
+    pub(crate) format: I32,
+    pub(crate) timestamp: I64,
+    pub(crate) receive_timestamp: I64,
+    pub(crate) size: I64,
 }
 
 const_assert_eq!(mem::size_of::<RecordedFrameMetadata>(), 48);
 
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
-struct GenericMetadataHeader {
-    magic: U32,
-    generic_metadata_size: U32,
+pub(crate) struct GenericMetadataHeader {
+    pub(crate) magic: U32,
+    pub(crate) generic_metadata_size: U32,
 }
 
 const_assert_eq!(mem::size_of::<GenericMetadataHeader>(), 8);
 
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
-struct RecordingIndexHeader {
-    magic: U32,
-    padding: U32,
+pub(crate) struct RecordingIndexHeader {
+    pub(crate) magic: U32,
+    pub(crate) padding: U32,
 }
 
 const_assert_eq!(mem::size_of::<RecordingIndexHeader>(), 8);
 
+// Every entry points at one complete frame. The format has no flag to continue a payload
+// in the next entry, so frames never have to be reassembled from chunks.
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 pub struct RecordingIndexEntry {
-    offset: I64,
-    receive_timestamp: I64,
+    pub(crate) offset: I64,
+    pub(crate) receive_timestamp: I64,
 }
 
 const_assert_eq!(mem::size_of::<RecordingIndexEntry>(), 16);
 
 #[derive(Debug, Clone, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
-struct RecordingIndexFooter {
-    magic: U32,
-    frame_count: U32,
+pub(crate) struct RecordingIndexFooter {
+    pub(crate) magic: U32,
+    pub(crate) frame_count: U32,
 }
 
 const_assert_eq!(mem::size_of::<RecordingIndexFooter>(), 8);
@@ -100,15 +105,61 @@ struct VideoPlacementMetadataFooter {
 
 const_assert_eq!(mem::size_of::<VideoPlacementMetadataFooter>(), 7);
 
+/// Errors that can occur while parsing a .vraw recording.
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    /// Reading from the underlying file failed.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The bytes could not be interpreted as the named structure.
+    #[error("Failed to parse {0}")]
+    Layout(&'static str),
+    /// The magic of the named structure does not match.
+    #[error("Magic does not match for {0}")]
+    MagicMismatch(&'static str),
+    /// The frame header contains values that are not valid for the frame format.
+    #[error("{0}")]
+    InvalidFrame(&'static str),
+    /// A video frame is in a different format than the stream it belongs to. Index entries
+    /// do not record a format, so the stream format is the one of the first video frame
+    /// (see [`crate::FrameSource::codec`]).
+    #[error("Frame format {frame_fmt:?} does not match stream format {stream_fmt:?}")]
+    FormatMismatch {
+        stream_fmt: VideoCaptureFormat,
+        frame_fmt: VideoCaptureFormat,
+    },
+    /// A session was selected that the recording does not have, see [`crate::Session`].
+    #[error("Session {session} selected but the recording has {sessions} sessions")]
+    MissingSession { session: usize, sessions: usize },
+    /// A frame was selected by its position in the index that the recording does not have.
+    #[error("Frame {index} selected but the recording has {frames} frames")]
+    MissingFrame { index: usize, frames: usize },
+}
+
+/// A single frame of a recording, as stored in the .vraw file.
 #[derive(Debug, Clone)]
-pub struct FrameInfo {
-    pub resolution: String,
+pub struct RawFrame {
+    /// The format of `raw_data`. Recordings interleave video frames with
+    /// [`VideoCaptureFormat::Stats`] frames, which carry no picture.
     pub format: VideoCaptureFormat,
+    /// Width of the picture in pixels. Always 0 for coded formats, where the
+    /// dimensions are only known from the bitstream.
+    pub width: u32,
+    /// Height of the picture in pixels. Always 0 for coded formats.
+    pub height: u32,
+    /// The frame payload with any video placement (alignment) data removed. For
+    /// H.264/H.265 this is an Annex B byte stream, i.e. NAL units prefixed with
+    /// `00 00 00 01` start codes, not length prefixed (AVCC/HVCC) NAL units.
     pub raw_data: Vec<u8>,
+    /// The time the frame was received by the recorder, in nanoseconds. The clock is
+    /// monotonic within a recording but usually has an arbitrary epoch, so only
+    /// differences between timestamps are meaningful. Some firmware uses UNIX time
+    /// instead, see [`crate::ClockKind`].
     pub timestamp: i64,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// The format of a frame, as stored in the `format` field of the frame header.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Serialize)]
 #[repr(i32)]
 pub enum VideoCaptureFormat {
     Rgb = 0,
@@ -125,9 +176,34 @@ pub enum VideoCaptureFormat {
     H265 = -4602,
     Mjpeg = -4603,
     Stats = -4701,
+    /// A format code this crate does not know (e.g. a codec of newer firmware), as the
+    /// bits of the `format` field. The parser never uses it for a known code.
+    Unknown(u32),
 }
 
 impl VideoCaptureFormat {
+    /// The code of the format in the `format` field of the frame header.
+    pub fn code(&self) -> i32 {
+        match self {
+            VideoCaptureFormat::Rgb => 0,
+            VideoCaptureFormat::Bgr => 1,
+            VideoCaptureFormat::Yuv => 2,
+            VideoCaptureFormat::Nv12 => 3,
+            VideoCaptureFormat::Yuyv => 4,
+            VideoCaptureFormat::Uyvy => 5,
+            VideoCaptureFormat::Raw => 6,
+            VideoCaptureFormat::Mono16 => 7,
+            VideoCaptureFormat::Raw16 => 8,
+            VideoCaptureFormat::Mono8 => 9,
+            VideoCaptureFormat::H264 => -4601,
+            VideoCaptureFormat::H265 => -4602,
+            VideoCaptureFormat::Mjpeg => -4603,
+            VideoCaptureFormat::Stats => -4701,
+            VideoCaptureFormat::Unknown(code) => *code as i32,
+        }
+    }
+
+    /// Returns true for compressed formats, where the frame is a bitstream rather than pixels.
     pub fn is_coded(&self) -> bool {
         matches!(
             self,
@@ -136,80 +212,102 @@ impl VideoCaptureFormat {
     }
 }
 
-impl TryFrom<i32> for VideoCaptureFormat {
-    type Error = Box<dyn Error>;
-
-    fn try_from(format: i32) -> Result<Self, Self::Error> {
+impl From<i32> for VideoCaptureFormat {
+    fn from(format: i32) -> Self {
         match format {
-            0 => Ok(VideoCaptureFormat::Rgb),
-            1 => Ok(VideoCaptureFormat::Bgr),
-            2 => Ok(VideoCaptureFormat::Yuv),
-            3 => Ok(VideoCaptureFormat::Nv12),
-            4 => Ok(VideoCaptureFormat::Yuyv),
-            5 => Ok(VideoCaptureFormat::Uyvy),
-            6 => Ok(VideoCaptureFormat::Raw),
-            7 => Ok(VideoCaptureFormat::Mono16),
-            8 => Ok(VideoCaptureFormat::Raw16),
-            9 => Ok(VideoCaptureFormat::Mono8),
-            -4601 => Ok(VideoCaptureFormat::H264),
-            -4602 => Ok(VideoCaptureFormat::H265),
-            -4603 => Ok(VideoCaptureFormat::Mjpeg),
-            -4701 => Ok(VideoCaptureFormat::Stats),
-            _ => Err(format!("Unknown video capture format {}", format).into()),
+            0 => VideoCaptureFormat::Rgb,
+            1 => VideoCaptureFormat::Bgr,
+            2 => VideoCaptureFormat::Yuv,
+            3 => VideoCaptureFormat::Nv12,
+            4 => VideoCaptureFormat::Yuyv,
+            5 => VideoCaptureFormat::Uyvy,
+            6 => VideoCaptureFormat::Raw,
+            7 => VideoCaptureFormat::Mono16,
+            8 => VideoCaptureFormat::Raw16,
+            9 => VideoCaptureFormat::Mono8,
+            -4601 => VideoCaptureFormat::H264,
+            -4602 => VideoCaptureFormat::H265,
+            -4603 => VideoCaptureFormat::Mjpeg,
+            -4701 => VideoCaptureFormat::Stats,
+            _ => VideoCaptureFormat::Unknown(format as u32),
         }
     }
 }
 
-fn parse_recording_index_footer(bytes: &[u8]) -> Result<&RecordingIndexFooter, Box<dyn Error>> {
+fn parse_recording_metadata(bytes: &[u8]) -> Result<&RecordingMetadata, ParseError> {
+    LayoutVerified::<&[u8], RecordingMetadata>::new_unaligned(bytes)
+        .ok_or(ParseError::Layout("RecordingMetadata"))
+        .map(|lv| lv.into_ref())
+        .and_then(|res| {
+            if res.magic.get() == RECORDING_MAGIC {
+                Ok(res)
+            } else {
+                Err(ParseError::MagicMismatch("RecordingMetadata"))
+            }
+        })
+}
+
+fn parse_recording_index_header(bytes: &[u8]) -> Result<&RecordingIndexHeader, ParseError> {
+    LayoutVerified::<&[u8], RecordingIndexHeader>::new_unaligned(bytes)
+        .ok_or(ParseError::Layout("RecordingIndexHeader"))
+        .map(|lv| lv.into_ref())
+        .and_then(|res| {
+            if res.magic.get() == RECORDING_INDEX_HEADER_MAGIC {
+                Ok(res)
+            } else {
+                Err(ParseError::MagicMismatch("RecordingIndexHeader"))
+            }
+        })
+}
+
+fn parse_recording_index_footer(bytes: &[u8]) -> Result<&RecordingIndexFooter, ParseError> {
     LayoutVerified::<&[u8], RecordingIndexFooter>::new_unaligned(bytes)
-        .ok_or_else(|| "Failed to parse RecordingIndexFooter".into())
+        .ok_or(ParseError::Layout("RecordingIndexFooter"))
         .map(|lv| lv.into_ref())
         .and_then(|res| {
             if res.magic.get() == RECORDING_INDEX_FOOTER_MAGIC {
                 Ok(res)
             } else {
-                Err("Magic does not match".into())
+                Err(ParseError::MagicMismatch("RecordingIndexFooter"))
             }
         })
 }
 
-fn parse_recording_index_entry(bytes: &[u8]) -> Result<&RecordingIndexEntry, Box<dyn Error>> {
+fn parse_recording_index_entry(bytes: &[u8]) -> Result<&RecordingIndexEntry, ParseError> {
     LayoutVerified::<&[u8], RecordingIndexEntry>::new_unaligned(bytes)
-        .ok_or_else(|| "Failed to parse RecordingIndexEntry".into())
+        .ok_or(ParseError::Layout("RecordingIndexEntry"))
         .map(|lv| lv.into_ref())
 }
 
-fn parse_recorded_frame_metadata(bytes: &[u8]) -> Result<&RecordedFrameMetadata, Box<dyn Error>> {
+fn parse_recorded_frame_metadata(bytes: &[u8]) -> Result<&RecordedFrameMetadata, ParseError> {
     LayoutVerified::<&[u8], RecordedFrameMetadata>::new_unaligned(bytes)
-        .ok_or_else(|| "Failed to parse RecordedFrameMetadata".into())
+        .ok_or(ParseError::Layout("RecordedFrameMetadata"))
         .map(|lv| lv.into_ref())
         .and_then(|res| {
             if res.magic.get() == RECORDING_FRAME_MAGIC {
                 Ok(res)
             } else {
-                Err("Magic does not match".into())
+                Err(ParseError::MagicMismatch("RecordedFrameMetadata"))
             }
         })
 }
 
-fn parse_generic_metadata_header(bytes: &[u8]) -> Result<&GenericMetadataHeader, Box<dyn Error>> {
+fn parse_generic_metadata_header(bytes: &[u8]) -> Result<&GenericMetadataHeader, ParseError> {
     LayoutVerified::<&[u8], GenericMetadataHeader>::new_unaligned(bytes)
-        .ok_or_else(|| "Failed to parse GenericMetadataHeader".into())
+        .ok_or(ParseError::Layout("GenericMetadataHeader"))
         .map(|lv| lv.into_ref())
         .and_then(|res| {
             if res.magic.get() == GENERIC_METADATA_HEADER_MAGIC {
                 Ok(res)
             } else {
-                Err("Magic does not match".into())
+                Err(ParseError::MagicMismatch("GenericMetadataHeader"))
             }
         })
 }
 
-fn parse_video_placement_footer(
-    bytes: &[u8],
-) -> Result<&VideoPlacementMetadataFooter, Box<dyn Error>> {
+fn parse_video_placement_footer(bytes: &[u8]) -> Result<&VideoPlacementMetadataFooter, ParseError> {
     LayoutVerified::<&[u8], VideoPlacementMetadataFooter>::new_unaligned(bytes)
-        .ok_or_else(|| "Failed to parse VideoPlacementMetadataFooter".into())
+        .ok_or(ParseError::Layout("VideoPlacementMetadataFooter"))
         .map(|lv| lv.into_ref())
         .and_then(|res| {
             if res.magic_1 == VIDEO_PLACEMENT_METADATA_MAGIC_1
@@ -220,27 +318,227 @@ fn parse_video_placement_footer(
             {
                 Ok(res)
             } else {
-                Err("Magic does not match".into())
+                Err(ParseError::MagicMismatch("VideoPlacementMetadataFooter"))
             }
         })
 }
 
-pub fn read_index(f: &mut BufReader<File>) -> Result<Vec<RecordingIndexEntry>, Box<dyn Error>> {
-    f.seek(SeekFrom::End(
-        -(mem::size_of::<RecordingIndexFooter>() as i64),
-    ))?;
+/// How many bytes the footer can be away from the end of a frame payload.
+const MAX_VIDEO_PLACEMENT_FOOTER_OFFSET: usize = 11;
+
+/// How many bytes at the end of a frame payload [`video_placement_len`] needs.
+pub(crate) const VIDEO_PLACEMENT_TAIL_LEN: usize =
+    size_of::<VideoPlacementMetadataFooter>() + MAX_VIDEO_PLACEMENT_FOOTER_OFFSET;
+
+/// The size of the video placement (alignment) data and its footer at the end of a video
+/// frame payload, from the last [`VIDEO_PLACEMENT_TAIL_LEN`] bytes of the payload (or all
+/// of a shorter one). None if the frame has no video placement data.
+pub(crate) fn video_placement_len(tail: &[u8]) -> Option<usize> {
+    // Loop from the end to try and match the video placement magic(s)
+    (0..=MAX_VIDEO_PLACEMENT_FOOTER_OFFSET)
+        .take_while(|offset| tail.len() >= size_of::<VideoPlacementMetadataFooter>() + offset)
+        .find_map(|offset| {
+            parse_video_placement_footer(
+                &tail[(tail.len() - size_of::<VideoPlacementMetadataFooter>() - offset)
+                    ..(tail.len() - offset)],
+            )
+            .ok()
+        })
+        .map(|footer| {
+            footer.metadata_size.get() as usize + size_of::<VideoPlacementMetadataFooter>()
+        })
+}
+
+/// Reads the recording header at the start of the file.
+pub(crate) fn read_recording_metadata<R: Read + Seek>(
+    f: &mut R,
+) -> Result<RecordingMetadata, ParseError> {
+    read_recording_metadata_at(f, 0)
+}
+
+/// Reads a recording header at `offset`, which is only nonzero for appended sessions.
+fn read_recording_metadata_at<R: Read + Seek>(
+    f: &mut R,
+    offset: u64,
+) -> Result<RecordingMetadata, ParseError> {
+    f.seek(SeekFrom::Start(offset))?;
+
+    let mut recording_metadata_bytes: [u8; mem::size_of::<RecordingMetadata>()] =
+        [0; mem::size_of::<RecordingMetadata>()];
+    f.read_exact(&mut recording_metadata_bytes)?;
+
+    parse_recording_metadata(&recording_metadata_bytes[..]).map(|res| res.to_owned())
+}
+
+/// The fields of the recording header, see [`read_raw_header`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderFields {
+    /// Start of the recording, whole seconds since the Unix epoch.
+    pub unix_epoch_time_sec: u64,
+    /// Nanoseconds to add to [`HeaderFields::unix_epoch_time_sec`].
+    pub unix_epoch_time_relative_nsec: u32,
+}
+
+/// Reads the recording header along with its exact bytes, for tools that patch fields of
+/// the header in place. The header occupies the first 16 bytes of the file: the magic,
+/// then `unix_epoch_time_relative_nsec` (4 bytes) and `unix_epoch_time_sec` (8 bytes),
+/// little endian.
+pub fn read_raw_header<R: Read + Seek>(f: &mut R) -> Result<(HeaderFields, Vec<u8>), ParseError> {
+    let recording_metadata = read_recording_metadata(f)?;
+
+    Ok((
+        HeaderFields {
+            unix_epoch_time_sec: recording_metadata.unix_epoch_time_sec.get(),
+            unix_epoch_time_relative_nsec: recording_metadata.unix_epoch_time_relative_nsec.get(),
+        },
+        recording_metadata.as_bytes().to_vec(),
+    ))
+}
+
+/// Reads the exact bytes of the index block read by [`read_index`], from the index header
+/// through the footer, for tools that patch entries in place. A block at the end of the
+/// file starts at the file size minus the number of bytes returned, a block at the start
+/// of the file right after the 16 byte recording header.
+pub fn read_raw_index_bytes<R: Read + Seek>(f: &mut R) -> Result<Vec<u8>, ParseError> {
+    let block_size = |entries: usize| {
+        (mem::size_of::<RecordingIndexHeader>()
+            + entries * mem::size_of::<RecordingIndexEntry>()
+            + mem::size_of::<RecordingIndexFooter>()) as u64
+    };
+
+    let end = f.seek(SeekFrom::End(0))?;
+    let (start, size) = match read_index_ending_at(f, end) {
+        Ok(entries) => {
+            let size = block_size(entries.len());
+            let start = end
+                .checked_sub(size)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            (start, size)
+        }
+        Err(e) => {
+            let entries = read_leading_index(f).map_err(|_| e)?;
+            (
+                mem::size_of::<RecordingMetadata>() as u64,
+                block_size(entries.len()),
+            )
+        }
+    };
+
+    f.seek(SeekFrom::Start(start))?;
+    let mut bytes = vec![0; size as usize];
+    f.read_exact(&mut bytes)?;
+    parse_recording_index_header(&bytes[..mem::size_of::<RecordingIndexHeader>()])?;
+
+    Ok(bytes)
+}
+
+/// Reads the index at the end of the recording, which is the index of the last session if
+/// sessions were appended to the file (see [`read_all_indexes`]). Recordings without one
+/// are read with the index at their start, see [`read_leading_index`].
+pub fn read_index<R: Read + Seek>(f: &mut R) -> Result<Vec<RecordingIndexEntry>, ParseError> {
+    let end = f.seek(SeekFrom::End(0))?;
+    read_index_ending_at(f, end).or_else(|e| read_leading_index(f).map_err(|_| e))
+}
+
+/// Reads the index of every recording session in the file, in file order.
+///
+/// A recorder appending to an existing file writes a new recording header, frames and
+/// index after the index of the previous session, with the frame offsets counted from the
+/// start of the file. Sessions are found from the last one backwards: a session whose
+/// first frame directly follows a recording header at a nonzero offset is preceded by
+/// another session, whose index ends at that header. A file with a single session returns
+/// its index only.
+pub(crate) fn read_all_indexes<R: Read + Seek>(
+    f: &mut R,
+) -> Result<Vec<Vec<RecordingIndexEntry>>, ParseError> {
+    let mut end = f.seek(SeekFrom::End(0))?;
+    let mut indexes = Vec::new();
+
+    loop {
+        let entries = match read_index_ending_at(f, end) {
+            Ok(entries) => entries,
+            // An earlier session without a valid index cannot be read, as before
+            Err(_) if !indexes.is_empty() => break,
+            // Nothing can be appended after an index at the start of the file
+            Err(e) => {
+                return read_leading_index(f)
+                    .map(|entries| vec![entries])
+                    .map_err(|_| e)
+            }
+        };
+
+        let session_start = entries
+            .first()
+            .and_then(|entry| u64::try_from(entry.offset.get()).ok())
+            .and_then(|offset| offset.checked_sub(mem::size_of::<RecordingMetadata>() as u64))
+            .filter(|&start| start > 0 && start < end);
+        indexes.push(entries);
+
+        match session_start {
+            Some(start) if read_recording_metadata_at(f, start).is_ok() => end = start,
+            _ => break,
+        }
+    }
+
+    indexes.reverse();
+    Ok(indexes)
+}
+
+/// Reads an index block (header, entries and footer) written right after the recording
+/// header instead of at the end of the file, as by recorders that reserve room for the
+/// index up front. The index header does not tell the number of entries, so the footer is
+/// the first entry position holding the footer magic and the number of entries before it.
+fn read_leading_index<R: Read + Seek>(f: &mut R) -> Result<Vec<RecordingIndexEntry>, ParseError> {
+    f.seek(SeekFrom::Start(mem::size_of::<RecordingMetadata>() as u64))?;
+
+    let mut index_header_bytes: [u8; mem::size_of::<RecordingIndexHeader>()] =
+        [0; mem::size_of::<RecordingIndexHeader>()];
+    f.read_exact(&mut index_header_bytes)?;
+    parse_recording_index_header(&index_header_bytes)?;
+
+    // Reading past the end of the file ends the scan
+    let mut res = Vec::new();
+    loop {
+        let mut index_entry_bytes: [u8; mem::size_of::<RecordingIndexEntry>()] =
+            [0; mem::size_of::<RecordingIndexEntry>()];
+        let (footer_bytes, rest) =
+            index_entry_bytes.split_at_mut(mem::size_of::<RecordingIndexFooter>());
+        f.read_exact(footer_bytes)?;
+        if let Ok(footer) = parse_recording_index_footer(footer_bytes) {
+            if footer.frame_count.get() as usize == res.len() {
+                return Ok(res);
+            }
+        }
+
+        f.read_exact(rest)?;
+        let entry = parse_recording_index_entry(&index_entry_bytes)?;
+        res.push(entry.to_owned());
+    }
+}
+
+/// Reads the index whose footer ends at `end`.
+fn read_index_ending_at<R: Read + Seek>(
+    f: &mut R,
+    end: u64,
+) -> Result<Vec<RecordingIndexEntry>, ParseError> {
+    let footer_start = end
+        .checked_sub(mem::size_of::<RecordingIndexFooter>() as u64)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    f.seek(SeekFrom::Start(footer_start))?;
 
     let mut index_footer_bytes: [u8; mem::size_of::<RecordingIndexFooter>()] =
         [0; mem::size_of::<RecordingIndexFooter>()];
-    f.read_exact(&mut index_footer_bytes).unwrap();
+    f.read_exact(&mut index_footer_bytes)?;
 
     let footer = parse_recording_index_footer(&index_footer_bytes)?;
 
-    f.seek(SeekFrom::End(
-        -((mem::size_of::<RecordingIndexFooter>()
-            + footer.frame_count.get() as usize * mem::size_of::<RecordingIndexEntry>())
-            as i64),
-    ))?;
+    // Entries and offsets are 64 bit, so recordings larger than 4 GB work on any platform
+    let index_size = mem::size_of::<RecordingIndexFooter>() as u64
+        + footer.frame_count.get() as u64 * mem::size_of::<RecordingIndexEntry>() as u64;
+    let index_start = end
+        .checked_sub(index_size)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    f.seek(SeekFrom::Start(index_start))?;
 
     // At the first frame now
     let mut res = Vec::with_capacity(footer.frame_count.get() as _);
@@ -258,11 +556,55 @@ pub fn read_index(f: &mut BufReader<File>) -> Result<Vec<RecordingIndexEntry>, B
     Ok(res)
 }
 
-pub fn parse_raw_frame(
-    f: &mut BufReader<File>,
+/// The header fields of a frame, see [`peek_frame_meta`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameMeta {
+    pub format: VideoCaptureFormat,
+    /// As in [`RawFrame::timestamp`].
+    pub timestamp: i64,
+    /// Size of the payload in bytes, including any video placement data that
+    /// [`RawFrame::raw_data`] leaves out.
+    pub payload_len: u64,
+}
+
+/// Reads only the header of the frame at `entry`, for filtering or counting frames without
+/// reading their payloads.
+pub fn peek_frame_meta<R: Read + Seek>(
+    f: &mut R,
+    entry: &crate::IndexEntry,
+) -> Result<FrameMeta, ParseError> {
+    // Offsets past i64::MAX are rejected like negative ones
+    let entry = RecordingIndexEntry {
+        offset: I64::new(i64::try_from(entry.offset).unwrap_or(-1)),
+        receive_timestamp: I64::new(entry.receive_timestamp),
+    };
+
+    read_frame_meta(f, &entry)
+}
+
+/// [`peek_frame_meta`] for an entry read by [`read_index`].
+pub(crate) fn read_frame_meta<R: Read + Seek>(
+    f: &mut R,
     entry: &RecordingIndexEntry,
-) -> Result<FrameInfo, Box<dyn Error>> {
-    f.seek(SeekFrom::Start(entry.offset.get() as _))?;
+) -> Result<FrameMeta, ParseError> {
+    let (recorded_frame_metadata, format) = parse_frame_header(f, entry)?;
+
+    Ok(FrameMeta {
+        format,
+        timestamp: recorded_frame_metadata.receive_timestamp.get(),
+        payload_len: recorded_frame_metadata.size.get() as u64,
+    })
+}
+
+/// Reads and validates the header of the frame at `entry`, leaving `f` at the start of the
+/// frame payload.
+pub(crate) fn parse_frame_header<R: Read + Seek>(
+    f: &mut R,
+    entry: &RecordingIndexEntry,
+) -> Result<(RecordedFrameMetadata, VideoCaptureFormat), ParseError> {
+    let offset = u64::try_from(entry.offset.get())
+        .map_err(|_| ParseError::InvalidFrame("Frame offset not parsed correctly."))?;
+    f.seek(SeekFrom::Start(offset))?;
 
     // ------------------------------------------------------------------------
     // Parse header
@@ -273,22 +615,69 @@ pub fn parse_raw_frame(
     let recorded_frame_metadata =
         parse_recorded_frame_metadata(&recorded_frame_metadata_bytes[..])?;
 
-    if recorded_frame_metadata.size.get() <= 0 {
-        return Err("Frame size not parsed correctly.".into());
+    // The payload is read into memory, so its size has to fit a usize as well. Frames
+    // without payload (e.g. keepalives) are valid, the converter skips them
+    if recorded_frame_metadata.size.get() < 0
+        || usize::try_from(recorded_frame_metadata.size.get()).is_err()
+    {
+        return Err(ParseError::InvalidFrame("Frame size not parsed correctly."));
     }
 
-    let format = VideoCaptureFormat::try_from(recorded_frame_metadata.format.get())?;
+    let format = VideoCaptureFormat::from(recorded_frame_metadata.format.get());
 
     if format.is_coded() {
         if recorded_frame_metadata.width.get() != 0 && recorded_frame_metadata.height.get() != 0 {
-            return Err("Frame width and height not parsed correctly.".into());
+            return Err(ParseError::InvalidFrame(
+                "Frame width and height not parsed correctly.",
+            ));
         }
-    } else if format != VideoCaptureFormat::Stats
-        && (recorded_frame_metadata.width.get() <= 0 || recorded_frame_metadata.height.get() <= 0)
+    } else if !matches!(
+        format,
+        VideoCaptureFormat::Stats | VideoCaptureFormat::Unknown(_)
+    ) && (recorded_frame_metadata.width.get() <= 0
+        || recorded_frame_metadata.height.get() <= 0)
     {
-        return Err("Frame width and height not parsed correctly.".into());
+        return Err(ParseError::InvalidFrame(
+            "Frame width and height not parsed correctly.",
+        ));
     }
 
+    Ok((recorded_frame_metadata.to_owned(), format))
+}
+
+/// Reads the frame at `entry` without interpreting its payload. Returns the frame header
+/// and the bytes following it, from the payload up to and including the generic metadata
+/// footer.
+pub(crate) fn read_frame_record<R: Read + Seek>(
+    f: &mut R,
+    entry: &RecordingIndexEntry,
+) -> Result<(RecordedFrameMetadata, Vec<u8>), ParseError> {
+    let (recorded_frame_metadata, _) = parse_frame_header(f, entry)?;
+
+    let payload_size = recorded_frame_metadata.size.get() as usize;
+    let mut record = vec![0; payload_size + mem::size_of::<GenericMetadataHeader>()];
+    f.read_exact(&mut record)?;
+
+    let generic_metadata_size = parse_generic_metadata_header(&record[payload_size..])?
+        .generic_metadata_size
+        .get() as usize;
+
+    let start = record.len();
+    record.resize(
+        start + generic_metadata_size + mem::size_of::<GenericMetadataHeader>(),
+        0,
+    );
+    f.read_exact(&mut record[start..])?;
+
+    Ok((recorded_frame_metadata, record))
+}
+
+pub fn parse_raw_frame<R: Read + Seek>(
+    f: &mut R,
+    entry: &RecordingIndexEntry,
+) -> Result<RawFrame, ParseError> {
+    let (recorded_frame_metadata, format) = parse_frame_header(f, entry)?;
+
     // ------------------------------------------------------------------------
     // Read frame data
     let mut raw_frame_data: Vec<u8> = vec![0; recorded_frame_metadata.size.get() as usize];
@@ -296,36 +685,15 @@ pub fn parse_raw_frame(
 
     // ------------------------------------------------------------------------
     // Parse VideoPlacementMetadataFooter
-    let frame_data: Vec<u8>;
-    if format != VideoCaptureFormat::Stats {
-        let mut offset = 0;
-
-        loop {
-            // Loop from the end to try and match the video placement magic(s)
-            if let Ok(video_placement_footer) = parse_video_placement_footer(
-                &raw_frame_data[(raw_frame_data.len()
-                    - size_of::<VideoPlacementMetadataFooter>()
-                    - offset)..(raw_frame_data.len() - offset)],
-            ) {
-                frame_data = raw_frame_data[..(raw_frame_data.len()
-                    - video_placement_footer.clone().metadata_size.get() as usize
-                    - size_of::<VideoPlacementMetadataFooter>())]
-                    .to_vec();
-
-                break;
-            } else {
-                if offset > 10 {
-                    // If the end has to be looped more than 10 times then it probably do not have alignment data
-                    frame_data = raw_frame_data.clone();
-                    break;
-                }
-
-                offset += 1;
-            }
+    let tail = &raw_frame_data[raw_frame_data
+        .len()
+        .saturating_sub(VIDEO_PLACEMENT_TAIL_LEN)..];
+    let frame_data = match video_placement_len(tail) {
+        Some(placement_len) if format != VideoCaptureFormat::Stats => {
+            raw_frame_data[..(raw_frame_data.len() - placement_len)].to_vec()
         }
-    } else {
-        frame_data = raw_frame_data.clone();
-    }
+        _ => raw_frame_data.clone(),
+    };
 
     // ------------------------------------------------------------------------
     // Parse generic metadata header
@@ -344,13 +712,10 @@ pub fn parse_raw_frame(
     // Parse generic metadata footer
     f.read_exact(&mut generic_metadata_header_or_footer_data)?;
 
-    let resolution = recorded_frame_metadata.width.to_string()
-        + "x"
-        + &recorded_frame_metadata.height.to_string();
-
-    Ok(FrameInfo {
-        resolution,
+    Ok(RawFrame {
         format,
+        width: recorded_frame_metadata.width.get().max(0) as u32,
+        height: recorded_frame_metadata.height.get().max(0) as u32,
         timestamp: recorded_frame_metadata.receive_timestamp.get(),
         raw_data: frame_data,
     })
@@ -0,0 +1,125 @@
+use crate::parser::{ParseError, RawFrame};
+use crate::source::FrameSource;
+use crate::VideoCaptureFormat;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Frames read ahead of the conversion by [`with_prefetch`], which caps the memory the
+/// read-ahead takes.
+pub(crate) const PREFETCH_FRAMES: usize = 32;
+
+enum Message {
+    Frame(Result<RawFrame, ParseError>),
+    End,
+    Refreshed(Result<usize, ParseError>),
+}
+
+/// A [`FrameSource`] handing out the frames another thread reads from the actual source,
+/// so that parsing the next frames overlaps writing the current one. The frames arrive in
+/// the order of the source.
+pub(crate) struct Prefetch {
+    codec: VideoCaptureFormat,
+    parameter_sets: [Option<Vec<u8>>; 3],
+    messages: Receiver<Message>,
+    refresh_requests: Sender<()>,
+    exhausted: bool,
+}
+
+/// Calls `f` with a [`Prefetch`] reading `source` on a worker thread, at most `capacity`
+/// frames ahead. The worker ends with `f`.
+pub(crate) fn with_prefetch<S: FrameSource + Send, T>(
+    mut source: S,
+    capacity: usize,
+    f: impl FnOnce(&mut Prefetch) -> T,
+) -> T {
+    let (message_sender, messages) = mpsc::sync_channel(capacity);
+    let (refresh_requests, refresh_receiver) = mpsc::channel();
+
+    let mut prefetch = Prefetch {
+        codec: source.codec(),
+        parameter_sets: [source.vps(), source.sps(), source.pps()]
+            .map(|parameter_set| parameter_set.map(|nal| nal.to_vec())),
+        messages,
+        refresh_requests,
+        exhausted: false,
+    };
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            // Sending fails once the conversion is done and the receiver is gone
+            loop {
+                let message = match source.next_frame() {
+                    Some(frame) => Message::Frame(frame),
+                    None => Message::End,
+                };
+                let end = matches!(message, Message::End);
+                if message_sender.send(message).is_err() {
+                    return;
+                }
+
+                if end {
+                    if refresh_receiver.recv().is_err() {
+                        return;
+                    }
+                    if message_sender
+                        .send(Message::Refreshed(source.refresh()))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let result = f(&mut prefetch);
+        drop(prefetch);
+        result
+    })
+}
+
+impl FrameSource for Prefetch {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.codec
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        if self.exhausted {
+            return None;
+        }
+
+        match self.messages.recv() {
+            Ok(Message::Frame(frame)) => Some(frame),
+            // The worker only sends refresh results when asked to
+            Ok(Message::End | Message::Refreshed(_)) | Err(_) => {
+                self.exhausted = true;
+                None
+            }
+        }
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.parameter_sets[0].as_deref()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.parameter_sets[1].as_deref()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.parameter_sets[2].as_deref()
+    }
+
+    fn refresh(&mut self) -> Result<usize, ParseError> {
+        if !self.exhausted || self.refresh_requests.send(()).is_err() {
+            return Ok(0);
+        }
+
+        match self.messages.recv() {
+            Ok(Message::Refreshed(new_frames)) => {
+                self.exhausted = false;
+                new_frames
+            }
+            _ => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::error::ConvertError;
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Writes the luma of every video frame of an uncompressed .vraw recording as a grayscale
+/// PGM image named `{out_prefix}00000.pgm`, `{out_prefix}00001.pgm` and so on, as a preview
+/// of recordings that cannot be converted to an mp4.
+///
+/// The images are as large as the frames, whose rows are expected without padding:
+///
+/// - `Mono8` and `Raw` frames are written as they are, 8 bits per pixel. A Bayer mosaic
+///   shows its color filter pattern.
+/// - `Mono16` and `Raw16` frames keep their 16 bits per pixel.
+/// - `Nv12` and `Yuv` (planar 4:2:0) frames start with their luma plane.
+/// - `Yuyv` and `Uyvy` frames interleave the luma with the chroma.
+/// - `Rgb` and `Bgr` frames are converted with the BT.601 luma weights.
+///
+/// input: path to .vraw file
+///
+/// out_prefix: path prefix of the generated .pgm files, e.g. `preview/frame_`
+///
+/// Returns the number of images written.
+pub fn write_luma_preview(input: &str, out_prefix: &str) -> Result<usize, ConvertError> {
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    let codec = source.codec();
+    if codec.is_coded() || codec == VideoCaptureFormat::Stats {
+        return Err(ConvertError::UnsupportedFormat(codec));
+    }
+
+    let mut images = 0;
+    while let Some(frame) = source.next_frame() {
+        let Ok(frame) = frame else {
+            // A broken frame ends the recording, as for the conversion
+            break;
+        };
+        if frame.format != codec {
+            continue;
+        }
+
+        let (max_value, luma) = luma(&frame)?;
+
+        let path = format!("{}{:05}.pgm", out_prefix, images);
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(
+            writer,
+            "P5\n{} {}\n{}\n",
+            frame.width, frame.height, max_value
+        )?;
+        writer.write_all(&luma)?;
+        writer.flush()?;
+
+        images += 1;
+    }
+
+    Ok(images)
+}
+
+/// A rectangle of a frame in pixels, see [`export_image_sequence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    /// Left edge, in pixels from the left of the frame.
+    pub x: u32,
+
+    /// Top edge, in pixels from the top of the frame.
+    pub y: u32,
+
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Writes every `stride`th video frame of an uncompressed .vraw recording as a 24 bit BMP
+/// image into `out_dir`, e.g. to label the frames one by one. `Rgb` and `Bgr` frames keep
+/// their colors, the other formats are written as grayscale images of their luma (see
+/// [`write_luma_preview`]), 16 bit samples reduced to 8 bits. Stats frames are skipped.
+///
+/// The file name is `pattern` with `{index}` replaced by the position of the frame among the
+/// video frames, zero-padded to six digits, and `{timestamp}` by its timestamp in
+/// nanoseconds, e.g. `frame_{index}.bmp`. `out_dir` is created if it does not exist.
+///
+/// The images are uncompressed, so every exported frame takes three bytes per pixel on
+/// disk, and writing them is slower than converting the recording to an mp4. A stride
+/// exports a fraction of that, and `crop` limits every image to a region of interest of
+/// the frame. A crop that does not fit a frame fails with
+/// [`ConvertError::CropOutsideFrame`]. Coded recordings (H.264, H.265) fail with
+/// [`ConvertError::UnsupportedFormat`], as this crate has no decoder, or with
+/// [`ConvertError::InvalidOption`] if `crop` is given, as it needs re-encoding.
+///
+/// input: path to .vraw file
+///
+/// Returns the number of images written.
+pub fn export_image_sequence(
+    input: &str,
+    out_dir: &str,
+    pattern: &str,
+    stride: usize,
+    crop: Option<Crop>,
+) -> Result<usize, ConvertError> {
+    if stride == 0 {
+        return Err(ConvertError::InvalidOption("stride must be at least 1"));
+    }
+
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    let codec = source.codec();
+    if codec.is_coded() && crop.is_some() {
+        return Err(ConvertError::InvalidOption(
+            "cropping H.264 or H.265 frames needs re-encoding, which is not supported",
+        ));
+    }
+    if codec.is_coded() || codec == VideoCaptureFormat::Stats {
+        return Err(ConvertError::UnsupportedFormat(codec));
+    }
+    fs::create_dir_all(out_dir)?;
+
+    let mut index = 0;
+    let mut images = 0;
+    while let Some(frame) = source.next_frame() {
+        let Ok(frame) = frame else {
+            // A broken frame ends the recording, as for the conversion
+            break;
+        };
+        if frame.format != codec {
+            continue;
+        }
+
+        if index % stride == 0 {
+            let name = pattern
+                .replace("{index}", &format!("{index:06}"))
+                .replace("{timestamp}", &frame.timestamp.to_string());
+            write_bmp(&Path::new(out_dir).join(name), &frame, crop)?;
+            images += 1;
+        }
+        index += 1;
+    }
+
+    Ok(images)
+}
+
+/// Writes a frame, or the part of it within `crop`, as a top-down 24 bit BMP image.
+fn write_bmp(path: &Path, frame: &RawFrame, crop: Option<Crop>) -> Result<(), ConvertError> {
+    let pixels = frame.width as usize * frame.height as usize;
+    let bgr: Vec<u8> = match frame.format {
+        VideoCaptureFormat::Rgb | VideoCaptureFormat::Bgr => {
+            if frame.raw_data.len() < pixels * 3 {
+                return Err(ParseError::InvalidFrame(
+                    "Frame payload smaller than its width and height.",
+                )
+                .into());
+            }
+
+            let data = frame.raw_data[..pixels * 3].chunks_exact(3);
+            match frame.format {
+                VideoCaptureFormat::Rgb => data.flat_map(|rgb| [rgb[2], rgb[1], rgb[0]]).collect(),
+                _ => data.flatten().copied().collect(),
+            }
+        }
+        _ => {
+            let (max_value, luma) = luma(frame)?;
+            let luma: Vec<u8> = match max_value {
+                // The high byte of the big endian samples
+                u16::MAX => luma.into_iter().step_by(2).collect(),
+                _ => luma,
+            };
+            luma.into_iter().flat_map(|y| [y; 3]).collect()
+        }
+    };
+
+    let crop = crop.unwrap_or(Crop {
+        x: 0,
+        y: 0,
+        width: frame.width,
+        height: frame.height,
+    });
+    if crop.x as u64 + crop.width as u64 > frame.width as u64
+        || crop.y as u64 + crop.height as u64 > frame.height as u64
+    {
+        return Err(ConvertError::CropOutsideFrame {
+            frame_width: frame.width,
+            frame_height: frame.height,
+        });
+    }
+
+    // Rows are padded to a multiple of 4 bytes
+    let row = crop.width as usize * 3;
+    let padded_row = row.div_ceil(4) * 4;
+    let image_size = (padded_row * crop.height as usize) as u32;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"BM")?;
+    writer.write_all(&(14 + 40 + image_size).to_le_bytes())?;
+    writer.write_all(&[0; 4])?;
+    writer.write_all(&(14u32 + 40).to_le_bytes())?;
+
+    writer.write_all(&40u32.to_le_bytes())?;
+    writer.write_all(&(crop.width as i32).to_le_bytes())?;
+    // A negative height stores the rows top to bottom
+    writer.write_all(&(-(crop.height as i32)).to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&24u16.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?; // BI_RGB
+    writer.write_all(&image_size.to_le_bytes())?;
+    writer.write_all(&[0; 16])?; // resolution and palette
+
+    for y in crop.y..crop.y + crop.height {
+        let start = (y as usize * frame.width as usize + crop.x as usize) * 3;
+        writer.write_all(&bgr[start..start + row])?;
+        writer.write_all(&[0; 3][..padded_row - row])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// The largest sample value and the PGM samples of the luma of a frame, 16 bit samples big
+/// endian.
+fn luma(frame: &RawFrame) -> Result<(u16, Vec<u8>), ParseError> {
+    let pixels = frame.width as usize * frame.height as usize;
+    let data = &frame.raw_data;
+
+    let bytes_per_pixel = match frame.format {
+        VideoCaptureFormat::Mono16 | VideoCaptureFormat::Raw16 => 2,
+        VideoCaptureFormat::Yuyv | VideoCaptureFormat::Uyvy => 2,
+        VideoCaptureFormat::Rgb | VideoCaptureFormat::Bgr => 3,
+        _ => 1,
+    };
+    if data.len() < pixels * bytes_per_pixel {
+        return Err(ParseError::InvalidFrame(
+            "Frame payload smaller than its width and height.",
+        ));
+    }
+
+    Ok(match frame.format {
+        VideoCaptureFormat::Mono16 | VideoCaptureFormat::Raw16 => (
+            u16::MAX,
+            data[..pixels * 2]
+                .chunks_exact(2)
+                .flat_map(|sample| [sample[1], sample[0]])
+                .collect(),
+        ),
+        VideoCaptureFormat::Yuyv => (255, data.iter().step_by(2).take(pixels).copied().collect()),
+        VideoCaptureFormat::Uyvy => (
+            255,
+            data.iter()
+                .skip(1)
+                .step_by(2)
+                .take(pixels)
+                .copied()
+                .collect(),
+        ),
+        VideoCaptureFormat::Rgb | VideoCaptureFormat::Bgr => {
+            let (r, b) = match frame.format {
+                VideoCaptureFormat::Rgb => (0, 2),
+                _ => (2, 0),
+            };
+            (
+                255,
+                data[..pixels * 3]
+                    .chunks_exact(3)
+                    .map(|rgb| {
+                        ((77 * rgb[r] as u32 + 150 * rgb[1] as u32 + 29 * rgb[b] as u32) >> 8) as u8
+                    })
+                    .collect(),
+            )
+        }
+        _ => (255, data[..pixels].to_vec()),
+    })
+}
@@ -1,10 +1,920 @@
-use crate::parser::{parse_raw_frame, read_index, VideoCaptureFormat};
-use chrono::Local;
-use mp4::{MediaConfig, Mp4Config, Mp4Sample, Mp4Writer, TrackConfig};
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use zerocopy::AsBytes;
+use crate::boxes::{
+    chpl, full_box, hvcc, ilst_meta, mp4_box, sample_group, timecode_trak, Moov, TimecodeRate,
+};
+use crate::error::ConvertError;
+use crate::fragmented::FragmentedMp4Writer;
+use crate::nal::{
+    h265_irap_type, has_valid_nal_headers, is_keyframe, parse_hevc_sps, sample_dependency,
+    sps_size, HevcSps, ParameterSets,
+};
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::session::Session;
+use crate::source::{
+    DropTrailingPartialGop, Follow, FrameSource, MaxTemporalId, Timelapse, VrawFrameSource,
+};
+use crate::stats::StatsFrame;
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, TimeZone, Utc};
+use mp4::{MediaConfig, Mp4Config, Mp4Reader, Mp4Sample, Mp4Writer, TrackConfig};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// The timescale of the written mp4 unless [`ConvertOptions::timescale`] says otherwise, in
+/// ticks per second.
+const DEFAULT_TIMESCALE: u32 = 1000; // This specifies milliseconds
+
+/// Seconds from the mp4 epoch (1904-01-01) to the UNIX epoch.
+const MP4_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+/// Timestamps from 2000-01-01 to 2100-01-01 in UNIX nanoseconds are taken as wall-clock
+/// time by [`ClockKind::Auto`]. A monotonic clock would need decades of uptime to get there.
+const WALL_CLOCK_RANGE: std::ops::Range<i64> = 946_684_800_000_000_000..4_102_444_800_000_000_000;
+
+/// The buffer size used for reading the recording and writing the mp4 unless
+/// [`ConvertOptions::read_buffer_size`] or [`ConvertOptions::write_buffer_size`] say
+/// otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 1 << 20;
+
+/// Frames held back to find their decode time with [`FrameOrder::Decode`]. Neither H.264
+/// nor H.265 buffer more than 16 pictures, so no frame is presented later than this many
+/// frames after it was decoded.
+const MAX_REORDER_DEPTH: usize = 16;
+
+/// The average frame interval in nanoseconds of a recording whose timestamps look like
+/// nanoseconds, from 100 µs (10000 fps) to a minute. Timestamps in another unit, e.g.
+/// milliseconds, end up far outside of it.
+const PLAUSIBLE_FRAME_INTERVAL: std::ops::RangeInclusive<f64> = 1e5..=6e10;
+
+/// See [`ConvertOptions::on_sample`].
+pub type SampleCallback<'a> = &'a mut dyn FnMut(&RawFrame, &mut Vec<u8>);
+
+/// See [`ConvertOptions::frame_filter`].
+pub type FrameFilter<'a> = &'a mut dyn FnMut(&RawFrame) -> bool;
+
+/// See [`ConvertOptions::chapter_marker`].
+pub type ChapterCallback<'a> = &'a mut dyn FnMut(&StatsFrame) -> Option<String>;
+
+/// See [`ConvertOptions::on_progress`].
+pub type ProgressCallback<'a> = &'a mut dyn FnMut(usize);
+
+/// Options controlling how a .vraw file is converted.
+///
+/// Serializes to the options of a [`ConvertOptions::report_path`] report, without the
+/// callbacks.
+#[derive(Default, Serialize)]
+pub struct ConvertOptions<'a> {
+    /// Write each sample with the distance from its own rebased timestamp to the next
+    /// sample's as `duration`, so that it lasts until the next frame. The last sample
+    /// repeats the previous duration. By default every sample lasts as long as the distance
+    /// to the previous frame instead, and the first one as long as the second.
+    pub exact_timestamps: bool,
+
+    /// Title written to the `©nam` tag of the output.
+    pub title: Option<String>,
+
+    /// Comment written to the `©cmt` tag of the output.
+    pub comment: Option<String>,
+
+    /// Language of the track as an ISO 639-2/T code of three lowercase letters (e.g.
+    /// `eng`), written to its `mdhd` box. None writes `und` (undetermined).
+    pub language: Option<String>,
+
+    /// Human-readable name of the track (e.g. `Main Camera`), written as the name of its
+    /// `hdlr` box and as a `name` box in its `udta`. None leaves the mp4 crate's
+    /// `VideoHandler`.
+    pub handler_name: Option<String>,
+
+    /// Arbitrary key/value metadata written to the output, e.g. the operator or mission of
+    /// the recording. Each entry is an iTunes freeform (`----`) item in `udta/meta/ilst`
+    /// with the `mean` `com.voysys.vraw_convert`, the key as its `name` and the value as
+    /// UTF-8 text, which ffprobe and exiftool list by key. [`crate::validate_mp4`] reads the
+    /// entries back into [`crate::Mp4Report::metadata`].
+    pub metadata: BTreeMap<String, String>,
+
+    /// Compute the [`crate::content_hash`] of the recording, which is the same for every
+    /// conversion of it whatever the options, for deduplicating archived recordings. It is
+    /// returned in [`ConvertSummary::content_hash`] and written to the output as the
+    /// `content_hash` entry of the metadata (see [`ConvertOptions::metadata`]). The frames
+    /// are hashed as they are converted, but fragmented output (whose metadata comes
+    /// first), [`ConvertOptions::sort_by_timestamp`] and files with several sessions need a
+    /// pass of their own over the recording. Needs the path of the recording, so it cannot
+    /// be used with [`convert_vraw_reader`] or [`convert_frame_source_to_mp4`].
+    #[cfg(feature = "content-hash")]
+    pub content_hash: bool,
+
+    /// Write a fragmented mp4. Superseded by a [`ConvertOptions::container`] of
+    /// [`Container::FragmentedMp4`], which it is the same as.
+    #[deprecated(note = "use `container: Container::FragmentedMp4` instead")]
+    pub fragmented: bool,
+
+    /// The file format to write, see [`Container`].
+    pub container: Container,
+
+    /// Minimum duration of a fragment in milliseconds when writing a fragmented mp4. A new
+    /// fragment starts at the first keyframe after that. If None every keyframe starts a
+    /// new fragment.
+    pub fragment_duration_ms: Option<u32>,
+
+    /// Level written to the `avcC` box, as `level_idc` (e.g. 51 for level 5.1), instead of
+    /// the level of the SPS.
+    ///
+    /// This is an interop escape hatch for decoders that gate on the advertised level. The
+    /// stream itself is not changed, so the file may claim a level the stream does not
+    /// conform to.
+    pub avc_level: Option<u8>,
+
+    /// Tier and level written to the `hvcC` box instead of the ones of the SPS. The same
+    /// caveats as for [`ConvertOptions::avc_level`] apply.
+    pub hevc_tier_level: Option<HevcTierLevel>,
+
+    /// Type of the H.265 sample entry, instead of `hev1` (`hvc1` for a
+    /// [`Container::Mov`]). This is an interop escape hatch for devices that only accept
+    /// one of the two. The parameter sets are left in the samples unless
+    /// [`ConvertOptions::strip_parameter_sets`], which `hvc1` does not allow, so forcing
+    /// `hvc1` for a stream carrying them in its frames produces a file that is not
+    /// conforming, with a warning.
+    pub hevc_sample_entry: Option<HevcEntryKind>,
+
+    /// Remove the VPS, SPS and PPS NAL units from the samples where they repeat the
+    /// parameter sets of the sample entry, as an `hvc1` sample entry requires. Parameter
+    /// sets that differ from those of the sample entry stay in the samples, and so does a
+    /// frame holding nothing but parameter sets. Not done for an H.265 stream whose SPS
+    /// cannot be parsed, whose sample entry then lacks the parameter sets.
+    pub strip_parameter_sets: bool,
+
+    /// Skip video frames whose format differs from the stream (see
+    /// [`ParseError::FormatMismatch`]) with a warning, instead of failing the conversion.
+    pub tolerate_format_mismatch: bool,
+
+    /// Drop the video frames after the last keyframe of the recording. When a recording is
+    /// cut off mid-GOP these frames reference a keyframe that never arrives, so dropping
+    /// them makes the output end on a fully decodable GOP. See
+    /// [`ConvertSummary::dropped_trailing_frames`].
+    pub drop_trailing_partial_gop: bool,
+
+    /// Called for every video sample right before it is written, with the frame and a copy
+    /// of its payload. Whatever the callback leaves in the buffer is written instead, e.g.
+    /// to strip or inject SEI NAL units or to collect statistics.
+    ///
+    /// Leaving the buffer empty drops the sample. Its duration is dropped with it, so the
+    /// following samples move up in time.
+    #[serde(skip)]
+    pub on_sample: Option<SampleCallback<'a>>,
+
+    /// Called for every frame read from the recording, including
+    /// [`VideoCaptureFormat::Stats`] frames, before anything else looks at it. Returning
+    /// false drops the frame, e.g. to leave out stats frames or frames of a sublayer, or
+    /// by any other predicate. A dropped video frame is left out as if it had not been
+    /// recorded: the frame before it lasts until the next frame that is kept, as with
+    /// [`ConvertOptions::max_temporal_id`].
+    ///
+    /// The frames up to the next keyframe reference a dropped keyframe and may not decode,
+    /// so every dropped keyframe is warned about. See [`ConvertSummary::filtered_frames`].
+    #[serde(skip)]
+    pub frame_filter: Option<FrameFilter<'a>>,
+
+    /// The clock the frame timestamps are taken from. With a wall-clock the timestamp of
+    /// the first video frame is written as the creation time of the output.
+    pub clock: ClockKind,
+
+    /// Flush the written samples to the output file every this many samples, so that a
+    /// long conversion makes visible progress on disk. Without it data is only written
+    /// when the write buffer fills up.
+    ///
+    /// The mp4 crate hands samples to the file in chunks of about a second, and a regular
+    /// mp4 is only playable once its `moov` box is written at the end. A fragmented mp4
+    /// is playable up to the last flushed fragment.
+    pub flush_every: Option<usize>,
+
+    /// When the recording is exhausted, wait this long and re-read its index (see
+    /// [`FrameSource::refresh`]) to pick up the frames of a recording that is still being
+    /// written. The conversion ends once the recording did not grow for a whole interval.
+    pub follow_interval: Option<Duration>,
+
+    /// Called for every [`VideoCaptureFormat::Stats`] frame to find the event boundaries
+    /// in a recording. The layout of the stats payload is not part of the recording
+    /// format, so it is up to the callback to read the marker from it.
+    ///
+    /// Returning a title (which may be empty) starts a chapter at the first keyframe at or
+    /// after the stats frame, written as a Nero `chpl` box that e.g. ffmpeg, VLC and mpv
+    /// show. If several markers lead up to the same keyframe the first title is used.
+    /// Without a callback, or if it never returns a title, no chapters are written. Not
+    /// supported for fragmented output, whose `moov` box is written before any sample.
+    #[serde(skip)]
+    pub chapter_marker: Option<ChapterCallback<'a>>,
+
+    /// Called after every frame read from the recording (of any format, including broken
+    /// ones) with the number of frames read so far, e.g. to drive a progress bar against
+    /// [`crate::VrawFrameSource::len`]. It runs on the converting thread, see
+    /// [`crate::convert_vraw_to_mp4_channel`] for a UI on another one.
+    #[serde(skip)]
+    pub on_progress: Option<ProgressCallback<'a>>,
+
+    /// Size in bytes of the buffer the recording is read through, [`DEFAULT_BUFFER_SIZE`]
+    /// if None. 0 reads from the file directly. Only used when converting a .vraw file,
+    /// a [`FrameSource`] reads the way it wants to.
+    pub read_buffer_size: Option<usize>,
+
+    /// Size in bytes of the buffer the mp4 is written through, [`DEFAULT_BUFFER_SIZE`] if
+    /// None. 0 writes to the file directly.
+    pub write_buffer_size: Option<usize>,
+
+    /// Resample the video to this frame rate without re-encoding: every sample of the
+    /// output lies on a uniform grid and shows the source frame closest to it in time,
+    /// duplicating frames where the source is slower and dropping frames where it is
+    /// faster. Takes precedence over [`ConvertOptions::exact_timestamps`].
+    ///
+    /// This changes the number of frames and is lossy in timing. Coded frames depend on
+    /// the ones before them, so a dropped or duplicated frame can also cause decoding
+    /// artifacts up to the next keyframe.
+    pub resample_to_fps: Option<f64>,
+
+    /// Write a JSON report of the conversion to this path once the mp4 is complete: the
+    /// input and output paths, these options and the [`ConvertSummary`] including its
+    /// warnings. No report is written if the conversion fails.
+    pub report_path: Option<String>,
+
+    /// The timezone of the time in the default output file name, used when no output
+    /// path is given.
+    pub filename_timezone: FilenameTz,
+
+    /// Drop the H.265 frames whose slices have a temporal id (`nuh_temporal_id_plus1 - 1`)
+    /// above this, keeping only the lower temporal sublayers of a stream coded with
+    /// temporal scalability. With two sublayers `Some(0)` halves the frame rate without
+    /// re-encoding, and the kept frames last until the next kept frame.
+    ///
+    /// A sublayer only references the ones at or below it and keyframes always have a
+    /// temporal id of 0, so the kept frames decode and every keyframe is kept. A stream
+    /// that does not follow those rules (e.g. without temporal scalability but with
+    /// nonzero temporal ids) may show artifacts until the next keyframe. Only supported
+    /// for H.265.
+    pub max_temporal_id: Option<u8>,
+
+    /// The recording sessions to convert if sessions were appended to the file, by
+    /// default the last one. See [`crate::read_all_indexes`].
+    pub session: Session,
+
+    /// Make a time-lapse keeping about every this many video frames without re-encoding,
+    /// played at the original frame rate. Only keyframes decode without the frames before
+    /// them, so the first keyframe at or after every multiple of the stride is kept and all
+    /// other frames are dropped. With a stride shorter than the keyframe interval every
+    /// keyframe is kept, so the speedup is the keyframe interval instead (see
+    /// [`ConvertSummary::timelapse_speedup`]). A stride of 1 keeps every frame.
+    ///
+    /// Each kept frame lasts the average frame interval of the source frames up to it, so
+    /// the time-lapse plays smoothly even when the keyframes are unevenly spaced.
+    pub timelapse_stride: Option<usize>,
+
+    /// Read the frames in timestamp order instead of index order, for recordings whose
+    /// index is not sorted by time (e.g. sorted by offset). The sort is stable and sorts
+    /// each session on its own (see [`ConvertOptions::session`]). Without it, frames out of
+    /// timestamp order produce a warning.
+    pub sort_by_timestamp: bool,
+
+    /// Write sample groups telling the random access points of an H.265 stream apart, for
+    /// players that seek wrongly on open-GOP streams with only the sync sample table: a
+    /// `sync` group with the NAL unit type of every IRAP (IDR, CRA or BLA) sample, and a
+    /// `rap ` group of the CRA and BLA samples, whose leading pictures may not decode
+    /// after seeking. Only supported for H.265 and non-fragmented output.
+    pub random_access_groups: bool,
+
+    /// Write an `sdtp` box with the dependency flags of every sample read from its NAL unit
+    /// types: whether it depends on other samples (keyframes do not), whether other samples
+    /// depend on it (non-reference frames, e.g. B-frames, are disposable) and whether it is
+    /// an H.265 leading picture. Trick-play players use it to pick the frames they can
+    /// skip. Off by default, as most players ignore it. Only supported for non-fragmented
+    /// output.
+    pub sample_dependencies: bool,
+
+    /// Whether the index lists the frames in presentation order (the default, for streams
+    /// without B-frames where decode and presentation order are the same) or in decode
+    /// order with presentation timestamps, see [`FrameOrder`].
+    pub frame_order: FrameOrder,
+
+    /// What to do with video frames whose timestamp is 0, which some recorders write when
+    /// the timestamp is unavailable, see [`ZeroTimestampPolicy`]. The first video frame is
+    /// not checked, as a recording whose clock starts with it begins at 0.
+    pub zero_timestamp_policy: ZeroTimestampPolicy,
+
+    /// The longest duration in milliseconds a sample is written with. A corrupt timestamp
+    /// can leave a gap of seconds between two frames that are otherwise fine, which shows
+    /// as the player freezing on one frame. Longer durations are shortened to this with a
+    /// warning, which moves the samples after it up in time. See
+    /// [`ConvertSummary::clamped_frames`].
+    pub max_frame_duration_ms: Option<u32>,
+
+    /// Fill every gap between frames longer than this many milliseconds with copies of the
+    /// frame before it, for consumers that need a steady cadence without missing slots. The
+    /// gap is split into evenly paced samples no longer than this, the first of them the
+    /// frame itself, so the timeline is kept rather than shortened as with
+    /// [`ConvertOptions::max_frame_duration_ms`] (which is applied first). See
+    /// [`ConvertSummary::filler_frames`].
+    ///
+    /// Coded frames depend on the ones before them, so a repeated inter frame can show
+    /// decoding artifacts up to the next keyframe. Repeated keyframes do not.
+    pub fill_gaps_ms: Option<u32>,
+
+    /// Fail with [`ConvertError::ImplausibleTimestamps`] instead of warning when the
+    /// timestamps of the index imply an average frame interval below 100 µs or above a
+    /// minute, which suggests they are not in nanoseconds. Only checked when converting a
+    /// .vraw file.
+    pub strict_timestamps: bool,
+
+    /// Refuse to write anything a strict validator could reject, failing with
+    /// [`ConvertError::NotConformant`] where the conversion otherwise makes do: missing
+    /// parameter sets (instead of placeholder ones for H.264), a picture size that cannot
+    /// be read from the SPS, video timestamps that do not increase, a track without a sync
+    /// sample, and any warning, e.g. about implausible timestamps, skipped frames or
+    /// clamped durations. A frame that cannot be read fails with [`ConvertError::Parse`]
+    /// unless it is the last one of the index. The picture size read from the SPS is
+    /// written to the track.
+    pub strict: bool,
+
+    /// Where the capture date written to the `©day` tag comes from, see [`CaptureDate`].
+    /// Media libraries like Apple Photos read the date from this tag rather than from the
+    /// creation time in the `mvhd` box.
+    pub capture_date: CaptureDate,
+
+    /// Write the chunk offsets as a 64 bit `co64` box even if they all fit the 32 bit `stco`
+    /// box. The mp4 crate switches to `co64` by itself once the media data passes 4 GB,
+    /// this is for tools that expect the same table layout in every file, e.g. to edit or
+    /// concatenate them later. Has no effect on fragmented output, which has no chunk
+    /// offsets.
+    pub force_co64: bool,
+
+    /// Write the samples of every GOP (a keyframe and the frames up to the next one) as a
+    /// single chunk, instead of a chunk per second as the mp4 crate does. With one entry
+    /// per GOP in the `stsc` and `stco` boxes the sample tables are smaller, and seeking to
+    /// a keyframe lands at the start of a chunk. Only supported for non-fragmented output,
+    /// which has no chunks.
+    pub chunk_per_gop: bool,
+
+    /// Move the `moov` box in front of the media data once the file is written
+    /// ("faststart"), so players can start while the file is downloading without it being
+    /// fragmented. The media data is moved back by the size of the box within the file,
+    /// and chunk offsets that would no longer fit 32 bits are written as `co64`. Only
+    /// supported for non-fragmented output, whose `moov` box comes first anyway.
+    pub faststart: bool,
+
+    /// Add a QuickTime timecode (`tmcd`) track, which editors use to place a clip on their
+    /// timeline. The timecode starts at the time of day of the first frame in UTC, to the
+    /// second, and counts the frames of [`ConvertOptions::resample_to_fps`] rounded to a
+    /// whole number per second, without drop-frame counting. It starts at 00:00:00:00 with
+    /// a warning if the timestamps are not a wall-clock time, see
+    /// [`ConvertOptions::clock`]. Needs resampling to a constant frame rate of at most
+    /// 255 fps, and is only supported for non-fragmented output without
+    /// [`ConvertOptions::faststart`].
+    pub timecode: bool,
+
+    /// Ticks per second of the written track, 1000 (milliseconds) if None. Every sample
+    /// start is rounded down to a tick and the durations are the distances between the
+    /// rounded starts, so the rounding errors do not add up, but at 1000 the frames of a
+    /// 23.976 fps video last 41 or 42 ms, which can visibly stutter. 90000 (the common
+    /// timescale of video) is fine enough for such frame rates.
+    pub timescale: Option<u32>,
+
+    /// Write a composition offset (`ctts`) for every sample so that its decode time plus the
+    /// offset is exactly its frame timestamp relative to the first frame, rounded down to
+    /// the timescale. The decode times are still the sums of the sample durations, which
+    /// drift from the timestamps by rounding and move with clamped durations, the offsets
+    /// make up for that. Once written the file is read back and every presentation time
+    /// compared to its timestamp, failing with [`ConvertError::TimestampMismatch`] if one
+    /// differs. Only supported for non-fragmented output, and not with
+    /// [`ConvertOptions::resample_to_fps`].
+    pub preserve_timestamps: bool,
+}
+
+impl fmt::Debug for ConvertOptions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("ConvertOptions");
+        debug
+            .field("exact_timestamps", &self.exact_timestamps)
+            .field("title", &self.title)
+            .field("comment", &self.comment)
+            .field("language", &self.language)
+            .field("handler_name", &self.handler_name)
+            .field("metadata", &self.metadata);
+        #[cfg(feature = "content-hash")]
+        debug.field("content_hash", &self.content_hash);
+        debug
+            .field("fragmented", &{
+                #[allow(deprecated)]
+                self.fragmented
+            })
+            .field("container", &self.container)
+            .field("fragment_duration_ms", &self.fragment_duration_ms)
+            .field("avc_level", &self.avc_level)
+            .field("hevc_tier_level", &self.hevc_tier_level)
+            .field("hevc_sample_entry", &self.hevc_sample_entry)
+            .field("strip_parameter_sets", &self.strip_parameter_sets)
+            .field("tolerate_format_mismatch", &self.tolerate_format_mismatch)
+            .field("drop_trailing_partial_gop", &self.drop_trailing_partial_gop)
+            .field("on_sample", &self.on_sample.as_ref().map(|_| "FnMut"))
+            .field("frame_filter", &self.frame_filter.as_ref().map(|_| "FnMut"))
+            .field("clock", &self.clock)
+            .field("flush_every", &self.flush_every)
+            .field("follow_interval", &self.follow_interval)
+            .field(
+                "chapter_marker",
+                &self.chapter_marker.as_ref().map(|_| "FnMut"),
+            )
+            .field("on_progress", &self.on_progress.as_ref().map(|_| "FnMut"))
+            .field("read_buffer_size", &self.read_buffer_size)
+            .field("write_buffer_size", &self.write_buffer_size)
+            .field("resample_to_fps", &self.resample_to_fps)
+            .field("report_path", &self.report_path)
+            .field("filename_timezone", &self.filename_timezone)
+            .field("max_temporal_id", &self.max_temporal_id)
+            .field("session", &self.session)
+            .field("timelapse_stride", &self.timelapse_stride)
+            .field("sort_by_timestamp", &self.sort_by_timestamp)
+            .field("random_access_groups", &self.random_access_groups)
+            .field("sample_dependencies", &self.sample_dependencies)
+            .field("frame_order", &self.frame_order)
+            .field("zero_timestamp_policy", &self.zero_timestamp_policy)
+            .field("max_frame_duration_ms", &self.max_frame_duration_ms)
+            .field("fill_gaps_ms", &self.fill_gaps_ms)
+            .field("strict_timestamps", &self.strict_timestamps)
+            .field("strict", &self.strict)
+            .field("capture_date", &self.capture_date)
+            .field("force_co64", &self.force_co64)
+            .field("chunk_per_gop", &self.chunk_per_gop)
+            .field("faststart", &self.faststart)
+            .field("timecode", &self.timecode)
+            .field("timescale", &self.timescale)
+            .field("preserve_timestamps", &self.preserve_timestamps)
+            .finish()
+    }
+}
+
+impl ConvertOptions<'_> {
+    /// Options for playback in browsers: an mp4 whose `moov` box is moved in front of the
+    /// samples ([`ConvertOptions::faststart`]) so playback can start while the file is
+    /// downloading, with an `hvc1` sample entry for H.265 as Safari requires and the
+    /// parameter sets that repeat those of the sample entry stripped from the samples
+    /// ([`ConvertOptions::strip_parameter_sets`]). Parameter sets that change during the
+    /// recording stay in the samples, which `hvc1` does not allow, with a warning. For DASH
+    /// or HLS set [`ConvertOptions::container`] to [`Container::FragmentedMp4`] instead.
+    ///
+    /// The other options are the defaults and can be changed with struct update syntax,
+    /// e.g. `ConvertOptions { title: Some(title), ..ConvertOptions::web() }`.
+    pub fn web() -> Self {
+        ConvertOptions {
+            hevc_sample_entry: Some(HevcEntryKind::Hvc1),
+            strip_parameter_sets: true,
+            faststart: true,
+            ..Default::default()
+        }
+    }
+
+    /// Options for archiving a recording with its timing intact: every sample is timed
+    /// from its own timestamp in a 90 kHz timescale, with composition offsets preserving
+    /// every timestamp, which are verified by reading the file back (see
+    /// [`ConvertOptions::preserve_timestamps`]). Implausible timestamps fail the
+    /// conversion instead of being converted as they are, and a JSON report is written to
+    /// `report_path`. The other options are the defaults, which neither relabel nor
+    /// rewrite frames.
+    ///
+    /// The container itself is not verified by these options: pass them to
+    /// [`crate::convert_vraw_to_mp4_verified`], which also checks the written sample
+    /// tables against the samples on a background thread.
+    pub fn archival(report_path: impl Into<String>) -> Self {
+        ConvertOptions {
+            exact_timestamps: true,
+            timescale: Some(90_000),
+            preserve_timestamps: true,
+            strict_timestamps: true,
+            report_path: Some(report_path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Options for video editors: a QuickTime movie at the constant frame rate `fps`,
+    /// which editors handle better than the variable frame rate of a recording. See
+    /// [`ConvertOptions::resample_to_fps`] for what resampling does to the frames.
+    ///
+    /// A [`ConvertOptions::timecode`] track starts the clip at its capture time, which is
+    /// also in the creation time and the `©day` tag (see [`ConvertOptions::capture_date`]).
+    pub fn editing(fps: f64) -> Self {
+        ConvertOptions {
+            container: Container::Mov,
+            resample_to_fps: Some(fps),
+            timecode: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_fragmented(&self) -> bool {
+        #[allow(deprecated)]
+        let fragmented = self.fragmented;
+        fragmented || self.container == Container::FragmentedMp4
+    }
+
+    fn timescale(&self) -> u32 {
+        self.timescale.unwrap_or(DEFAULT_TIMESCALE)
+    }
+}
+
+/// What a conversion did.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConvertSummary {
+    /// Path of the written mp4, e.g. the timestamped default name when no output was given.
+    #[serde(skip)]
+    pub output_path: PathBuf,
+
+    /// The format of the video frames.
+    pub codec: VideoCaptureFormat,
+
+    /// Number of samples written to the mp4.
+    pub samples_written: usize,
+
+    /// Duration of the written samples in milliseconds.
+    pub duration_ms: u64,
+
+    /// Video frames skipped by [`ConvertOptions::tolerate_format_mismatch`].
+    pub skipped_frames: usize,
+
+    /// Video frames without payload, such as the keepalive frames of some recorders, which
+    /// are skipped as players reject empty samples. Empty stats frames are passed on as
+    /// usual.
+    pub empty_frames: usize,
+
+    /// Samples dropped by [`ConvertOptions::on_sample`].
+    pub dropped_samples: usize,
+
+    /// Frames of any format dropped by [`ConvertOptions::frame_filter`].
+    pub filtered_frames: usize,
+
+    /// Video frames with the timestamp 0 that were interpolated or skipped, see
+    /// [`ConvertOptions::zero_timestamp_policy`].
+    pub zero_timestamp_frames: usize,
+
+    /// Samples before the first keyframe, which are dropped from fragmented output.
+    pub dropped_leading_frames: usize,
+
+    /// Video frames dropped by [`ConvertOptions::drop_trailing_partial_gop`].
+    pub dropped_trailing_frames: usize,
+
+    /// Video frames dropped by [`ConvertOptions::max_temporal_id`].
+    pub dropped_sublayer_frames: usize,
+
+    /// The number of source video frames per written frame of a
+    /// [`ConvertOptions::timelapse_stride`] time-lapse, None without one.
+    pub timelapse_speedup: Option<f64>,
+
+    /// Samples shortened to [`ConvertOptions::max_frame_duration_ms`].
+    pub clamped_frames: usize,
+
+    /// Copies of frames written to fill gaps, see [`ConvertOptions::fill_gaps_ms`].
+    pub filler_frames: usize,
+
+    /// The [`crate::content_hash`] of the recording with [`ConvertOptions::content_hash`],
+    /// None without.
+    #[cfg(feature = "content-hash")]
+    pub content_hash: Option<String>,
+
+    /// The warnings of the conversion, e.g. about skipped frames or clamped durations. The
+    /// library does not print them, the command line tool writes them to stderr.
+    pub warnings: Vec<String>,
+}
+
+/// The JSON report of [`ConvertOptions::report_path`].
+#[derive(Serialize)]
+struct Report<'a, 'o> {
+    input: Option<&'a str>,
+    output: &'a str,
+    options: &'a ConvertOptions<'o>,
+    #[serde(flatten)]
+    summary: &'a ConvertSummary,
+}
+
+/// An H.265 tier and level, see [`ConvertOptions::hevc_tier_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HevcTierLevel {
+    /// High tier instead of main tier.
+    pub high_tier: bool,
+
+    /// `general_level_idc`, 30 times the level (e.g. 153 for level 5.1).
+    pub level_idc: u8,
+}
+
+/// The type of an H.265 sample entry, see [`ConvertOptions::hevc_sample_entry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HevcEntryKind {
+    /// `hvc1`: every parameter set is in the `hvcC` box and none in the samples.
+    Hvc1,
+
+    /// `hev1`: parameter sets may also be in the samples.
+    Hev1,
+}
+
+/// A file format [`convert_vraw_to_mp4`] can write, see [`ConvertOptions::container`] and
+/// [`supported_containers`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum Container {
+    /// A regular mp4 file, with the sample tables in the `moov` box at the end.
+    #[default]
+    Mp4,
+
+    /// A fragmented mp4 file: an init segment (`ftyp` and a `moov` without samples)
+    /// followed by `moof`/`mdat` fragments, e.g. for DASH/CMAF delivery. Every fragment
+    /// starts on a keyframe, see [`ConvertOptions::fragment_duration_ms`], and video frames
+    /// before the first keyframe are dropped.
+    FragmentedMp4,
+
+    /// A QuickTime movie for editors that integrate `.mov` files better: an mp4 with the
+    /// `qt  ` brand, H.265 sample entries of type `hvc1` (which QuickTime requires instead of
+    /// `hev1`) and `.mov` as the extension of the default output name. The samples are the
+    /// same as in an mp4.
+    Mov,
+}
+
+/// The video formats this build can write to an mp4 file. Recordings of any other format
+/// fail to convert with [`ConvertError::UnsupportedFormat`].
+pub fn supported_output_codecs() -> &'static [VideoCaptureFormat] {
+    &[VideoCaptureFormat::H264, VideoCaptureFormat::H265]
+}
+
+/// The file formats this build can write.
+pub fn supported_containers() -> &'static [Container] {
+    &[Container::Mp4, Container::FragmentedMp4, Container::Mov]
+}
+
+/// The order of the frames in the index of a recording, see [`ConvertOptions::frame_order`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum FrameOrder {
+    /// The frames are listed in the order they are presented, which is also the order
+    /// they are decoded in. Every sample is presented at its decode time (without a
+    /// composition offset), timed from the frame timestamps. Frames whose timestamps go
+    /// back in time produce a warning.
+    #[default]
+    Presentation,
+
+    /// The frames are listed in the order they are decoded, as for streams with B-frames,
+    /// and the frame timestamps are presentation times. The decode times are the
+    /// presentation times in ascending order, and each sample gets the offset from its
+    /// decode time to its presentation time (which may be negative) as composition offset.
+    /// A frame may be presented at most 16 frames after it is decoded.
+    ///
+    /// The timing is exact, so [`ConvertOptions::exact_timestamps`] has no effect, and the
+    /// option cannot be combined with [`ConvertOptions::resample_to_fps`].
+    Decode,
+}
+
+/// How video frames with the timestamp 0 are handled, see
+/// [`ConvertOptions::zero_timestamp_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum ZeroTimestampPolicy {
+    /// Fail the conversion with [`ConvertError::ZeroTimestamp`].
+    #[default]
+    Error,
+
+    /// Give the frame a timestamp between the video frames before and after it, spacing
+    /// a run of such frames evenly. A run at the end of the recording continues with the
+    /// frame interval before it.
+    Interpolate,
+
+    /// Leave the frame out. The frame before it lasts until the next frame.
+    Skip,
+}
+
+/// The clock of the frame timestamps, see [`ConvertOptions::clock`].
+///
+/// Samples are timed relative to the first video frame either way, this only decides
+/// whether the timestamps also say when the recording was made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum ClockKind {
+    /// Wall-clock if the first video timestamp lies between the years 2000 and 2100 in
+    /// UNIX nanoseconds, monotonic otherwise.
+    #[default]
+    Auto,
+
+    /// A clock with an arbitrary start, e.g. the uptime of the recording device. The
+    /// creation time of the output is left unset.
+    Monotonic,
+
+    /// Nanoseconds since the UNIX epoch.
+    WallClock,
+}
+
+impl ClockKind {
+    /// The creation time of the output in seconds since 1904-01-01 (the mp4 epoch), if
+    /// `first_timestamp` is a wall-clock time.
+    fn creation_time(self, first_timestamp: i64) -> Option<u64> {
+        let wall_clock = match self {
+            ClockKind::Auto => WALL_CLOCK_RANGE.contains(&first_timestamp),
+            ClockKind::Monotonic => false,
+            ClockKind::WallClock => first_timestamp >= 0,
+        };
+
+        wall_clock.then(|| first_timestamp as u64 / 1_000_000_000 + MP4_EPOCH_OFFSET)
+    }
+}
+
+/// The source of the `©day` tag, see [`ConvertOptions::capture_date`]. The date is written
+/// in ISO 8601 as UTC, e.g. `2024-05-17T08:30:00Z`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub enum CaptureDate {
+    /// The timestamp of the first video frame if it is a wall-clock time according to
+    /// [`ConvertOptions::clock`], no tag otherwise.
+    #[default]
+    FirstFrame,
+
+    /// The modification time of the input file, no tag when converting a [`FrameSource`].
+    FileModified,
+
+    /// This text, written as it is.
+    Fixed(String),
+
+    /// No `©day` tag.
+    None,
+}
+
+/// When the recording was made, as written to the output, and its content hash.
+struct CaptureTime {
+    /// Seconds since 1904-01-01 for the `mvhd`, `tkhd` and `mdhd` boxes.
+    creation_time: Option<u64>,
+
+    /// The `©day` tag.
+    date: Option<String>,
+
+    /// The `content_hash` metadata entry, see [`ConvertOptions::content_hash`].
+    #[cfg(feature = "content-hash")]
+    content_hash: Option<String>,
+}
+
+/// The timezone of the time in the default output file name, see
+/// [`ConvertOptions::filename_timezone`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum FilenameTz {
+    /// The timezone of the machine running the conversion.
+    #[default]
+    Local,
+
+    /// UTC, which names the output the same on every machine.
+    Utc,
+
+    /// A fixed offset in seconds east of UTC, e.g. 7200 for UTC+02:00. Must be less than a
+    /// day either way.
+    Fixed(i32),
+}
+
+impl FilenameTz {
+    /// Formats the current time in this timezone.
+    fn format_now(self, format: &str) -> Result<String, ConvertError> {
+        Ok(match self {
+            FilenameTz::Local => Local::now().format(format).to_string(),
+            FilenameTz::Utc => Utc::now().format(format).to_string(),
+            FilenameTz::Fixed(offset) => {
+                let offset = FixedOffset::east_opt(offset).ok_or(ConvertError::InvalidOption(
+                    "filename_timezone offset must be less than a day",
+                ))?;
+                Utc::now().with_timezone(&offset).format(format).to_string()
+            }
+        })
+    }
+}
+
+/// Removes a partially written output file when dropped, which includes a panic unwinding
+/// through the conversion, unless it was disarmed once the file is complete.
+pub(crate) struct RemoveOnDrop<'a>(pub(crate) Option<&'a str>);
+
+impl RemoveOnDrop<'_> {
+    pub(crate) fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The output file, shared between the mp4 writer writing to it and the [`Output`]
+/// flushing it, which the mp4 crate offers no access for.
+#[derive(Clone)]
+struct SharedFile(Rc<RefCell<BufWriter<File>>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.borrow_mut().seek(pos)
+    }
+}
+
+/// The writer the samples go to, depending on [`ConvertOptions::container`].
+enum SampleWriter {
+    Mp4(Mp4Writer<SharedFile>),
+    Fragmented(FragmentedMp4Writer<SharedFile>),
+}
+
+/// The output of a conversion, flushed every [`ConvertOptions::flush_every`] samples.
+struct Output {
+    writer: SampleWriter,
+    file: SharedFile,
+    flush_every: Option<usize>,
+    samples: usize,
+
+    /// The sum of the written sample durations, i.e. the start time of the next sample.
+    time: u64,
+
+    /// Timestamps and titles of the chapter markers not yet followed by a keyframe.
+    markers: VecDeque<(i64, String)>,
+
+    /// Start times and titles of the chapters.
+    chapters: Vec<(u64, String)>,
+
+    /// What the sample tables of the `moov` box need to know about every sample.
+    sample_properties: SampleProperties,
+
+    /// Samples dropped by the writer and by [`ConvertOptions::on_sample`].
+    dropped_leading_frames: usize,
+    dropped_samples: usize,
+
+    /// Samples shortened to [`ConvertOptions::max_frame_duration_ms`].
+    clamped_frames: usize,
+
+    /// Samples written by [`ConvertOptions::fill_gaps_ms`].
+    filler_frames: usize,
+
+    /// Sync samples written, for [`ConvertOptions::strict`].
+    sync_samples: usize,
+
+    /// A sample carries a parameter set, which a forced `hvc1` sample entry does not allow.
+    in_band_parameter_sets: bool,
+
+    /// The parameter sets of the sample entry, removed from the samples repeating them
+    /// with [`ConvertOptions::strip_parameter_sets`].
+    stripped_parameter_sets: Option<ParameterSets>,
+
+    /// Timestamp of the first video frame, which the presentation times of
+    /// [`ConvertOptions::preserve_timestamps`] are relative to.
+    first_timestamp: Option<i64>,
+
+    /// The presentation time every sample should have in the file, in timescale units.
+    presentation_times: Vec<u64>,
+
+    warnings: Vec<String>,
+}
+
+/// Per sample data for the boxes added by [`complete_moov`], empty unless the options ask
+/// for them.
+#[derive(Default)]
+struct SampleProperties {
+    /// The IRAP NAL unit type of every sample, for [`ConvertOptions::random_access_groups`].
+    irap_types: Vec<Option<u8>>,
+
+    /// The `sdtp` flags of every sample, for [`ConvertOptions::sample_dependencies`].
+    dependencies: Vec<u8>,
+
+    /// The composition offset of every sample, for [`ConvertOptions::preserve_timestamps`].
+    composition_offsets: Vec<i32>,
+}
+
+impl Output {
+    fn write_sample(&mut self, sample: &Mp4Sample) -> mp4::Result<()> {
+        match &mut self.writer {
+            SampleWriter::Mp4(mp4_writer) => mp4_writer.write_sample(1, sample)?,
+            SampleWriter::Fragmented(fragmented_writer) => {
+                if !fragmented_writer.write_sample(sample)? {
+                    self.dropped_leading_frames += 1;
+                    return Ok(());
+                }
+            }
+        }
+
+        self.samples += 1;
+        self.sync_samples += sample.is_sync as usize;
+        self.time += sample.duration as u64;
+        if let Some(flush_every) = self.flush_every {
+            if self.samples.is_multiple_of(flush_every.max(1)) {
+                self.file.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
 
 /// Function that converts a .vraw file to an .mp4 file.
 /// NOTE: Currently only HEVC is supported!!!
@@ -12,130 +922,2058 @@ use zerocopy::AsBytes;
 /// input: path to .vraw file
 ///
 /// output: name of the gengerated .mp4 file. If None is specified the file will
-/// be named after the input and the time of generation.
-pub fn convert_vraw_to_mp4(input: &String, output: Option<String>) -> Result<(), String> {
-    let input_file = File::open(input).map_err(|_| "vraw_convert: failed to open file")?;
+/// be named after the input and the time of generation, the path it was written to is
+/// returned in [`ConvertSummary::output_path`].
+///
+/// If the conversion fails, or panics, the partially written output file is removed.
+pub fn convert_vraw_to_mp4(
+    input: &String,
+    output: Option<String>,
+) -> Result<ConvertSummary, ConvertError> {
+    convert_vraw_to_mp4_with_options(input, output, &mut ConvertOptions::default())
+}
+
+/// Same as [`convert_vraw_to_mp4`], but with explicit [`ConvertOptions`].
+pub fn convert_vraw_to_mp4_with_options(
+    input: &String,
+    output: Option<String>,
+    options: &mut ConvertOptions,
+) -> Result<ConvertSummary, ConvertError> {
+    let input_file = File::open(input)?;
+
+    let output = match output {
+        Some(output) => output,
+        None => default_output_path(input, options.filename_timezone, options.container)?,
+    };
+
+    write_vraw_file(input_file, input, &output, options)?.finish(Some(input), options)
+}
+
+/// Same as [`convert_vraw_to_mp4_with_options`], but the written file is verified on a
+/// new thread, so the verification overlaps with whatever the caller does next, e.g.
+/// converting the next file of a batch. The function returns once the file is written,
+/// joining the thread returns the summary once the file is verified.
+///
+/// The verification reads the file back and checks that its sample tables agree and hold
+/// every sample written, at least one of them a sync sample, as well as the presentation
+/// times with [`ConvertOptions::preserve_timestamps`]. A file that fails it is removed and
+/// the thread returns [`ConvertError::VerificationFailed`]. The report of
+/// [`ConvertOptions::report_path`] is only written once the file is verified.
+pub fn convert_vraw_to_mp4_verified(
+    input: &String,
+    output: Option<String>,
+    options: &mut ConvertOptions,
+) -> Result<JoinHandle<Result<ConvertSummary, ConvertError>>, ConvertError> {
+    let input_file = File::open(input)?;
+
+    let output = match output {
+        Some(output) => output,
+        None => default_output_path(input, options.filename_timezone, options.container)?,
+    };
+
+    let written = write_vraw_file(input_file, input, &output, options)?;
+    // The options cannot be sent to another thread, so the report is serialized here
+    let report = match &options.report_path {
+        Some(report_path) => Some((report_path.clone(), written.report(Some(input), options)?)),
+        None => None,
+    };
+
+    Ok(thread::spawn(move || {
+        written.verify(true)?;
+        if let Some((report_path, report)) = report {
+            fs::write(report_path, report)?;
+        }
+
+        Ok(written.summary)
+    }))
+}
+
+/// Writes the mp4 file of [`convert_vraw_to_mp4_with_options`].
+fn write_vraw_file(
+    mut input_file: File,
+    input: &str,
+    output: &str,
+    options: &mut ConvertOptions,
+) -> Result<WrittenMp4, ConvertError> {
+    if let Some(compression) = compression(&mut input_file)? {
+        return Err(ConvertError::CompressedInput(compression));
+    }
+
+    let read_buffer_size = options.read_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let reader = BufReader::with_capacity(read_buffer_size, input_file);
+
+    // Files can be read on another thread, unlike any reader given to convert_vraw_reader
+    #[cfg(feature = "parallel")]
+    {
+        let mut warnings = Vec::new();
+        let source = open_source(reader, options, &mut warnings)?;
+        crate::prefetch::with_prefetch(source, crate::prefetch::PREFETCH_FRAMES, |source| {
+            write_mp4(source, Some(input), output, options, None, warnings)
+        })
+    }
+    #[cfg(not(feature = "parallel"))]
+    write_reader(reader, Some(input), output, options)
+}
+
+/// Same as [`convert_vraw_to_mp4_with_options`], reading the recording from `reader`
+/// instead of a file, e.g. a recording decompressed into memory.
+///
+/// The index of a recording is at its end, so the reader has to seek and a compressed
+/// recording cannot be decompressed while it is read. Decompress it into a buffer (or
+/// a temporary file) and pass a [`std::io::Cursor`] over it.
+///
+/// output: path of the .mp4 file to create.
+pub fn convert_vraw_reader<R: Read + Seek>(
+    reader: R,
+    output: &str,
+    options: &mut ConvertOptions,
+) -> Result<ConvertSummary, ConvertError> {
+    write_reader(reader, None, output, options)?.finish(None, options)
+}
+
+fn write_reader<R: Read + Seek>(
+    reader: R,
+    input: Option<&str>,
+    output: &str,
+    options: &mut ConvertOptions,
+) -> Result<WrittenMp4, ConvertError> {
+    let mut warnings = Vec::new();
+    let mut source = open_source(reader, options, &mut warnings)?;
+    write_mp4(&mut source, input, output, options, None, warnings)
+}
+
+/// Reads the index of the recording for [`write_reader`].
+fn open_source<R: Read + Seek>(
+    reader: R,
+    options: &ConvertOptions,
+    warnings: &mut Vec<String>,
+) -> Result<VrawFrameSource<R>, ConvertError> {
+    let mut source = VrawFrameSource::with_session(reader, options.session)?;
+    if options.sort_by_timestamp {
+        source.sort_by_timestamp();
+    }
+
+    if source.is_empty() {
+        return Err(ConvertError::NoFrames);
+    }
+
+    check_timestamps(&source, options, warnings)?;
+
+    Ok(source)
+}
+
+/// Catches timestamps that are not in nanoseconds, which would make the output last
+/// wildly too long or too short, from the average frame interval over the whole index.
+fn check_timestamps<R: Read + Seek>(
+    source: &VrawFrameSource<R>,
+    options: &ConvertOptions,
+    warnings: &mut Vec<String>,
+) -> Result<(), ConvertError> {
+    let Some((first, last)) = source.timestamp_span() else {
+        return Ok(());
+    };
+    let frames = source.len();
+    if frames < 2 {
+        return Ok(());
+    }
+
+    let span = last.saturating_sub(first);
+    if PLAUSIBLE_FRAME_INTERVAL.contains(&(span as f64 / (frames - 1) as f64)) {
+        return Ok(());
+    }
+
+    let error = ConvertError::ImplausibleTimestamps {
+        span_ms: span / 1_000_000,
+        frames,
+    };
+    if options.strict_timestamps || options.strict {
+        return Err(error);
+    }
+    warnings.push(error.to_string());
+
+    Ok(())
+}
+
+/// The compression format of a file starting with the magic of a zstd frame or a gzip
+/// member, as the recordings are often archived compressed. Leaves the file at its start.
+fn compression(file: &mut File) -> io::Result<Option<&'static str>> {
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    Ok(match &magic[..read] {
+        [0x28, 0xb5, 0x2f, 0xfd] => Some("zstd"),
+        [0x1f, 0x8b, ..] => Some("gzip"),
+        _ => None,
+    })
+}
+
+/// The output path used when none is given: the file name of `input` without its
+/// extension and the current time, in the directory above the one of `input` (or next to
+/// it if there is none).
+///
+/// The time has a resolution of a second and repeats when the local time falls back for
+/// daylight saving time, so if a file of that name exists already a counter is appended
+/// (`_1`, `_2`, ...) to name the output after the first free one.
+fn default_output_path(
+    input: &str,
+    timezone: FilenameTz,
+    container: Container,
+) -> Result<String, ConvertError> {
+    let input_path = Path::new(input);
+    let stem = input_path
+        .file_stem()
+        .ok_or_else(|| ConvertError::InvalidPath(input.to_string()))?;
+
+    let mut output_stem = stem.to_os_string();
+    output_stem.push("_");
+    output_stem.push(timezone.format_now("%Y-%m-%dT%H_%M_%S")?);
+    let extension = match container {
+        Container::Mov => ".mov",
+        _ => ".mp4",
+    };
+
+    let directory = input_path
+        .ancestors()
+        .nth(2)
+        .or_else(|| input_path.parent())
+        .unwrap_or(Path::new(""));
+
+    let mut collisions = 0;
+    let output_path = loop {
+        let mut output_file_name = output_stem.clone();
+        if collisions > 0 {
+            output_file_name.push(format!("_{collisions}"));
+        }
+        output_file_name.push(extension);
+
+        let output_path = directory.join(output_file_name);
+        if !output_path.exists() {
+            break output_path;
+        }
+        collisions += 1;
+    };
+
+    output_path
+        .into_os_string()
+        .into_string()
+        .map_err(|_| ConvertError::InvalidPath(input.to_string()))
+}
+
+/// Writes the video frames of a [`FrameSource`] to an .mp4 file.
+///
+/// output: path of the .mp4 file to create. It is removed again if the conversion fails.
+pub fn convert_frame_source_to_mp4<S: FrameSource>(
+    source: &mut S,
+    output: &str,
+    options: &mut ConvertOptions,
+) -> Result<ConvertSummary, ConvertError> {
+    convert(source, None, output, options, None, Vec::new())
+}
+
+/// Appends the frames of a growing .vraw recording to an .mp4 file converted from the
+/// start of it, so a long recording can be converted in chunks.
+///
+/// The samples of the existing file are copied to a new file followed by the frames
+/// starting at `from_frame_index`, which then replaces the existing file. The appended
+/// samples continue the track where it ends, timed as if the recording had been converted
+/// as a whole, and the sample tables are written anew. The new file is written with
+/// `options`, which should be the ones the existing file was converted with: its title,
+/// comment and other metadata are not copied. The timescale defaults to the one of the
+/// existing file, resampling and decode order are not supported.
+///
+/// input: path to .vraw file
+///
+/// existing_mp4: path of a non-fragmented .mp4 file converted from the first frames of
+/// `input`
+///
+/// from_frame_index: position in the index of the first frame to append, i.e. the number
+/// of frames (including stats frames) the recording had when `existing_mp4` was written
+pub fn convert_vraw_append(
+    input: &str,
+    existing_mp4: &str,
+    from_frame_index: usize,
+    options: &mut ConvertOptions,
+) -> Result<ConvertSummary, ConvertError> {
+    let existing_file = File::open(existing_mp4)?;
+    let size = existing_file.metadata()?.len();
+    let mp4 = Mp4Reader::read_header(BufReader::new(existing_file), size)?;
+    if mp4.is_fragmented() || options.is_fragmented() {
+        return Err(ConvertError::InvalidOption(
+            "cannot append to a fragmented mp4",
+        ));
+    }
+    if options.resample_to_fps.is_some() || options.frame_order == FrameOrder::Decode {
+        return Err(ConvertError::InvalidOption(
+            "appending does not support resample_to_fps or decode order",
+        ));
+    }
+
+    let timescale = mp4.tracks().get(&1).map(|track| track.timescale());
+    match (options.timescale, timescale) {
+        (None, _) => options.timescale = timescale,
+        (Some(_), None) => {}
+        (Some(a), Some(b)) if a == b => {}
+        _ => {
+            return Err(ConvertError::InvalidOption(
+                "timescale differs from the one of the existing mp4",
+            ))
+        }
+    }
+
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    source.seek_frame(from_frame_index);
+
+    // The timing continues from the frames converted to the existing file
+    let mut first_timestamp = None;
+    let mut last_timestamp = None;
+    for index in 0..from_frame_index.min(source.len()) {
+        let frame = source.get(index)?;
+        if frame.format == source.codec() {
+            first_timestamp.get_or_insert(frame.timestamp);
+            last_timestamp = Some(frame.timestamp);
+        }
+    }
+    let existing = ExistingSamples {
+        mp4,
+        timestamps: first_timestamp.zip(last_timestamp),
+    };
+
+    // The existing file is only replaced once the new one is complete
+    let appended = format!("{existing_mp4}.append");
+    let mut summary = convert(
+        &mut source,
+        Some(input),
+        &appended,
+        options,
+        Some(existing),
+        Vec::new(),
+    )?;
+    fs::rename(&appended, existing_mp4)?;
+    summary.output_path = PathBuf::from(existing_mp4);
+
+    Ok(summary)
+}
+
+/// The samples of an mp4 file that [`convert_vraw_append`] continues.
+struct ExistingSamples {
+    mp4: Mp4Reader<BufReader<File>>,
+    /// The timestamps of the first and the last video frame converted to the file.
+    timestamps: Option<(i64, i64)>,
+}
+
+/// Converts the frames of `source` to `output_path`, after writing the samples of
+/// `existing` (see [`convert_vraw_append`]). `warnings` are the ones from opening the
+/// source.
+fn convert<S: FrameSource>(
+    source: &mut S,
+    input: Option<&str>,
+    output_path: &str,
+    options: &mut ConvertOptions,
+    existing: Option<ExistingSamples>,
+    warnings: Vec<String>,
+) -> Result<ConvertSummary, ConvertError> {
+    write_mp4(source, input, output_path, options, existing, warnings)?.finish(input, options)
+}
+
+/// An mp4 file written by [`write_mp4`], which [`WrittenMp4::finish`] verifies and
+/// reports on.
+struct WrittenMp4 {
+    output_path: String,
+    summary: ConvertSummary,
+    /// The presentation time of every sample with [`ConvertOptions::preserve_timestamps`].
+    presentation_times: Option<Vec<u64>>,
+}
+
+impl WrittenMp4 {
+    /// Verifies the presentation times and writes the report, returning the summary.
+    fn finish(
+        self,
+        input: Option<&str>,
+        options: &ConvertOptions,
+    ) -> Result<ConvertSummary, ConvertError> {
+        self.verify(false)?;
+        if let Some(report_path) = &options.report_path {
+            fs::write(report_path, self.report(input, options)?)?;
+        }
+
+        Ok(self.summary)
+    }
+
+    /// Reads the file back to check the presentation times, and with `container` the
+    /// sample tables (see [`convert_vraw_to_mp4_verified`]). The file is removed if a
+    /// check fails.
+    fn verify(&self, container: bool) -> Result<(), ConvertError> {
+        let mut partial_output = RemoveOnDrop(Some(&self.output_path));
+
+        if let Some(presentation_times) = &self.presentation_times {
+            verify_presentation_times(&self.output_path, presentation_times)?;
+        }
+        if container {
+            verify_sample_tables(&self.output_path, self.summary.samples_written)?;
+        }
+
+        partial_output.disarm();
+        Ok(())
+    }
+
+    /// The JSON report of [`ConvertOptions::report_path`].
+    fn report(&self, input: Option<&str>, options: &ConvertOptions) -> io::Result<Vec<u8>> {
+        let report = Report {
+            input,
+            output: &self.output_path,
+            options,
+            summary: &self.summary,
+        };
+
+        serde_json::to_vec_pretty(&report).map_err(io::Error::from)
+    }
+}
+
+/// Writes the mp4 file of [`convert`], leaving the verification and the report to
+/// [`WrittenMp4::finish`].
+fn write_mp4<S: FrameSource>(
+    source: &mut S,
+    input: Option<&str>,
+    output_path: &str,
+    options: &mut ConvertOptions,
+    existing: Option<ExistingSamples>,
+    mut warnings: Vec<String>,
+) -> Result<WrittenMp4, ConvertError> {
+    if let Some(fps) = options.resample_to_fps {
+        if !(fps.is_finite() && fps > 0.0) {
+            return Err(ConvertError::InvalidOption(
+                "resample_to_fps must be a positive frame rate",
+            ));
+        }
+    }
+
+    if options.frame_order == FrameOrder::Decode && options.resample_to_fps.is_some() {
+        return Err(ConvertError::InvalidOption(
+            "resample_to_fps cannot be combined with decode order",
+        ));
+    }
+
+    if options.timescale == Some(0) {
+        return Err(ConvertError::InvalidOption("timescale must be at least 1"));
+    }
+
+    if options.max_frame_duration_ms == Some(0) {
+        return Err(ConvertError::InvalidOption(
+            "max_frame_duration_ms must be at least 1",
+        ));
+    }
+    if options.fill_gaps_ms == Some(0) {
+        return Err(ConvertError::InvalidOption(
+            "fill_gaps_ms must be at least 1",
+        ));
+    }
+
+    #[allow(deprecated)]
+    let fragmented = options.fragmented;
+    if options.container == Container::Mov && fragmented {
+        return Err(ConvertError::InvalidOption("a mov cannot be fragmented"));
+    }
+
+    if options.timelapse_stride == Some(0) {
+        return Err(ConvertError::InvalidOption(
+            "timelapse_stride must be at least 1",
+        ));
+    }
+
+    if let Some(language) = &options.language {
+        if language.len() != 3 || !language.bytes().all(|byte| byte.is_ascii_lowercase()) {
+            return Err(ConvertError::InvalidOption(
+                "language must be an ISO 639-2 code of three lowercase letters",
+            ));
+        }
+    }
+    if options
+        .handler_name
+        .as_ref()
+        .is_some_and(|name| name.contains('\0'))
+    {
+        return Err(ConvertError::InvalidOption(
+            "handler_name cannot contain a NUL character",
+        ));
+    }
+
+    // The hash is taken from the frames as they are converted if they are all read in
+    // index order, and the tags are only written with the moov box once they are
+    #[cfg(feature = "content-hash")]
+    let (hash_frames, content_hash) = match (options.content_hash, input) {
+        (false, _) => (false, None),
+        (true, Some(input))
+            if !options.is_fragmented()
+                && !options.sort_by_timestamp
+                && crate::session::read_all_indexes(input)?.len() == 1 =>
+        {
+            (true, None)
+        }
+        (true, Some(input)) => (false, Some(crate::hash::content_hash(input)?)),
+        (true, None) => {
+            return Err(ConvertError::InvalidOption(
+                "content_hash needs the path of the recording",
+            ))
+        }
+    };
+
+    let source = Follow::new(source, options.follow_interval);
+    #[cfg(feature = "content-hash")]
+    let source = crate::hash::HashingSource::new(source, hash_frames);
+    let mut source = DropTrailingPartialGop::new(
+        Timelapse::new(
+            MaxTemporalId::new(source, options.max_temporal_id),
+            options.timelapse_stride,
+        ),
+        options.drop_trailing_partial_gop,
+    );
+
+    let codec = source.codec();
+    if options.max_temporal_id.is_some() && codec != VideoCaptureFormat::H265 {
+        return Err(ConvertError::InvalidOption(
+            "max_temporal_id is only supported for H.265",
+        ));
+    }
+    if options.random_access_groups
+        && (codec != VideoCaptureFormat::H265 || options.is_fragmented())
+    {
+        return Err(ConvertError::InvalidOption(
+            "random_access_groups is only supported for non-fragmented H.265",
+        ));
+    }
+    if options.preserve_timestamps && (options.is_fragmented() || options.resample_to_fps.is_some())
+    {
+        return Err(ConvertError::InvalidOption(
+            "preserve_timestamps is only supported for non-fragmented output without resampling",
+        ));
+    }
+    if options.chunk_per_gop && options.is_fragmented() {
+        return Err(ConvertError::InvalidOption(
+            "chunk_per_gop is only supported for non-fragmented output",
+        ));
+    }
+    if options.faststart && options.is_fragmented() {
+        return Err(ConvertError::InvalidOption(
+            "faststart is only supported for non-fragmented output",
+        ));
+    }
+    if options.timecode && (options.faststart || options.is_fragmented()) {
+        return Err(ConvertError::InvalidOption(
+            "timecode is only supported for non-fragmented output without faststart",
+        ));
+    }
+    if options.timecode
+        && !options
+            .resample_to_fps
+            .is_some_and(|fps| (1.0..=255.0).contains(&fps.round()))
+    {
+        return Err(ConvertError::InvalidOption(
+            "timecode needs resample_to_fps of at most 255 fps",
+        ));
+    }
+    if options.sample_dependencies && options.is_fragmented() {
+        return Err(ConvertError::InvalidOption(
+            "sample_dependencies is only supported for non-fragmented output",
+        ));
+    }
+
+    // The picture size of the stream, which every SPS in the stream has to keep
+    let stream_size = source.sps().and_then(|sps| sps_size(codec, sps));
+
+    if options.strict {
+        let missing = match codec {
+            VideoCaptureFormat::H265 => source.vps().is_none() || source.sps().is_none(),
+            _ => source.sps().is_none(),
+        };
+        if missing || source.pps().is_none() {
+            return Err(ConvertError::NotConformant(
+                "the parameter sets of the stream are missing".to_string(),
+            ));
+        }
+        let Some((width, height)) = stream_size.filter(|&(width, height)| width > 0 && height > 0)
+        else {
+            return Err(ConvertError::NotConformant(
+                "the picture size cannot be read from the SPS".to_string(),
+            ));
+        };
+        if u16::try_from(width).is_err() || u16::try_from(height).is_err() {
+            return Err(ConvertError::NotConformant(format!(
+                "the picture size {width}x{height} does not fit the track header"
+            )));
+        }
+    }
+    // Written in strict mode only, so the output of the default mode stays the same
+    let (width, height) = stream_size
+        .filter(|_| options.strict)
+        .and_then(|(width, height)| Some((u16::try_from(width).ok()?, u16::try_from(height).ok()?)))
+        .unwrap_or_default();
+
+    let media_config = match codec {
+        VideoCaptureFormat::H265 => MediaConfig::HevcConfig(mp4::HevcConfig { width, height }),
+        VideoCaptureFormat::H264 => {
+            // Some junk to fulfill H264 requirement for SPS/PPS unless the source knows the real
+            // ones. VLC corrects for anything we did wrong apparently
+            let sps = source.sps().map(|sps| sps.to_vec()).unwrap_or_else(|| {
+                vec![
+                    0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x0a, 0xf8, 0x41, 0xa2,
+                ]
+            });
+            let pps = source
+                .pps()
+                .map(|pps| pps.to_vec())
+                .unwrap_or_else(|| vec![0x00, 0x00, 0x00, 0x01, 0x68, 0xce, 0x38, 0x80]);
+
+            MediaConfig::AvcConfig(mp4::AvcConfig {
+                width,
+                height,
+                seq_param_set: sps,
+                pic_param_set: pps,
+            })
+        }
+        format => return Err(ConvertError::UnsupportedFormat(format)),
+    };
+
+    let mut config: Mp4Config = Mp4Config {
+        major_brand: str::parse("isom").unwrap(),
+        minor_version: 512,
+        compatible_brands: vec![
+            str::parse("isom").unwrap(),
+            str::parse("iso2").unwrap(),
+            str::parse("avc1").unwrap(),
+            str::parse("mp41").unwrap(),
+            str::parse("hev1").unwrap(),
+        ],
+        timescale: options.timescale(),
+    };
+
+    if options.is_fragmented() {
+        config.major_brand = str::parse("iso6").unwrap();
+        config.compatible_brands.splice(
+            0..0,
+            ["iso6", "cmfc", "dash"].map(|brand| str::parse(brand).unwrap()),
+        );
+    }
+    if options.container == Container::Mov {
+        config.major_brand = str::parse("qt  ").unwrap();
+        config.compatible_brands = vec![str::parse("qt  ").unwrap()];
+    }
+
+    // The creation time goes into the moov box, which the fragmented writer writes before
+    // any sample, so the frames up to the first video frame with a payload are read up front
+    let mut read_ahead = VecDeque::new();
+    while let Some(raw_frame) = source.next_frame() {
+        let is_skipped = matches!(
+            &raw_frame,
+            Ok(frame) if frame.format == VideoCaptureFormat::Stats || frame.raw_data.is_empty()
+        );
+        read_ahead.push_back(raw_frame);
+        if !is_skipped {
+            break;
+        }
+    }
+    let creation_time = match read_ahead.back() {
+        Some(Ok(frame)) if frame.format != VideoCaptureFormat::Stats => {
+            options.clock.creation_time(frame.timestamp)
+        }
+        _ => None,
+    };
+    let date = match &options.capture_date {
+        CaptureDate::FirstFrame => creation_time.and_then(|creation_time| {
+            let unix_time = creation_time.checked_sub(MP4_EPOCH_OFFSET)?;
+            Utc.timestamp_opt(unix_time as i64, 0).single()
+        }),
+        CaptureDate::FileModified => match input {
+            Some(input) => Some(DateTime::<Utc>::from(fs::metadata(input)?.modified()?)),
+            None => None,
+        },
+        CaptureDate::Fixed(_) | CaptureDate::None => None,
+    }
+    .map(|date| date.to_rfc3339_opts(SecondsFormat::Secs, true));
+    #[cfg_attr(not(feature = "content-hash"), allow(unused_mut))]
+    let mut capture_time = CaptureTime {
+        creation_time,
+        date: match &options.capture_date {
+            CaptureDate::Fixed(date) => Some(date.clone()),
+            _ => date,
+        },
+        #[cfg(feature = "content-hash")]
+        content_hash,
+    };
+
+    // Catch a mislabeled recording before writing a track its frames cannot be decoded with
+    if let Some(Ok(frame)) = read_ahead.back() {
+        if frame.format == codec && !has_valid_nal_headers(codec, &frame.raw_data) {
+            return Err(ConvertError::CodecValidationFailed(codec));
+        }
+    }
+
+    // Declared before the file, so the file is closed by the time it is removed
+    let mut partial_output = RemoveOnDrop(None);
+
+    // Opened for reading as well since the tags are added to the moov box after writing
+    let dst_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)?;
+    partial_output.0 = Some(output_path);
+    let write_buffer_size = options.write_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let file = SharedFile(Rc::new(RefCell::new(BufWriter::with_capacity(
+        write_buffer_size,
+        dst_file,
+    ))));
+
+    let mut track_config = TrackConfig::from(media_config);
+    track_config.timescale = options.timescale();
+    if let Some(language) = &options.language {
+        track_config.language = language.clone();
+    }
+
+    let mut skipped_frames = 0;
+    let mut filtered_frames = 0;
+    let mut zero_timestamp_frames = 0;
+    let mut empty_frames = 0;
+
+    let writer = if options.is_fragmented() {
+        let fragment_duration =
+            options.fragment_duration_ms.unwrap_or(0) as u64 * options.timescale() as u64 / 1000;
+
+        SampleWriter::Fragmented(FragmentedMp4Writer::new(
+            file.clone(),
+            &config,
+            &track_config,
+            fragment_duration,
+            |moov| {
+                complete_moov(
+                    moov,
+                    &source,
+                    options,
+                    &capture_time,
+                    &[],
+                    &SampleProperties::default(),
+                    &mut warnings,
+                )
+            },
+        )?)
+    } else {
+        let mut mp4_writer = Mp4Writer::write_start(file.clone(), &config)?;
+        mp4_writer.add_track(&track_config)?;
+
+        SampleWriter::Mp4(mp4_writer)
+    };
+    // The sample entry only has the parameter sets of an H.265 stream if its SPS parses
+    let stripped_parameter_sets = (options.strip_parameter_sets
+        && (codec != VideoCaptureFormat::H265
+            || options.hevc_tier_level.is_some()
+            || source.sps().and_then(parse_hevc_sps).is_some()))
+    .then(|| ParameterSets {
+        vps: source.vps().map(<[u8]>::to_vec),
+        sps: source.sps().map(<[u8]>::to_vec),
+        pps: source.pps().map(<[u8]>::to_vec),
+    });
+    let mut output = Output {
+        writer,
+        file,
+        flush_every: options.flush_every,
+        samples: 0,
+        time: 0,
+        markers: VecDeque::new(),
+        chapters: Vec::new(),
+        sample_properties: SampleProperties::default(),
+        dropped_leading_frames: 0,
+        dropped_samples: 0,
+        clamped_frames: 0,
+        filler_frames: 0,
+        sync_samples: 0,
+        in_band_parameter_sets: false,
+        stripped_parameter_sets,
+        first_timestamp: None,
+        presentation_times: Vec::new(),
+        warnings,
+    };
+    // In exact mode the last existing sample lasts until the first appended one, so it is
+    // held back until that is known
+    let mut last_existing_sample = None;
+    let existing_timestamps = existing.as_ref().and_then(|existing| existing.timestamps);
+    if let Some(mut existing) = existing {
+        for sample_id in 1..=existing.mp4.sample_count(1)? {
+            if let Some(sample) = last_existing_sample.take() {
+                write_existing_sample(&mut output, options, codec, &sample)?;
+            }
+            last_existing_sample = existing.mp4.read_sample(1, sample_id)?;
+        }
+        if !options.exact_timestamps || existing_timestamps.is_none() {
+            if let Some(sample) = last_existing_sample.take() {
+                write_existing_sample(&mut output, options, codec, &sample)?;
+            }
+        }
+    }
+
+    // Timestamps are taken relative to the first video frame
+    let mut first_timestamp = existing_timestamps.map(|(first, _)| first);
+    let mut last_timestamp = existing_timestamps.map_or(0, |(_, last)| last);
+
+    let mut video_frames = 0;
+
+    // The timestamp of the previous video frame and the interval before it, to interpolate
+    // zero timestamps
+    let mut previous_video_timestamp = existing_timestamps.map(|(_, last)| last);
+    let mut previous_video_interval = 0;
+
+    // The latest video timestamp so far, to warn once about frames out of order
+    let mut latest_timestamp = existing_timestamps.map_or(i64::MIN, |(_, last)| last);
+    let mut warned_out_of_order = false;
+
+    // In exact mode a frame is held back until the next one arrives, since its duration
+    // is the distance to the next frame's start time. Otherwise only the first frame is held
+    // back, as it has no previous frame to take its duration from.
+    let mut pending: Option<(u64, RawFrame)> = None;
+    let mut last_duration = 0;
+
+    // When resampling, the previous frame and its rebased timestamp as well as the next
+    // point on the grid
+    let mut previous: Option<(i64, RawFrame)> = None;
+    let mut grid_index = 0;
+
+    // In decode order, the frames held back until their decode time is known and the
+    // presentation times of the frames not written yet
+    let mut reordered = VecDeque::new();
+    let mut presentation_times = BinaryHeap::new();
+
+    let mut frames_read = 0;
+    while let Some(raw_frame) = read_ahead.pop_front().or_else(|| source.next_frame()) {
+        frames_read += 1;
+        if let Some(on_progress) = options.on_progress.as_mut() {
+            on_progress(frames_read);
+        }
+
+        match raw_frame {
+            Ok(mut frame) => {
+                if let Some(frame_filter) = options.frame_filter.as_mut() {
+                    if !frame_filter(&frame) {
+                        if frame.format == codec && is_keyframe(codec, &frame.raw_data) {
+                            output.warnings.push(format!(
+                                "frame filter dropped the keyframe at {}, the frames up to \
+                                     the next keyframe may not decode",
+                                frame.timestamp
+                            ));
+                        }
+                        filtered_frames += 1;
+                        continue;
+                    }
+                }
+
+                if frame.format == VideoCaptureFormat::Stats {
+                    if let Some(chapter_marker) = options.chapter_marker.as_mut() {
+                        let stats = StatsFrame {
+                            timestamp: frame.timestamp,
+                            raw_data: frame.raw_data,
+                        };
+                        if let Some(title) = chapter_marker(&stats) {
+                            output.markers.push_back((stats.timestamp, title));
+                        }
+                    }
+
+                    continue;
+                }
+
+                if frame.raw_data.is_empty() {
+                    empty_frames += 1;
+                    continue;
+                }
+
+                if frame.format != codec {
+                    let e = ParseError::FormatMismatch {
+                        stream_fmt: codec,
+                        frame_fmt: frame.format,
+                    };
+                    if !options.tolerate_format_mismatch {
+                        return Err(e.into());
+                    }
+
+                    output.warnings.push(format!("skipping frame: {e}"));
+                    skipped_frames += 1;
+                    continue;
+                }
+
+                if let (0, Some(previous)) = (frame.timestamp, previous_video_timestamp) {
+                    match options.zero_timestamp_policy {
+                        ZeroTimestampPolicy::Error => {
+                            return Err(ConvertError::ZeroTimestamp {
+                                at_frame: video_frames,
+                            })
+                        }
+                        ZeroTimestampPolicy::Skip => {
+                            zero_timestamp_frames += 1;
+                            continue;
+                        }
+                        ZeroTimestampPolicy::Interpolate => {
+                            frame.timestamp =
+                                match next_video_timestamp(&mut read_ahead, &mut source, codec) {
+                                    (Some(next), zero_frames) => {
+                                        previous + (next - previous) / (zero_frames as i64 + 2)
+                                    }
+                                    (None, _) => previous + previous_video_interval,
+                                };
+                            zero_timestamp_frames += 1;
+                        }
+                    }
+                }
+                if let Some(previous) = previous_video_timestamp {
+                    previous_video_interval = frame.timestamp - previous;
+                }
+                previous_video_timestamp = Some(frame.timestamp);
+
+                if let (Some(size), Some(sps)) =
+                    (stream_size, ParameterSets::find(codec, &frame.raw_data).sps)
+                {
+                    if sps_size(codec, &sps).is_some_and(|frame_size| frame_size != size) {
+                        return Err(ConvertError::ResolutionChanged {
+                            at_frame: video_frames,
+                        });
+                    }
+                }
+                video_frames += 1;
+
+                if options.strict
+                    && frame.timestamp <= latest_timestamp
+                    && options.frame_order == FrameOrder::Presentation
+                {
+                    return Err(ConvertError::NotConformant(format!(
+                        "the timestamp of video frame {video_frames} does not increase"
+                    )));
+                }
+                if frame.timestamp < latest_timestamp
+                    && !warned_out_of_order
+                    && options.frame_order == FrameOrder::Presentation
+                {
+                    output.warnings.push(
+                        "frames are not in timestamp order, see sort_by_timestamp".to_string(),
+                    );
+                    warned_out_of_order = true;
+                }
+                latest_timestamp = latest_timestamp.max(frame.timestamp);
+
+                let is_first_frame = first_timestamp.is_none();
+                let first_timestamp = *first_timestamp.get_or_insert(frame.timestamp);
+                output.first_timestamp = Some(first_timestamp);
+
+                if let Some(fps) = options.resample_to_fps {
+                    // Every grid point up to this frame lies between the previous frame
+                    // and this one, so the closer of the two is shown there
+                    let time = frame.timestamp.saturating_sub(first_timestamp);
+                    while grid_time(grid_index, fps) <= time as f64 {
+                        let grid_start = grid_time(grid_index, fps);
+                        let closest = match &previous {
+                            Some((previous_time, previous_frame))
+                                if grid_start - (*previous_time as f64)
+                                    < time as f64 - grid_start =>
+                            {
+                                previous_frame
+                            }
+                            _ => &frame,
+                        };
+
+                        let timescale = options.timescale();
+                        let start_time = ticks(grid_start, timescale).round() as u64;
+                        let end_time =
+                            ticks(grid_time(grid_index + 1, fps), timescale).round() as u64;
+                        let duration = clamp_duration(
+                            (end_time - start_time) as f64,
+                            timescale,
+                            &mut output.warnings,
+                        );
+                        write_frame(&mut output, options, closest, start_time, duration, 0)?;
+                        grid_index += 1;
+                    }
+
+                    previous = Some((time, frame));
+                    continue;
+                }
+
+                if options.frame_order == FrameOrder::Decode {
+                    presentation_times.push(Reverse(frame.timestamp));
+                    reordered.push_back(frame);
+                    if reordered.len() > MAX_REORDER_DEPTH {
+                        write_reordered(
+                            &mut output,
+                            options,
+                            &mut reordered,
+                            &mut presentation_times,
+                            first_timestamp,
+                            &mut last_duration,
+                        )?;
+                    }
 
-    let output = output.unwrap_or_else(|| {
-        let input_path = Path::new(&input);
+                    continue;
+                }
 
-        let output_file_name = input_path.file_name().unwrap().to_str().unwrap();
+                if options.exact_timestamps {
+                    let start_time =
+                        rebase_timestamp(frame.timestamp, first_timestamp, options.timescale());
 
-        let output_file_name = format!(
-            "{}_{}.mp4",
-            output_file_name.trim_end_matches(".vraw"),
-            Local::now().format("%Y-%m-%dT%H_%M_%S")
-        );
+                    if let Some(mut sample) = last_existing_sample.take() {
+                        let last_start =
+                            rebase_timestamp(last_timestamp, first_timestamp, options.timescale());
+                        last_duration = clamp_duration(
+                            start_time as f64 - last_start as f64,
+                            options.timescale(),
+                            &mut output.warnings,
+                        );
+                        sample.duration = last_duration;
+                        write_existing_sample(&mut output, options, codec, &sample)?;
+                    }
 
-        input_path
-            .ancestors()
-            .nth(2)
-            .unwrap()
-            .join(output_file_name)
-            .to_string_lossy()
-            .to_string()
-    });
+                    if let Some((pending_start, pending_frame)) =
+                        pending.replace((start_time, frame))
+                    {
+                        last_duration = clamp_duration(
+                            start_time as f64 - pending_start as f64,
+                            options.timescale(),
+                            &mut output.warnings,
+                        );
+                        write_frame(
+                            &mut output,
+                            options,
+                            &pending_frame,
+                            pending_start,
+                            last_duration,
+                            0,
+                        )?;
+                    }
 
-    let mut f: BufReader<File> = BufReader::new(input_file);
+                    continue;
+                }
 
-    let entries =
-        read_index(&mut f).map_err(|e| format!("vraw_convert: failed to read index: {e}"))?;
+                let start_time =
+                    rebase_timestamp(frame.timestamp, first_timestamp, options.timescale());
+                if is_first_frame {
+                    last_timestamp = frame.timestamp;
+                    pending = Some((start_time, frame));
 
-    if entries.is_empty() {
-        return Err("vraw_convert: index contains no frames".into());
-    }
+                    continue;
+                }
 
-    let config: Mp4Config = Mp4Config {
-        major_brand: str::parse("isom").unwrap(),
-        minor_version: 512,
-        compatible_brands: vec![
-            str::parse("isom").unwrap(),
-            str::parse("iso2").unwrap(),
-            str::parse("avc1").unwrap(),
-            str::parse("mp41").unwrap(),
-            str::parse("hev1").unwrap()
-        ],        
-        timescale: 1000, // This specifies milliseconds
-    };
-
-    let dst_file = File::create(output).map_err(|_| "vraw_convert: file creation failed")?;
-    let writer = BufWriter::new(dst_file);
-
-    let mut mp4_writer = Mp4Writer::write_start(writer, &config)
-        .map_err(|_| "vraw_convert: failed to start writing mp4")?;
-
-    // find first h265 frame
-    let mut last_timestamp = 0;
-    for entry in &entries {
-        let frame =
-            parse_raw_frame(&mut f, entry).map_err(|_| "vraw_convert: unable to read frame")?; // we discard the first frame for information about the video media
-        match frame.format {
-            VideoCaptureFormat::H265 => {
-                mp4_writer
-                    .add_track(&TrackConfig::from(MediaConfig::HevcConfig(
-                        mp4::HevcConfig::default(),
-                    )))
-                    .map_err(|_| "vraw_convert: failed to add mp4 track")?;
+                // Duration of the frame in timescale units, the distance between the rounded
+                // start times so that the rounding errors do not add up
+                let last_start =
+                    rebase_timestamp(last_timestamp, first_timestamp, options.timescale());
+                let duration = clamp_duration(
+                    start_time as f64 - last_start as f64,
+                    options.timescale(),
+                    &mut output.warnings,
+                );
 
-                last_timestamp = frame.timestamp;
+                if let Some((pending_start, pending_frame)) = pending.take() {
+                    // The first frame gets the delta to the second frame
+                    write_frame(
+                        &mut output,
+                        options,
+                        &pending_frame,
+                        pending_start,
+                        duration,
+                        0,
+                    )?;
+                }
 
-                break;
-            }
-            VideoCaptureFormat::H264 => {
-                // Some junk to fulfill H264 requirement for SPS/PPS. VLC corrects for anything we did wrong apparently
-                let newsps = vec![0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x0a, 0xf8, 0x41, 0xa2];
-                let newpps = vec![0x00, 0x00, 0x00, 0x01, 0x68, 0xce, 0x38, 0x80];                
-                mp4_writer
-                    .add_track(&TrackConfig::from(MediaConfig::AvcConfig(
-                        mp4::AvcConfig{width:0, height:0, seq_param_set:newsps, pic_param_set:newpps},
-                    )))
-                    .map_err(|_| "vraw_convert: failed to add mp4 track")?;
+                write_frame(&mut output, options, &frame, start_time, duration, 0)?;
 
                 last_timestamp = frame.timestamp;
-
+            }
+            Err(e) => {
+                // Here, we don't have a valid frame. The last one of a recording that was
+                // cut off is expected to fail, but anything after it is left out as well
+                let frames_left = read_ahead.drain(..).count()
+                    + std::iter::from_fn(|| source.next_frame()).count();
+                if frames_left > 0 {
+                    if options.strict {
+                        return Err(e.into());
+                    }
+                    output.warnings.push(format!(
+                        "could not read frame {frames_read} of the recording, leaving out \
+                         the {frames_left} frames after it: {e}"
+                    ));
+                }
                 break;
-            }            
-            VideoCaptureFormat::Stats => {
-                continue;
             }
-            _ => return Err("VideoCaptureFormat not supported".into()),
+        }
+    }
+
+    if let Some(sample) = last_existing_sample {
+        write_existing_sample(&mut output, options, codec, &sample)?;
+    }
+    if let Some((start_time, frame)) = pending {
+        write_frame(&mut output, options, &frame, start_time, last_duration, 0)?;
+    }
+    if let Some(first_timestamp) = first_timestamp {
+        while !reordered.is_empty() {
+            write_reordered(
+                &mut output,
+                options,
+                &mut reordered,
+                &mut presentation_times,
+                first_timestamp,
+                &mut last_duration,
+            )?;
+        }
+    }
+
+    match output.writer {
+        SampleWriter::Mp4(mut mp4_writer) => mp4_writer.write_end()?,
+        SampleWriter::Fragmented(fragmented_writer) => {
+            fragmented_writer.write_end()?;
+        }
+    }
+
+    // The writers are gone, so the file is not shared anymore
+    let mut dst_file = Rc::try_unwrap(output.file.0)
+        .expect("the mp4 writers are dropped")
+        .into_inner()
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+
+    #[cfg(feature = "content-hash")]
+    if let (true, Some(input)) = (hash_frames, input) {
+        // A conversion that stopped before the end of the recording falls back to a pass
+        // of its own
+        capture_time.content_hash = match source.inner().inner().inner().content_hash() {
+            Some(content_hash) => Some(content_hash),
+            None => Some(crate::hash::content_hash(input)?),
         };
     }
+    if !options.is_fragmented() {
+        rewrite_moov(
+            &mut dst_file,
+            &source,
+            options,
+            &capture_time,
+            &output.chapters,
+            &output.sample_properties,
+            &mut output.warnings,
+        )?;
+        if options.faststart {
+            move_moov_to_front(&mut dst_file, write_buffer_size)?;
+        }
+    }
+    if options.strict {
+        if output.sync_samples == 0 {
+            return Err(ConvertError::NotConformant(
+                "no sample is a sync sample".to_string(),
+            ));
+        }
+        if let Some(warning) = output.warnings.first() {
+            return Err(ConvertError::NotConformant(warning.clone()));
+        }
+    }
+    partial_output.disarm();
 
-    for entry in &entries {
-        let raw_frame = parse_raw_frame(&mut f, entry);
+    let summary = ConvertSummary {
+        output_path: PathBuf::from(output_path),
+        codec,
+        samples_written: output.samples,
+        duration_ms: output.time * 1000 / options.timescale() as u64,
+        skipped_frames,
+        empty_frames,
+        dropped_samples: output.dropped_samples,
+        filtered_frames,
+        zero_timestamp_frames,
+        dropped_leading_frames: output.dropped_leading_frames,
+        dropped_trailing_frames: source.dropped(),
+        dropped_sublayer_frames: source.inner().inner().dropped(),
+        timelapse_speedup: source.inner().speedup(),
+        clamped_frames: output.clamped_frames,
+        filler_frames: output.filler_frames,
+        #[cfg(feature = "content-hash")]
+        content_hash: capture_time.content_hash,
+        warnings: output.warnings,
+    };
 
-        match raw_frame {
-            Ok(frame) => {
-                if frame.format == VideoCaptureFormat::Stats {
-                    continue;
+    Ok(WrittenMp4 {
+        output_path: output_path.to_string(),
+        summary,
+        presentation_times: options
+            .preserve_timestamps
+            .then_some(output.presentation_times),
+    })
+}
+
+/// Completes the `moov` box of a finished mp4 file.
+fn rewrite_moov<S: FrameSource>(
+    file: &mut File,
+    source: &S,
+    options: &ConvertOptions,
+    capture_time: &CaptureTime,
+    chapters: &[(u64, String)],
+    sample_properties: &SampleProperties,
+    warnings: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let mut moov = Moov::read(file)?;
+    complete_moov(
+        &mut moov,
+        source,
+        options,
+        capture_time,
+        chapters,
+        sample_properties,
+        warnings,
+    );
+    if let Some(fps) = options.resample_to_fps.filter(|_| options.timecode) {
+        add_timecode_track(file, &mut moov, fps, capture_time.creation_time, warnings)?;
+    }
+    moov.write(file)?;
+
+    let end = file.stream_position()?;
+    file.set_len(end)
+}
+
+/// Adds what the mp4 crate does not write to the `moov` box: the user data tags, a complete
+/// `hvcC` box, the level overrides, the creation time and capture date, the handler name,
+/// the chapters (start times in timescale units and titles), the random access sample
+/// groups and the sample dependencies.
+fn complete_moov<S: FrameSource>(
+    moov: &mut Moov,
+    source: &S,
+    options: &ConvertOptions,
+    capture_time: &CaptureTime,
+    chapters: &[(u64, String)],
+    sample_properties: &SampleProperties,
+    warnings: &mut Vec<String>,
+) {
+    const SAMPLE_DESCRIPTION: [&[u8; 4]; 5] = [b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+
+    match source.codec() {
+        VideoCaptureFormat::H264 => {
+            let path = [&SAMPLE_DESCRIPTION[..], &[b"avc1", b"avcC"]].concat();
+
+            if let (Some(level), Some(avcc)) = (options.avc_level, moov.get(&path)) {
+                let mut avcc = avcc.to_vec();
+                avcc[8 + 3] = level; // AVCLevelIndication
+                moov.replace(&path, &avcc);
+            }
+        }
+        VideoCaptureFormat::H265 => {
+            // The mp4 crate only writes the configuration version
+            let sps = source.sps().and_then(parse_hevc_sps);
+            if sps.is_some() || options.hevc_tier_level.is_some() {
+                let mut sps = sps.unwrap_or(HevcSps {
+                    profile_tier_level: [0; 12],
+                    max_sub_layers_minus1: 0,
+                    temporal_id_nesting: true,
+                    chroma_format_idc: 1,
+                    bit_depth_luma_minus8: 0,
+                    bit_depth_chroma_minus8: 0,
+                    width: 0,
+                    height: 0,
+                });
+
+                if let Some(tier_level) = options.hevc_tier_level {
+                    let profile = &mut sps.profile_tier_level;
+                    profile[0] = profile[0] & !0x20 | (tier_level.high_tier as u8) << 5;
+                    profile[11] = tier_level.level_idc;
+                }
+
+                let parameter_sets: Vec<&[u8]> = [source.vps(), source.sps(), source.pps()]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+
+                let path = [&SAMPLE_DESCRIPTION[..], &[b"hev1", b"hvcC"]].concat();
+                moov.replace(&path, &hvcc(&sps, &parameter_sets));
+            }
+
+            let sample_entry = options
+                .hevc_sample_entry
+                .unwrap_or(match options.container {
+                    Container::Mov => HevcEntryKind::Hvc1,
+                    _ => HevcEntryKind::Hev1,
+                });
+            if sample_entry == HevcEntryKind::Hvc1 {
+                let path = [&SAMPLE_DESCRIPTION[..], &[b"hev1"]].concat();
+                if let Some(sample_entry) = moov.get(&path) {
+                    let mut sample_entry = sample_entry.to_vec();
+                    sample_entry[4..8].copy_from_slice(b"hvc1");
+                    moov.replace(&path, &sample_entry);
                 }
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(creation_time) = capture_time.creation_time {
+        for path in [
+            &[b"mvhd"][..],
+            &[b"trak", b"tkhd"],
+            &[b"trak", b"mdia", b"mdhd"],
+        ] {
+            set_creation_time(moov, path, creation_time, warnings);
+        }
+    }
+
+    if let Some(name) = &options.handler_name {
+        let mut hdlr = Vec::new();
+        hdlr.extend_from_slice(&0u32.to_be_bytes()); // pre_defined
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0; 12]); // reserved
+        hdlr.extend_from_slice(name.as_bytes());
+        hdlr.push(0);
+        moov.replace(
+            &[b"trak", b"mdia", b"hdlr"],
+            &full_box(b"hdlr", 0, 0, &hdlr),
+        );
+        moov.append(&[b"trak", b"udta"], &mp4_box(b"name", name.as_bytes()));
+    }
+
+    moov.append(&[b"udta"], &tags(options, capture_time));
+
+    if !chapters.is_empty() {
+        let chapters: Vec<(u64, &str)> = chapters
+            .iter()
+            .map(|(start, title)| {
+                (
+                    start * 10_000_000 / options.timescale() as u64,
+                    title.as_str(),
+                )
+            })
+            .collect();
+        moov.append(&[b"udta"], &chpl(&chapters));
+    }
+
+    if options.frame_order == FrameOrder::Decode {
+        // Only version 1 allows negative composition offsets
+        let path = [b"trak", b"mdia", b"minf", b"stbl", b"ctts"];
+        if let Some(ctts) = moov.get(&path) {
+            let mut ctts = ctts.to_vec();
+            ctts[8] = 1;
+            moov.replace(&path, &ctts);
+        }
+    }
+
+    if options.preserve_timestamps {
+        let path = [b"trak", b"mdia", b"minf", b"stbl", b"ctts"];
+        let ctts = ctts(&sample_properties.composition_offsets);
+        if !moov.replace(&path, &ctts) {
+            moov.append(&path[..4], &ctts);
+        }
+    }
+
+    if options.chunk_per_gop {
+        chunk_per_gop(moov);
+    }
+
+    if options.force_co64 {
+        let path = [b"trak", b"mdia", b"minf", b"stbl", b"stco"];
+        if let Some(stco) = moov.get(&path) {
+            let co64 = co64(stco);
+            moov.replace(&path, &co64);
+        }
+    }
+
+    if options.random_access_groups {
+        moov.append(
+            &[b"trak", b"mdia", b"minf", b"stbl"],
+            &random_access_groups(&sample_properties.irap_types),
+        );
+    }
+
+    if options.sample_dependencies {
+        moov.append(
+            &[b"trak", b"mdia", b"minf", b"stbl"],
+            &full_box(b"sdtp", 0, 0, &sample_properties.dependencies),
+        );
+    }
+}
+
+/// Adds the timecode track of [`ConvertOptions::timecode`] as the second track, referenced
+/// by the video track. Its sample goes into an `mdat` box of its own in front of the
+/// `moov` box.
+fn add_timecode_track(
+    file: &mut File,
+    moov: &mut Moov,
+    fps: f64,
+    creation_time: Option<u64>,
+    warnings: &mut Vec<String>,
+) -> std::io::Result<()> {
+    const TRACK_ID: u32 = 2;
+
+    let rate = TimecodeRate {
+        timescale: (fps * 1000.0).round() as u32,
+        frame_duration: 1000,
+        frames_per_second: fps.round() as u8,
+    };
+    // The mp4 epoch starts at midnight UTC
+    let seconds_of_day = match creation_time {
+        Some(creation_time) => (creation_time % 86_400) as u32,
+        None => {
+            warnings.push(
+                "the timestamps are not a wall-clock time, starting the timecode at 00:00:00:00"
+                    .to_string(),
+            );
+            0
+        }
+    };
+    let frame_count = seconds_of_day * rate.frames_per_second as u32;
+    let mdat_offset = moov.insert_before(file, &mp4_box(b"mdat", &frame_count.to_be_bytes()))?;
+
+    let Some(mvhd) = moov.get(&[b"mvhd"]) else {
+        return Ok(());
+    };
+    let mut mvhd = mvhd.to_vec();
+    let read_u32 = |at: usize| u32::from_be_bytes(mvhd[at..at + 4].try_into().unwrap());
+    // The timescale follows the creation and modification times, the next track id
+    // ends the box
+    let (movie_timescale, duration) = if mvhd[8] == 1 {
+        (
+            read_u32(28),
+            (read_u32(32) as u64) << 32 | read_u32(36) as u64,
+        )
+    } else {
+        (read_u32(20), read_u32(24) as u64)
+    };
+    let next_track_id = mvhd.len() - 4;
+    mvhd[next_track_id..].copy_from_slice(&(TRACK_ID + 1).to_be_bytes());
+    moov.replace(&[b"mvhd"], &mvhd);
+
+    moov.append(
+        &[b"trak", b"tref"],
+        &mp4_box(b"tmcd", &TRACK_ID.to_be_bytes()),
+    );
+    moov.append(
+        &[],
+        &timecode_trak(TRACK_ID, &rate, movie_timescale, duration, mdat_offset + 8),
+    );
+
+    Ok(())
+}
+
+/// Serializes composition offsets as a version 1 `ctts` box, which allows negative offsets.
+fn ctts(offsets: &[i32]) -> Vec<u8> {
+    let mut runs: Vec<(u32, i32)> = Vec::new();
+    for &offset in offsets {
+        match runs.last_mut() {
+            Some((count, run_offset)) if *run_offset == offset => *count += 1,
+            _ => runs.push((1, offset)),
+        }
+    }
+
+    let mut payload = (runs.len() as u32).to_be_bytes().to_vec();
+    for (count, offset) in runs {
+        payload.extend_from_slice(&count.to_be_bytes());
+        payload.extend_from_slice(&offset.to_be_bytes());
+    }
+
+    full_box(b"ctts", 1, 0, &payload)
+}
+
+/// Reads a written mp4 file back and checks that the decode time plus the composition
+/// offset of every sample is its expected presentation time, see
+/// [`ConvertOptions::preserve_timestamps`].
+fn verify_presentation_times(path: &str, expected: &[u64]) -> Result<(), ConvertError> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut mp4 = Mp4Reader::read_header(BufReader::new(file), size)?;
+
+    for (sample_id, &expected) in (1..).zip(expected) {
+        let Some(sample) = mp4.read_sample(1, sample_id)? else {
+            return Err(ConvertError::TimestampMismatch {
+                sample: sample_id,
+                expected,
+                found: None,
+            });
+        };
+
+        let found = sample.start_time as i64 + sample.rendering_offset as i64;
+        if found != expected as i64 {
+            return Err(ConvertError::TimestampMismatch {
+                sample: sample_id,
+                expected,
+                found: Some(found),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a written mp4 file back and checks that its sample tables agree and hold
+/// `samples` samples, at least one of them a sync sample, see
+/// [`convert_vraw_to_mp4_verified`].
+fn verify_sample_tables(path: &str, samples: usize) -> Result<(), ConvertError> {
+    let report = crate::validate_mp4(path)?;
+
+    if !report.valid_moov {
+        return Err(ConvertError::VerificationFailed(
+            "the sample tables do not agree".to_string(),
+        ));
+    }
+    let found = report
+        .tracks
+        .iter()
+        .map(|track| track.sample_count)
+        .sum::<u32>();
+    if found as usize != samples {
+        return Err(ConvertError::VerificationFailed(format!(
+            "the file holds {found} samples instead of {samples}"
+        )));
+    }
+    if !report.tracks.iter().any(|track| track.has_sync_samples) {
+        return Err(ConvertError::VerificationFailed(
+            "no sample is a sync sample".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Moves the `moov` box of a finished mp4 file from the end to right after the `ftyp` box,
+/// see [`ConvertOptions::faststart`]. Everything in between moves back by the size of the
+/// box, and so do the chunk offsets. The file has a single track.
+fn move_moov_to_front(file: &mut File, buffer_size: usize) -> std::io::Result<()> {
+    let stbl = [b"trak", b"mdia", b"minf", b"stbl"];
+    let stco_path = [&stbl[..], &[b"stco"]].concat();
+    let co64_path = [&stbl[..], &[b"co64"]].concat();
+    let read_u32 = |bytes: &[u8], at: usize| {
+        u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
 
-                let delta_t = (frame.timestamp - last_timestamp) as f64 * 1e-6; // duration in milliseconds of the frame
-                let video_sample = Mp4Sample {
-                    start_time: frame.timestamp as u64,
-                    duration: delta_t.round() as u32, // round to nearest millisecond
-                    rendering_offset: 0,
-                    is_sync: false,
-                    bytes: mp4::Bytes::copy_from_slice(frame.raw_data.as_bytes()),
+    let mut moov = Moov::read(file)?;
+    let moov_offset = file.seek(SeekFrom::End(0))? - moov.as_bytes().len() as u64;
+
+    let mut header = [0; 8];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    let front = match &header[4..] {
+        b"ftyp" => read_u32(&header, 0) as u64,
+        _ => 0,
+    };
+
+    // Offsets that pass 4 GB once moved need the 64 bit table, which makes moov larger
+    if let Some(stco) = moov.get(&stco_path) {
+        let entries = read_u32(stco, 12) as usize;
+        let last = (0..entries)
+            .map(|i| read_u32(stco, 16 + 4 * i) as u64)
+            .max()
+            .unwrap_or(0);
+        if last + moov.as_bytes().len() as u64 + 4 * entries as u64 > u32::MAX as u64 {
+            let co64 = co64(stco);
+            moov.replace(&stco_path, &co64);
+        }
+    }
+
+    let shift = moov.as_bytes().len() as u64;
+    if let Some(stco) = moov.get(&stco_path) {
+        let mut stco = stco.to_vec();
+        for i in 0..read_u32(&stco, 12) as usize {
+            let offset = read_u32(&stco, 16 + 4 * i) as u64 + shift;
+            stco[16 + 4 * i..20 + 4 * i].copy_from_slice(&(offset as u32).to_be_bytes());
+        }
+        moov.replace(&stco_path, &stco);
+    } else if let Some(co64) = moov.get(&co64_path) {
+        let mut co64 = co64.to_vec();
+        for i in 0..read_u32(&co64, 12) as usize {
+            let at = 16 + 8 * i;
+            let offset = (read_u32(&co64, at) as u64) << 32 | read_u32(&co64, at + 4) as u64;
+            co64[at..at + 8].copy_from_slice(&(offset + shift).to_be_bytes());
+        }
+        moov.replace(&co64_path, &co64);
+    }
+
+    // From the end, so nothing is overwritten before it is moved
+    let mut buffer = vec![0; buffer_size.max(1)];
+    let mut end = moov_offset;
+    while end > front {
+        let len = (end - front).min(buffer.len() as u64) as usize;
+        let start = end - len as u64;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut buffer[..len])?;
+        file.seek(SeekFrom::Start(start + shift))?;
+        file.write_all(&buffer[..len])?;
+        end = start;
+    }
+
+    file.seek(SeekFrom::Start(front))?;
+    file.write_all(moov.as_bytes())?;
+    file.set_len(moov_offset + shift)
+}
+
+/// Rewrites the `stsc` and `stco`/`co64` boxes so that every GOP is one chunk, see
+/// [`ConvertOptions::chunk_per_gop`]. The file has a single track, so the samples follow
+/// each other in the media data and only the chunk tables have to change.
+fn chunk_per_gop(moov: &mut Moov) {
+    let stbl = [b"trak", b"mdia", b"minf", b"stbl"];
+    let read_u32 = |bytes: &[u8], at: usize| {
+        u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
+
+    let Some(stsz) = moov.get(&[&stbl[..], &[b"stsz"]].concat()) else {
+        return;
+    };
+    let sample_count = read_u32(stsz, 16) as usize;
+    let sizes: Vec<u64> = match read_u32(stsz, 12) {
+        0 => (0..sample_count)
+            .map(|i| read_u32(stsz, 20 + 4 * i) as u64)
+            .collect(),
+        size => vec![size as u64; sample_count],
+    };
+    if sizes.is_empty() {
+        return;
+    }
+
+    // Without a sync sample table every sample is a keyframe
+    let mut chunk_starts: Vec<usize> = match moov.get(&[&stbl[..], &[b"stss"]].concat()) {
+        Some(stss) => (0..read_u32(stss, 12) as usize)
+            .map(|i| read_u32(stss, 16 + 4 * i) as usize - 1)
+            .collect(),
+        None => (0..sample_count).collect(),
+    };
+    if chunk_starts.first() != Some(&0) {
+        chunk_starts.insert(0, 0);
+    }
+
+    let stco_path = [&stbl[..], &[b"stco"]].concat();
+    let co64_path = [&stbl[..], &[b"co64"]].concat();
+    let (first_offset, was_co64) = match (moov.get(&stco_path), moov.get(&co64_path)) {
+        (Some(stco), _) => (read_u32(stco, 16) as u64, false),
+        (_, Some(co64)) => (
+            (read_u32(co64, 16) as u64) << 32 | read_u32(co64, 20) as u64,
+            true,
+        ),
+        _ => return,
+    };
+
+    let mut offsets = Vec::with_capacity(chunk_starts.len());
+    let mut stsc_entries: Vec<(u32, u32)> = Vec::new(); // first chunk, samples per chunk
+    let mut offset = first_offset;
+    for (chunk, &start) in chunk_starts.iter().enumerate() {
+        let end = chunk_starts.get(chunk + 1).copied().unwrap_or(sample_count);
+        offsets.push(offset);
+        offset += sizes[start..end].iter().sum::<u64>();
+
+        let samples = (end - start) as u32;
+        if stsc_entries.last().map(|&(_, count)| count) != Some(samples) {
+            stsc_entries.push((chunk as u32 + 1, samples));
+        }
+    }
+
+    let mut stsc = (stsc_entries.len() as u32).to_be_bytes().to_vec();
+    for (first_chunk, samples) in stsc_entries {
+        stsc.extend_from_slice(&first_chunk.to_be_bytes());
+        stsc.extend_from_slice(&samples.to_be_bytes());
+        stsc.extend_from_slice(&1u32.to_be_bytes()); // sample_description_index
+    }
+    moov.replace(
+        &[&stbl[..], &[b"stsc"]].concat(),
+        &full_box(b"stsc", 0, 0, &stsc),
+    );
+
+    let mut chunk_offsets = (offsets.len() as u32).to_be_bytes().to_vec();
+    if was_co64 {
+        for offset in offsets {
+            chunk_offsets.extend_from_slice(&offset.to_be_bytes());
+        }
+        moov.replace(&co64_path, &full_box(b"co64", 0, 0, &chunk_offsets));
+    } else {
+        for offset in offsets {
+            chunk_offsets.extend_from_slice(&(offset as u32).to_be_bytes());
+        }
+        moov.replace(&stco_path, &full_box(b"stco", 0, 0, &chunk_offsets));
+    }
+}
+
+/// Serializes the entries of an `stco` box (including its header) as a `co64` box.
+fn co64(stco: &[u8]) -> Vec<u8> {
+    let entries = &stco[16..];
+
+    let mut payload = stco[12..16].to_vec(); // entry_count
+    for offset in entries.chunks_exact(4) {
+        let offset = u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]);
+        payload.extend_from_slice(&(offset as u64).to_be_bytes());
+    }
+
+    full_box(b"co64", 0, 0, &payload)
+}
+
+/// Serializes the `sync` (ISO/IEC 14496-15) and `rap ` (ISO/IEC 14496-12) sample groups of
+/// [`ConvertOptions::random_access_groups`], leaving out a group without samples.
+fn random_access_groups(irap_types: &[Option<u8>]) -> Vec<u8> {
+    let mut boxes = Vec::new();
+
+    // One description per NAL unit type, in order of appearance
+    let mut nal_types: Vec<[u8; 1]> = Vec::new();
+    let sync: Vec<u32> = irap_types
+        .iter()
+        .map(|irap_type| match irap_type {
+            Some(nal_type) => {
+                let index = match nal_types.iter().position(|entry| entry[0] == *nal_type) {
+                    Some(index) => index,
+                    None => {
+                        nal_types.push([*nal_type]);
+                        nal_types.len() - 1
+                    }
                 };
+                index as u32 + 1
+            }
+            None => 0,
+        })
+        .collect();
+    if !nal_types.is_empty() {
+        let descriptions: Vec<&[u8]> = nal_types.iter().map(|entry| &entry[..]).collect();
+        boxes.extend_from_slice(&sample_group(b"sync", &descriptions, &sync));
+    }
 
-                mp4_writer
-                    .write_sample(1, &video_sample)
-                    .map_err(|_| "vraw_convert: failed to write sample")?;
+    // BLA and CRA pictures, with an unknown number of leading samples
+    let rap: Vec<u32> = irap_types
+        .iter()
+        .map(|irap_type| matches!(irap_type, Some(16..=18 | 21)) as u32)
+        .collect();
+    if rap.contains(&1) {
+        boxes.extend_from_slice(&sample_group(b"rap ", &[&[0]], &rap));
+    }
 
-                last_timestamp = frame.timestamp;
+    boxes
+}
+
+/// Sets the creation and modification time of an `mvhd`, `tkhd` or `mdhd` box.
+fn set_creation_time(
+    moov: &mut Moov,
+    path: &[&[u8; 4]],
+    creation_time: u64,
+    warnings: &mut Vec<String>,
+) {
+    let Some(header) = moov.get(path) else {
+        return;
+    };
+    let mut header = header.to_vec();
+
+    // Both times directly follow the version and flags, in 32 bits for version 0
+    if header[8] == 1 {
+        header[12..20].copy_from_slice(&creation_time.to_be_bytes());
+        header[20..28].copy_from_slice(&creation_time.to_be_bytes());
+    } else if let Ok(creation_time) = u32::try_from(creation_time) {
+        header[12..16].copy_from_slice(&creation_time.to_be_bytes());
+        header[16..20].copy_from_slice(&creation_time.to_be_bytes());
+    } else {
+        warnings.push(format!(
+            "creation time {creation_time} does not fit the mp4 header, leaving it unset"
+        ));
+        return;
+    }
+
+    moov.replace(path, &header);
+}
+
+/// Serializes the user data tags of the output as a `meta` box.
+fn tags(options: &ConvertOptions, capture_time: &CaptureTime) -> Vec<u8> {
+    let encoder = concat!("vraw_convert ", env!("CARGO_PKG_VERSION"));
+
+    let mut tags = vec![(*b"\xA9too", encoder)];
+    if let Some(title) = &options.title {
+        tags.push((*b"\xA9nam", title));
+    }
+    if let Some(comment) = &options.comment {
+        tags.push((*b"\xA9cmt", comment));
+    }
+    if let Some(date) = &capture_time.date {
+        tags.push((*b"\xA9day", date));
+    }
+
+    #[allow(unused_mut)]
+    let mut metadata: Vec<(&str, &str)> = options
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    #[cfg(feature = "content-hash")]
+    if let Some(content_hash) = &capture_time.content_hash {
+        metadata.push(("content_hash", content_hash));
+    }
+
+    ilst_meta(&tags, &metadata)
+}
+
+/// The timestamp of the next video frame whose timestamp is not 0, and the number of video
+/// frames with timestamp 0 before it, reading ahead from `source` as far as needed. None
+/// if the recording ends first.
+fn next_video_timestamp<S: FrameSource>(
+    read_ahead: &mut VecDeque<Result<RawFrame, ParseError>>,
+    source: &mut S,
+    codec: VideoCaptureFormat,
+) -> (Option<i64>, usize) {
+    let mut zero_frames = 0;
+
+    for position in 0.. {
+        if position == read_ahead.len() {
+            match source.next_frame() {
+                Some(frame) => read_ahead.push_back(frame),
+                None => break,
             }
-            Err(_) => {
-                // Here, we don't have a valid frame (we most likely reached the end of the recording)
+        }
+
+        match &read_ahead[position] {
+            Ok(frame) if frame.format == codec && !frame.raw_data.is_empty() => {
+                if frame.timestamp != 0 {
+                    return (Some(frame.timestamp), zero_frames);
+                }
+                zero_frames += 1;
+            }
+            Ok(_) => {}
+            // A broken frame ends the recording
+            Err(_) => break,
+        }
+    }
+
+    (None, zero_frames)
+}
+
+/// Converts a .vraw timestamp in nanoseconds to mp4 timescale units relative to
+/// `first_timestamp`.
+fn rebase_timestamp(timestamp: i64, first_timestamp: i64, timescale: u32) -> u64 {
+    let nanoseconds = timestamp.saturating_sub(first_timestamp).max(0) as u128;
+
+    (nanoseconds * timescale as u128 / 1_000_000_000) as u64
+}
+
+/// Converts nanoseconds to timescale units, without rounding.
+fn ticks(nanoseconds: f64, timescale: u32) -> f64 {
+    nanoseconds * timescale as f64 / 1e9
+}
+
+/// The largest sample duration written. The mp4 crate sums the durations of a chunk (which
+/// is flushed after a second) in a u32, so a full `u32::MAX` would overflow there.
+pub(crate) fn max_duration(timescale: u32) -> u32 {
+    u32::MAX - timescale
+}
+
+/// Start of the `index`th sample on the grid of `fps`, in nanoseconds.
+pub(crate) fn grid_time(index: u64, fps: f64) -> f64 {
+    index as f64 * 1e9 / fps
+}
+
+/// Rounds a frame duration in timescale units to the nearest tick. Durations that do not fit
+/// the 32 bit sample duration (e.g. because of out-of-order timestamps) are clamped to
+/// [`max_duration`] with a warning instead of wrapping around to some small value.
+fn clamp_duration(duration: f64, timescale: u32, warnings: &mut Vec<String>) -> u32 {
+    let max_duration = max_duration(timescale);
+    if duration.is_nan() || duration < 0.0 {
+        warnings.push(format!("negative frame duration {duration}, writing 0"));
+        0
+    } else if duration.round() > max_duration as f64 {
+        warnings.push(format!(
+            "frame duration {duration} too large, writing {max_duration}"
+        ));
+        max_duration
+    } else {
+        duration.round() as u32
+    }
+}
+
+/// Writes the first of the frames held back in decode order, at the lowest presentation time
+/// not written yet, lasting until the next one.
+fn write_reordered(
+    output: &mut Output,
+    options: &mut ConvertOptions,
+    reordered: &mut VecDeque<RawFrame>,
+    presentation_times: &mut BinaryHeap<Reverse<i64>>,
+    first_timestamp: i64,
+    last_duration: &mut u32,
+) -> Result<(), ConvertError> {
+    let (Some(frame), Some(Reverse(decode_time))) =
+        (reordered.pop_front(), presentation_times.pop())
+    else {
+        return Ok(());
+    };
+
+    // Durations between rounded decode times, so the rounding errors do not add up
+    let timescale = options.timescale();
+    let start_time = rebase_timestamp(decode_time, first_timestamp, timescale);
+    if let Some(Reverse(next_decode_time)) = presentation_times.peek() {
+        let next_start_time = rebase_timestamp(*next_decode_time, first_timestamp, timescale);
+        *last_duration = clamp_duration(
+            next_start_time as f64 - start_time as f64,
+            timescale,
+            &mut output.warnings,
+        );
+    }
+    let rendering_offset = ticks((frame.timestamp - decode_time) as f64, timescale).round() as i32;
+
+    write_frame(
+        output,
+        options,
+        &frame,
+        start_time,
+        *last_duration,
+        rendering_offset,
+    )
+}
+
+fn write_frame(
+    output: &mut Output,
+    options: &mut ConvertOptions,
+    frame: &RawFrame,
+    start_time: u64,
+    mut duration: u32,
+    mut rendering_offset: i32,
+) -> Result<(), ConvertError> {
+    let mut payload = frame.raw_data.clone();
+    if let Some(on_sample) = options.on_sample.as_mut() {
+        on_sample(frame, &mut payload);
+        if payload.is_empty() {
+            output.dropped_samples += 1;
+            return Ok(());
+        }
+    }
+
+    if let Some(parameter_sets) = &output.stripped_parameter_sets {
+        if let Some(stripped) = parameter_sets.strip_repeated(frame.format, &payload) {
+            payload = stripped;
+        }
+    }
+
+    if frame.format == VideoCaptureFormat::H265
+        && options.hevc_sample_entry == Some(HevcEntryKind::Hvc1)
+        && !output.in_band_parameter_sets
+    {
+        let parameter_sets = ParameterSets::find(frame.format, &payload);
+        if parameter_sets.vps.is_some()
+            || parameter_sets.sps.is_some()
+            || parameter_sets.pps.is_some()
+        {
+            output.warnings.push(
+                "hvc1 sample entry with parameter sets in the samples, the file is not conforming"
+                    .to_string(),
+            );
+            output.in_band_parameter_sets = true;
+        }
+    }
+
+    let max_duration = options
+        .max_frame_duration_ms
+        .map(|max_duration| (max_duration as u64 * options.timescale() as u64 / 1000) as u32);
+    if let Some(max_duration) = max_duration.filter(|&max_duration| duration > max_duration) {
+        output.warnings.push(format!(
+            "frame duration {duration} too large, writing {max_duration}"
+        ));
+        output.clamped_frames += 1;
+        duration = max_duration;
+    }
+
+    // A gap is split into samples of the same length, the first one taking the remainder
+    let fill_interval = options.fill_gaps_ms.map(|fill_gaps_ms| {
+        (fill_gaps_ms as u64 * options.timescale() as u64 / 1000).max(1) as u32
+    });
+    let samples = match fill_interval {
+        Some(fill_interval) if duration > fill_interval => duration.div_ceil(fill_interval),
+        _ => 1,
+    };
+    let filler_duration = duration / samples;
+    duration -= filler_duration * (samples - 1);
+
+    if options.preserve_timestamps {
+        let presentation_time = rebase_timestamp(
+            frame.timestamp,
+            output.first_timestamp.unwrap_or(frame.timestamp),
+            options.timescale(),
+        );
+        rendering_offset = (presentation_time as i64 - output.time as i64)
+            .clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        output.presentation_times.push(presentation_time);
+        output
+            .sample_properties
+            .composition_offsets
+            .push(rendering_offset);
+    }
+
+    let video_sample = Mp4Sample {
+        start_time,
+        duration,
+        rendering_offset,
+        is_sync: is_keyframe(frame.format, &payload),
+        bytes: mp4::Bytes::from(payload),
+    };
+    push_sample_properties(output, options, frame.format, &video_sample);
+
+    if video_sample.is_sync {
+        // The markers up to this frame start a chapter here
+        let mut title = None;
+        while let Some((timestamp, _)) = output.markers.front() {
+            if *timestamp > frame.timestamp {
                 break;
             }
+
+            let (_, marker_title) = output.markers.pop_front().unwrap();
+            title.get_or_insert(marker_title);
         }
+
+        if let Some(title) = title {
+            output.chapters.push((output.time, title));
+        }
+    }
+
+    output.write_sample(&video_sample)?;
+
+    for n in 1..samples {
+        if options.preserve_timestamps {
+            // Fillers are presented at their decode time
+            output.presentation_times.push(output.time);
+            output.sample_properties.composition_offsets.push(0);
+        }
+
+        let filler = Mp4Sample {
+            start_time: start_time + (duration + (n - 1) * filler_duration) as u64,
+            duration: filler_duration,
+            rendering_offset: 0,
+            is_sync: video_sample.is_sync,
+            bytes: video_sample.bytes.clone(),
+        };
+        push_sample_properties(output, options, frame.format, &filler);
+        output.write_sample(&filler)?;
+        output.filler_frames += 1;
     }
 
-    mp4_writer
-        .write_end()
-        .map_err(|_| "vraw_convert: failed to end mp4 writing")?;
+    Ok(())
+}
+
+/// Writes a sample copied from the file [`convert_vraw_append`] continues.
+fn write_existing_sample(
+    output: &mut Output,
+    options: &ConvertOptions,
+    codec: VideoCaptureFormat,
+    sample: &Mp4Sample,
+) -> Result<(), ConvertError> {
+    if options.preserve_timestamps {
+        let presentation_time = output.time as i64 + sample.rendering_offset as i64;
+        output
+            .presentation_times
+            .push(presentation_time.max(0) as u64);
+        output
+            .sample_properties
+            .composition_offsets
+            .push(sample.rendering_offset);
+    }
+    push_sample_properties(output, options, codec, sample);
+    output.write_sample(sample)?;
 
     Ok(())
 }
+
+/// Records what [`complete_moov`] needs to know about a sample about to be written.
+fn push_sample_properties(
+    output: &mut Output,
+    options: &ConvertOptions,
+    format: VideoCaptureFormat,
+    sample: &Mp4Sample,
+) {
+    if options.random_access_groups {
+        output
+            .sample_properties
+            .irap_types
+            .push(h265_irap_type(&sample.bytes));
+    }
+    if options.sample_dependencies {
+        output
+            .sample_properties
+            .dependencies
+            .push(sample_dependency(format, &sample.bytes));
+    }
+}
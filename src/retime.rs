@@ -0,0 +1,112 @@
+use crate::count::count_frames;
+use crate::error::ConvertError;
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::processing::{convert_frame_source_to_mp4, ConvertOptions, ConvertSummary};
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::{self, File};
+use std::io::BufReader;
+
+/// Converts a .vraw file to an .mp4 file timed by an external timestamp log instead of the
+/// timestamps embedded in the recording, e.g. one written by a hardware clock.
+///
+/// The log is a text file with one timestamp in nanoseconds per line, the first for the
+/// first video frame of the index and so on. Empty lines are ignored. It has to have an
+/// entry for every video frame (as counted by [`crate::count_frames`]), otherwise
+/// [`ConvertError::TimestampLogLength`] is returned before anything is written. Every
+/// sample starts at its logged time as with [`ConvertOptions::exact_timestamps`]. Stats
+/// frames keep their embedded timestamps.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .mp4 file to create
+///
+/// log_path: path to the timestamp log
+pub fn convert_vraw_with_timestamp_log(
+    input: &str,
+    output: &str,
+    log_path: &str,
+) -> Result<ConvertSummary, ConvertError> {
+    let timestamps = read_timestamp_log(&fs::read_to_string(log_path)?)?;
+
+    let frames = count_frames(input)?.video;
+    if timestamps.len() != frames {
+        return Err(ConvertError::TimestampLogLength {
+            entries: timestamps.len(),
+            frames,
+        });
+    }
+
+    let source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    if source.is_empty() {
+        return Err(ConvertError::NoFrames);
+    }
+
+    let mut retimed = Retimed {
+        inner: source,
+        timestamps,
+        position: 0,
+    };
+    convert_frame_source_to_mp4(
+        &mut retimed,
+        output,
+        &mut ConvertOptions {
+            exact_timestamps: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Parses the lines of a timestamp log, see [`convert_vraw_with_timestamp_log`].
+fn read_timestamp_log(log: &str) -> Result<Vec<i64>, ConvertError> {
+    log.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            line.trim()
+                .parse()
+                .map_err(|_| ConvertError::TimestampLog { line: n + 1 })
+        })
+        .collect()
+}
+
+/// A [`FrameSource`] giving the video frames the timestamps of a log, in order.
+struct Retimed<S> {
+    inner: S,
+    timestamps: Vec<i64>,
+    position: usize,
+}
+
+impl<S: FrameSource> FrameSource for Retimed<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        let mut frame = match self.inner.next_frame()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if frame.format == self.codec() {
+            // The log has as many entries as the index has video frames
+            if let Some(&timestamp) = self.timestamps.get(self.position) {
+                frame.timestamp = timestamp;
+            }
+            self.position += 1;
+        }
+
+        Some(Ok(frame))
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
@@ -0,0 +1,171 @@
+use crate::error::ConvertError;
+use crate::nal::is_keyframe;
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::processing::{convert_frame_source_to_mp4, ConvertOptions};
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::File;
+use std::io::BufReader;
+
+/// Decides where [`convert_vraw_by_scene`] starts a new scene.
+pub enum SceneDetector<'a> {
+    /// A keyframe at least `factor` times as large as the average of the keyframes before
+    /// it starts a new scene, as a scene cut makes the encoder spend more bits on it.
+    KeyframeSizeSpike { factor: f64 },
+
+    /// Called for every frame, including [`VideoCaptureFormat::Stats`] frames. Returning
+    /// true starts a new scene at the first keyframe at or after the frame.
+    Custom(&'a mut dyn FnMut(&RawFrame) -> bool),
+}
+
+/// A scene written by [`convert_vraw_by_scene`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scene {
+    /// Path of the .mp4 file of the scene.
+    pub path: String,
+
+    /// Timestamp of the first video frame of the scene, in nanoseconds.
+    pub start_timestamp: i64,
+
+    /// Timestamp of the last video frame of the scene, in nanoseconds.
+    pub end_timestamp: i64,
+
+    /// Number of video frames in the scene.
+    pub frames: usize,
+}
+
+/// Converts a .vraw file to one .mp4 file per scene, named `{out_prefix}000.mp4`,
+/// `{out_prefix}001.mp4` and so on. Every scene starts on a keyframe, so each file can be
+/// decoded on its own.
+///
+/// input: path to .vraw file
+///
+/// out_prefix: path prefix of the generated .mp4 files, e.g. `shots/scene_`
+pub fn convert_vraw_by_scene(
+    input: &str,
+    out_prefix: &str,
+    detector: SceneDetector,
+) -> Result<Vec<Scene>, ConvertError> {
+    let source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    if source.is_empty() {
+        return Err(ConvertError::NoFrames);
+    }
+
+    let mut splitter = SceneSplitter {
+        inner: source,
+        detector,
+        held: None,
+        at_boundary: false,
+        boundary_pending: false,
+        keyframe_bytes: 0,
+        keyframes: 0,
+        scene: None,
+        scene_frames: 0,
+    };
+
+    let mut scenes = Vec::new();
+    loop {
+        let path = format!("{}{:03}.mp4", out_prefix, scenes.len());
+        convert_frame_source_to_mp4(&mut splitter, &path, &mut ConvertOptions::default())?;
+
+        let (start_timestamp, end_timestamp) = splitter.scene.take().unwrap_or_default();
+        scenes.push(Scene {
+            path,
+            start_timestamp,
+            end_timestamp,
+            frames: splitter.scene_frames,
+        });
+
+        if splitter.held.is_none() {
+            return Ok(scenes);
+        }
+        splitter.at_boundary = false;
+        splitter.scene_frames = 0;
+    }
+}
+
+/// A [`FrameSource`] that ends at every scene boundary, holding back the keyframe that
+/// starts the next scene.
+struct SceneSplitter<'a, S> {
+    inner: S,
+    detector: SceneDetector<'a>,
+    held: Option<RawFrame>,
+    at_boundary: bool,
+    boundary_pending: bool,
+    keyframe_bytes: usize,
+    keyframes: usize,
+    scene: Option<(i64, i64)>, // first and last video timestamp
+    scene_frames: usize,
+}
+
+impl<S: FrameSource> SceneSplitter<'_, S> {
+    fn add_to_scene(&mut self, frame: &RawFrame) {
+        if frame.format == self.codec() {
+            let (first, _) = self.scene.unwrap_or((frame.timestamp, frame.timestamp));
+            self.scene = Some((first, frame.timestamp));
+            self.scene_frames += 1;
+        }
+    }
+}
+
+impl<S: FrameSource> FrameSource for SceneSplitter<'_, S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        if self.at_boundary {
+            return None;
+        }
+
+        if let Some(frame) = self.held.take() {
+            self.add_to_scene(&frame);
+            return Some(Ok(frame));
+        }
+
+        let frame = match self.inner.next_frame()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let keyframe = frame.format == self.codec() && is_keyframe(frame.format, &frame.raw_data);
+        let boundary = match &mut self.detector {
+            SceneDetector::KeyframeSizeSpike { factor } => {
+                keyframe
+                    && self.keyframes > 0
+                    && frame.raw_data.len() as f64
+                        >= *factor * self.keyframe_bytes as f64 / self.keyframes as f64
+            }
+            SceneDetector::Custom(detect) => detect(&frame),
+        };
+        if keyframe {
+            self.keyframe_bytes += frame.raw_data.len();
+            self.keyframes += 1;
+        }
+
+        self.boundary_pending |= boundary;
+        if keyframe && self.boundary_pending {
+            self.boundary_pending = false;
+
+            if self.scene_frames > 0 {
+                self.held = Some(frame);
+                self.at_boundary = true;
+                return None;
+            }
+        }
+
+        self.add_to_scene(&frame);
+        Some(Ok(frame))
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
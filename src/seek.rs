@@ -0,0 +1,73 @@
+use crate::error::ConvertError;
+use crate::nal::is_keyframe;
+use crate::parser::{parse_raw_frame, read_frame_meta, read_index, VideoCaptureFormat};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+/// A video frame of a seek index, see [`export_seek_index`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SeekEntry {
+    /// Timestamp of the frame in nanoseconds, as in [`crate::RawFrame::timestamp`].
+    pub timestamp_ns: i64,
+
+    /// Offset of the frame in the .vraw file, i.e. of its frame header (the offset in the
+    /// index of the recording), not of its payload.
+    pub byte_offset: u64,
+
+    /// The frame can be decoded without any previous frame, so playback can start here.
+    pub is_keyframe: bool,
+}
+
+/// Lists the video frames of a .vraw file in index order, so a player can map a position
+/// to the frame to read (or the keyframe to start decoding at) without scanning the file.
+/// [`VideoCaptureFormat::Stats`] frames and frames in another format than the first video
+/// frame are left out.
+///
+/// input: path to .vraw file
+pub fn export_seek_index(input: &str) -> Result<Vec<SeekEntry>, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    let mut codec = VideoCaptureFormat::Stats;
+    let mut entries = Vec::new();
+
+    for entry in read_index(&mut f)? {
+        // Only the payloads of video frames are read, to find the keyframes
+        let meta = read_frame_meta(&mut f, &entry)?;
+        if meta.format == VideoCaptureFormat::Stats {
+            continue;
+        }
+        if codec == VideoCaptureFormat::Stats {
+            codec = meta.format;
+        }
+
+        if meta.format == codec {
+            let frame = parse_raw_frame(&mut f, &entry)?;
+            entries.push(SeekEntry {
+                timestamp_ns: frame.timestamp,
+                byte_offset: entry.offset.get() as u64,
+                is_keyframe: is_keyframe(codec, &frame.raw_data),
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Writes the seek index of [`export_seek_index`] to a JSON file, as an array of
+/// `{"timestamp_ns", "byte_offset", "is_keyframe"}` objects.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .json file to create
+///
+/// Returns the number of entries written.
+pub fn write_seek_index_json(input: &str, output: &str) -> Result<usize, ConvertError> {
+    let entries = export_seek_index(input)?;
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    serde_json::to_writer(&mut writer, &entries).map_err(std::io::Error::from)?;
+    writer.flush()?;
+
+    Ok(entries.len())
+}
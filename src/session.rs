@@ -0,0 +1,92 @@
+use crate::error::ConvertError;
+use crate::parser::{self, ParseError, RecordingIndexEntry};
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufReader;
+
+/// The recording sessions of a .vraw file to read, see [`ConvertOptions::session`]
+/// and [`read_all_indexes`].
+///
+/// [`ConvertOptions::session`]: crate::ConvertOptions::session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum Session {
+    /// The last session, whose index ends the file. For a file with a single session this
+    /// is the whole recording.
+    #[default]
+    Last,
+
+    /// The session with this position in file order, starting at 0.
+    Nth(usize),
+
+    /// Every session in file order, as one continuous recording. The timestamps of each
+    /// session are shifted to continue one frame interval after the last video frame of
+    /// the session before it, since the sessions may use different clocks.
+    All,
+}
+
+impl Session {
+    /// The index entries of the selected sessions, and the positions in them where each
+    /// but the first session starts.
+    pub(crate) fn select(
+        self,
+        indexes: Vec<Vec<RecordingIndexEntry>>,
+    ) -> Result<(Vec<RecordingIndexEntry>, Vec<usize>), ParseError> {
+        let sessions = indexes.len();
+
+        match self {
+            Session::Last => Ok((indexes.into_iter().last().unwrap_or_default(), Vec::new())),
+            Session::Nth(session) => indexes
+                .into_iter()
+                .nth(session)
+                .map(|entries| (entries, Vec::new()))
+                .ok_or(ParseError::MissingSession { session, sessions }),
+            Session::All => {
+                let mut entries = Vec::new();
+                let mut session_starts = Vec::new();
+                for index in indexes {
+                    if !entries.is_empty() {
+                        session_starts.push(entries.len());
+                    }
+                    entries.extend(index);
+                }
+
+                Ok((entries, session_starts))
+            }
+        }
+    }
+}
+
+/// An entry of the index of a recording session, see [`read_all_indexes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Offset of the frame header in the .vraw file.
+    pub offset: u64,
+
+    /// Receive timestamp of the frame in nanoseconds.
+    pub receive_timestamp: i64,
+}
+
+/// Reads the index of every recording session of a .vraw file, in file order.
+///
+/// A recorder that appends to an existing file adds a second session with its own
+/// recording header and index. The other functions of this crate read the last session
+/// unless told otherwise through [`Session`]. A file with a single session returns a
+/// single index.
+///
+/// input: path to .vraw file
+pub fn read_all_indexes(input: &str) -> Result<Vec<Vec<IndexEntry>>, ConvertError> {
+    let mut f = BufReader::new(File::open(input)?);
+
+    Ok(parser::read_all_indexes(&mut f)?
+        .into_iter()
+        .map(|entries| {
+            entries
+                .iter()
+                .map(|entry| IndexEntry {
+                    offset: entry.offset.get() as u64,
+                    receive_timestamp: entry.receive_timestamp.get(),
+                })
+                .collect()
+        })
+        .collect())
+}
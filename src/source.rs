@@ -0,0 +1,596 @@
+use crate::nal::{h265_frame_temporal_id, is_keyframe, ParameterSets};
+use crate::parser::{
+    parse_raw_frame, read_all_indexes, read_index, ParseError, RawFrame, RecordingIndexEntry,
+};
+use crate::session::Session;
+use crate::VideoCaptureFormat;
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::thread;
+use std::time::Duration;
+
+/// A source of frames that can be written to a container.
+///
+/// The conversion functions only talk to this trait, so anything that can produce
+/// [`RawFrame`]s (the .vraw parser, other recording formats or an in-memory list of
+/// frames in a test) can be converted.
+pub trait FrameSource {
+    /// The format of the video frames produced by this source.
+    fn codec(&self) -> VideoCaptureFormat;
+
+    /// Returns the next frame, or `None` when the source is exhausted.
+    ///
+    /// Non-video frames such as [`VideoCaptureFormat::Stats`] are returned as well, it is
+    /// up to the consumer to skip them.
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>>;
+
+    /// The video parameter set (H.265 only), if it is known up front.
+    fn vps(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The sequence parameter set, if it is known up front.
+    fn sps(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// The picture parameter set, if it is known up front.
+    fn pps(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Looks for frames that were added after the source was exhausted, e.g. because the
+    /// recording is still being written. Returns the number of new frames, which are
+    /// returned by the following calls to [`FrameSource::next_frame`].
+    fn refresh(&mut self) -> Result<usize, ParseError> {
+        Ok(0)
+    }
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for &mut S {
+    fn codec(&self) -> VideoCaptureFormat {
+        (**self).codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        (**self).next_frame()
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        (**self).vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        (**self).sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        (**self).pps()
+    }
+
+    fn refresh(&mut self) -> Result<usize, ParseError> {
+        (**self).refresh()
+    }
+}
+
+/// A [`FrameSource`] reading the frames of a .vraw recording in index order.
+pub struct VrawFrameSource<R> {
+    reader: R,
+    session: Session,
+    entries: Vec<RecordingIndexEntry>,
+    position: usize,
+    codec: VideoCaptureFormat,
+    parameter_sets: ParameterSets,
+
+    // Where the sessions after the first one start in `entries` with `Session::All`, the
+    // shift of the timestamps of the current session and the timestamp and duration of the
+    // last video frame
+    session_starts: Vec<usize>,
+    timestamp_shift: i64,
+    last_video: Option<(i64, i64)>,
+
+    sorted: bool,
+}
+
+impl<R: Read + Seek> VrawFrameSource<R> {
+    /// Reads the index of the recording and scans for the first video frame to determine
+    /// the codec. If the recording contains no video frames the codec is
+    /// [`VideoCaptureFormat::Stats`].
+    ///
+    /// For H.264 and H.265 the scan continues until the SPS, PPS and (for H.265) VPS of the
+    /// stream are found, even if the recording starts with inter frames or sends the
+    /// parameter sets in separate frames. Missing parameter sets are looked for up to the
+    /// first keyframe after the SPS.
+    ///
+    /// Reads the last session of a file with appended sessions, see [`Self::with_session`].
+    pub fn new(reader: R) -> Result<Self, ParseError> {
+        Self::with_session(reader, Session::Last)
+    }
+
+    /// Like [`Self::new`], reading the given sessions of a file with appended sessions.
+    pub fn with_session(mut reader: R, session: Session) -> Result<Self, ParseError> {
+        let (entries, session_starts) = read_session(&mut reader, session)?;
+
+        let mut codec = VideoCaptureFormat::Stats;
+        let mut parameter_sets = ParameterSets::default();
+        for entry in &entries {
+            let frame = match parse_raw_frame(&mut reader, entry) {
+                Ok(frame) => frame,
+                // Past the first video frame a broken frame only ends the recording early
+                Err(_) if codec != VideoCaptureFormat::Stats => break,
+                Err(e) => return Err(e),
+            };
+
+            if frame.format == VideoCaptureFormat::Stats {
+                continue;
+            }
+            if codec == VideoCaptureFormat::Stats {
+                codec = frame.format;
+            }
+            if !matches!(codec, VideoCaptureFormat::H264 | VideoCaptureFormat::H265) {
+                break;
+            }
+
+            if frame.format == codec {
+                let after_sps = parameter_sets.sps.is_some() && is_keyframe(codec, &frame.raw_data);
+                parameter_sets.merge(ParameterSets::find(codec, &frame.raw_data));
+                if parameter_sets.is_complete(codec) || after_sps {
+                    break;
+                }
+            }
+        }
+
+        Ok(VrawFrameSource {
+            reader,
+            session,
+            entries,
+            position: 0,
+            codec,
+            parameter_sets,
+            session_starts,
+            timestamp_shift: 0,
+            last_video: None,
+            sorted: false,
+        })
+    }
+
+    /// The number of frames in the index, including non-video frames.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Reads the frame at this position in the index, e.g. to jump to "frame 500 of
+    /// [`Self::len`]" without reading the frames before it. This does not change where
+    /// [`FrameSource::next_frame`] continues.
+    ///
+    /// The frame is returned as it is stored: a coded frame may only decode after the
+    /// keyframe before it, which is up to the caller, and with [`Session::All`] its
+    /// timestamp is not shifted to continue the previous session.
+    pub fn get(&mut self, index: usize) -> Result<RawFrame, ParseError> {
+        let entry = self.entries.get(index).ok_or(ParseError::MissingFrame {
+            index,
+            frames: self.entries.len(),
+        })?;
+
+        parse_raw_frame(&mut self.reader, entry)
+    }
+
+    /// The earliest and latest timestamp of the index, None if it is empty.
+    pub(crate) fn timestamp_span(&self) -> Option<(i64, i64)> {
+        let timestamps = self
+            .entries
+            .iter()
+            .map(|entry| entry.receive_timestamp.get());
+        Some((timestamps.clone().min()?, timestamps.max()?))
+    }
+
+    /// Returns true if the index contains no frames.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reads the frames that were not read yet in the order of their index timestamps
+    /// instead of index order, each session on its own. The sort is stable, and is applied
+    /// to the frames found by [`FrameSource::refresh`] as well.
+    pub(crate) fn sort_by_timestamp(&mut self) {
+        self.sorted = true;
+        self.sort_unread();
+    }
+
+    fn sort_unread(&mut self) {
+        let mut end = self.entries.len();
+        for start in self.session_starts.iter().rev().chain(&[0]) {
+            let start = (*start).max(self.position);
+            if start < end {
+                self.entries[start..end].sort_by_key(|entry| entry.receive_timestamp.get());
+            }
+            end = end.min(start);
+        }
+    }
+
+    /// Continues reading at the frame with this position in the index.
+    pub(crate) fn seek_frame(&mut self, index: usize) {
+        self.position = index.min(self.entries.len());
+    }
+}
+
+impl<R: Read + Seek> FrameSource for VrawFrameSource<R> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.codec
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.parameter_sets.vps.as_deref()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.parameter_sets.sps.as_deref()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.parameter_sets.pps.as_deref()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        let entry = self.entries.get(self.position)?;
+        let starts_session = self.session_starts.contains(&self.position);
+        self.position += 1;
+
+        let mut frame = match parse_raw_frame(&mut self.reader, entry) {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if starts_session {
+            if let Some((last, duration)) = self.last_video {
+                self.timestamp_shift = last + duration - frame.timestamp;
+            }
+        }
+        frame.timestamp += self.timestamp_shift;
+
+        if frame.format == self.codec {
+            let duration = self
+                .last_video
+                .map(|(last, _)| frame.timestamp - last)
+                .unwrap_or(0);
+            self.last_video = Some((frame.timestamp, duration));
+        }
+
+        Some(Ok(frame))
+    }
+
+    /// Reads the index again. Only helps if the recorder rewrites the index while
+    /// recording, frames that are not listed in an index cannot be found.
+    fn refresh(&mut self) -> Result<usize, ParseError> {
+        let (entries, session_starts) = read_session(&mut self.reader, self.session)?;
+        let new_frames = entries.len().saturating_sub(self.entries.len());
+        if new_frames > 0 {
+            self.entries = entries;
+            self.session_starts = session_starts;
+            if self.sorted {
+                self.sort_unread();
+            }
+        }
+
+        Ok(new_frames)
+    }
+}
+
+/// The index entries of `session`, and where the sessions after the first one start in
+/// them.
+fn read_session<R: Read + Seek>(
+    reader: &mut R,
+    session: Session,
+) -> Result<(Vec<RecordingIndexEntry>, Vec<usize>), ParseError> {
+    match session {
+        Session::Last => Ok((read_index(reader)?, Vec::new())),
+        _ => session.select(read_all_indexes(reader)?),
+    }
+}
+
+/// A [`FrameSource`] that waits for `inner` to grow when it is exhausted, see
+/// [`crate::ConvertOptions::follow_interval`]. It ends once `inner` did not grow for a
+/// whole `interval`.
+pub(crate) struct Follow<S> {
+    inner: S,
+    interval: Option<Duration>,
+}
+
+impl<S: FrameSource> Follow<S> {
+    /// Wraps `inner`, passing its frames through unchanged if there is no `interval`.
+    pub(crate) fn new(inner: S, interval: Option<Duration>) -> Self {
+        Follow { inner, interval }
+    }
+}
+
+impl<S: FrameSource> FrameSource for Follow<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        loop {
+            if let Some(frame) = self.inner.next_frame() {
+                return Some(frame);
+            }
+
+            thread::sleep(self.interval?);
+            match self.inner.refresh() {
+                Ok(0) => return None,
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
+
+/// A [`FrameSource`] that drops the H.265 frames of `inner` whose slices have a temporal
+/// id above `max_temporal_id`, keeping the lower temporal sublayers of the stream.
+pub(crate) struct MaxTemporalId<S> {
+    inner: S,
+    max_temporal_id: Option<u8>,
+    dropped: usize,
+}
+
+impl<S: FrameSource> MaxTemporalId<S> {
+    /// Wraps `inner`, passing its frames through unchanged if there is no
+    /// `max_temporal_id`.
+    pub(crate) fn new(inner: S, max_temporal_id: Option<u8>) -> Self {
+        MaxTemporalId {
+            inner,
+            max_temporal_id,
+            dropped: 0,
+        }
+    }
+
+    /// The number of video frames dropped so far.
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The wrapped source.
+    #[cfg_attr(not(feature = "content-hash"), allow(dead_code))]
+    pub(crate) fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: FrameSource> FrameSource for MaxTemporalId<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        let Some(max_temporal_id) = self.max_temporal_id else {
+            return self.inner.next_frame();
+        };
+
+        loop {
+            match self.inner.next_frame()? {
+                Ok(frame)
+                    if frame.format == VideoCaptureFormat::H265
+                        && frame.format == self.codec()
+                        && h265_frame_temporal_id(&frame.raw_data)
+                            .is_some_and(|temporal_id| temporal_id > max_temporal_id) =>
+                {
+                    self.dropped += 1;
+                }
+                frame => return Some(frame),
+            }
+        }
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
+
+/// A [`FrameSource`] that keeps about every `stride`th video frame of `inner` for a
+/// time-lapse, see [`crate::ConvertOptions::timelapse_stride`]. Only keyframes are kept
+/// (unless `stride` is 1), the first one at or after every multiple of `stride`, and the
+/// kept frames are retimed to the average frame interval of the frames they stand for.
+pub(crate) struct Timelapse<S> {
+    inner: S,
+    stride: Option<usize>,
+    position: usize,
+    next_target: usize,
+    // Source position and timestamp as well as output timestamp of the last kept frame
+    last_kept: Option<(usize, i64, i64)>,
+    kept: usize,
+}
+
+impl<S: FrameSource> Timelapse<S> {
+    /// Wraps `inner`, passing its frames through unchanged if there is no `stride`.
+    pub(crate) fn new(inner: S, stride: Option<usize>) -> Self {
+        Timelapse {
+            inner,
+            stride,
+            position: 0,
+            next_target: 0,
+            last_kept: None,
+            kept: 0,
+        }
+    }
+
+    /// The number of video frames seen so far divided by the number kept, None without a
+    /// `stride` or before the first kept frame.
+    pub(crate) fn speedup(&self) -> Option<f64> {
+        self.stride?;
+        (self.kept > 0).then(|| self.position as f64 / self.kept as f64)
+    }
+
+    /// The wrapped source.
+    pub(crate) fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: FrameSource> FrameSource for Timelapse<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        let Some(stride) = self.stride else {
+            return self.inner.next_frame();
+        };
+
+        loop {
+            let mut frame = match self.inner.next_frame()? {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if frame.format != self.codec() {
+                // Stats frames show up at the last kept frame, where e.g. a chapter marker
+                // applies to the next kept keyframe
+                if let Some((_, _, last_output)) = self.last_kept {
+                    frame.timestamp = last_output;
+                }
+                return Some(Ok(frame));
+            }
+
+            let position = self.position;
+            self.position += 1;
+            if position < self.next_target
+                || (stride > 1 && !is_keyframe(frame.format, &frame.raw_data))
+            {
+                continue;
+            }
+            self.next_target = (position / stride + 1) * stride;
+
+            let output = match self.last_kept {
+                Some((last_position, last_timestamp, last_output)) => {
+                    last_output
+                        + (frame.timestamp - last_timestamp) / (position - last_position) as i64
+                }
+                None => frame.timestamp,
+            };
+            self.last_kept = Some((position, frame.timestamp, output));
+            self.kept += 1;
+
+            frame.timestamp = output;
+            return Some(Ok(frame));
+        }
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
+
+/// A [`FrameSource`] that drops the video frames after the last keyframe of `inner`, which
+/// reference a keyframe that was never recorded.
+///
+/// The frames following a keyframe are held back until the next keyframe shows up, so up
+/// to a GOP of frames is buffered.
+pub(crate) struct DropTrailingPartialGop<S> {
+    inner: S,
+    enabled: bool,
+    held_back: VecDeque<Result<RawFrame, ParseError>>,
+    released: usize,
+    dropped: usize,
+}
+
+impl<S: FrameSource> DropTrailingPartialGop<S> {
+    /// Wraps `inner`, passing its frames through unchanged if not `enabled`.
+    pub(crate) fn new(inner: S, enabled: bool) -> Self {
+        DropTrailingPartialGop {
+            inner,
+            enabled,
+            held_back: VecDeque::new(),
+            released: 0,
+            dropped: 0,
+        }
+    }
+
+    /// The number of video frames dropped so far.
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The wrapped source.
+    pub(crate) fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: FrameSource> FrameSource for DropTrailingPartialGop<S> {
+    fn codec(&self) -> VideoCaptureFormat {
+        self.inner.codec()
+    }
+
+    fn next_frame(&mut self) -> Option<Result<RawFrame, ParseError>> {
+        if !self.enabled {
+            return self.inner.next_frame();
+        }
+
+        loop {
+            if self.released > 0 {
+                self.released -= 1;
+                return self.held_back.pop_front();
+            }
+
+            match self.inner.next_frame() {
+                Some(Ok(frame))
+                    if frame.format == self.codec()
+                        && is_keyframe(frame.format, &frame.raw_data) =>
+                {
+                    // Everything held back is followed by a keyframe now
+                    self.held_back.push_back(Ok(frame));
+                    self.released = self.held_back.len();
+                }
+                Some(frame) => self.held_back.push_back(frame),
+                None => {
+                    let codec = self.codec();
+                    let held_back = self.held_back.len();
+                    self.held_back
+                        .retain(|frame| !matches!(frame, Ok(frame) if frame.format == codec));
+                    self.dropped += held_back - self.held_back.len();
+
+                    return self.held_back.pop_front();
+                }
+            }
+        }
+    }
+
+    fn vps(&self) -> Option<&[u8]> {
+        self.inner.vps()
+    }
+
+    fn sps(&self) -> Option<&[u8]> {
+        self.inner.sps()
+    }
+
+    fn pps(&self) -> Option<&[u8]> {
+        self.inner.pps()
+    }
+}
@@ -0,0 +1,44 @@
+use crate::parser::{ParseError, RawFrame, VideoCaptureFormat};
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::File;
+use std::io::BufReader;
+
+/// A [`VideoCaptureFormat::Stats`] frame. The layout of the payload is not part of the
+/// recording format, so it is kept as is.
+#[derive(Debug, Clone)]
+pub struct StatsFrame {
+    /// Receive time in nanoseconds, on the same clock as [`RawFrame::timestamp`].
+    pub timestamp: i64,
+
+    /// The stats payload.
+    pub raw_data: Vec<u8>,
+}
+
+/// Returns every video frame of a .vraw file together with the closest stats frame before
+/// it in the index, or None if no stats frame precedes it. A stats frame is attached to all
+/// video frames up to the next stats frame.
+///
+/// input: path to .vraw file
+pub fn pair_stats_with_frames(
+    input: &str,
+) -> Result<Vec<(RawFrame, Option<StatsFrame>)>, ParseError> {
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+
+    let mut pairs = Vec::new();
+    let mut last_stats = None;
+
+    while let Some(frame) = source.next_frame() {
+        let frame = frame?;
+
+        if frame.format == VideoCaptureFormat::Stats {
+            last_stats = Some(StatsFrame {
+                timestamp: frame.timestamp,
+                raw_data: frame.raw_data,
+            });
+        } else {
+            pairs.push((frame, last_stats.clone()));
+        }
+    }
+
+    Ok(pairs)
+}
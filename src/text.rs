@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// The UTF-8 encoding of the byte order mark U+FEFF.
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+
+/// The line ending of a text export, see [`TextOutputOptions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`, as on Linux and macOS.
+    #[default]
+    Lf,
+
+    /// `\r\n`, as on Windows and as some subtitle players expect.
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// How the text exports (e.g. [`crate::write_exposure_vtt_with_options`]) write their
+/// files, for tools that expect a particular platform's conventions. By default lines end
+/// in `\n` and there is no byte order mark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextOutputOptions {
+    /// The line ending of every line. Line breaks in text passed in by the caller (e.g. a
+    /// cue template), whether `\n`, `\r\n` or a lone `\r`, are written with it as well.
+    pub line_ending: LineEnding,
+
+    /// Start the file with a UTF-8 byte order mark, which some Windows tools need to
+    /// detect the encoding and others choke on.
+    pub bom: bool,
+}
+
+/// Writes `text`, whose lines may end in `\n`, `\r\n` or `\r`, to a new file at `path`
+/// with the line endings and byte order mark of `options`.
+pub(crate) fn write_text_file(
+    path: &str,
+    text: &str,
+    options: &TextOutputOptions,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    if options.bom {
+        writer.write_all(UTF8_BOM)?;
+    }
+
+    let line_ending = options.line_ending.as_str();
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        for (i, part) in line.split('\r').enumerate() {
+            if i > 0 {
+                writer.write_all(line_ending.as_bytes())?;
+            }
+            writer.write_all(part.as_bytes())?;
+        }
+        if lines.peek().is_some() {
+            writer.write_all(line_ending.as_bytes())?;
+        }
+    }
+
+    writer.flush()
+}
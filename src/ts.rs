@@ -0,0 +1,288 @@
+use crate::error::ConvertError;
+use crate::nal::{h264_nal_type, h265_nal_type, is_keyframe, nal_units};
+use crate::parser::VideoCaptureFormat;
+use crate::processing::RemoveOnDrop;
+use crate::source::{FrameSource, VrawFrameSource};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+
+/// The PTS and PCR clock, 90 kHz.
+const CLOCK_RATE: i64 = 90_000;
+
+/// How long the PTS of a frame lies after the PCR of the packet carrying it, for the
+/// decoder buffer to fill. One second, in ticks of [`CLOCK_RATE`].
+const DECODER_DELAY: u64 = 90_000;
+
+/// Converts a .vraw file to an MPEG-2 transport stream (.ts) with a single program
+/// holding the video, for ingest systems that do not accept mp4.
+///
+/// Every video frame becomes one PES packet whose PTS is its timestamp relative to the
+/// first video frame in 90 kHz ticks, plus a second for the decoder buffer. The first TS
+/// packet of every frame carries the PCR, and a PAT and PMT are repeated before every
+/// keyframe, whose first packet is marked with the random access indicator, so a
+/// receiver can start at any keyframe. An access unit delimiter is added to frames that
+/// do not start with one, as transport streams require. Stats frames are left out.
+///
+/// input: path to .vraw file
+///
+/// output: path of the .ts file to create. It is removed again if the conversion fails.
+///
+/// Returns the number of video frames written.
+pub fn convert_vraw_to_ts(input: &str, output: &str) -> Result<usize, ConvertError> {
+    let mut source = VrawFrameSource::new(BufReader::new(File::open(input)?))?;
+    if source.is_empty() {
+        return Err(ConvertError::NoFrames);
+    }
+
+    let codec = source.codec();
+    let stream_type = match codec {
+        VideoCaptureFormat::H264 => 0x1b,
+        VideoCaptureFormat::H265 => 0x24,
+        _ => return Err(ConvertError::UnsupportedFormat(codec)),
+    };
+
+    let mut partial_output = RemoveOnDrop(Some(output));
+    let mut writer = TsWriter {
+        writer: BufWriter::new(File::create(output)?),
+        continuity_counters: [0; 3],
+    };
+
+    let mut first_timestamp = None;
+    let mut frames = 0;
+    while let Some(frame) = source.next_frame() {
+        let Ok(frame) = frame else {
+            // A broken frame ends the recording, as for the conversion
+            break;
+        };
+        if frame.format != codec || frame.raw_data.is_empty() {
+            continue;
+        }
+
+        let first_timestamp = *first_timestamp.get_or_insert(frame.timestamp);
+        let time = ((frame.timestamp - first_timestamp).max(0) as i128 * CLOCK_RATE as i128
+            / 1_000_000_000) as u64;
+
+        let keyframe = is_keyframe(codec, &frame.raw_data);
+        if keyframe || frames == 0 {
+            writer.write_section(PAT_PID, &pat())?;
+            writer.write_section(PMT_PID, &pmt(stream_type))?;
+        }
+
+        let mut pes = pes_header(time + DECODER_DELAY);
+        if !starts_with_access_unit_delimiter(codec, &frame.raw_data) {
+            pes.extend_from_slice(match codec {
+                VideoCaptureFormat::H264 => &[0, 0, 0, 1, 0x09, 0xf0][..],
+                _ => &[0, 0, 0, 1, 0x46, 0x01, 0x50],
+            });
+        }
+        pes.extend_from_slice(&frame.raw_data);
+        writer.write_pes(&pes, time, keyframe)?;
+
+        frames += 1;
+    }
+
+    writer.writer.flush()?;
+    partial_output.disarm();
+
+    Ok(frames)
+}
+
+/// Writes TS packets, counting the packets of every PID.
+struct TsWriter<W> {
+    writer: W,
+    continuity_counters: [u8; 3],
+}
+
+impl<W: Write> TsWriter<W> {
+    /// Writes a PSI section in a single packet, which the PAT and PMT always fit.
+    fn write_section(&mut self, pid: u16, section: &[u8]) -> io::Result<()> {
+        // The pointer field, then stuffing bytes after the section
+        let mut payload = vec![0xff; PACKET_SIZE - 4];
+        payload[0] = 0;
+        payload[1..=section.len()].copy_from_slice(section);
+
+        self.write_packet(pid, true, None, &payload)
+    }
+
+    /// Splits a PES packet into TS packets. The first one carries the PCR at `time` (in
+    /// 90 kHz ticks) and the random access indicator if `keyframe` is true, the last one
+    /// is filled up with stuffing bytes in its adaptation field.
+    fn write_pes(&mut self, pes: &[u8], time: u64, keyframe: bool) -> io::Result<()> {
+        let mut rest = pes;
+        let mut first = true;
+
+        while !rest.is_empty() {
+            // The adaptation field after its length byte
+            let mut adaptation = if first {
+                let mut adaptation = vec![0x10 | (keyframe as u8) << 6];
+                adaptation.extend_from_slice(&pcr(time));
+                Some(adaptation)
+            } else {
+                None
+            };
+
+            let room = PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| field.len() + 1);
+            if rest.len() < room {
+                let stuffing = room - rest.len();
+                match &mut adaptation {
+                    Some(field) => field.resize(field.len() + stuffing, 0xff),
+                    // The length byte alone takes the one byte left
+                    None if stuffing == 1 => adaptation = Some(Vec::new()),
+                    None => {
+                        let mut field = vec![0xff; stuffing - 1];
+                        field[0] = 0;
+                        adaptation = Some(field);
+                    }
+                }
+            }
+
+            let (payload, remaining) = rest.split_at(rest.len().min(room));
+            self.write_packet(VIDEO_PID, first, adaptation.as_deref(), payload)?;
+
+            rest = remaining;
+            first = false;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a single TS packet, which `adaptation` (without its length byte) and
+    /// `payload` have to fill exactly.
+    fn write_packet(
+        &mut self,
+        pid: u16,
+        payload_unit_start: bool,
+        adaptation: Option<&[u8]>,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let counter = &mut self.continuity_counters[match pid {
+            PAT_PID => 0,
+            PMT_PID => 1,
+            _ => 2,
+        }];
+
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        packet.push(SYNC_BYTE);
+        packet.push((payload_unit_start as u8) << 6 | (pid >> 8) as u8 & 0x1f);
+        packet.push(pid as u8);
+        packet.push((adaptation.is_some() as u8) << 5 | 0x10 | *counter);
+        if let Some(adaptation) = adaptation {
+            packet.push(adaptation.len() as u8);
+            packet.extend_from_slice(adaptation);
+        }
+        packet.extend_from_slice(payload);
+        debug_assert_eq!(packet.len(), PACKET_SIZE);
+
+        *counter = (*counter + 1) & 0x0f;
+        self.writer.write_all(&packet)
+    }
+}
+
+/// The program association table, listing the single program.
+fn pat() -> Vec<u8> {
+    let mut program = PROGRAM_NUMBER.to_be_bytes().to_vec();
+    program.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+
+    psi_section(0x00, 1, &program)
+}
+
+/// The program map table of the program, with the video as its only stream.
+fn pmt(stream_type: u8) -> Vec<u8> {
+    let mut program = (0xe000 | VIDEO_PID).to_be_bytes().to_vec();
+    // No program descriptors
+    program.extend_from_slice(&[0xf0, 0x00]);
+    program.push(stream_type);
+    program.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes());
+    // No stream descriptors
+    program.extend_from_slice(&[0xf0, 0x00]);
+
+    psi_section(0x02, PROGRAM_NUMBER, &program)
+}
+
+/// A PSI section with the syntax of the PAT and PMT, version 0 and its CRC.
+fn psi_section(table_id: u8, table_id_extension: u16, data: &[u8]) -> Vec<u8> {
+    // The bytes after the length up to the data, the data and the CRC
+    let section_length = 5 + data.len() + 4;
+
+    let mut section = vec![table_id];
+    section.extend_from_slice(&(0xb000 | section_length as u16).to_be_bytes());
+    section.extend_from_slice(&table_id_extension.to_be_bytes());
+    // Version 0, current, section 0 of 0
+    section.extend_from_slice(&[0xc1, 0x00, 0x00]);
+    section.extend_from_slice(data);
+    section.extend_from_slice(&crc32_mpeg2(&section).to_be_bytes());
+
+    section
+}
+
+/// The header of a video PES packet with a PTS. The length is left at 0, which is allowed
+/// for video and keeps frames of any size in a single packet.
+fn pes_header(pts: u64) -> Vec<u8> {
+    let pts = pts & 0x1_ffff_ffff;
+
+    vec![
+        0x00,
+        0x00,
+        0x01,
+        0xe0,
+        0x00,
+        0x00,
+        0x80,
+        0x80,
+        5,
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | ((pts >> 14) & 0xfe) as u8,
+        (pts >> 7) as u8,
+        0x01 | ((pts << 1) & 0xfe) as u8,
+    ]
+}
+
+/// The program clock reference field of an adaptation field, with no 27 MHz extension.
+fn pcr(time: u64) -> [u8; 6] {
+    let base = time & 0x1_ffff_ffff;
+
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base & 1) as u8) << 7 | 0x7e,
+        0x00,
+    ]
+}
+
+/// Returns true if the first NAL unit of a frame is an access unit delimiter.
+fn starts_with_access_unit_delimiter(format: VideoCaptureFormat, data: &[u8]) -> bool {
+    match nal_units(data).first() {
+        Some(nal) if format == VideoCaptureFormat::H264 => h264_nal_type(nal) == 9,
+        Some(nal) => h265_nal_type(nal) == 35,
+        None => false,
+    }
+}
+
+/// The CRC-32 of MPEG-2 PSI sections: polynomial 0x04C11DB7, not reflected.
+pub(crate) fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
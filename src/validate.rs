@@ -0,0 +1,146 @@
+use crate::boxes::{read_freeform_tags, Moov};
+use crate::error::ConvertError;
+use mp4::{Mp4Reader, Mp4Track};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+
+/// `sample_is_non_sync_sample` in the sample flags of a track fragment
+const NON_SYNC_SAMPLE_FLAG: u32 = 0x0001_0000;
+
+/// What [`validate_mp4`] found in an mp4 file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mp4Report {
+    /// The samples are stored in `moof`/`mdat` fragments instead of the `moov` box.
+    pub fragmented: bool,
+
+    /// Duration of the longest track.
+    pub duration: Duration,
+
+    /// The tracks of the file, ordered by track id.
+    pub tracks: Vec<TrackReport>,
+
+    /// The sample tables of every track agree on the number of samples and have chunk
+    /// offsets. Always true for fragmented files, whose sample tables are empty.
+    pub valid_moov: bool,
+
+    /// The entries of [`crate::ConvertOptions::metadata`] found in the file, empty for
+    /// files with no such entries.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A single track of an [`Mp4Report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackReport {
+    pub track_id: u32,
+
+    /// The type of the sample entry (e.g. `hev1` or `avc1`), None if the mp4 crate does
+    /// not know it.
+    pub codec: Option<String>,
+
+    /// Number of samples, including the ones in fragments.
+    pub sample_count: u32,
+
+    /// Duration of the track, including the samples in fragments.
+    pub duration: Duration,
+
+    /// At least one sample can be decoded on its own.
+    pub has_sync_samples: bool,
+}
+
+/// Opens an mp4 file (written by this crate or any other tool) and reports its tracks,
+/// to sanity check it without decoding any sample.
+///
+/// path: path to the .mp4 file
+pub fn validate_mp4(path: &str) -> Result<Mp4Report, ConvertError> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mp4 = Mp4Reader::read_header(BufReader::new(file), size)?;
+
+    // The mp4 crate only reads the iTunes tags it knows
+    let metadata = Moov::read_anywhere(&mut BufReader::new(File::open(path)?))?
+        .get(&[b"udta", b"meta", b"ilst"])
+        .map(read_freeform_tags)
+        .unwrap_or_default();
+
+    let mut tracks: Vec<&Mp4Track> = mp4.tracks().values().collect();
+    tracks.sort_by_key(|track| track.track_id());
+
+    Ok(Mp4Report {
+        fragmented: mp4.is_fragmented(),
+        duration: tracks
+            .iter()
+            .map(|track| track_duration(track))
+            .max()
+            .unwrap_or(Duration::ZERO),
+        valid_moov: tracks.iter().all(|track| has_valid_sample_tables(track)),
+        tracks: tracks
+            .iter()
+            .map(|track| TrackReport {
+                track_id: track.track_id(),
+                codec: track.box_type().ok().map(|box_type| box_type.to_string()),
+                sample_count: track.sample_count(),
+                duration: track_duration(track),
+                has_sync_samples: has_sync_samples(track),
+            })
+            .collect(),
+        metadata,
+    })
+}
+
+fn track_duration(track: &Mp4Track) -> Duration {
+    if track.trafs.is_empty() {
+        return track.duration();
+    }
+
+    let ticks: u64 = track
+        .trafs
+        .iter()
+        .filter_map(|traf| traf.trun.as_ref())
+        .map(|trun| {
+            if trun.sample_durations.is_empty() {
+                trun.sample_count as u64 * track.default_sample_duration as u64
+            } else {
+                trun.sample_durations.iter().map(|&d| d as u64).sum()
+            }
+        })
+        .sum();
+
+    Duration::from_micros(ticks * 1_000_000 / track.timescale().max(1) as u64)
+}
+
+fn has_valid_sample_tables(track: &Mp4Track) -> bool {
+    let stbl = &track.trak.mdia.minf.stbl;
+
+    let timed_samples: u64 = stbl
+        .stts
+        .entries
+        .iter()
+        .map(|entry| entry.sample_count as u64)
+        .sum();
+
+    timed_samples == stbl.stsz.sample_count as u64
+        && (stbl.stsz.sample_count == 0 || stbl.stco.is_some() || stbl.co64.is_some())
+}
+
+fn has_sync_samples(track: &Mp4Track) -> bool {
+    if !track.trafs.is_empty() {
+        return track
+            .trafs
+            .iter()
+            .filter_map(|traf| traf.trun.as_ref())
+            .any(|trun| {
+                trun.first_sample_flags
+                    .iter()
+                    .chain(&trun.sample_flags)
+                    .any(|flags| flags & NON_SYNC_SAMPLE_FLAG == 0)
+            });
+    }
+
+    // Without a sync sample table every sample is a sync sample
+    match &track.trak.mdia.minf.stbl.stss {
+        Some(stss) => !stss.entries.is_empty(),
+        None => track.sample_count() > 0,
+    }
+}